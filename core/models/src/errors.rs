@@ -81,6 +81,9 @@ pub enum ErrorKind {
     CpuSettings = 69,
     Firmware = 70,
     Timeout = 71,
+    UntrustedSignature = 72,
+    Acme = 73,
+    WireGuard = 74,
 }
 impl ErrorKind {
     pub fn as_str(&self) -> &'static str {
@@ -157,6 +160,9 @@ impl ErrorKind {
             CpuSettings => "CPU Settings Error",
             Firmware => "Firmware Error",
             Timeout => "Timeout Error",
+            UntrustedSignature => "Package Not Signed by a Trusted Key",
+            Acme => "ACME Error",
+            WireGuard => "WireGuard Error",
         }
     }
 }
@@ -0,0 +1,5 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS backup_schedule (
+    id SERIAL PRIMARY KEY CHECK (id = 0),
+    password TEXT NOT NULL
+);
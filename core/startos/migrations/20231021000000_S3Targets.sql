@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS s3_targets (
+    id SERIAL PRIMARY KEY,
+    endpoint TEXT NOT NULL,
+    bucket TEXT NOT NULL,
+    prefix TEXT NOT NULL DEFAULT '',
+    access_key_id TEXT NOT NULL,
+    secret_access_key TEXT NOT NULL
+);
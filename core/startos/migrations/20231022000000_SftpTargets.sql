@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS sftp_targets (
+    id SERIAL PRIMARY KEY,
+    hostname TEXT NOT NULL,
+    port INTEGER NOT NULL DEFAULT 22 CHECK (port > 0 AND port < 65536),
+    path TEXT NOT NULL,
+    username TEXT NOT NULL
+);
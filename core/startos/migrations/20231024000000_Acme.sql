@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS acme_accounts (
+    directory_url TEXT PRIMARY KEY,
+    key_pem TEXT NOT NULL,
+    kid TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS acme_domains (
+    domain TEXT PRIMARY KEY,
+    directory_url TEXT NOT NULL,
+    challenge TEXT NOT NULL,
+    contact TEXT,
+    key_pem TEXT,
+    fullchain_pem TEXT,
+    created_at TEXT NOT NULL
+);
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS domain_bindings (
+    hostname TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    package TEXT NOT NULL,
+    interface TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (hostname, port)
+);
@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS wireguard (
+    id SERIAL PRIMARY KEY CHECK (id = 0),
+    private_key BYTEA NOT NULL,
+    listen_port INTEGER NOT NULL,
+    subnet TEXT NOT NULL,
+    endpoint TEXT,
+    enabled BOOLEAN NOT NULL
+);
+CREATE TABLE IF NOT EXISTS wireguard_peers (
+    name TEXT PRIMARY KEY,
+    private_key BYTEA NOT NULL,
+    preshared_key BYTEA NOT NULL,
+    address TEXT NOT NULL UNIQUE,
+    created_at TEXT NOT NULL
+);
@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS proxy_settings (
+    package TEXT NOT NULL,
+    interface TEXT NOT NULL,
+    forwarded_headers BOOLEAN NOT NULL,
+    auth_username TEXT,
+    auth_password_hash TEXT,
+    require_session BOOLEAN NOT NULL,
+    rate_limit INTEGER,
+    rate_limit_period INTEGER,
+    PRIMARY KEY (package, interface)
+);
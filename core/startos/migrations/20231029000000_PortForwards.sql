@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS port_forward_overrides (
+    package TEXT NOT NULL,
+    interface TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    external INTEGER,
+    PRIMARY KEY (package, interface, port)
+);
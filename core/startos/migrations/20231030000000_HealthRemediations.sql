@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS health_remediations (
+    id SERIAL PRIMARY KEY,
+    package TEXT NOT NULL,
+    health_check TEXT NOT NULL,
+    remediation TEXT NOT NULL,
+    performed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    error TEXT
+);
@@ -0,0 +1,4 @@
+-- Add migration script here
+DELETE FROM backup_schedule;
+ALTER TABLE backup_schedule DROP COLUMN password;
+ALTER TABLE backup_schedule ADD COLUMN key TEXT NOT NULL;
@@ -0,0 +1,4 @@
+-- Add migration script here
+DELETE FROM wireguard_peers;
+ALTER TABLE wireguard_peers DROP COLUMN private_key;
+ALTER TABLE wireguard_peers ADD COLUMN public_key TEXT NOT NULL;
@@ -14,6 +14,7 @@ use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
 use tracing::instrument;
 
+use super::schedule::BackupRetention;
 use super::target::BackupTargetId;
 use super::PackageBackupReport;
 use crate::auth::check_password_against_db;
@@ -93,7 +94,7 @@ pub async fn backup_all(
     }
     assure_backing_up(&ctx.db, &package_ids).await?;
     tokio::task::spawn(async move {
-        let backup_res = perform_backup(&ctx, backup_guard, &package_ids).await;
+        let backup_res = perform_backup(&ctx, backup_guard, &package_ids, None).await;
         match backup_res {
             Ok(report) if report.iter().all(|(_, rep)| rep.error.is_none()) => ctx
                 .notification_manager
@@ -176,7 +177,7 @@ pub async fn backup_all(
 }
 
 #[instrument(skip(db, packages))]
-async fn assure_backing_up(
+pub(super) async fn assure_backing_up(
     db: &PatchDb,
     packages: impl IntoIterator<Item = &(PackageId, Version)> + UnwindSafe + Send,
 ) -> Result<(), Error> {
@@ -214,10 +215,11 @@ async fn assure_backing_up(
 }
 
 #[instrument(skip(ctx, backup_guard))]
-async fn perform_backup(
+pub(super) async fn perform_backup(
     ctx: &RpcContext,
     backup_guard: BackupMountGuard<TmpMountGuard>,
     package_ids: &OrdSet<(PackageId, Version)>,
+    retention: Option<&BackupRetention>,
 ) -> Result<BTreeMap<(PackageId, Version), PackageBackupReport>, Error> {
     let mut backup_report = BTreeMap::new();
     let backup_guard = Arc::new(Mutex::new(backup_guard));
@@ -312,6 +314,10 @@ async fn perform_backup(
     backup_guard.metadata.version = crate::version::Current::new().semver().into();
     backup_guard.metadata.timestamp = timestamp;
 
+    if let Some(retention) = retention {
+        backup_guard.prune_snapshots(retention).await?;
+    }
+
     backup_guard.save_and_unmount().await?;
 
     ctx.db
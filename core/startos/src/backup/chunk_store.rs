@@ -0,0 +1,252 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use helpers::AtomicFile;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tracing::instrument;
+
+use crate::prelude::*;
+use crate::util::serde::{deserialize_from_str, serialize_display};
+
+pub const MIN_CHUNK_SIZE: usize = 512 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+const AVG_CHUNK_BITS: u32 = 20; // 1 MiB
+
+lazy_static::lazy_static! {
+    /// Random (but fixed) table for the gear rolling hash. Changing it invalidates deduplication
+    /// against every existing chunk store.
+    static ref GEAR: [u64; 256] = {
+        let mut state = 0x5374_6172_744f_5321_u64;
+        let mut table = [0_u64; 256];
+        for entry in table.iter_mut() {
+            // splitmix64
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *entry = z ^ (z >> 31);
+        }
+        table
+    };
+}
+
+/// Finds the end of the first content defined chunk in `buf`.
+/// Returns `None` if more data is needed to decide.
+pub fn find_boundary(buf: &[u8]) -> Option<usize> {
+    if buf.len() <= MIN_CHUNK_SIZE {
+        return None;
+    }
+    let mask = !0_u64 << (64 - AVG_CHUNK_BITS);
+    let mut hash = 0_u64;
+    for (idx, byte) in buf
+        .iter()
+        .enumerate()
+        .take(MAX_CHUNK_SIZE)
+        .skip(MIN_CHUNK_SIZE)
+    {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        if hash & mask == 0 {
+            return Some(idx + 1);
+        }
+    }
+    if buf.len() >= MAX_CHUNK_SIZE {
+        Some(MAX_CHUNK_SIZE)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChunkId([u8; 32]);
+impl ChunkId {
+    pub fn for_data(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+}
+impl std::fmt::Display for ChunkId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+impl std::str::FromStr for ChunkId {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut res = [0; 32];
+        hex::decode_to_slice(s, &mut res).with_kind(ErrorKind::Deserialization)?;
+        Ok(Self(res))
+    }
+}
+impl<'de> Deserialize<'de> for ChunkId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserialize_from_str(deserializer)
+    }
+}
+impl Serialize for ChunkId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serialize_display(self, serializer)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ChunkStats {
+    pub chunks: u64,
+    pub bytes: u64,
+    pub new_chunks: u64,
+    pub new_bytes: u64,
+}
+impl std::ops::AddAssign for ChunkStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.chunks += rhs.chunks;
+        self.bytes += rhs.bytes;
+        self.new_chunks += rhs.new_chunks;
+        self.new_bytes += rhs.new_bytes;
+    }
+}
+
+/// Content addressed store of file chunks, keyed by the sha256 of their contents.
+/// Lives inside the encrypted backup mount, so chunks are encrypted with the backup key.
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    path: PathBuf,
+}
+impl ChunkStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    pub fn chunk_path(&self, id: &ChunkId) -> PathBuf {
+        let id = id.to_string();
+        self.path.join(&id[..2]).join(id)
+    }
+
+    pub async fn contains(&self, id: &ChunkId) -> bool {
+        tokio::fs::metadata(self.chunk_path(id)).await.is_ok()
+    }
+
+    /// Returns whether the chunk was newly written
+    pub async fn put(&self, data: &[u8]) -> Result<(ChunkId, bool), Error> {
+        let id = ChunkId::for_data(data);
+        if self.contains(&id).await {
+            return Ok((id, false));
+        }
+        let path = self.chunk_path(&id);
+        let mut file = AtomicFile::new(&path, None::<PathBuf>)
+            .await
+            .with_kind(ErrorKind::Filesystem)?;
+        file.write_all(data).await?;
+        file.save().await.with_kind(ErrorKind::Filesystem)?;
+        Ok((id, true))
+    }
+
+    pub async fn get(&self, id: &ChunkId) -> Result<Vec<u8>, Error> {
+        let path = self.chunk_path(id);
+        let data = tokio::fs::read(&path)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+        if &ChunkId::for_data(&data) != id {
+            return Err(Error::new(
+                eyre!("Chunk {} is corrupted", id),
+                ErrorKind::Backup,
+            ));
+        }
+        Ok(data)
+    }
+
+    pub async fn remove(&self, id: &ChunkId) -> Result<(), Error> {
+        let path = self.chunk_path(id);
+        tokio::fs::remove_file(&path)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))
+    }
+
+    #[instrument(skip_all)]
+    pub async fn put_reader(
+        &self,
+        mut rdr: impl AsyncRead + Unpin,
+    ) -> Result<(Vec<ChunkId>, ChunkStats), Error> {
+        let mut chunks = Vec::new();
+        let mut stats = ChunkStats::default();
+        let mut buf = Vec::with_capacity(MAX_CHUNK_SIZE);
+        let mut eof = false;
+        loop {
+            while !eof && buf.len() < MAX_CHUNK_SIZE {
+                let mut rdr = (&mut rdr).take((MAX_CHUNK_SIZE - buf.len()) as u64);
+                if rdr.read_to_end(&mut buf).await? == 0 {
+                    eof = true;
+                }
+            }
+            let len = match find_boundary(&buf) {
+                Some(len) => len,
+                None if buf.is_empty() => break,
+                None => buf.len(),
+            };
+            let (id, new) = self.put(&buf[..len]).await?;
+            stats.chunks += 1;
+            stats.bytes += len as u64;
+            if new {
+                stats.new_chunks += 1;
+                stats.new_bytes += len as u64;
+            }
+            chunks.push(id);
+            buf.drain(..len);
+        }
+        Ok((chunks, stats))
+    }
+
+    pub async fn list(&self) -> Result<BTreeSet<ChunkId>, Error> {
+        let mut res = BTreeSet::new();
+        if tokio::fs::metadata(&self.path).await.is_err() {
+            return Ok(res);
+        }
+        let mut prefixes = tokio::fs::read_dir(&self.path).await?;
+        while let Some(prefix) = prefixes.next_entry().await? {
+            if !prefix.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut chunks = tokio::fs::read_dir(prefix.path()).await?;
+            while let Some(chunk) = chunks.next_entry().await? {
+                if let Some(id) = chunk
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.parse().ok())
+                {
+                    res.insert(id);
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[test]
+fn boundaries_resync_after_insertion() {
+    use rand::{RngCore, SeedableRng};
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let mut data = vec![0_u8; 8 * MAX_CHUNK_SIZE];
+    rng.fill_bytes(&mut data);
+    fn boundaries(mut data: &[u8]) -> Vec<usize> {
+        let mut res = Vec::new();
+        let mut pos = 0;
+        while let Some(len) = find_boundary(data) {
+            pos += len;
+            res.push(pos);
+            data = &data[len..];
+        }
+        res
+    }
+    let original = boundaries(&data);
+    data.splice(100..100, [1, 2, 3]);
+    let shifted = boundaries(&data);
+    assert!(original.iter().skip(1).any(|b| shifted.contains(&(b + 3))));
+}
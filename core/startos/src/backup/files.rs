@@ -0,0 +1,556 @@
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use chrono::{DateTime, TimeZone, Utc};
+use clap::ArgMatches;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::instrument;
+
+use super::snapshot::{Snapshot, SnapshotEntry, SnapshotEntryKind};
+use super::target::BackupTargetId;
+use crate::context::RpcContext;
+use crate::disk::mount::backup::BackupMountGuard;
+use crate::disk::mount::filesystem::ReadOnly;
+use crate::disk::mount::guard::TmpMountGuard;
+use crate::prelude::*;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::status::MainStatus;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::Invoke;
+use crate::volume::{Volume, VolumeId};
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackupFileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BackupFileInfo {
+    pub path: PathBuf,
+    #[serde(rename = "type")]
+    pub kind: BackupFileType,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileChange {
+    Create,
+    Update,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RestoredFile {
+    pub path: PathBuf,
+    pub change: FileChange,
+    /// The change summary reported by `rsync --itemize-changes`
+    pub flags: String,
+}
+
+fn parse_comma_separated(arg: &str, _: &ArgMatches) -> Result<Vec<PathBuf>, Error> {
+    Ok(arg.split(',').map(|s| PathBuf::from(s.trim())).collect())
+}
+
+/// Paths inside a package backup are relative, and may not escape it.
+/// Returns the path without `.` components, as it is keyed in a snapshot.
+fn normalize(path: &Path) -> Result<PathBuf, Error> {
+    if path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(path
+            .components()
+            .filter(|c| !matches!(c, Component::CurDir))
+            .collect())
+    } else {
+        Err(Error::new(
+            eyre!(
+                "{} is not a relative path inside the backup",
+                path.display()
+            ),
+            ErrorKind::InvalidRequest,
+        ))
+    }
+}
+
+/// The volume a path inside a package backup restores into, and the path inside that volume (empty for all of it).
+/// The backup volume is at `data`, and only the paths in it that the package declares in `backup.volumes`
+/// hold a copy of a volume: everything else is in whatever layout the package chose, and can not be restored in place.
+fn split_volume_path(
+    path: &Path,
+    volumes: &BTreeMap<PathBuf, VolumeId>,
+) -> Result<(VolumeId, PathBuf), Error> {
+    let path = normalize(path)?;
+    let Ok(in_backup) = path.strip_prefix("data") else {
+        return Err(Error::new(
+            eyre!(
+                "{} is not inside the backup volume: paths to restore start with data/",
+                path.display()
+            ),
+            ErrorKind::InvalidRequest,
+        ));
+    };
+    volumes
+        .iter()
+        .filter_map(|(prefix, volume)| Some((prefix, volume, in_backup.strip_prefix(prefix).ok()?)))
+        // the most specific mapping wins
+        .max_by_key(|(prefix, _, _)| prefix.components().count())
+        .map(|(_, volume, relative)| (volume.clone(), relative.to_owned()))
+        .ok_or_else(|| {
+            Error::new(
+                eyre!(
+                    "{} is not inside a path the package declares as a copy of one of its volumes",
+                    path.display()
+                ),
+                ErrorKind::InvalidRequest,
+            )
+        })
+}
+
+/// The source and destination for rsync that put `src`, which is `relative` inside its volume,
+/// at the same place inside `volume_path`
+fn rsync_endpoints(src: &Path, relative: &Path, volume_path: &Path) -> (String, PathBuf) {
+    match relative.parent() {
+        Some(parent) => (src.display().to_string(), volume_path.join(parent)),
+        // the whole volume: its contents are copied, rather than the directory itself
+        None => (format!("{}/", src.display()), volume_path.to_owned()),
+    }
+}
+
+/// The contents of a package backup
+enum PackageBackup {
+    Snapshot(Snapshot),
+    /// A full copy, on targets written before snapshots existed
+    Copy(PathBuf),
+}
+
+async fn mount_package_backup(
+    ctx: &RpcContext,
+    id: &PackageId,
+    target_id: BackupTargetId,
+    password: &str,
+    before: Option<DateTime<Utc>>,
+) -> Result<(BackupMountGuard<TmpMountGuard>, PackageBackup), Error> {
+    let fs = target_id
+        .load(ctx.secret_store.acquire().await?.as_mut())
+        .await?;
+    let backup_guard =
+        BackupMountGuard::mount_read_only(TmpMountGuard::mount(&fs, ReadOnly).await?, password)
+            .await?;
+    let backup = match backup_guard.package_snapshot(id, before).await {
+        Ok(Some(snapshot)) => PackageBackup::Snapshot(snapshot),
+        Ok(None) => PackageBackup::Copy(backup_guard.as_ref().join(id)),
+        Err(e) => {
+            backup_guard.unmount().await?;
+            return Err(e);
+        }
+    };
+    Ok((backup_guard, backup))
+}
+
+async fn remove_scratch(scratch: &Path) -> Result<(), Error> {
+    if tokio::fs::metadata(scratch).await.is_ok() {
+        tokio::fs::remove_dir_all(scratch).await.with_ctx(|_| {
+            (
+                ErrorKind::Filesystem,
+                format!("rm -rf {}", scratch.display()),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+#[command(display(display_serializable))]
+#[instrument(skip(ctx, password))]
+pub async fn browse(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(rename = "target-id")] target_id: BackupTargetId,
+    #[arg] password: String,
+    #[arg] before: Option<DateTime<Utc>>,
+    #[arg] path: Option<PathBuf>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<BackupFileInfo>, Error> {
+    let path = normalize(&path.unwrap_or_default())?;
+    let (backup_guard, backup) =
+        mount_package_backup(&ctx, &id, target_id, &password, before).await?;
+    let res = match &backup {
+        PackageBackup::Snapshot(snapshot) => list_snapshot_dir(snapshot, &path),
+        PackageBackup::Copy(root) => list_dir(root, &path).await,
+    };
+    backup_guard.unmount().await?;
+    res
+}
+
+fn list_snapshot_dir(snapshot: &Snapshot, path: &Path) -> Result<Vec<BackupFileInfo>, Error> {
+    let entry = snapshot.entries.get(path).ok_or_else(|| {
+        Error::new(
+            eyre!("{} not found in backup", path.display()),
+            ErrorKind::NotFound,
+        )
+    })?;
+    if !matches!(entry.kind, SnapshotEntryKind::Directory) {
+        return Ok(vec![snapshot_file_info(path.to_owned(), entry)]);
+    }
+    Ok(snapshot
+        .entries
+        .range::<Path, _>(path..)
+        .skip(1)
+        .take_while(|(p, _)| p.starts_with(path))
+        .filter(|(p, _)| p.parent() == Some(path))
+        .map(|(p, e)| snapshot_file_info(p.clone(), e))
+        .collect())
+}
+
+fn snapshot_file_info(path: PathBuf, entry: &SnapshotEntry) -> BackupFileInfo {
+    BackupFileInfo {
+        path,
+        kind: match entry.kind {
+            SnapshotEntryKind::Directory => BackupFileType::Directory,
+            SnapshotEntryKind::File { .. } => BackupFileType::File,
+            SnapshotEntryKind::Symlink { .. } => BackupFileType::Symlink,
+        },
+        size: match entry.kind {
+            SnapshotEntryKind::File { size, .. } => size,
+            _ => 0,
+        },
+        modified: Utc
+            .timestamp_opt(entry.mtime, entry.mtime_nsec as u32)
+            .single(),
+    }
+}
+
+async fn list_dir(root: &Path, path: &Path) -> Result<Vec<BackupFileInfo>, Error> {
+    let full_path = root.join(path);
+    let metadata = tokio::fs::symlink_metadata(&full_path)
+        .await
+        .with_ctx(|_| (ErrorKind::NotFound, path.display().to_string()))?;
+    if !metadata.is_dir() {
+        return Ok(vec![file_info(path.to_owned(), &metadata)]);
+    }
+    let mut res = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(&full_path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, full_path.display().to_string()))?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let metadata = tokio::fs::symlink_metadata(entry.path()).await?;
+        res.push(file_info(path.join(entry.file_name()), &metadata));
+    }
+    res.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(res)
+}
+
+fn file_info(path: PathBuf, metadata: &std::fs::Metadata) -> BackupFileInfo {
+    BackupFileInfo {
+        path,
+        kind: if metadata.is_dir() {
+            BackupFileType::Directory
+        } else if metadata.is_symlink() {
+            BackupFileType::Symlink
+        } else {
+            BackupFileType::File
+        },
+        size: metadata.len(),
+        modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+    }
+}
+
+/// Copies `paths` from a package backup into the live volumes of the package.
+/// Each path is inside one of the `backup.volumes` the package declares, and is restored to the same place in that volume.
+/// Nothing is deleted from the volumes: existing files are overwritten, missing files are created.
+#[command(rename = "restore-files", display(display_serializable))]
+#[instrument(skip(ctx, password))]
+pub async fn restore_files(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(rename = "target-id")] target_id: BackupTargetId,
+    #[arg] password: String,
+    #[arg] before: Option<DateTime<Utc>>,
+    #[arg(parse(parse_comma_separated))] paths: Vec<PathBuf>,
+    #[arg(rename = "dry-run", long = "dry-run", default)] dry_run: bool,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<RestoredFile>, Error> {
+    let manifest: Manifest = {
+        let peek = ctx.db.peek().await;
+        let installed = peek
+            .as_package_data()
+            .as_idx(&id)
+            .or_not_found(&id)?
+            .expect_as_installed()?
+            .as_installed();
+        if !matches!(installed.as_status().as_main().de()?, MainStatus::Stopped) {
+            return Err(Error::new(
+                eyre!("{} must be stopped to restore files into it", id),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        installed.as_manifest().de()?
+    };
+    if manifest.backup.volumes.is_empty() {
+        return Err(Error::new(
+            eyre!(
+                "{} does not declare which parts of its backup are copies of its volumes",
+                id
+            ),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let paths = paths
+        .iter()
+        .map(|p| normalize(p))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut destinations = Vec::with_capacity(paths.len());
+    for path in &paths {
+        let (volume, relative) = split_volume_path(path, &manifest.backup.volumes)?;
+        destinations.push((relative, volume_path(&ctx, &manifest, &volume)?));
+    }
+
+    let (backup_guard, backup) =
+        mount_package_backup(&ctx, &id, target_id, &password, before).await?;
+    // unique, so concurrent restores do not remove each other's files
+    let scratch = ctx.datadir.join("package-data/tmp").join(format!(
+        "backup-files-{}-{:016x}",
+        id,
+        rand::random::<u64>()
+    ));
+    let res = async {
+        // only the requested paths are taken out of the snapshot
+        let root = match &backup {
+            PackageBackup::Snapshot(snapshot) => {
+                snapshot
+                    .subset(paths.iter().map(|p| p.as_path()))
+                    .restore(&backup_guard.chunk_store(), &scratch)
+                    .await?;
+                &scratch
+            }
+            PackageBackup::Copy(root) => root,
+        };
+        let mut res = Vec::new();
+        for (path, (relative, volume_path)) in paths.iter().zip(&destinations) {
+            res.extend(rsync_path(root, path, relative, volume_path, dry_run).await?);
+        }
+        Ok::<_, Error>(res)
+    }
+    .await;
+    remove_scratch(&scratch).await?;
+    backup_guard.unmount().await?;
+    res
+}
+
+/// The live directory of a data volume
+fn volume_path(ctx: &RpcContext, manifest: &Manifest, volume: &VolumeId) -> Result<PathBuf, Error> {
+    let (volume_id, volume) = manifest.volumes.get_key_value(volume).ok_or_else(|| {
+        Error::new(
+            eyre!("{} has no volume {}", manifest.id, volume),
+            ErrorKind::NotFound,
+        )
+    })?;
+    if !matches!(volume, Volume::Data { .. }) {
+        return Err(Error::new(
+            eyre!(
+                "Volume {} of {} is not a data volume",
+                volume_id,
+                manifest.id
+            ),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    Ok(volume.path_for(&ctx.datadir, &manifest.id, &manifest.version, volume_id))
+}
+
+#[instrument(skip_all)]
+async fn rsync_path(
+    root: &Path,
+    path: &Path,
+    relative: &Path,
+    volume_path: &Path,
+    dry_run: bool,
+) -> Result<Vec<RestoredFile>, Error> {
+    let src = root.join(path);
+    if tokio::fs::symlink_metadata(&src).await.is_err() {
+        return Err(Error::new(
+            eyre!("{} not found in backup", path.display()),
+            ErrorKind::NotFound,
+        ));
+    }
+    let (src, dst) = rsync_endpoints(&src, relative, volume_path);
+    if !dry_run {
+        tokio::fs::create_dir_all(&dst).await?;
+    }
+    let mut cmd = Command::new("rsync");
+    cmd.arg("-a").arg("--itemize-changes");
+    if dry_run {
+        cmd.arg("--dry-run");
+    }
+    let out = String::from_utf8(
+        cmd.arg(&src)
+            .arg(format!("{}/", dst.display()))
+            .invoke(ErrorKind::Filesystem)
+            .await?,
+    )?;
+    // rsync reports paths relative to the parent of what it copies, or to the volume itself when copying all of it
+    let prefix = if relative.parent().is_some() {
+        path.parent().unwrap_or(Path::new(""))
+    } else {
+        path
+    };
+    Ok(out
+        .lines()
+        .filter_map(parse_itemized)
+        .filter(|(_, file)| *file != ".")
+        .map(|(flags, file)| RestoredFile {
+            path: prefix.join(file),
+            change: if flags[2..].chars().all(|c| c == '+') {
+                FileChange::Create
+            } else {
+                FileChange::Update
+            },
+            flags: flags.to_owned(),
+        })
+        .collect())
+}
+
+/// Splits a line of `rsync --itemize-changes` output into its flags and path,
+/// skipping entries where nothing changes
+fn parse_itemized(line: &str) -> Option<(&str, &str)> {
+    let (flags, file) = line.split_once(' ')?;
+    if flags.len() < 3 || !flags.is_char_boundary(2) {
+        return None;
+    }
+    if flags.starts_with('.') && flags[2..].chars().all(|c| c == '.' || c == ' ') {
+        return None;
+    }
+    Some((flags, file.trim_end_matches('/')))
+}
+
+#[test]
+fn volume_paths() {
+    let volumes: BTreeMap<PathBuf, VolumeId> = [
+        ("main", "main"),
+        ("main/config", "config"),
+        ("db-dump", "db"),
+    ]
+    .into_iter()
+    .map(|(path, volume)| {
+        (
+            PathBuf::from(path),
+            VolumeId::Custom(volume.try_into().unwrap()),
+        )
+    })
+    .collect();
+    let split =
+        |path: &str| split_volume_path(Path::new(path), &volumes).map(|(v, p)| (v.to_string(), p));
+    assert_eq!(
+        split("data/main").unwrap(),
+        ("main".to_owned(), PathBuf::new())
+    );
+    assert_eq!(
+        split("./data/main/dir/file").unwrap(),
+        ("main".to_owned(), PathBuf::from("dir/file"))
+    );
+    assert_eq!(
+        split("data/main/config/settings.toml").unwrap(),
+        ("config".to_owned(), PathBuf::from("settings.toml"))
+    );
+    assert_eq!(
+        split("data/db-dump/dump.sql").unwrap(),
+        ("db".to_owned(), PathBuf::from("dump.sql"))
+    );
+    for path in [
+        "data",
+        "data/other/file",
+        "data/mainframe",
+        "metadata.cbor",
+        "test.s9pk",
+        "main/file",
+        "/data/main",
+        "data/../main",
+    ] {
+        assert!(split(path).is_err(), "{}", path);
+    }
+}
+
+#[test]
+fn list_snapshot() {
+    let entry = |kind| SnapshotEntry {
+        mode: 0o644,
+        uid: 0,
+        gid: 0,
+        mtime: 0,
+        mtime_nsec: 0,
+        kind,
+    };
+    let file = || {
+        entry(SnapshotEntryKind::File {
+            size: 3,
+            chunks: Vec::new(),
+        })
+    };
+    let snapshot = Snapshot {
+        timestamp: Utc::now(),
+        entries: [
+            ("", entry(SnapshotEntryKind::Directory)),
+            ("data", entry(SnapshotEntryKind::Directory)),
+            ("data/main", entry(SnapshotEntryKind::Directory)),
+            ("data/main/a", file()),
+            ("data/main/sub", entry(SnapshotEntryKind::Directory)),
+            ("data/main/sub/b", file()),
+            ("data/main-old", entry(SnapshotEntryKind::Directory)),
+            ("metadata.cbor", file()),
+        ]
+        .into_iter()
+        .map(|(p, e)| (PathBuf::from(p), e))
+        .collect(),
+    };
+    let list = |path: &str| {
+        list_snapshot_dir(&snapshot, Path::new(path))
+            .unwrap()
+            .into_iter()
+            .map(|i| i.path.display().to_string())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(list(""), ["data", "metadata.cbor"]);
+    assert_eq!(list("data/main"), ["data/main/a", "data/main/sub"]);
+    assert_eq!(list("data/main/a"), ["data/main/a"]);
+    assert!(list_snapshot_dir(&snapshot, Path::new("data/missing")).is_err());
+
+    let subset = snapshot.subset([Path::new("data/main/sub")]);
+    assert_eq!(
+        subset
+            .entries
+            .keys()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>(),
+        ["", "data", "data/main", "data/main/sub", "data/main/sub/b"]
+    );
+}
+
+#[test]
+fn rsync_endpoints_do_not_nest() {
+    let volume = Path::new("/volumes/main");
+    let src = Path::new("/backup/data/main");
+    assert_eq!(
+        rsync_endpoints(src, Path::new(""), volume),
+        ("/backup/data/main/".to_owned(), volume.to_owned())
+    );
+    assert_eq!(
+        rsync_endpoints(&src.join("dir"), Path::new("dir"), volume),
+        ("/backup/data/main/dir".to_owned(), volume.to_owned())
+    );
+    assert_eq!(
+        rsync_endpoints(&src.join("dir/file"), Path::new("dir/file"), volume),
+        ("/backup/data/main/dir/file".to_owned(), volume.join("dir"))
+    );
+}
@@ -30,9 +30,14 @@ use crate::volume::{backup_dir, Volume, VolumeId, Volumes, BACKUP_DIR};
 use crate::{Error, ErrorKind, ResultExt};
 
 pub mod backup_bulk;
+pub mod chunk_store;
+pub mod files;
 pub mod os;
 pub mod restore;
+pub mod schedule;
+pub mod snapshot;
 pub mod target;
+pub mod verify;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct BackupReport {
@@ -51,12 +56,15 @@ pub struct PackageBackupReport {
     pub error: Option<String>,
 }
 
-#[command(subcommands(backup_bulk::backup_all, target::target))]
+#[command(subcommands(backup_bulk::backup_all, schedule::schedule, target::target))]
 pub fn backup() -> Result<(), Error> {
     Ok(())
 }
 
-#[command(rename = "backup", subcommands(restore::restore_packages_rpc))]
+#[command(
+    rename = "backup",
+    subcommands(restore::restore_packages_rpc, files::browse, files::restore_files)
+)]
 pub fn package_backup() -> Result<(), Error> {
     Ok(())
 }
@@ -76,6 +84,10 @@ struct BackupMetadata {
 pub struct BackupActions {
     pub create: PackageProcedure,
     pub restore: PackageProcedure,
+    /// Paths inside the backup volume where `create` puts a plain copy of a data volume.
+    /// Single files can only be restored from these.
+    #[serde(default)]
+    pub volumes: BTreeMap<PathBuf, VolumeId>,
 }
 impl BackupActions {
     pub fn validate(
@@ -91,6 +103,26 @@ impl BackupActions {
         self.restore
             .validate(eos_version, volumes, image_ids, false)
             .with_ctx(|_| (crate::ErrorKind::ValidateS9pk, "Backup Restore"))?;
+        for (path, volume_id) in &self.volumes {
+            if !path
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)))
+            {
+                return Err(Error::new(
+                    eyre!(
+                        "Backup Volumes: {} is not a relative path inside the backup volume",
+                        path.display()
+                    ),
+                    crate::ErrorKind::ValidateS9pk,
+                ));
+            }
+            if !matches!(volumes.get(volume_id), Some(Volume::Data { .. })) {
+                return Err(Error::new(
+                    eyre!("Backup Volumes: {} is not a data volume", volume_id),
+                    crate::ErrorKind::ValidateS9pk,
+                ));
+            }
+        }
         Ok(())
     }
 
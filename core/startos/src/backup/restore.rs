@@ -4,6 +4,7 @@ use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 
+use chrono::{DateTime, Utc};
 use clap::ArgMatches;
 use futures::future::BoxFuture;
 use futures::{stream, FutureExt, StreamExt};
@@ -35,7 +36,7 @@ use crate::setup::SetupStatus;
 use crate::util::display_none;
 use crate::util::io::dir_size;
 use crate::util::serde::IoFormat;
-use crate::volume::{backup_dir, BACKUP_DIR, PKG_VOLUME_DIR};
+use crate::volume::{backup_dir, backup_staging_dir, BACKUP_DIR, PKG_VOLUME_DIR};
 
 fn parse_comma_separated(arg: &str, _: &ArgMatches) -> Result<Vec<PackageId>, Error> {
     arg.split(',')
@@ -50,6 +51,7 @@ pub async fn restore_packages_rpc(
     #[arg(parse(parse_comma_separated))] ids: Vec<PackageId>,
     #[arg(rename = "target-id")] target_id: BackupTargetId,
     #[arg] password: String,
+    #[arg] before: Option<DateTime<Utc>>,
 ) -> Result<(), Error> {
     let fs = target_id
         .load(ctx.secret_store.acquire().await?.as_mut())
@@ -57,7 +59,7 @@ pub async fn restore_packages_rpc(
     let backup_guard =
         BackupMountGuard::mount(TmpMountGuard::mount(&fs, ReadWrite).await?, &password).await?;
 
-    let (backup_guard, tasks, _) = restore_packages(&ctx, backup_guard, ids).await?;
+    let (backup_guard, tasks, _) = restore_packages(&ctx, backup_guard, ids, before).await?;
 
     tokio::spawn(async move {
         stream::iter(tasks.into_iter().map(|x| (x, ctx.clone())))
@@ -212,7 +214,7 @@ pub async fn recover_full_embassy(
         .cloned()
         .collect();
     let (backup_guard, tasks, progress_info) =
-        restore_packages(&rpc_ctx, backup_guard, ids).await?;
+        restore_packages(&rpc_ctx, backup_guard, ids, None).await?;
     let task_consumer_rpc_ctx = rpc_ctx.clone();
     tokio::select! {
         _ = async move {
@@ -257,6 +259,7 @@ async fn restore_packages(
     ctx: &RpcContext,
     backup_guard: BackupMountGuard<TmpMountGuard>,
     ids: Vec<PackageId>,
+    before: Option<DateTime<Utc>>,
 ) -> Result<
     (
         BackupMountGuard<TmpMountGuard>,
@@ -265,7 +268,7 @@ async fn restore_packages(
     ),
     Error,
 > {
-    let guards = assure_restoring(ctx, ids, &backup_guard).await?;
+    let guards = assure_restoring(ctx, ids, before, &backup_guard).await?;
 
     let mut progress_info = ProgressInfo::default();
 
@@ -303,6 +306,7 @@ async fn restore_packages(
 async fn assure_restoring(
     ctx: &RpcContext,
     ids: Vec<PackageId>,
+    before: Option<DateTime<Utc>>,
     backup_guard: &BackupMountGuard<TmpMountGuard>,
 ) -> Result<Vec<(Manifest, PackageBackupMountGuard)>, Error> {
     let mut guards = Vec::with_capacity(ids.len());
@@ -320,7 +324,9 @@ async fn assure_restoring(
                 crate::ErrorKind::InvalidRequest,
             ));
         }
-        let guard = backup_guard.mount_package_backup(&id).await?;
+        let guard = backup_guard
+            .restore_package_backup(&id, before, backup_staging_dir(&ctx.datadir, &id))
+            .await?;
         let s9pk_path = Path::new(BACKUP_DIR).join(&id).join(format!("{}.s9pk", id));
         let mut rdr = S9pkReader::open(&s9pk_path, false).await?;
 
@@ -450,7 +456,8 @@ async fn restore_package<'a>(
     Ok((
         progress.clone(),
         async move {
-            download_install_s9pk(ctx, manifest, marketplace_url, progress, file, None).await?;
+            download_install_s9pk(ctx, manifest, marketplace_url, progress, file, None, None)
+                .await?;
 
             guard.unmount().await?;
 
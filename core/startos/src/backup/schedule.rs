@@ -0,0 +1,412 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use imbl::OrdSet;
+use models::Version;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use super::backup_bulk::{assure_backing_up, perform_backup};
+use super::target::BackupTargetId;
+use super::{BackupReport, PackageBackupReport, ServerBackupReport};
+use crate::auth::check_password_against_db;
+use crate::context::RpcContext;
+use crate::db::package::get_packages;
+use crate::disk::mount::backup::BackupMountGuard;
+use crate::disk::mount::filesystem::ReadWrite;
+use crate::disk::mount::guard::TmpMountGuard;
+use crate::notifications::NotificationLevel;
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::serde::{deserialize_from_str, display_serializable, serialize_display, IoFormat};
+
+/// How often the scheduler checks whether a backup is due
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A cron expression. Standard 5 field expressions are accepted as well as the
+/// 6 or 7 field (with seconds / year) syntax of the `cron` crate.
+#[derive(Debug, Clone)]
+pub struct CronSchedule {
+    expr: String,
+    schedule: cron::Schedule,
+}
+impl CronSchedule {
+    /// The first time this schedule fires strictly after `after`
+    pub fn next_after(&self, after: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.schedule.after(after).next()
+    }
+}
+impl std::fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expr)
+    }
+}
+impl std::str::FromStr for CronSchedule {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expr = s.split_whitespace().collect::<Vec<_>>().join(" ");
+        let schedule = if expr.split(' ').count() == 5 {
+            format!("0 {}", expr).parse()
+        } else {
+            expr.parse()
+        }
+        .map_err(|e| {
+            Error::new(
+                eyre!("Invalid cron expression {:?}: {}", s, e),
+                ErrorKind::InvalidRequest,
+            )
+        })?;
+        Ok(Self { expr, schedule })
+    }
+}
+impl<'de> Deserialize<'de> for CronSchedule {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserialize_from_str(deserializer)
+    }
+}
+impl Serialize for CronSchedule {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serialize_display(self, serializer)
+    }
+}
+
+/// Which snapshots of each package survive a scheduled backup.
+/// The most recent snapshot is always kept.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, HasModel)]
+#[serde(rename_all = "kebab-case")]
+#[model = "Model<Self>"]
+pub struct BackupRetention {
+    /// Keep the latest snapshot of each of the last `keep-daily` days that have one
+    pub keep_daily: usize,
+    /// Keep the latest snapshot of each of the last `keep-weekly` ISO weeks that have one
+    pub keep_weekly: usize,
+}
+impl BackupRetention {
+    pub fn retain(
+        &self,
+        timestamps: impl IntoIterator<Item = DateTime<Utc>>,
+    ) -> BTreeSet<DateTime<Utc>> {
+        let mut timestamps = timestamps.into_iter().collect::<Vec<_>>();
+        timestamps.sort_unstable_by(|a, b| b.cmp(a));
+        let mut res = BTreeSet::new();
+        res.extend(timestamps.first().copied());
+        let mut days = BTreeMap::<NaiveDate, DateTime<Utc>>::new();
+        let mut weeks = BTreeMap::<(i32, u32), DateTime<Utc>>::new();
+        for ts in timestamps {
+            let week = ts.iso_week();
+            if days.len() < self.keep_daily {
+                days.entry(ts.date_naive()).or_insert(ts);
+            }
+            if weeks.len() < self.keep_weekly {
+                weeks.entry((week.year(), week.week())).or_insert(ts);
+            }
+        }
+        res.extend(days.into_values());
+        res.extend(weeks.into_values());
+        res
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, HasModel)]
+#[serde(rename_all = "kebab-case")]
+#[model = "Model<Self>"]
+pub struct BackupSchedule {
+    pub cron: CronSchedule,
+    pub target_id: BackupTargetId,
+    /// `None` backs up every installed package
+    pub package_ids: Option<BTreeSet<PackageId>>,
+    pub retention: BackupRetention,
+    pub last_run: Option<DateTime<Utc>>,
+    pub next_run: Option<DateTime<Utc>>,
+}
+
+fn parse_comma_separated(arg: &str, _: &ArgMatches) -> Result<BTreeSet<PackageId>, Error> {
+    arg.split(',')
+        .map(|s| s.trim().parse::<PackageId>().map_err(Error::from))
+        .collect()
+}
+
+#[command(subcommands(get, set, clear))]
+pub fn schedule() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(display(display_serializable))]
+pub async fn get(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Option<BackupSchedule>, Error> {
+    ctx.db
+        .peek()
+        .await
+        .as_server_info()
+        .as_backup_schedule()
+        .de()
+}
+
+#[command(display(display_none))]
+#[instrument(skip(ctx, password))]
+pub async fn set(
+    #[context] ctx: RpcContext,
+    #[arg] cron: CronSchedule,
+    #[arg(rename = "target-id")] target_id: BackupTargetId,
+    #[arg(
+        rename = "package-ids",
+        long = "package-ids",
+        parse(parse_comma_separated)
+    )]
+    package_ids: Option<BTreeSet<PackageId>>,
+    #[arg(rename = "keep-daily", long = "keep-daily")] keep_daily: Option<usize>,
+    #[arg(rename = "keep-weekly", long = "keep-weekly")] keep_weekly: Option<usize>,
+    #[arg] password: crate::auth::PasswordType,
+) -> Result<(), Error> {
+    let password = password.decrypt(&ctx)?;
+    let mut secrets = ctx.secret_store.acquire().await?;
+    check_password_against_db(secrets.as_mut(), &password).await?;
+    // the password is not kept: the target gets a key of its own that only unlocks its backups
+    let fs = target_id.clone().load(secrets.as_mut()).await?;
+    let mut backup_guard =
+        BackupMountGuard::mount(TmpMountGuard::mount(&fs, ReadWrite).await?, &password).await?;
+    let schedule_key = backup_guard.add_schedule_key()?;
+    backup_guard.save_and_unmount().await?;
+    let next_run = cron.next_after(&Utc::now());
+    let schedule = BackupSchedule {
+        cron,
+        target_id,
+        package_ids,
+        retention: BackupRetention {
+            keep_daily: keep_daily.unwrap_or(7),
+            keep_weekly: keep_weekly.unwrap_or(4),
+        },
+        last_run: None,
+        next_run,
+    };
+    sqlx::query!(
+        "INSERT INTO backup_schedule (id, key) VALUES (0, $1) ON CONFLICT (id) DO UPDATE SET key = $1",
+        schedule_key,
+    )
+    .execute(secrets.as_mut())
+    .await?;
+    ctx.db
+        .mutate(|v| {
+            v.as_server_info_mut()
+                .as_backup_schedule_mut()
+                .ser(&Some(schedule))
+        })
+        .await
+}
+
+#[command(display(display_none))]
+pub async fn clear(#[context] ctx: RpcContext) -> Result<(), Error> {
+    ctx.db
+        .mutate(|v| v.as_server_info_mut().as_backup_schedule_mut().ser(&None))
+        .await?;
+    sqlx::query!("DELETE FROM backup_schedule")
+        .execute(&ctx.secret_store)
+        .await?;
+    Ok(())
+}
+
+/// Runs scheduled backups until the server shuts down
+pub async fn scheduler(ctx: RpcContext) {
+    let mut shutdown = ctx.shutdown.subscribe();
+    loop {
+        if let Err(e) = tick(&ctx).await {
+            tracing::error!("Error running scheduled backup: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(SCHEDULER_INTERVAL) => (),
+            _ = shutdown.recv() => break,
+        }
+    }
+}
+
+async fn tick(ctx: &RpcContext) -> Result<(), Error> {
+    let Some(schedule) = ctx
+        .db
+        .peek()
+        .await
+        .as_server_info()
+        .as_backup_schedule()
+        .de()?
+    else {
+        return Ok(());
+    };
+    let now = Utc::now();
+    if schedule.next_run.map_or(true, |next| next > now) {
+        return Ok(());
+    }
+    let next_run = schedule.cron.next_after(&now);
+    ctx.db
+        .mutate(|v| {
+            if let Some(s) = v
+                .as_server_info_mut()
+                .as_backup_schedule_mut()
+                .transpose_mut()
+            {
+                s.as_last_run_mut().ser(&Some(now))?;
+                s.as_next_run_mut().ser(&next_run)?;
+            }
+            Ok(())
+        })
+        .await?;
+    run_scheduled_backup(ctx, &schedule).await
+}
+
+#[instrument(skip_all)]
+async fn run_scheduled_backup(ctx: &RpcContext, schedule: &BackupSchedule) -> Result<(), Error> {
+    tracing::info!("Starting scheduled backup to {}", schedule.target_id);
+    let prepare = async {
+        let schedule_key = sqlx::query!("SELECT key FROM backup_schedule WHERE id = 0")
+            .fetch_optional(&ctx.secret_store)
+            .await?
+            .ok_or_else(|| {
+                Error::new(
+                    eyre!("Scheduled backups need to be set up again with backup.schedule.set"),
+                    ErrorKind::NotFound,
+                )
+            })?
+            .key;
+        let fs = schedule
+            .target_id
+            .clone()
+            .load(ctx.secret_store.acquire().await?.as_mut())
+            .await?;
+        let backup_guard = BackupMountGuard::mount_scheduled(
+            TmpMountGuard::mount(&fs, ReadWrite).await?,
+            &schedule_key,
+        )
+        .await?;
+        let db = ctx.db.peek().await;
+        let package_ids: OrdSet<(PackageId, Version)> = match &schedule.package_ids {
+            Some(ids) => ids
+                .iter()
+                .filter_map(|id| {
+                    let version = db
+                        .as_package_data()
+                        .as_idx(id)?
+                        .as_manifest()
+                        .as_version()
+                        .de()
+                        .ok()?;
+                    Some((id.clone(), version))
+                })
+                .collect(),
+            None => get_packages(db)?.into_iter().collect(),
+        };
+        assure_backing_up(&ctx.db, &package_ids).await?;
+        Ok::<_, Error>((backup_guard, package_ids))
+    };
+    let (backup_guard, package_ids) = match prepare.await {
+        Ok(a) => a,
+        Err(e) => return notify_result(ctx, Err(e)).await,
+    };
+    let backup_res =
+        perform_backup(ctx, backup_guard, &package_ids, Some(&schedule.retention)).await;
+    notify_result(ctx, backup_res).await?;
+    ctx.db
+        .mutate(|v| {
+            v.as_server_info_mut()
+                .as_status_info_mut()
+                .as_backup_progress_mut()
+                .ser(&None)
+        })
+        .await
+}
+
+async fn notify_result(
+    ctx: &RpcContext,
+    backup_res: Result<BTreeMap<(PackageId, Version), PackageBackupReport>, Error>,
+) -> Result<(), Error> {
+    let (level, message, report) = match backup_res {
+        Ok(report) => {
+            let failed = report.values().any(|rep| rep.error.is_some());
+            (
+                if failed {
+                    NotificationLevel::Warning
+                } else {
+                    NotificationLevel::Success
+                },
+                if failed {
+                    "Your scheduled backup has completed, but some package(s) failed to backup"
+                } else {
+                    "Your scheduled backup has completed"
+                },
+                BackupReport {
+                    server: ServerBackupReport {
+                        attempted: true,
+                        error: None,
+                    },
+                    packages: report
+                        .into_iter()
+                        .map(|((package_id, _), value)| (package_id, value))
+                        .collect(),
+                },
+            )
+        }
+        Err(e) => {
+            tracing::error!("Scheduled Backup Failed: {}", e);
+            tracing::debug!("{:?}", e);
+            (
+                NotificationLevel::Error,
+                "Your scheduled backup failed to complete.",
+                BackupReport {
+                    server: ServerBackupReport {
+                        attempted: true,
+                        error: Some(e.to_string()),
+                    },
+                    packages: BTreeMap::new(),
+                },
+            )
+        }
+    };
+    ctx.notification_manager
+        .notify(
+            ctx.db.clone(),
+            None,
+            level,
+            "Scheduled Backup".to_owned(),
+            message.to_owned(),
+            report,
+            None,
+        )
+        .await
+}
+
+#[test]
+fn retention_keeps_latest_per_period() {
+    use chrono::TimeZone;
+
+    let ts = |d: u32, h: u32| Utc.with_ymd_and_hms(2023, 10, d, h, 0, 0).unwrap();
+    // Mon 2023-10-02 through Sun 2023-10-15, two snapshots a day
+    let all = (2..=15)
+        .flat_map(|d| [ts(d, 1), ts(d, 13)])
+        .collect::<Vec<_>>();
+    let kept = BackupRetention {
+        keep_daily: 3,
+        keep_weekly: 2,
+    }
+    .retain(all);
+    assert_eq!(
+        kept,
+        [ts(8, 13), ts(13, 13), ts(14, 13), ts(15, 13)]
+            .into_iter()
+            .collect()
+    );
+    let kept = BackupRetention::default().retain([ts(2, 1), ts(3, 1)]);
+    assert_eq!(kept, [ts(3, 1)].into_iter().collect());
+}
@@ -0,0 +1,384 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::unix::prelude::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, TimeZone, Utc};
+use helpers::AtomicFile;
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::instrument;
+
+use super::chunk_store::{ChunkId, ChunkStats, ChunkStore};
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::util::serde::IoFormat;
+
+pub const CHUNK_DIR: &str = "chunks";
+pub const SNAPSHOT_DIR: &str = "snapshots";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotEntryKind {
+    Directory,
+    File { size: u64, chunks: Vec<ChunkId> },
+    Symlink { target: PathBuf },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SnapshotEntry {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub kind: SnapshotEntryKind,
+}
+
+/// A point in time copy of a package backup directory.
+/// File contents live in the `ChunkStore`, the snapshot only references them.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Snapshot {
+    pub timestamp: DateTime<Utc>,
+    pub entries: BTreeMap<PathBuf, SnapshotEntry>,
+}
+impl Snapshot {
+    /// Chunks `src` into `store`.
+    /// Files whose size and mtime match `previous` are not read again.
+    #[instrument(skip_all)]
+    pub async fn create(
+        store: &ChunkStore,
+        src: impl AsRef<Path>,
+        timestamp: DateTime<Utc>,
+        previous: Option<&Snapshot>,
+    ) -> Result<(Self, ChunkStats), Error> {
+        let src = src.as_ref();
+        let mut entries = BTreeMap::new();
+        let mut stats = ChunkStats::default();
+        let root = tokio::fs::symlink_metadata(src)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, src.display().to_string()))?;
+        entries.insert(
+            PathBuf::new(),
+            SnapshotEntry::new(&root, SnapshotEntryKind::Directory),
+        );
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            let src_dir = src.join(&dir);
+            let mut read_dir = tokio::fs::read_dir(&src_dir)
+                .await
+                .with_ctx(|_| (ErrorKind::Filesystem, src_dir.display().to_string()))?;
+            while let Some(entry) = read_dir.next_entry().await? {
+                let path = dir.join(entry.file_name());
+                let src_path = entry.path();
+                let m = tokio::fs::symlink_metadata(&src_path)
+                    .await
+                    .with_ctx(|_| (ErrorKind::Filesystem, src_path.display().to_string()))?;
+                let kind = if m.is_dir() {
+                    dirs.push(path.clone());
+                    SnapshotEntryKind::Directory
+                } else if m.file_type().is_symlink() {
+                    SnapshotEntryKind::Symlink {
+                        target: tokio::fs::read_link(&src_path).await.with_ctx(|_| {
+                            (
+                                ErrorKind::Filesystem,
+                                format!("readlink {}", src_path.display()),
+                            )
+                        })?,
+                    }
+                } else if m.is_file() {
+                    let chunks = if let Some(chunks) = previous
+                        .and_then(|p| p.entries.get(&path))
+                        .and_then(|e| e.unchanged_chunks(&m))
+                    {
+                        stats.chunks += chunks.len() as u64;
+                        stats.bytes += m.len();
+                        chunks.to_vec()
+                    } else {
+                        let file = tokio::fs::File::open(&src_path).await.with_ctx(|_| {
+                            (
+                                ErrorKind::Filesystem,
+                                format!("open {}", src_path.display()),
+                            )
+                        })?;
+                        let (chunks, file_stats) = store.put_reader(file).await?;
+                        stats += file_stats;
+                        chunks
+                    };
+                    SnapshotEntryKind::File {
+                        size: m.len(),
+                        chunks,
+                    }
+                } else {
+                    tracing::warn!("Skipping special file {} in backup", src_path.display());
+                    continue;
+                };
+                entries.insert(path, SnapshotEntry::new(&m, kind));
+            }
+        }
+        Ok((Self { timestamp, entries }, stats))
+    }
+
+    /// Recreates the snapshot at `dst`, which must not exist yet.
+    #[instrument(skip_all)]
+    pub async fn restore(&self, store: &ChunkStore, dst: impl AsRef<Path>) -> Result<(), Error> {
+        let dst = dst.as_ref();
+        for (path, entry) in &self.entries {
+            let dst_path = dst.join(path);
+            match &entry.kind {
+                SnapshotEntryKind::Directory => {
+                    tokio::fs::create_dir_all(&dst_path).await.with_ctx(|_| {
+                        (
+                            ErrorKind::Filesystem,
+                            format!("mkdir {}", dst_path.display()),
+                        )
+                    })?;
+                }
+                SnapshotEntryKind::File { chunks, .. } => {
+                    let mut file = tokio::fs::File::create(&dst_path).await.with_ctx(|_| {
+                        (
+                            ErrorKind::Filesystem,
+                            format!("create {}", dst_path.display()),
+                        )
+                    })?;
+                    for chunk in chunks {
+                        file.write_all(&store.get(chunk).await?).await?;
+                    }
+                    file.sync_all().await?;
+                }
+                SnapshotEntryKind::Symlink { target } => {
+                    tokio::fs::symlink(target, &dst_path).await.with_ctx(|_| {
+                        (
+                            ErrorKind::Filesystem,
+                            format!("ln -s {} {}", target.display(), dst_path.display()),
+                        )
+                    })?;
+                }
+            }
+            entry.apply_metadata(&dst_path).await?;
+        }
+        // directory mtimes are bumped by creating their children, so fix them up last
+        for (path, entry) in self.entries.iter().rev() {
+            if let SnapshotEntryKind::Directory = entry.kind {
+                entry.apply_mtime(dst.join(path)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Only the entries at or below `paths`, and the directories leading to them
+    pub fn subset<'a>(&self, paths: impl IntoIterator<Item = &'a Path>) -> Self {
+        let mut entries = BTreeMap::new();
+        for path in paths {
+            for ancestor in path.ancestors().skip(1) {
+                if let Some(entry) = self.entries.get(ancestor) {
+                    entries.insert(ancestor.to_owned(), entry.clone());
+                }
+            }
+            entries.extend(
+                self.entries
+                    .range::<Path, _>(path..)
+                    .take_while(|(p, _)| p.starts_with(path))
+                    .map(|(p, e)| (p.clone(), e.clone())),
+            );
+        }
+        Self {
+            timestamp: self.timestamp,
+            entries,
+        }
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = &ChunkId> {
+        self.entries.values().flat_map(|e| match &e.kind {
+            SnapshotEntryKind::File { chunks, .. } => chunks.as_slice(),
+            _ => &[][..],
+        })
+    }
+
+    #[instrument(skip_all)]
+    pub async fn load(
+        root: impl AsRef<Path>,
+        id: &PackageId,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Self, Error> {
+        let path = snapshot_path(root, id, timestamp);
+        IoFormat::Cbor.from_slice(
+            &tokio::fs::read(&path)
+                .await
+                .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?,
+        )
+    }
+
+    #[instrument(skip_all)]
+    pub async fn save(&self, root: impl AsRef<Path>, id: &PackageId) -> Result<(), Error> {
+        let mut file = AtomicFile::new(snapshot_path(root, id, self.timestamp), None::<PathBuf>)
+            .await
+            .with_kind(ErrorKind::Filesystem)?;
+        file.write_all(&IoFormat::Cbor.to_vec(self)?).await?;
+        file.save().await.with_kind(ErrorKind::Filesystem)?;
+        Ok(())
+    }
+}
+
+impl SnapshotEntry {
+    fn new(m: &std::fs::Metadata, kind: SnapshotEntryKind) -> Self {
+        Self {
+            mode: m.mode(),
+            uid: m.uid(),
+            gid: m.gid(),
+            mtime: m.mtime(),
+            mtime_nsec: m.mtime_nsec(),
+            kind,
+        }
+    }
+
+    fn unchanged_chunks(&self, m: &std::fs::Metadata) -> Option<&[ChunkId]> {
+        match &self.kind {
+            SnapshotEntryKind::File { size, chunks }
+                if *size == m.len()
+                    && self.mtime == m.mtime()
+                    && self.mtime_nsec == m.mtime_nsec() =>
+            {
+                Some(chunks)
+            }
+            _ => None,
+        }
+    }
+
+    async fn apply_metadata(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref().to_owned();
+        let (uid, gid) = (Uid::from_raw(self.uid), Gid::from_raw(self.gid));
+        let tmp_path = path.clone();
+        tokio::task::spawn_blocking(move || {
+            fchownat(
+                None,
+                &tmp_path,
+                Some(uid),
+                Some(gid),
+                FchownatFlags::NoFollowSymlink,
+            )
+        })
+        .await
+        .with_kind(ErrorKind::Unknown)?
+        .with_ctx(|_| (ErrorKind::Filesystem, format!("chown {}", path.display())))?;
+        if let SnapshotEntryKind::Symlink { .. } = self.kind {
+            // Do not set permissions (see https://unix.stackexchange.com/questions/87200/change-permissions-for-a-symbolic-link)
+            return Ok(());
+        }
+        tokio::fs::set_permissions(
+            &path,
+            std::os::unix::fs::PermissionsExt::from_mode(self.mode),
+        )
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, format!("chmod {}", path.display())))?;
+        if let SnapshotEntryKind::File { .. } = self.kind {
+            self.apply_mtime(&path).await?;
+        }
+        Ok(())
+    }
+
+    async fn apply_mtime(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref().to_owned();
+        let mtime = TimeSpec::new(self.mtime, self.mtime_nsec);
+        let tmp_path = path.clone();
+        tokio::task::spawn_blocking(move || {
+            utimensat(
+                None,
+                &tmp_path,
+                &mtime,
+                &mtime,
+                UtimensatFlags::NoFollowSymlink,
+            )
+        })
+        .await
+        .with_kind(ErrorKind::Unknown)?
+        .with_ctx(|_| (ErrorKind::Filesystem, format!("touch {}", path.display())))?;
+        Ok(())
+    }
+}
+
+pub fn snapshot_path(root: impl AsRef<Path>, id: &PackageId, timestamp: DateTime<Utc>) -> PathBuf {
+    root.as_ref()
+        .join(SNAPSHOT_DIR)
+        .join(id)
+        .join(format!("{}.cbor", timestamp.timestamp_millis()))
+}
+
+#[instrument(skip_all)]
+pub async fn remove(
+    root: impl AsRef<Path>,
+    id: &PackageId,
+    timestamp: DateTime<Utc>,
+) -> Result<(), Error> {
+    let path = snapshot_path(root, id, timestamp);
+    tokio::fs::remove_file(&path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))
+}
+
+/// Snapshots are keyed by millisecond, so this is the precision a timestamp survives a round trip with
+pub fn snapshot_timestamp(timestamp: DateTime<Utc>) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(timestamp.timestamp_millis())
+        .single()
+        .unwrap_or(timestamp)
+}
+
+#[instrument(skip_all)]
+pub async fn list(
+    root: impl AsRef<Path>,
+    id: &PackageId,
+) -> Result<BTreeSet<DateTime<Utc>>, Error> {
+    let dir = root.as_ref().join(SNAPSHOT_DIR).join(id);
+    let mut res = BTreeSet::new();
+    if tokio::fs::metadata(&dir).await.is_err() {
+        return Ok(res);
+    }
+    let mut read_dir = tokio::fs::read_dir(&dir)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, dir.display().to_string()))?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        if let Some(timestamp) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_suffix(".cbor"))
+            .and_then(|millis| millis.parse().ok())
+            .and_then(|millis| Utc.timestamp_millis_opt(millis).single())
+        {
+            res.insert(timestamp);
+        }
+    }
+    Ok(res)
+}
+
+#[instrument(skip_all)]
+pub async fn list_all(
+    root: impl AsRef<Path>,
+) -> Result<BTreeMap<PackageId, BTreeSet<DateTime<Utc>>>, Error> {
+    let root = root.as_ref();
+    let dir = root.join(SNAPSHOT_DIR);
+    let mut res = BTreeMap::new();
+    if tokio::fs::metadata(&dir).await.is_err() {
+        return Ok(res);
+    }
+    let mut read_dir = tokio::fs::read_dir(&dir)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, dir.display().to_string()))?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        if let Some(id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<PackageId>().ok())
+        {
+            let snapshots = list(root, &id).await?;
+            if !snapshots.is_empty() {
+                res.insert(id, snapshots);
+            }
+        }
+    }
+    Ok(res)
+}
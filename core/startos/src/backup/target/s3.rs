@@ -0,0 +1,221 @@
+use color_eyre::eyre::eyre;
+use futures::TryStreamExt;
+use reqwest::Url;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres};
+
+use super::{BackupTarget, BackupTargetId};
+use crate::context::RpcContext;
+use crate::disk::mount::filesystem::s3fs::S3;
+use crate::disk::mount::filesystem::ReadOnly;
+use crate::disk::mount::guard::TmpMountGuard;
+use crate::disk::util::{recovery_info, EmbassyOsRecoveryInfo};
+use crate::prelude::*;
+use crate::util::display_none;
+use crate::util::serde::KeyVal;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct S3BackupTarget {
+    endpoint: Url,
+    bucket: String,
+    prefix: String,
+    access_key_id: String,
+    mountable: bool,
+    embassy_os: Option<EmbassyOsRecoveryInfo>,
+}
+
+#[command(subcommands(add, update, remove))]
+pub fn s3() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(display(display_none))]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] endpoint: Url,
+    #[arg] bucket: String,
+    #[arg] prefix: Option<String>,
+    #[arg(rename = "access-key-id")] access_key_id: String,
+    #[arg(rename = "secret-access-key")] secret_access_key: String,
+) -> Result<KeyVal<BackupTargetId, BackupTarget>, Error> {
+    let s3 = S3 {
+        endpoint,
+        bucket,
+        prefix: prefix.unwrap_or_default(),
+        access_key_id,
+        secret_access_key,
+    };
+    let guard = TmpMountGuard::mount(&s3, ReadOnly).await?;
+    let embassy_os = recovery_info(&guard).await?;
+    guard.unmount().await?;
+    let id: i32 = sqlx::query!(
+        "INSERT INTO s3_targets (endpoint, bucket, prefix, access_key_id, secret_access_key) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        s3.endpoint.as_str(),
+        s3.bucket,
+        s3.prefix,
+        s3.access_key_id,
+        s3.secret_access_key,
+    )
+    .fetch_one(&ctx.secret_store)
+    .await?.id;
+    Ok(KeyVal {
+        key: BackupTargetId::S3 { id },
+        value: BackupTarget::S3(S3BackupTarget {
+            endpoint: s3.endpoint,
+            bucket: s3.bucket,
+            prefix: s3.prefix,
+            access_key_id: s3.access_key_id,
+            mountable: true,
+            embassy_os,
+        }),
+    })
+}
+
+#[command(display(display_none))]
+pub async fn update(
+    #[context] ctx: RpcContext,
+    #[arg] id: BackupTargetId,
+    #[arg] endpoint: Url,
+    #[arg] bucket: String,
+    #[arg] prefix: Option<String>,
+    #[arg(rename = "access-key-id")] access_key_id: String,
+    #[arg(rename = "secret-access-key")] secret_access_key: String,
+) -> Result<KeyVal<BackupTargetId, BackupTarget>, Error> {
+    let id = if let BackupTargetId::S3 { id } = id {
+        id
+    } else {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", id),
+            ErrorKind::NotFound,
+        ));
+    };
+    let s3 = S3 {
+        endpoint,
+        bucket,
+        prefix: prefix.unwrap_or_default(),
+        access_key_id,
+        secret_access_key,
+    };
+    let guard = TmpMountGuard::mount(&s3, ReadOnly).await?;
+    let embassy_os = recovery_info(&guard).await?;
+    guard.unmount().await?;
+    if sqlx::query!(
+        "UPDATE s3_targets SET endpoint = $1, bucket = $2, prefix = $3, access_key_id = $4, secret_access_key = $5 WHERE id = $6",
+        s3.endpoint.as_str(),
+        s3.bucket,
+        s3.prefix,
+        s3.access_key_id,
+        s3.secret_access_key,
+        id,
+    )
+    .execute(&ctx.secret_store)
+    .await?
+    .rows_affected()
+        == 0
+    {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", BackupTargetId::S3 { id }),
+            ErrorKind::NotFound,
+        ));
+    };
+    Ok(KeyVal {
+        key: BackupTargetId::S3 { id },
+        value: BackupTarget::S3(S3BackupTarget {
+            endpoint: s3.endpoint,
+            bucket: s3.bucket,
+            prefix: s3.prefix,
+            access_key_id: s3.access_key_id,
+            mountable: true,
+            embassy_os,
+        }),
+    })
+}
+
+#[command(display(display_none))]
+pub async fn remove(#[context] ctx: RpcContext, #[arg] id: BackupTargetId) -> Result<(), Error> {
+    let id = if let BackupTargetId::S3 { id } = id {
+        id
+    } else {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", id),
+            ErrorKind::NotFound,
+        ));
+    };
+    if sqlx::query!("DELETE FROM s3_targets WHERE id = $1", id)
+        .execute(&ctx.secret_store)
+        .await?
+        .rows_affected()
+        == 0
+    {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", BackupTargetId::S3 { id }),
+            ErrorKind::NotFound,
+        ));
+    };
+    Ok(())
+}
+
+pub async fn load<Ex>(secrets: &mut Ex, id: i32) -> Result<S3, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    let record = sqlx::query!(
+        "SELECT endpoint, bucket, prefix, access_key_id, secret_access_key FROM s3_targets WHERE id = $1",
+        id
+    )
+    .fetch_one(secrets)
+    .await?;
+
+    Ok(S3 {
+        endpoint: record.endpoint.parse()?,
+        bucket: record.bucket,
+        prefix: record.prefix,
+        access_key_id: record.access_key_id,
+        secret_access_key: record.secret_access_key,
+    })
+}
+
+pub async fn list<Ex>(secrets: &mut Ex) -> Result<Vec<(i32, S3BackupTarget)>, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    let mut records = sqlx::query!(
+        "SELECT id, endpoint, bucket, prefix, access_key_id, secret_access_key FROM s3_targets"
+    )
+    .fetch_many(secrets);
+
+    let mut s3 = Vec::new();
+    while let Some(query_result) = records.try_next().await? {
+        if let Some(record) = query_result.right() {
+            let mount_info = S3 {
+                endpoint: record.endpoint.parse()?,
+                bucket: record.bucket,
+                prefix: record.prefix,
+                access_key_id: record.access_key_id,
+                secret_access_key: record.secret_access_key,
+            };
+            let embassy_os = async {
+                let guard = TmpMountGuard::mount(&mount_info, ReadOnly).await?;
+                let embassy_os = recovery_info(&guard).await?;
+                guard.unmount().await?;
+                Ok::<_, Error>(embassy_os)
+            }
+            .await;
+            s3.push((
+                record.id,
+                S3BackupTarget {
+                    endpoint: mount_info.endpoint,
+                    bucket: mount_info.bucket,
+                    prefix: mount_info.prefix,
+                    access_key_id: mount_info.access_key_id,
+                    mountable: embassy_os.is_ok(),
+                    embassy_os: embassy_os.ok().and_then(|a| a),
+                },
+            ));
+        }
+    }
+
+    Ok(s3)
+}
@@ -0,0 +1,222 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::eyre;
+use futures::TryStreamExt;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres};
+
+use super::{BackupTarget, BackupTargetId};
+use crate::account::AccountInfo;
+use crate::context::RpcContext;
+use crate::disk::mount::filesystem::sshfs::Sftp;
+use crate::disk::mount::filesystem::ReadOnly;
+use crate::disk::mount::guard::TmpMountGuard;
+use crate::disk::util::{recovery_info, EmbassyOsRecoveryInfo};
+use crate::prelude::*;
+use crate::util::display_none;
+use crate::util::serde::KeyVal;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SftpBackupTarget {
+    hostname: String,
+    port: u16,
+    path: PathBuf,
+    username: String,
+    mountable: bool,
+    embassy_os: Option<EmbassyOsRecoveryInfo>,
+}
+
+#[command(subcommands(add, update, remove))]
+pub fn sftp() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(display(display_none))]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] hostname: String,
+    #[arg] port: Option<u16>,
+    #[arg] path: PathBuf,
+    #[arg] username: String,
+) -> Result<KeyVal<BackupTargetId, BackupTarget>, Error> {
+    let sftp = Sftp {
+        hostname,
+        port: port.unwrap_or(22),
+        path,
+        username,
+        key: Some(ctx.account.read().await.key.clone()),
+    };
+    let guard = TmpMountGuard::mount(&sftp, ReadOnly).await?;
+    let embassy_os = recovery_info(&guard).await?;
+    guard.unmount().await?;
+    let path_string = Path::new("/").join(&sftp.path).display().to_string();
+    let id: i32 = sqlx::query!(
+        "INSERT INTO sftp_targets (hostname, port, path, username) VALUES ($1, $2, $3, $4) RETURNING id",
+        sftp.hostname,
+        sftp.port as i32,
+        path_string,
+        sftp.username,
+    )
+    .fetch_one(&ctx.secret_store)
+    .await?.id;
+    Ok(KeyVal {
+        key: BackupTargetId::Sftp { id },
+        value: BackupTarget::Sftp(SftpBackupTarget {
+            hostname: sftp.hostname,
+            port: sftp.port,
+            path: sftp.path,
+            username: sftp.username,
+            mountable: true,
+            embassy_os,
+        }),
+    })
+}
+
+#[command(display(display_none))]
+pub async fn update(
+    #[context] ctx: RpcContext,
+    #[arg] id: BackupTargetId,
+    #[arg] hostname: String,
+    #[arg] port: Option<u16>,
+    #[arg] path: PathBuf,
+    #[arg] username: String,
+) -> Result<KeyVal<BackupTargetId, BackupTarget>, Error> {
+    let id = if let BackupTargetId::Sftp { id } = id {
+        id
+    } else {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", id),
+            ErrorKind::NotFound,
+        ));
+    };
+    let sftp = Sftp {
+        hostname,
+        port: port.unwrap_or(22),
+        path,
+        username,
+        key: Some(ctx.account.read().await.key.clone()),
+    };
+    let guard = TmpMountGuard::mount(&sftp, ReadOnly).await?;
+    let embassy_os = recovery_info(&guard).await?;
+    guard.unmount().await?;
+    let path_string = Path::new("/").join(&sftp.path).display().to_string();
+    if sqlx::query!(
+        "UPDATE sftp_targets SET hostname = $1, port = $2, path = $3, username = $4 WHERE id = $5",
+        sftp.hostname,
+        sftp.port as i32,
+        path_string,
+        sftp.username,
+        id,
+    )
+    .execute(&ctx.secret_store)
+    .await?
+    .rows_affected()
+        == 0
+    {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", BackupTargetId::Sftp { id }),
+            ErrorKind::NotFound,
+        ));
+    };
+    Ok(KeyVal {
+        key: BackupTargetId::Sftp { id },
+        value: BackupTarget::Sftp(SftpBackupTarget {
+            hostname: sftp.hostname,
+            port: sftp.port,
+            path: sftp.path,
+            username: sftp.username,
+            mountable: true,
+            embassy_os,
+        }),
+    })
+}
+
+#[command(display(display_none))]
+pub async fn remove(#[context] ctx: RpcContext, #[arg] id: BackupTargetId) -> Result<(), Error> {
+    let id = if let BackupTargetId::Sftp { id } = id {
+        id
+    } else {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", id),
+            ErrorKind::NotFound,
+        ));
+    };
+    if sqlx::query!("DELETE FROM sftp_targets WHERE id = $1", id)
+        .execute(&ctx.secret_store)
+        .await?
+        .rows_affected()
+        == 0
+    {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", BackupTargetId::Sftp { id }),
+            ErrorKind::NotFound,
+        ));
+    };
+    Ok(())
+}
+
+/// Loads the target along with the server's key, which the remote host is expected to authorize
+pub async fn load<Ex>(secrets: &mut Ex, id: i32) -> Result<Sftp, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    let record = sqlx::query!(
+        "SELECT hostname, port, path, username FROM sftp_targets WHERE id = $1",
+        id
+    )
+    .fetch_one(&mut *secrets)
+    .await?;
+    let key = AccountInfo::load(&mut *secrets).await?.key;
+
+    Ok(Sftp {
+        hostname: record.hostname,
+        port: record.port as u16,
+        path: PathBuf::from(record.path),
+        username: record.username,
+        key: Some(key),
+    })
+}
+
+pub async fn list<Ex>(secrets: &mut Ex) -> Result<Vec<(i32, SftpBackupTarget)>, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    let key = AccountInfo::load(&mut *secrets).await?.key;
+    let mut records = sqlx::query!("SELECT id, hostname, port, path, username FROM sftp_targets")
+        .fetch_many(secrets);
+
+    let mut sftp = Vec::new();
+    while let Some(query_result) = records.try_next().await? {
+        if let Some(record) = query_result.right() {
+            let mount_info = Sftp {
+                hostname: record.hostname,
+                port: record.port as u16,
+                path: PathBuf::from(record.path),
+                username: record.username,
+                key: Some(key.clone()),
+            };
+            let embassy_os = async {
+                let guard = TmpMountGuard::mount(&mount_info, ReadOnly).await?;
+                let embassy_os = recovery_info(&guard).await?;
+                guard.unmount().await?;
+                Ok::<_, Error>(embassy_os)
+            }
+            .await;
+            sftp.push((
+                record.id,
+                SftpBackupTarget {
+                    hostname: mount_info.hostname,
+                    port: mount_info.port,
+                    path: mount_info.path,
+                    username: mount_info.username,
+                    mountable: embassy_os.is_ok(),
+                    embassy_os: embassy_os.ok().and_then(|a| a),
+                },
+            ));
+        }
+    }
+
+    Ok(sftp)
+}
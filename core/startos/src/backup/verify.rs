@@ -0,0 +1,536 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::instrument;
+
+use super::chunk_store::{ChunkId, ChunkStore};
+use super::os::OsBackup;
+use super::snapshot::{snapshot_path, Snapshot, SnapshotEntryKind};
+use super::target::{BackupTargetId, PackageBackupInfo};
+use super::BackupMetadata;
+use crate::context::RpcContext;
+use crate::disk::mount::backup::BackupMountGuard;
+use crate::disk::mount::filesystem::ReadOnly;
+use crate::disk::mount::guard::TmpMountGuard;
+use crate::notifications::{NotificationLevel, NotificationType};
+use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
+use crate::s9pk::reader::S9pkReader;
+use crate::util::serde::{display_serializable, IoFormat};
+
+/// A problem found on a backup target. Paths are relative to the decrypted backup.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+pub enum VerifyIssue {
+    #[serde(rename_all = "kebab-case")]
+    Missing { path: PathBuf },
+    #[serde(rename_all = "kebab-case")]
+    ChecksumMismatch { path: PathBuf },
+    #[serde(rename_all = "kebab-case")]
+    Undecryptable { path: PathBuf, error: String },
+    /// The file is readable, but does not agree with the backup metadata
+    #[serde(rename_all = "kebab-case")]
+    Mismatch {
+        path: PathBuf,
+        expected: String,
+        found: String,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VerifyReport {
+    pub target_id: BackupTargetId,
+    pub os: Vec<VerifyIssue>,
+    pub packages: BTreeMap<PackageId, Vec<VerifyIssue>>,
+    pub chunks_checked: usize,
+}
+impl VerifyReport {
+    pub fn issue_count(&self) -> usize {
+        self.os.len() + self.packages.values().map(|p| p.len()).sum::<usize>()
+    }
+}
+impl NotificationType for VerifyReport {
+    const CODE: i32 = 2;
+}
+
+#[command(display(display_serializable))]
+#[instrument(skip(ctx, password))]
+pub async fn verify(
+    #[context] ctx: RpcContext,
+    #[arg(rename = "target-id")] target_id: BackupTargetId,
+    #[arg] password: String,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<VerifyReport, Error> {
+    let guard = BackupMountGuard::mount_read_only(
+        TmpMountGuard::mount(
+            &target_id
+                .clone()
+                .load(ctx.secret_store.acquire().await?.as_mut())
+                .await?,
+            ReadOnly,
+        )
+        .await?,
+        &password,
+    )
+    .await?;
+    // unique, so concurrent verifications do not remove each other's files
+    let tmp_dir = ctx
+        .datadir
+        .join("package-data/tmp")
+        .join(format!("backup-verify-{:016x}", rand::random::<u64>()));
+    let res = Verifier::new(guard.as_ref(), guard.chunk_store(), &tmp_dir)
+        .verify(
+            target_id.clone(),
+            &guard.metadata.package_backups,
+            &guard.metadata.snapshots,
+        )
+        .await;
+    if tokio::fs::metadata(&tmp_dir).await.is_ok() {
+        tokio::fs::remove_dir_all(&tmp_dir).await?;
+    }
+    guard.unmount().await?;
+    let report = res?;
+
+    let issues = report.issue_count();
+    if issues > 0 {
+        ctx.notification_manager
+            .notify(
+                ctx.db.clone(),
+                None,
+                NotificationLevel::Error,
+                "Backup Verification Failed".to_owned(),
+                format!(
+                    "Found {} problem(s) with the backup on {}",
+                    issues, target_id
+                ),
+                report.clone(),
+                None,
+            )
+            .await?;
+    }
+
+    Ok(report)
+}
+
+struct Verifier<'a> {
+    root: &'a Path,
+    store: ChunkStore,
+    tmp_dir: &'a Path,
+    /// Chunks that have been checked, and what was wrong with them if anything
+    checked: BTreeMap<ChunkId, Option<VerifyIssue>>,
+}
+impl<'a> Verifier<'a> {
+    fn new(root: &'a Path, store: ChunkStore, tmp_dir: &'a Path) -> Self {
+        Self {
+            root,
+            store,
+            tmp_dir,
+            checked: BTreeMap::new(),
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn verify(
+        mut self,
+        target_id: BackupTargetId,
+        package_backups: &BTreeMap<PackageId, PackageBackupInfo>,
+        snapshots: &BTreeMap<PackageId, BTreeSet<DateTime<Utc>>>,
+    ) -> Result<VerifyReport, Error> {
+        let mut os = Vec::new();
+        match self.read("os-backup.cbor").await {
+            Ok(data) => {
+                if let Err(e) = IoFormat::Cbor.from_slice::<OsBackup>(&data) {
+                    os.push(VerifyIssue::Undecryptable {
+                        path: "os-backup.cbor".into(),
+                        error: e.to_string(),
+                    });
+                }
+            }
+            Err(issue) => os.push(issue),
+        }
+        let mut packages = BTreeMap::new();
+        for (id, info) in package_backups {
+            let issues = if let Some(snapshots) = snapshots.get(id).filter(|s| !s.is_empty()) {
+                self.verify_snapshots(id, info, snapshots).await?
+            } else {
+                self.verify_legacy(id, info).await?
+            };
+            packages.insert(id.clone(), issues);
+        }
+        Ok(VerifyReport {
+            target_id,
+            os,
+            packages,
+            chunks_checked: self.checked.len(),
+        })
+    }
+
+    /// Reads a file relative to the backup root
+    async fn read(&self, path: impl AsRef<Path>) -> Result<Vec<u8>, VerifyIssue> {
+        let path = path.as_ref();
+        tokio::fs::read(self.root.join(path))
+            .await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => VerifyIssue::Missing {
+                    path: path.to_owned(),
+                },
+                _ => VerifyIssue::Undecryptable {
+                    path: path.to_owned(),
+                    error: e.to_string(),
+                },
+            })
+    }
+
+    /// Every chunk is only read once per verification, no matter how many snapshots refer to it
+    async fn check_chunk(&mut self, id: &ChunkId) -> Option<VerifyIssue> {
+        if let Some(issue) = self.checked.get(id) {
+            return issue.clone();
+        }
+        let path = self
+            .store
+            .chunk_path(id)
+            .strip_prefix(self.root)
+            .map(|p| p.to_owned())
+            .unwrap_or_else(|_| self.store.chunk_path(id));
+        let issue = match self.read(&path).await {
+            Ok(data) if &ChunkId::for_data(&data) == id => None,
+            Ok(_) => Some(VerifyIssue::ChecksumMismatch { path }),
+            Err(issue) => Some(issue),
+        };
+        self.checked.insert(*id, issue.clone());
+        issue
+    }
+
+    #[instrument(skip_all)]
+    async fn verify_snapshots(
+        &mut self,
+        id: &PackageId,
+        info: &PackageBackupInfo,
+        timestamps: &BTreeSet<DateTime<Utc>>,
+    ) -> Result<Vec<VerifyIssue>, Error> {
+        let mut issues = Vec::new();
+        let mut reported = BTreeSet::new();
+        let mut latest = None;
+        for timestamp in timestamps {
+            let path = snapshot_path("", id, *timestamp);
+            let data = match self.read(&path).await {
+                Ok(a) => a,
+                Err(issue) => {
+                    issues.push(issue);
+                    continue;
+                }
+            };
+            let snapshot: Snapshot = match IoFormat::Cbor.from_slice(&data) {
+                Ok(a) => a,
+                Err(e) => {
+                    issues.push(VerifyIssue::Undecryptable {
+                        path,
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            let mut intact = true;
+            for chunk in snapshot.chunks() {
+                if let Some(issue) = self.check_chunk(chunk).await {
+                    intact = false;
+                    if reported.insert(*chunk) {
+                        issues.push(issue);
+                    }
+                }
+            }
+            latest = Some((snapshot, intact));
+        }
+        let Some((snapshot, true)) = latest else {
+            // the damage has already been reported
+            return Ok(issues);
+        };
+
+        let metadata_path = PathBuf::from("metadata.cbor");
+        if let Some(data) = self
+            .snapshot_file(&snapshot, id, &metadata_path, &mut issues)
+            .await?
+        {
+            self.check_metadata(id, info, &data, &mut issues);
+        }
+        let s9pk_path = PathBuf::from(format!("{}.s9pk", id));
+        if let Some(data) = self
+            .snapshot_file(&snapshot, id, &s9pk_path, &mut issues)
+            .await?
+        {
+            tokio::fs::create_dir_all(self.tmp_dir).await?;
+            let tmp = self.tmp_dir.join(&s9pk_path);
+            let mut file = tokio::fs::File::create(&tmp).await?;
+            file.write_all(&data).await?;
+            file.sync_all().await?;
+            drop(data);
+            self.check_s9pk(id, info, &tmp, &mut issues).await;
+            tokio::fs::remove_file(&tmp).await?;
+        }
+        Ok(issues)
+    }
+
+    /// Reassembles a file of an intact snapshot
+    async fn snapshot_file(
+        &self,
+        snapshot: &Snapshot,
+        id: &PackageId,
+        path: &Path,
+        issues: &mut Vec<VerifyIssue>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        match snapshot.entries.get(path).map(|e| &e.kind) {
+            Some(SnapshotEntryKind::File { size, chunks }) => {
+                let mut data = Vec::with_capacity(*size as usize);
+                for chunk in chunks {
+                    data.extend_from_slice(&self.store.get(chunk).await?);
+                }
+                Ok(Some(data))
+            }
+            _ => {
+                issues.push(VerifyIssue::Missing {
+                    path: Path::new(&**id).join(path),
+                });
+                Ok(None)
+            }
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn verify_legacy(
+        &mut self,
+        id: &PackageId,
+        info: &PackageBackupInfo,
+    ) -> Result<Vec<VerifyIssue>, Error> {
+        let mut issues = Vec::new();
+        let dir = Path::new(&**id);
+        match self.read(dir.join("metadata.cbor")).await {
+            Ok(data) => self.check_metadata(id, info, &data, &mut issues),
+            Err(issue) => issues.push(issue),
+        }
+        let s9pk_path = dir.join(format!("{}.s9pk", id));
+        if tokio::fs::metadata(self.root.join(&s9pk_path))
+            .await
+            .is_ok()
+        {
+            self.check_s9pk(id, info, &self.root.join(&s9pk_path), &mut issues)
+                .await;
+        } else {
+            issues.push(VerifyIssue::Missing { path: s9pk_path });
+        }
+        let data_path = dir.join("data");
+        if tokio::fs::metadata(self.root.join(&data_path))
+            .await
+            .is_ok()
+        {
+            self.read_tree(&data_path, &mut issues).await;
+        } else {
+            issues.push(VerifyIssue::Missing { path: data_path });
+        }
+        Ok(issues)
+    }
+
+    /// Reads every file below `dir`, since that is the only way to find out whether it can be decrypted
+    async fn read_tree(&self, dir: &Path, issues: &mut Vec<VerifyIssue>) {
+        let mut dirs = vec![dir.to_owned()];
+        while let Some(dir) = dirs.pop() {
+            let entries = async {
+                let mut read_dir = tokio::fs::read_dir(self.root.join(&dir)).await?;
+                let mut entries = Vec::new();
+                while let Some(entry) = read_dir.next_entry().await? {
+                    entries.push((entry.file_name(), entry.file_type().await?));
+                }
+                Ok::<_, std::io::Error>(entries)
+            }
+            .await;
+            let entries = match entries {
+                Ok(a) => a,
+                Err(e) => {
+                    issues.push(VerifyIssue::Undecryptable {
+                        path: dir,
+                        error: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            for (name, file_type) in entries {
+                let path = dir.join(name);
+                if file_type.is_dir() {
+                    dirs.push(path);
+                } else if file_type.is_file() {
+                    if let Err(issue) = self.read(&path).await {
+                        issues.push(issue);
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_metadata(
+        &self,
+        id: &PackageId,
+        info: &PackageBackupInfo,
+        data: &[u8],
+        issues: &mut Vec<VerifyIssue>,
+    ) {
+        let path = Path::new(&**id).join("metadata.cbor");
+        match IoFormat::Cbor.from_slice::<BackupMetadata>(data) {
+            Ok(metadata) if metadata.timestamp != info.timestamp => {
+                issues.push(VerifyIssue::Mismatch {
+                    path,
+                    expected: info.timestamp.to_rfc3339(),
+                    found: metadata.timestamp.to_rfc3339(),
+                })
+            }
+            Ok(_) => (),
+            Err(e) => issues.push(VerifyIssue::Undecryptable {
+                path,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    async fn check_s9pk(
+        &self,
+        id: &PackageId,
+        info: &PackageBackupInfo,
+        file: &Path,
+        issues: &mut Vec<VerifyIssue>,
+    ) {
+        let path = Path::new(&**id).join(format!("{}.s9pk", id));
+        let manifest = async { S9pkReader::open(file, true).await?.manifest().await }.await;
+        match manifest {
+            Ok(manifest) if manifest.version != info.version => {
+                issues.push(VerifyIssue::Mismatch {
+                    path,
+                    expected: info.version.to_string(),
+                    found: manifest.version.to_string(),
+                })
+            }
+            Ok(_) => (),
+            Err(e) => {
+                tracing::debug!("{:?}", e);
+                issues.push(VerifyIssue::ChecksumMismatch { path })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}-{:016x}", name, rand::random::<u64>()))
+}
+
+#[cfg(test)]
+fn test_backup() -> (PackageId, PackageBackupInfo, Vec<u8>) {
+    let timestamp = crate::backup::snapshot::snapshot_timestamp(Utc::now());
+    let metadata = IoFormat::Cbor
+        .to_vec(&BackupMetadata {
+            timestamp,
+            network_keys: BTreeMap::new(),
+            tor_keys: BTreeMap::new(),
+            marketplace_url: None,
+        })
+        .unwrap();
+    (
+        "test".parse().unwrap(),
+        PackageBackupInfo {
+            title: "Test".to_owned(),
+            version: "0.1.0".parse().unwrap(),
+            os_version: "0.3.5".parse().unwrap(),
+            timestamp,
+        },
+        metadata,
+    )
+}
+
+#[tokio::test]
+async fn verify_legacy_tree() {
+    let (id, info, metadata) = test_backup();
+    let root = test_dir("verify-legacy");
+    let dir = root.join(&**id);
+    tokio::fs::create_dir_all(dir.join("data/main/nested"))
+        .await
+        .unwrap();
+    tokio::fs::write(dir.join("metadata.cbor"), &metadata)
+        .await
+        .unwrap();
+    tokio::fs::write(dir.join("data/main/nested/file"), b"contents")
+        .await
+        .unwrap();
+    let store = ChunkStore::new(root.join(crate::backup::snapshot::CHUNK_DIR));
+    let mut verifier = Verifier::new(&root, store, &root);
+
+    let issues = verifier.verify_legacy(&id, &info).await.unwrap();
+    assert!(
+        matches!(&issues[..], [VerifyIssue::Missing { path }] if path == Path::new("test/test.s9pk")),
+        "{:?}",
+        issues
+    );
+
+    tokio::fs::remove_dir_all(dir.join("data")).await.unwrap();
+    let issues = verifier.verify_legacy(&id, &info).await.unwrap();
+    assert!(issues
+        .iter()
+        .any(|i| matches!(i, VerifyIssue::Missing { path } if path == Path::new("test/data"))));
+
+    tokio::fs::remove_dir_all(&root).await.unwrap();
+}
+
+#[tokio::test]
+async fn verify_snapshot_detects_corrupt_chunks() {
+    let (id, info, metadata) = test_backup();
+    let root = test_dir("verify-snapshot");
+    let staging = root.join("staging");
+    tokio::fs::create_dir_all(staging.join("data"))
+        .await
+        .unwrap();
+    tokio::fs::write(staging.join("metadata.cbor"), &metadata)
+        .await
+        .unwrap();
+    tokio::fs::write(staging.join("data/file"), b"contents")
+        .await
+        .unwrap();
+    let store = ChunkStore::new(root.join(crate::backup::snapshot::CHUNK_DIR));
+    let (snapshot, _) = Snapshot::create(&store, &staging, info.timestamp, None)
+        .await
+        .unwrap();
+    snapshot.save(&root, &id).await.unwrap();
+    let timestamps = [info.timestamp].into_iter().collect();
+
+    let mut verifier = Verifier::new(&root, store.clone(), &root);
+    let issues = verifier
+        .verify_snapshots(&id, &info, &timestamps)
+        .await
+        .unwrap();
+    // only the s9pk is missing from the staged backup
+    assert!(
+        matches!(&issues[..], [VerifyIssue::Missing { path }] if path == Path::new("test/test.s9pk")),
+        "{:?}",
+        issues
+    );
+
+    let chunk = *snapshot.chunks().next().unwrap();
+    tokio::fs::write(store.chunk_path(&chunk), b"corrupted")
+        .await
+        .unwrap();
+    let mut verifier = Verifier::new(&root, store, &root);
+    let issues = verifier
+        .verify_snapshots(&id, &info, &timestamps)
+        .await
+        .unwrap();
+    assert!(
+        matches!(&issues[..], [VerifyIssue::ChecksumMismatch { .. }]),
+        "{:?}",
+        issues
+    );
+
+    tokio::fs::remove_dir_all(&root).await.unwrap();
+}
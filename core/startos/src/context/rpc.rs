@@ -28,8 +28,10 @@ use crate::dependencies::compute_dependency_config_errs;
 use crate::disk::OsPartitionInfo;
 use crate::init::{check_time_is_synchronized, init_postgres};
 use crate::install::cleanup::{cleanup_failed, uninstall};
+use crate::manager::health::{HealthHistory, DEFAULT_FAILURE_GRACE_PERIOD};
 use crate::manager::ManagerMap;
 use crate::middleware::auth::HashSessionToken;
+use crate::net::acme::AcmeManager;
 use crate::net::net_controller::NetController;
 use crate::net::ssl::{root_ca_start_time, SslManager};
 use crate::net::wifi::WpaCli;
@@ -56,6 +58,10 @@ pub struct RpcContextConfig {
     pub revision_cache_size: Option<usize>,
     pub datadir: Option<PathBuf>,
     pub log_server: Option<Url>,
+    /// Additional root CA to trust when connecting to an ACME directory
+    pub acme_root_ca: Option<PathBuf>,
+    /// How long a health check has to keep failing before a notification is sent
+    pub health_failure_grace_period: Option<crate::util::serde::Duration>,
 }
 impl RpcContextConfig {
     pub async fn load<P: AsRef<Path> + Send + 'static>(path: Option<P>) -> Result<Self, Error> {
@@ -116,6 +122,7 @@ pub struct RpcContextSeed {
     pub net_controller: Arc<NetController>,
     pub managers: ManagerMap,
     pub metrics_cache: RwLock<Option<crate::system::Metrics>>,
+    pub health_history: HealthHistory,
     pub shutdown: broadcast::Sender<Option<Shutdown>>,
     pub tor_socks: SocketAddr,
     pub notification_manager: NotificationManager,
@@ -162,8 +169,13 @@ impl RpcContext {
                     .as_deref()
                     .unwrap_or(&[SocketAddr::from(([127, 0, 0, 1], 53))]),
                 SslManager::new(&account, root_ca_start_time().await?)?,
+                AcmeManager::init(&secret_store, base.acme_root_ca.as_deref()).await?,
+                &secret_store,
                 &account.hostname,
                 &account.key,
+                std::iter::once(base.ethernet_interface.clone())
+                    .chain(base.wifi_interface.clone())
+                    .collect(),
             )
             .await?,
         );
@@ -201,6 +213,10 @@ impl RpcContext {
             net_controller,
             managers,
             metrics_cache,
+            health_history: HealthHistory::new(
+                base.health_failure_grace_period
+                    .map_or(DEFAULT_FAILURE_GRACE_PERIOD, |d| *d),
+            ),
             shutdown,
             tor_socks: tor_proxy,
             notification_manager,
@@ -236,6 +252,13 @@ impl RpcContext {
         let res = Self(seed.clone());
         res.cleanup_and_initialize().await?;
         tracing::info!("Cleaned up transient states");
+        // custom domains bound before they were published
+        crate::net::domain::sync_clearnet_addresses(&res).await?;
+        tokio::spawn(crate::backup::schedule::scheduler(res.clone()));
+        tokio::spawn(crate::net::acme::renewal(res.clone()));
+        tokio::spawn(crate::net::dns::refresh(res.clone()));
+        tokio::spawn(crate::net::wireguard::init(res.clone()));
+        tokio::spawn(crate::net::tor::supervisor(res.clone()));
         Ok(res)
     }
 
@@ -343,7 +366,9 @@ impl RpcContext {
                         .as_installed_mut()
                         .as_status_mut()
                         .as_main_mut();
-                    let running = status.clone().de()?.running();
+                    let status_main = status.clone().de()?;
+                    let running = status_main.running()
+                        || matches!(status_main, MainStatus::CrashLooping { .. });
                     status.ser(&if running {
                         MainStatus::Starting
                     } else {
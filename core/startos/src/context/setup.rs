@@ -2,12 +2,14 @@ use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use color_eyre::eyre::eyre;
 use josekit::jwk::Jwk;
 use patch_db::json_ptr::JsonPointer;
 use patch_db::PatchDb;
 use rpc_toolkit::yajrc::RpcError;
 use rpc_toolkit::Context;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::postgres::PgConnectOptions;
 use sqlx::PgPool;
 use tokio::sync::broadcast::Sender;
@@ -18,9 +20,13 @@ use crate::account::AccountInfo;
 use crate::db::model::Database;
 use crate::disk::OsPartitionInfo;
 use crate::init::init_postgres;
+use crate::net::keys::Key;
 use crate::setup::SetupStatus;
 use crate::util::config::load_config_from_paths;
-use crate::{Error, ResultExt};
+use crate::{Error, ErrorKind, ResultExt};
+
+/// Host key of the SSH server, generated on first boot and carried over by updates
+const SSH_HOST_KEY_PATH: &str = "/etc/ssh/ssh_host_ed25519_key";
 
 lazy_static::lazy_static! {
     pub static ref CURRENT_SECRET: Jwk = Jwk::generate_ec_key(josekit::jwk::alg::ec::EcCurve::P256).unwrap_or_else(|e| {
@@ -84,6 +90,8 @@ pub struct SetupContextSeed {
     pub cached_product_key: RwLock<Option<Arc<String>>>,
     pub setup_status: RwLock<Option<Result<SetupStatus, RpcError>>>,
     pub setup_result: RwLock<Option<(Arc<String>, SetupResult)>>,
+    /// Used to authenticate to SFTP recovery sources, since the server's own key is not known until recovery
+    pub sftp_key: Key,
 }
 
 impl AsRef<Jwk> for SetupContextSeed {
@@ -92,6 +100,27 @@ impl AsRef<Jwk> for SetupContextSeed {
     }
 }
 
+/// Derives the key used to recover from SFTP from the SSH host key,
+/// so the public key authorized on the SFTP server stays valid across reboots
+async fn load_sftp_key() -> Result<Key, Error> {
+    let host_key = ssh_key::PrivateKey::from_openssh(
+        tokio::fs::read(SSH_HOST_KEY_PATH)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, SSH_HOST_KEY_PATH))?,
+    )
+    .with_kind(ErrorKind::ParseSshKey)?;
+    let host_key = host_key.key_data().ed25519().ok_or_else(|| {
+        Error::new(
+            eyre!("{} is not an ed25519 key", SSH_HOST_KEY_PATH),
+            ErrorKind::ParseSshKey,
+        )
+    })?;
+    let mut hasher = Sha256::new();
+    hasher.update(b"startos-sftp-key");
+    hasher.update(host_key.private.to_bytes());
+    Ok(Key::from_bytes(None, hasher.finalize().into()))
+}
+
 #[derive(Clone)]
 pub struct SetupContext(Arc<SetupContextSeed>);
 impl SetupContext {
@@ -112,6 +141,11 @@ impl SetupContext {
             cached_product_key: RwLock::new(None),
             setup_status: RwLock::new(None),
             setup_result: RwLock::new(None),
+            sftp_key: load_sftp_key().await.unwrap_or_else(|e| {
+                tracing::warn!("Could not derive SFTP key from SSH host key: {}", e);
+                tracing::debug!("{:?}", e);
+                Key::new(None)
+            }),
         })))
     }
     #[instrument(skip_all)]
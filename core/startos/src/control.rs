@@ -1,12 +1,15 @@
 use color_eyre::eyre::eyre;
 use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
 use crate::context::RpcContext;
 use crate::prelude::*;
 use crate::s9pk::manifest::PackageId;
+use crate::status::restart::{Crashes, RestartMode, RestartPolicy};
 use crate::status::MainStatus;
 use crate::util::display_none;
+use crate::util::serde::{display_serializable, Duration, IoFormat};
 use crate::Error;
 
 #[command(display(display_none), metadata(sync_db = true))]
@@ -90,3 +93,119 @@ pub async fn restart(#[context] ctx: RpcContext, #[arg] id: PackageId) -> Result
 
     Ok(())
 }
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RestartPolicyInfo {
+    /// The policy in effect
+    pub policy: RestartPolicy,
+    /// Whether the policy was set by the user rather than taken from the manifest
+    pub overridden: bool,
+    pub crashes: Crashes,
+}
+
+#[command(
+    rename = "restart-policy",
+    subcommands(get_restart_policy, set_restart_policy, clear_restart_policy)
+)]
+pub fn restart_policy() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(rename = "get", display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn get_restart_policy(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<RestartPolicyInfo, Error> {
+    let peek = ctx.db.peek().await;
+    let installed = peek
+        .as_package_data()
+        .as_idx(&id)
+        .or_not_found(&id)?
+        .expect_as_installed()?
+        .as_installed();
+    let status = installed.as_status();
+    let (policy, overridden) = match status.as_restart_policy().de()? {
+        Some(policy) => (policy, true),
+        None => (installed.as_manifest().as_restart_policy().de()?, false),
+    };
+    Ok(RestartPolicyInfo {
+        policy,
+        overridden,
+        crashes: status.as_crashes().de()?,
+    })
+}
+
+/// Options that are not passed keep the values of the policy currently in effect
+#[command(rename = "set", display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn set_restart_policy(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(long = "mode")] mode: Option<RestartMode>,
+    #[arg(long = "max-retries")] max_retries: Option<u32>,
+    #[arg(long = "backoff")] backoff: Option<Duration>,
+    #[arg(long = "max-backoff")] max_backoff: Option<Duration>,
+) -> Result<(), Error> {
+    ctx.db
+        .mutate(|db| {
+            let installed = db
+                .as_package_data_mut()
+                .as_idx_mut(&id)
+                .or_not_found(&id)?
+                .as_installed_mut()
+                .or_not_found(&id)?;
+            let mut policy = match installed.as_status().as_restart_policy().de()? {
+                Some(policy) => policy,
+                None => installed.as_manifest().as_restart_policy().de()?,
+            };
+            if let Some(mode) = mode {
+                policy.mode = mode;
+            }
+            if let Some(max_retries) = max_retries {
+                policy.max_retries = Some(max_retries);
+            }
+            if let Some(backoff) = backoff {
+                policy.backoff = backoff;
+            }
+            if let Some(max_backoff) = max_backoff {
+                policy.max_backoff = max_backoff;
+            }
+            if policy.backoff.is_zero() || *policy.max_backoff < *policy.backoff {
+                return Err(Error::new(
+                    eyre!("backoff must be nonzero and no greater than max-backoff"),
+                    ErrorKind::InvalidRequest,
+                ));
+            }
+            installed
+                .as_status_mut()
+                .as_restart_policy_mut()
+                .ser(&Some(policy))
+        })
+        .await
+}
+
+/// Go back to the restart policy of the manifest
+#[command(rename = "clear", display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn clear_restart_policy(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+) -> Result<(), Error> {
+    ctx.db
+        .mutate(|db| {
+            db.as_package_data_mut()
+                .as_idx_mut(&id)
+                .or_not_found(&id)?
+                .as_installed_mut()
+                .or_not_found(&id)?
+                .as_status_mut()
+                .as_restart_policy_mut()
+                .ser(&None)
+        })
+        .await
+}
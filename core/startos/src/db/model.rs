@@ -16,13 +16,15 @@ use serde::{Deserialize, Serialize};
 use ssh_key::public::Ed25519PublicKey;
 
 use crate::account::AccountInfo;
+use crate::backup::schedule::BackupSchedule;
 use crate::config::spec::PackagePointerSpec;
 use crate::install::progress::InstallProgress;
+use crate::net::dns::DnsSettings;
 use crate::net::utils::{get_iface_ipv4_addr, get_iface_ipv6_addr};
 use crate::prelude::*;
 use crate::s9pk::manifest::{Manifest, PackageId};
 use crate::status::Status;
-use crate::util::cpupower::{Governor};
+use crate::util::cpupower::Governor;
 use crate::util::Version;
 use crate::version::{Current, VersionT};
 use crate::{ARCH, PLATFORM};
@@ -47,6 +49,7 @@ impl Database {
                 version: Current::new().semver().into(),
                 hostname: account.hostname.no_dot_host_name(),
                 last_backup: None,
+                backup_schedule: None,
                 last_wifi_region: None,
                 eos_version_compat: Current::new().compat().clone(),
                 lan_address,
@@ -85,6 +88,7 @@ impl Database {
                 ntp_synced: false,
                 zram: true,
                 governor: None,
+                dns: Default::default(),
             },
             package_data: AllPackageData::default(),
             ui: serde_json::from_str(include_str!(concat!(
@@ -118,6 +122,8 @@ pub struct ServerInfo {
     pub hostname: String,
     pub version: Version,
     pub last_backup: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub backup_schedule: Option<BackupSchedule>,
     /// Used in the wifi to determine the region to set the system to
     pub last_wifi_region: Option<CountryCode>,
     pub eos_version_compat: VersionRange,
@@ -137,6 +143,8 @@ pub struct ServerInfo {
     #[serde(default)]
     pub zram: bool,
     pub governor: Option<Governor>,
+    #[serde(default)]
+    pub dns: DnsSettings,
 }
 
 #[derive(Debug, Deserialize, Serialize, HasModel)]
@@ -205,10 +213,12 @@ pub struct ServerSpecs {
     pub memory: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, HasModel)]
 #[serde(rename_all = "kebab-case")]
+#[model = "Model<Self>"]
 pub struct ConnectionAddresses {
     pub tor: Vec<String>,
+    /// URLs of the custom domains bound to package interfaces
     pub clearnet: Vec<String>,
 }
 
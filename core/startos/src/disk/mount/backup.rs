@@ -1,5 +1,7 @@
+use std::collections::BTreeSet;
 use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, Utc};
 use color_eyre::eyre::eyre;
 use helpers::AtomicFile;
 use tokio::io::AsyncWriteExt;
@@ -9,8 +11,11 @@ use super::filesystem::ecryptfs::EcryptFS;
 use super::guard::{GenericMountGuard, TmpMountGuard};
 use super::util::{bind, unmount};
 use crate::auth::check_password;
+use crate::backup::chunk_store::ChunkStore;
+use crate::backup::schedule::BackupRetention;
+use crate::backup::snapshot::{self, snapshot_timestamp, Snapshot, CHUNK_DIR};
 use crate::backup::target::BackupInfo;
-use crate::disk::mount::filesystem::ReadWrite;
+use crate::disk::mount::filesystem::{MountType, ReadOnly, ReadWrite};
 use crate::disk::util::EmbassyOsRecoveryInfo;
 use crate::middleware::encrypt::{decrypt_slice, encrypt_slice};
 use crate::s9pk::manifest::PackageId;
@@ -37,41 +42,14 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
 
     #[instrument(skip_all)]
     pub async fn mount(backup_disk_mount_guard: G, password: &str) -> Result<Self, Error> {
-        let backup_disk_path = backup_disk_mount_guard.as_ref();
-        let unencrypted_metadata_path =
-            backup_disk_path.join("EmbassyBackups/unencrypted-metadata.cbor");
-        let mut unencrypted_metadata: EmbassyOsRecoveryInfo =
-            if tokio::fs::metadata(&unencrypted_metadata_path)
-                .await
-                .is_ok()
-            {
-                IoFormat::Cbor.from_slice(
-                    &tokio::fs::read(&unencrypted_metadata_path)
-                        .await
-                        .with_ctx(|_| {
-                            (
-                                crate::ErrorKind::Filesystem,
-                                unencrypted_metadata_path.display().to_string(),
-                            )
-                        })?,
-                )?
-            } else {
-                Default::default()
-            };
+        let mut unencrypted_metadata =
+            load_unencrypted_metadata(backup_disk_mount_guard.as_ref()).await?;
         let enc_key = if let (Some(hash), Some(wrapped_key)) = (
             unencrypted_metadata.password_hash.as_ref(),
             unencrypted_metadata.wrapped_key.as_ref(),
         ) {
-            let wrapped_key =
-                base32::decode(base32::Alphabet::RFC4648 { padding: true }, wrapped_key)
-                    .ok_or_else(|| {
-                        Error::new(
-                            eyre!("failed to decode wrapped key"),
-                            crate::ErrorKind::Backup,
-                        )
-                    })?;
             check_password(hash, password)?;
-            String::from_utf8(decrypt_slice(wrapped_key, password))?
+            unwrap_key(wrapped_key, password)?
         } else {
             base32::encode(
                 base32::Alphabet::RFC4648 { padding: false },
@@ -96,8 +74,87 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
             ));
         }
 
+        Self::open(
+            backup_disk_mount_guard,
+            unencrypted_metadata,
+            enc_key,
+            ReadWrite,
+        )
+        .await
+    }
+
+    /// Mounts an existing backup without writing anything to the target.
+    /// `backup_disk_mount_guard` can be mounted read only.
+    #[instrument(skip_all)]
+    pub async fn mount_read_only(
+        backup_disk_mount_guard: G,
+        password: &str,
+    ) -> Result<Self, Error> {
+        let unencrypted_metadata =
+            load_unencrypted_metadata(backup_disk_mount_guard.as_ref()).await?;
+        let (Some(hash), Some(wrapped_key)) = (
+            unencrypted_metadata.password_hash.as_ref(),
+            unencrypted_metadata.wrapped_key.as_ref(),
+        ) else {
+            return Err(Error::new(
+                eyre!("No backup found on target"),
+                crate::ErrorKind::NotFound,
+            ));
+        };
+        check_password(hash, password)?;
+        let enc_key = unwrap_key(wrapped_key, password)?;
+        Self::open(
+            backup_disk_mount_guard,
+            unencrypted_metadata,
+            enc_key,
+            ReadOnly,
+        )
+        .await
+    }
+
+    /// Mounts the target with the key returned by `add_schedule_key`, so scheduled backups do not need the password
+    #[instrument(skip_all)]
+    pub async fn mount_scheduled(
+        backup_disk_mount_guard: G,
+        schedule_key: &str,
+    ) -> Result<Self, Error> {
+        let unencrypted_metadata =
+            load_unencrypted_metadata(backup_disk_mount_guard.as_ref()).await?;
+        let (Some(hash), Some(wrapped_key)) = (
+            unencrypted_metadata.schedule_key_hash.as_ref(),
+            unencrypted_metadata.schedule_wrapped_key.as_ref(),
+        ) else {
+            return Err(Error::new(
+                eyre!("Backup target is not set up for scheduled backups"),
+                crate::ErrorKind::Backup,
+            ));
+        };
+        check_password(hash, schedule_key)?;
+        let enc_key = unwrap_key(wrapped_key, schedule_key)?;
+        Self::open(
+            backup_disk_mount_guard,
+            unencrypted_metadata,
+            enc_key,
+            ReadWrite,
+        )
+        .await
+    }
+
+    async fn open(
+        backup_disk_mount_guard: G,
+        unencrypted_metadata: EmbassyOsRecoveryInfo,
+        enc_key: String,
+        mount_type: MountType,
+    ) -> Result<Self, Error> {
+        let backup_disk_path = backup_disk_mount_guard.as_ref();
         let crypt_path = backup_disk_path.join("EmbassyBackups/crypt");
         if tokio::fs::metadata(&crypt_path).await.is_err() {
+            if mount_type == ReadOnly {
+                return Err(Error::new(
+                    eyre!("No backup found on target"),
+                    crate::ErrorKind::NotFound,
+                ));
+            }
             tokio::fs::create_dir_all(&crypt_path).await.with_ctx(|_| {
                 (
                     crate::ErrorKind::Filesystem,
@@ -106,10 +163,10 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
             })?;
         }
         let encrypted_guard =
-            TmpMountGuard::mount(&EcryptFS::new(&crypt_path, &enc_key), ReadWrite).await?;
+            TmpMountGuard::mount(&EcryptFS::new(&crypt_path, &enc_key), mount_type).await?;
 
         let metadata_path = encrypted_guard.as_ref().join("metadata.cbor");
-        let metadata: BackupInfo = if tokio::fs::metadata(&metadata_path).await.is_ok() {
+        let mut metadata: BackupInfo = if tokio::fs::metadata(&metadata_path).await.is_ok() {
             IoFormat::Cbor.from_slice(&tokio::fs::read(&metadata_path).await.with_ctx(|_| {
                 (
                     crate::ErrorKind::Filesystem,
@@ -119,6 +176,8 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
         } else {
             Default::default()
         };
+        let encrypted_path: &Path = encrypted_guard.as_ref();
+        metadata.snapshots = snapshot::list_all(encrypted_path).await?;
 
         Ok(Self {
             backup_disk_mount_guard: Some(backup_disk_mount_guard),
@@ -145,18 +204,172 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
         Ok(())
     }
 
+    /// Wraps the encryption key with a new random key, replacing any previous one, and returns it.
+    /// It only unlocks this target, so it can be kept on the server for scheduled backups instead of the password.
+    pub fn add_schedule_key(&mut self) -> Result<String, Error> {
+        let schedule_key = base32::encode(
+            base32::Alphabet::RFC4648 { padding: false },
+            &rand::random::<[u8; 32]>()[..],
+        );
+        self.unencrypted_metadata.schedule_key_hash = Some(
+            argon2::hash_encoded(
+                schedule_key.as_bytes(),
+                &rand::random::<[u8; 16]>()[..],
+                &argon2::Config::rfc9106_low_mem(),
+            )
+            .with_kind(crate::ErrorKind::PasswordHashGeneration)?,
+        );
+        self.unencrypted_metadata.schedule_wrapped_key = Some(base32::encode(
+            base32::Alphabet::RFC4648 { padding: true },
+            &encrypt_slice(&self.enc_key, &schedule_key),
+        ));
+        Ok(schedule_key)
+    }
+
     #[instrument(skip_all)]
     pub async fn mount_package_backup(
         &self,
         id: &PackageId,
     ) -> Result<PackageBackupMountGuard, Error> {
-        let lock = FileLock::new(Path::new(BACKUP_DIR).join(format!("{}.lock", id)), false).await?;
-        let mountpoint = Path::new(BACKUP_DIR).join(id);
-        bind(self.as_ref().join(id), &mountpoint, false).await?;
-        Ok(PackageBackupMountGuard {
-            mountpoint: Some(mountpoint),
-            lock: Some(lock),
-        })
+        let lock = lock_package_backup(id).await?;
+        bind_package_backup(id, self.as_ref().join(id), lock).await
+    }
+
+    pub fn chunk_store(&self) -> ChunkStore {
+        ChunkStore::new(self.as_ref().join(CHUNK_DIR))
+    }
+
+    /// Binds an empty local staging directory of a package to its backup volume.
+    /// Call `commit_package_snapshot` once the package has written its backup, then `remove_staging`.
+    #[instrument(skip_all)]
+    pub async fn stage_package_backup(
+        &self,
+        id: &PackageId,
+        staging: impl AsRef<Path>,
+    ) -> Result<PackageBackupMountGuard, Error> {
+        let lock = lock_package_backup(id).await?;
+        // left over if a previous backup was interrupted
+        remove_staging(staging.as_ref()).await?;
+        bind_package_backup(id, staging, lock).await
+    }
+
+    /// Uploads the chunks of the staging directory that are not on the target yet, and records a snapshot of it.
+    #[instrument(skip_all)]
+    pub async fn commit_package_snapshot(
+        &mut self,
+        id: &PackageId,
+        staging: impl AsRef<Path>,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let root: &Path = self.as_ref();
+        let timestamp = snapshot_timestamp(timestamp);
+        let previous = if let Some(latest) = snapshot::list(root, id).await?.last() {
+            Some(Snapshot::load(root, id, *latest).await?)
+        } else {
+            None
+        };
+        let (snapshot, stats) =
+            Snapshot::create(&self.chunk_store(), staging, timestamp, previous.as_ref()).await?;
+        snapshot.save(root, id).await?;
+        tracing::info!(
+            "Backed up {}: wrote {} new chunks ({} of {} bytes)",
+            id,
+            stats.new_chunks,
+            stats.new_bytes,
+            stats.bytes
+        );
+        self.metadata
+            .snapshots
+            .entry(id.clone())
+            .or_default()
+            .insert(timestamp);
+        Ok(())
+    }
+
+    /// The latest snapshot of a package taken at or before `before`.
+    /// `None` if the target was written before snapshots existed, and only contains a full copy at `<id>`.
+    #[instrument(skip_all)]
+    pub async fn package_snapshot(
+        &self,
+        id: &PackageId,
+        before: Option<DateTime<Utc>>,
+    ) -> Result<Option<Snapshot>, Error> {
+        let root: &Path = self.as_ref();
+        let snapshots = snapshot::list(root, id).await?;
+        match (
+            snapshots
+                .iter()
+                .rev()
+                .find(|ts| before.map_or(true, |before| **ts <= before)),
+            before,
+        ) {
+            (Some(timestamp), _) => Ok(Some(Snapshot::load(root, id, *timestamp).await?)),
+            (None, None) => Ok(None),
+            (None, Some(before)) => Err(Error::new(
+                eyre!("No backup of {} found from {} or earlier", id, before),
+                crate::ErrorKind::NotFound,
+            )),
+        }
+    }
+
+    /// Makes the latest snapshot of a package taken at or before `before` available at its backup volume.
+    /// Targets written before snapshots existed only contain a full copy, which is bound directly.
+    /// The snapshot is restored to `staging`, which is removed again when the returned guard is unmounted.
+    #[instrument(skip_all)]
+    pub async fn restore_package_backup(
+        &self,
+        id: &PackageId,
+        before: Option<DateTime<Utc>>,
+        staging: impl AsRef<Path>,
+    ) -> Result<PackageBackupMountGuard, Error> {
+        let root: &Path = self.as_ref();
+        // held while the staging directory is rebuilt, so a concurrent backup or restore can not use it
+        let lock = lock_package_backup(id).await?;
+        let Some(snapshot) = self.package_snapshot(id, before).await? else {
+            return bind_package_backup(id, root.join(id), lock).await;
+        };
+        let staging = staging.as_ref();
+        remove_staging(staging).await?;
+        if let Err(e) = snapshot.restore(&self.chunk_store(), staging).await {
+            remove_staging(staging).await?;
+            return Err(e);
+        }
+        let mut guard = bind_package_backup(id, staging, lock).await?;
+        guard.staging = Some(staging.to_owned());
+        Ok(guard)
+    }
+
+    /// Deletes the snapshots not kept by `retention`, then any chunks no remaining snapshot refers to.
+    #[instrument(skip_all)]
+    pub async fn prune_snapshots(&mut self, retention: &BackupRetention) -> Result<(), Error> {
+        let root: PathBuf = self.as_ref().to_owned();
+        let store = self.chunk_store();
+        let mut referenced = BTreeSet::new();
+        for (id, snapshots) in &mut self.metadata.snapshots {
+            let keep = retention.retain(snapshots.iter().copied());
+            for timestamp in snapshots.iter().filter(|ts| !keep.contains(ts)) {
+                tracing::info!("Pruning backup of {} from {}", id, timestamp);
+                snapshot::remove(&root, id, *timestamp).await?;
+            }
+            for timestamp in &keep {
+                referenced.extend(
+                    Snapshot::load(&root, id, *timestamp)
+                        .await?
+                        .chunks()
+                        .copied(),
+                );
+            }
+            *snapshots = keep;
+        }
+        let mut removed = 0;
+        for chunk in store.list().await? {
+            if !referenced.contains(&chunk) {
+                store.remove(&chunk).await?;
+                removed += 1;
+            }
+        }
+        tracing::info!("Removed {} unreferenced backup chunks", removed);
+        Ok(())
     }
 
     #[instrument(skip_all)]
@@ -222,8 +435,74 @@ impl<G: GenericMountGuard> Drop for BackupMountGuard<G> {
     }
 }
 
+async fn load_unencrypted_metadata(
+    backup_disk_path: &Path,
+) -> Result<EmbassyOsRecoveryInfo, Error> {
+    let unencrypted_metadata_path =
+        backup_disk_path.join("EmbassyBackups/unencrypted-metadata.cbor");
+    if tokio::fs::metadata(&unencrypted_metadata_path)
+        .await
+        .is_ok()
+    {
+        IoFormat::Cbor.from_slice(&tokio::fs::read(&unencrypted_metadata_path).await.with_ctx(
+            |_| {
+                (
+                    crate::ErrorKind::Filesystem,
+                    unencrypted_metadata_path.display().to_string(),
+                )
+            },
+        )?)
+    } else {
+        Ok(Default::default())
+    }
+}
+
+fn unwrap_key(wrapped_key: &str, password: &str) -> Result<String, Error> {
+    let wrapped_key = base32::decode(base32::Alphabet::RFC4648 { padding: true }, wrapped_key)
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("failed to decode wrapped key"),
+                crate::ErrorKind::Backup,
+            )
+        })?;
+    Ok(String::from_utf8(decrypt_slice(wrapped_key, password))?)
+}
+
+/// Removes the local copy of a package backup, which is only needed while it is being backed up or restored
+pub async fn remove_staging(staging: &Path) -> Result<(), Error> {
+    if tokio::fs::metadata(staging).await.is_ok() {
+        tokio::fs::remove_dir_all(staging).await.with_ctx(|_| {
+            (
+                crate::ErrorKind::Filesystem,
+                format!("rm -rf {}", staging.display()),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+async fn lock_package_backup(id: &PackageId) -> Result<FileLock, Error> {
+    FileLock::new(Path::new(BACKUP_DIR).join(format!("{}.lock", id)), false).await
+}
+
+async fn bind_package_backup(
+    id: &PackageId,
+    src: impl AsRef<Path>,
+    lock: FileLock,
+) -> Result<PackageBackupMountGuard, Error> {
+    let mountpoint = Path::new(BACKUP_DIR).join(id);
+    bind(src, &mountpoint, false).await?;
+    Ok(PackageBackupMountGuard {
+        mountpoint: Some(mountpoint),
+        staging: None,
+        lock: Some(lock),
+    })
+}
+
 pub struct PackageBackupMountGuard {
     mountpoint: Option<PathBuf>,
+    /// Removed after unmounting
+    staging: Option<PathBuf>,
     lock: Option<FileLock>,
 }
 impl PackageBackupMountGuard {
@@ -231,6 +510,9 @@ impl PackageBackupMountGuard {
         if let Some(mountpoint) = self.mountpoint.take() {
             unmount(&mountpoint).await?;
         }
+        if let Some(staging) = self.staging.take() {
+            remove_staging(&staging).await?;
+        }
         if let Some(lock) = self.lock.take() {
             lock.unlock().await?;
         }
@@ -249,11 +531,15 @@ impl AsRef<Path> for PackageBackupMountGuard {
 impl Drop for PackageBackupMountGuard {
     fn drop(&mut self) {
         let mountpoint = self.mountpoint.take();
+        let staging = self.staging.take();
         let lock = self.lock.take();
         tokio::spawn(async move {
             if let Some(mountpoint) = mountpoint {
                 unmount(&mountpoint).await.unwrap();
             }
+            if let Some(staging) = staging {
+                remove_staging(&staging).await.unwrap();
+            }
             if let Some(lock) = lock {
                 lock.unlock().await.unwrap();
             }
@@ -0,0 +1,156 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use digest::generic_array::GenericArray;
+use digest::{Digest, OutputSizeUser};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::process::Command;
+use tracing::instrument;
+
+use super::{FileSystem, MountType, ReadOnly};
+use crate::util::Invoke;
+use crate::Error;
+
+/// Mounts `bucket` (optionally below `prefix`) of an S3 compatible object store using s3fs-fuse.
+/// Path style requests are used so that self hosted stores (e.g. MinIO) work without wildcard DNS.
+#[instrument(skip_all)]
+pub async fn mount_s3fs(
+    endpoint: &Url,
+    bucket: &str,
+    prefix: &str,
+    access_key_id: &str,
+    secret_access_key: &str,
+    mountpoint: impl AsRef<Path>,
+    mount_type: MountType,
+) -> Result<(), Error> {
+    tokio::fs::create_dir_all(mountpoint.as_ref()).await?;
+    let prefix = prefix.trim_matches('/');
+    let mut source = bucket.to_owned();
+    if !prefix.is_empty() {
+        source += ":/";
+        source += prefix;
+    }
+    let mut options = vec![
+        format!("url={}", endpoint.as_str().trim_end_matches('/')),
+        "use_path_request_style".to_owned(),
+    ];
+    if mount_type == ReadOnly {
+        options.push("ro".to_owned());
+    }
+    Command::new("s3fs")
+        .env("AWSACCESSKEYID", access_key_id)
+        .env("AWSSECRETACCESSKEY", secret_access_key)
+        .arg(source)
+        .arg(mountpoint.as_ref())
+        .arg("-o")
+        .arg(options.join(","))
+        .invoke(crate::ErrorKind::Filesystem)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct S3 {
+    pub endpoint: Url,
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+#[async_trait]
+impl FileSystem for S3 {
+    async fn mount<P: AsRef<std::path::Path> + Send + Sync>(
+        &self,
+        mountpoint: P,
+        mount_type: MountType,
+    ) -> Result<(), Error> {
+        mount_s3fs(
+            &self.endpoint,
+            &self.bucket,
+            &self.prefix,
+            &self.access_key_id,
+            &self.secret_access_key,
+            mountpoint,
+            mount_type,
+        )
+        .await
+    }
+    async fn source_hash(
+        &self,
+    ) -> Result<GenericArray<u8, <Sha256 as OutputSizeUser>::OutputSize>, Error> {
+        let mut sha = Sha256::new();
+        sha.update("S3");
+        sha.update(self.endpoint.as_str().as_bytes());
+        sha.update(self.bucket.as_bytes());
+        sha.update(self.prefix.trim_matches('/').as_bytes());
+        Ok(sha.finalize())
+    }
+}
+
+/// Run as root with s3fs and ecryptfs installed, against MinIO (https://min.io) with a bucket named `startos-test`:
+/// `S3_ENDPOINT=http://localhost:9000 cargo test -- --ignored s3_minio`
+#[tokio::test]
+#[ignore]
+async fn s3_minio() {
+    use crate::disk::mount::backup::BackupMountGuard;
+    use crate::disk::mount::filesystem::ReadWrite;
+    use crate::disk::mount::guard::TmpMountGuard;
+    use crate::disk::util::recovery_info;
+
+    let env = |var: &str, default: &str| std::env::var(var).unwrap_or_else(|_| default.to_owned());
+    let s3 = S3 {
+        endpoint: env("S3_ENDPOINT", "http://localhost:9000").parse().unwrap(),
+        bucket: env("S3_BUCKET", "startos-test"),
+        prefix: String::new(),
+        access_key_id: env("S3_ACCESS_KEY_ID", "minioadmin"),
+        secret_access_key: env("S3_SECRET_ACCESS_KEY", "minioadmin"),
+    };
+    let file = format!("s3_minio-{}", rand::random::<u32>());
+
+    let guard = TmpMountGuard::mount(&s3, ReadWrite).await.unwrap();
+    tokio::fs::write(guard.as_ref().join(&file), b"hello")
+        .await
+        .unwrap();
+    guard.unmount().await.unwrap();
+
+    let guard = TmpMountGuard::mount(&s3, ReadOnly).await.unwrap();
+    assert_eq!(
+        tokio::fs::read(guard.as_ref().join(&file)).await.unwrap(),
+        b"hello"
+    );
+    assert!(tokio::fs::write(guard.as_ref().join("read-only"), b"")
+        .await
+        .is_err());
+    guard.unmount().await.unwrap();
+
+    // the encrypted backup layout has to survive a round trip through the bucket
+    let backup = BackupMountGuard::mount(
+        TmpMountGuard::mount(&s3, ReadWrite).await.unwrap(),
+        "password",
+    )
+    .await
+    .unwrap();
+    backup.save_and_unmount().await.unwrap();
+    let guard = TmpMountGuard::mount(&s3, ReadOnly).await.unwrap();
+    assert!(recovery_info(&guard).await.unwrap().is_some());
+    guard.unmount().await.unwrap();
+    assert!(BackupMountGuard::mount(
+        TmpMountGuard::mount(&s3, ReadWrite).await.unwrap(),
+        "wrong password",
+    )
+    .await
+    .is_err());
+    BackupMountGuard::mount(
+        TmpMountGuard::mount(&s3, ReadWrite).await.unwrap(),
+        "password",
+    )
+    .await
+    .unwrap()
+    .unmount()
+    .await
+    .unwrap();
+}
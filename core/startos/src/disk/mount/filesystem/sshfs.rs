@@ -0,0 +1,157 @@
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use digest::generic_array::GenericArray;
+use digest::{Digest, OutputSizeUser};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use ssh_key::private::Ed25519Keypair;
+use ssh_key::LineEnding;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::instrument;
+
+use super::{FileSystem, MountType, ReadOnly};
+use crate::net::keys::Key;
+use crate::prelude::*;
+use crate::util::Invoke;
+
+/// Identity files and known hosts for sshfs mounts. Lives on a tmpfs so private keys never touch disk.
+pub const SSHFS_DIR: &str = "/run/embassy/sshfs";
+
+fn default_port() -> u16 {
+    22
+}
+
+/// sshfs reads the identity again whenever it reconnects, so each mount keeps its own until it is unmounted
+fn identity_path(mountpoint: &Path) -> PathBuf {
+    Path::new(SSHFS_DIR).join(format!(
+        "{}.key",
+        hex::encode(Sha256::digest(mountpoint.as_os_str().as_bytes()))
+    ))
+}
+
+/// Writes the OpenSSH encoding of `key` to a file only readable by root, and returns its path
+async fn write_identity(key: &Key, mountpoint: &Path) -> Result<PathBuf, Error> {
+    let private = ssh_key::PrivateKey::from(Ed25519Keypair::from(key.ssh_key()));
+    let path = identity_path(mountpoint);
+    tokio::fs::create_dir_all(SSHFS_DIR).await?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+    file.write_all(private.to_openssh(LineEnding::LF)?.as_bytes())
+        .await?;
+    file.sync_all().await?;
+    Ok(path)
+}
+
+/// Removes the identity written for an sshfs mount at `mountpoint`, if there is one
+pub async fn remove_identity(mountpoint: impl AsRef<Path>) -> Result<(), Error> {
+    let path = identity_path(mountpoint.as_ref());
+    match tokio::fs::remove_file(&path).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        a => a,
+    }
+    .with_ctx(|_| (ErrorKind::Filesystem, format!("rm {}", path.display())))
+}
+
+#[instrument(skip_all)]
+pub async fn mount_sshfs(
+    hostname: &str,
+    port: u16,
+    path: impl AsRef<Path>,
+    username: &str,
+    key: &Key,
+    mountpoint: impl AsRef<Path>,
+    mount_type: MountType,
+) -> Result<(), Error> {
+    tokio::fs::create_dir_all(mountpoint.as_ref()).await?;
+    let host = if hostname.ends_with(".local") {
+        crate::net::mdns::resolve_mdns(hostname).await?.to_string()
+    } else {
+        hostname.to_owned()
+    };
+    let identity = write_identity(key, mountpoint.as_ref()).await?;
+    let absolute_path = Path::new("/").join(path.as_ref());
+    let mut options = vec![
+        format!("IdentityFile={}", identity.display()),
+        "IdentitiesOnly=yes".to_owned(),
+        "BatchMode=yes".to_owned(),
+        "StrictHostKeyChecking=accept-new".to_owned(),
+        format!(
+            "UserKnownHostsFile={}",
+            Path::new(SSHFS_DIR).join("known_hosts").display()
+        ),
+        "reconnect".to_owned(),
+        "ServerAliveInterval=15".to_owned(),
+    ];
+    if mount_type == ReadOnly {
+        options.push("ro".to_owned());
+    }
+    let res = Command::new("sshfs")
+        .arg("-p")
+        .arg(port.to_string())
+        .arg(format!("{}@{}:{}", username, host, absolute_path.display()))
+        .arg(mountpoint.as_ref())
+        .arg("-o")
+        .arg(options.join(","))
+        .invoke(ErrorKind::Filesystem)
+        .await;
+    if res.is_err() {
+        remove_identity(mountpoint).await?;
+    }
+    res.map(|_| ())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Sftp {
+    pub hostname: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub path: PathBuf,
+    pub username: String,
+    /// The key to authenticate with. Never (de)serialized: it is always the server's own key.
+    #[serde(skip)]
+    pub key: Option<Key>,
+}
+#[async_trait]
+impl FileSystem for Sftp {
+    async fn mount<P: AsRef<std::path::Path> + Send + Sync>(
+        &self,
+        mountpoint: P,
+        mount_type: MountType,
+    ) -> Result<(), Error> {
+        mount_sshfs(
+            &self.hostname,
+            self.port,
+            &self.path,
+            &self.username,
+            self.key.as_ref().ok_or_else(|| {
+                Error::new(
+                    eyre!("No SSH key provided for SFTP target"),
+                    ErrorKind::Filesystem,
+                )
+            })?,
+            mountpoint,
+            mount_type,
+        )
+        .await
+    }
+    async fn source_hash(
+        &self,
+    ) -> Result<GenericArray<u8, <Sha256 as OutputSizeUser>::OutputSize>, Error> {
+        let mut sha = Sha256::new();
+        sha.update("Sftp");
+        sha.update(self.hostname.as_bytes());
+        sha.update(self.port.to_be_bytes());
+        sha.update(self.path.as_os_str().as_bytes());
+        Ok(sha.finalize())
+    }
+}
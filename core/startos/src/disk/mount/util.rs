@@ -48,5 +48,6 @@ pub async fn unmount<P: AsRef<Path>>(mountpoint: P) -> Result<(), Error> {
         .arg(mountpoint.as_ref())
         .invoke(crate::ErrorKind::Filesystem)
         .await?;
+    super::filesystem::sshfs::remove_identity(mountpoint).await?;
     Ok(())
 }
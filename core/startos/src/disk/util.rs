@@ -59,6 +59,12 @@ pub struct EmbassyOsRecoveryInfo {
     pub full: bool,
     pub password_hash: Option<String>,
     pub wrapped_key: Option<String>,
+    /// Hash of the key scheduled backups mount the target with
+    #[serde(default)]
+    pub schedule_key_hash: Option<String>,
+    /// The encryption key, wrapped with the schedule key
+    #[serde(default)]
+    pub schedule_wrapped_key: Option<String>,
 }
 
 const DISK_PATH: &str = "/dev/disk/by-path";
@@ -322,7 +322,7 @@ pub async fn init(cfg: &RpcContextConfig) -> Result<InitResult, Error> {
     if should_rebuild || !tmp_docker_exists {
         if CONTAINER_TOOL == "docker" {
             tracing::info!("Creating Docker Network");
-            create_bridge_network("start9", "172.18.0.1/24", "br-start9").await?;
+            create_bridge_network("start9", crate::BRIDGE_SUBNET, "br-start9").await?;
             tracing::info!("Created Docker Network");
         }
 
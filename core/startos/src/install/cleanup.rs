@@ -174,10 +174,22 @@ where
     cleanup(ctx, id, &version).await?;
     cleanup_folder(volume_dir, Arc::new(dependents_paths)).await;
     remove_network_keys(secrets, id).await?;
+    ctx.net_controller.remove_domains(id).await;
+    let clearnet = ctx.net_controller.clearnet_addresses().await;
+    ctx.net_controller.remove_proxy_settings(id).await;
+    ctx.health_history.remove(id).await;
 
     ctx.db
         .mutate(|d| {
             d.as_package_data_mut().remove(id)?;
+            let mut dns = d.as_server_info().as_dns().de()?;
+            if dns.packages.remove(id).is_some() {
+                d.as_server_info_mut().as_dns_mut().ser(&dns)?;
+            }
+            d.as_server_info_mut()
+                .as_connection_addresses_mut()
+                .as_clearnet_mut()
+                .ser(&clearnet)?;
             remove_from_current_dependents_lists(
                 d,
                 id,
@@ -198,6 +210,24 @@ where
     sqlx::query!("DELETE FROM tor WHERE package = $1", &*id)
         .execute(&mut *secrets)
         .await?;
+    sqlx::query!("DELETE FROM tor_client_auth WHERE package = $1", &*id)
+        .execute(&mut *secrets)
+        .await?;
+    sqlx::query!("DELETE FROM domain_bindings WHERE package = $1", &*id)
+        .execute(&mut *secrets)
+        .await?;
+    sqlx::query!("DELETE FROM proxy_settings WHERE package = $1", &*id)
+        .execute(&mut *secrets)
+        .await?;
+    sqlx::query!(
+        "DELETE FROM port_forward_overrides WHERE package = $1",
+        &*id
+    )
+    .execute(&mut *secrets)
+    .await?;
+    sqlx::query!("DELETE FROM health_remediations WHERE package = $1", &*id)
+        .execute(&mut *secrets)
+        .await?;
     Ok(())
 }
 
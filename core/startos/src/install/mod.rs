@@ -41,9 +41,12 @@ use crate::dependencies::{
 };
 use crate::install::cleanup::cleanup;
 use crate::install::progress::{InstallProgress, InstallProgressTracker};
+use crate::net::forward::{self, ForwardOverrides};
+use crate::net::interface::{ForwardProtocol, InterfaceId, Interfaces};
 use crate::notifications::NotificationLevel;
 use crate::prelude::*;
 use crate::registry::marketplace::with_query_params;
+use crate::s9pk::delta::{self, DeltaHeader};
 use crate::s9pk::manifest::{Manifest, PackageId};
 use crate::s9pk::reader::S9pkReader;
 use crate::status::{MainStatus, Status};
@@ -56,6 +59,7 @@ use crate::{Error, ErrorKind, ResultExt};
 
 pub mod cleanup;
 pub mod progress;
+pub mod rollback;
 
 pub const PKG_ARCHIVE_DIR: &str = "package-data/archive";
 pub const PKG_PUBLIC_DIR: &str = "package-data/public";
@@ -167,21 +171,6 @@ pub async fn install(
         .json()
         .await
         .with_kind(crate::ErrorKind::Registry)?;
-    let s9pk = ctx
-        .client
-        .get(with_query_params(
-            ctx.clone(),
-            format!(
-                "{}/package/v0/{}.s9pk?spec=={}&version-priority={}",
-                marketplace_url, id, man.version, version_priority,
-            )
-            .parse()?,
-        ))
-        .send()
-        .await
-        .with_kind(crate::ErrorKind::Registry)?
-        .error_for_status()?;
-
     if *man.id != *id || !man.version.satisfies(&version) {
         return Err(Error::new(
             eyre!("Fetched package does not match requested id and version"),
@@ -189,6 +178,60 @@ pub async fn install(
         ));
     }
 
+    let installed_version = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(&man.id)
+        .and_then(|pde| pde.as_installed())
+        .map(|i| i.as_manifest().as_version().de())
+        .transpose()?;
+    let delta = match &installed_version {
+        Some(from) if from != &man.version => fetch_delta(&ctx, &marketplace_url, &man, from)
+            .await
+            .map(|delta| (from.clone(), delta)),
+        _ => None,
+    };
+    let s9pk_url: Url = format!(
+        "{}/package/v0/{}.s9pk?spec=={}&version-priority={}",
+        marketplace_url, id, man.version, version_priority,
+    )
+    .parse()?;
+    let (s9pk, size, from_delta): (Box<dyn AsyncRead + Unpin + Send>, _, _) = match delta {
+        Some((from, (header, rdr))) => {
+            let source = ctx
+                .datadir
+                .join(PKG_ARCHIVE_DIR)
+                .join(&man.id)
+                .join(from.as_str())
+                .join(AsRef::<Path>::as_ref(&man.id).with_extension("s9pk"));
+            (
+                Box::new(tokio_util::io::StreamReader::new(
+                    delta::apply(source, header.clone(), rdr)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
+                )),
+                Some(header.target_len),
+                Some(FromDelta {
+                    expected_hash: header.target_hash,
+                    fallback: {
+                        let ctx = ctx.clone();
+                        let s9pk_url = s9pk_url.clone();
+                        async move {
+                            let (s9pk, _) = fetch_s9pk(&ctx, s9pk_url).await?;
+                            Ok(Box::new(s9pk) as Box<dyn AsyncRead + Unpin + Send>)
+                        }
+                        .boxed()
+                    },
+                }),
+            )
+        }
+        None => {
+            let (s9pk, size) = fetch_s9pk(&ctx, s9pk_url).await?;
+            (Box::new(s9pk), size, None)
+        }
+    };
+
     let public_dir_path = ctx
         .datadir
         .join(PKG_PUBLIC_DIR)
@@ -272,7 +315,7 @@ pub async fn install(
         tracing::warn!("Failed to pre-download icon: {}", e);
     }
 
-    let progress = Arc::new(InstallProgress::new(s9pk.content_length()));
+    let progress = Arc::new(InstallProgress::new(size));
     let static_files = StaticFiles::local(&man.id, &man.version, icon_type);
     ctx.db
         .mutate(|db| {
@@ -312,9 +355,10 @@ pub async fn install(
         ctx.clone(),
         man.clone(),
         Some(marketplace_url),
-        Arc::new(InstallProgress::new(s9pk.content_length())),
-        response_to_reader(s9pk),
+        Arc::new(InstallProgress::new(size)),
+        s9pk,
         None,
+        from_delta,
     );
     tokio::spawn(async move {
         if let Err(e) = downloading.await {
@@ -343,6 +387,69 @@ pub async fn install(
 
     Ok(())
 }
+
+/// The full s9pk from the marketplace, and its size if known
+async fn fetch_s9pk(
+    ctx: &RpcContext,
+    url: Url,
+) -> Result<(impl AsyncRead + Unpin + Send + 'static, Option<u64>), Error> {
+    let s9pk = ctx
+        .client
+        .get(with_query_params(ctx.clone(), url))
+        .send()
+        .await
+        .with_kind(crate::ErrorKind::Registry)?
+        .error_for_status()?;
+    let size = s9pk.content_length();
+    Ok((response_to_reader(s9pk), size))
+}
+
+/// A delta from the installed version of a package, if the marketplace has one.
+/// On any failure, the full s9pk is downloaded instead.
+#[instrument(skip_all)]
+async fn fetch_delta(
+    ctx: &RpcContext,
+    marketplace_url: &Url,
+    man: &Manifest,
+    from: &Version,
+) -> Option<(DeltaHeader, impl AsyncRead + Unpin + Send + 'static)> {
+    let res = async {
+        let res = ctx
+            .client
+            .get(with_query_params(
+                ctx.clone(),
+                format!(
+                    "{}/package/v0/delta/{}?from={}&spec=={}",
+                    marketplace_url, man.id, from, man.version,
+                )
+                .parse()?,
+            ))
+            .send()
+            .await
+            .with_kind(crate::ErrorKind::Registry)?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let mut rdr = response_to_reader(res.error_for_status()?);
+        let header = DeltaHeader::deserialize(&mut rdr).await?;
+        Ok::<_, Error>(Some((header, rdr)))
+    }
+    .await;
+    match res {
+        Ok(delta) => delta,
+        Err(e) => {
+            tracing::warn!(
+                "Failed to fetch delta for {}@{}: {}",
+                man.id,
+                man.version,
+                e
+            );
+            tracing::debug!("{:?}", e);
+            None
+        }
+    }
+}
+
 #[command(rpc_only, display(display_none))]
 #[instrument(skip_all)]
 pub async fn sideload(
@@ -460,6 +567,7 @@ pub async fn sideload(
                         )
                     })),
                     Some(send),
+                    None,
                 )
                 .await
                 {
@@ -655,6 +763,14 @@ pub async fn uninstall(
     Ok(return_id)
 }
 
+/// Where an s9pk being installed is reconstructed from a delta
+pub struct FromDelta {
+    /// What [S9pkReader::hash] of the reconstructed s9pk must be
+    pub expected_hash: [u8; 64],
+    /// The full s9pk, downloaded instead if the delta can not be applied
+    pub fallback: BoxFuture<'static, Result<Box<dyn AsyncRead + Unpin + Send>, Error>>,
+}
+
 #[instrument(skip_all)]
 pub async fn download_install_s9pk(
     ctx: RpcContext,
@@ -663,6 +779,7 @@ pub async fn download_install_s9pk(
     progress: Arc<InstallProgress>,
     mut s9pk: impl AsyncRead + Unpin,
     download_complete: Option<oneshot::Sender<()>>,
+    from_delta: Option<FromDelta>,
 ) -> Result<(), Error> {
     let pkg_id = &temp_manifest.id;
     let version = &temp_manifest.version;
@@ -707,6 +824,17 @@ pub async fn download_install_s9pk(
                     }
                 }
             }
+            let overrides = forward::overrides(&ctx.secret_store).await?;
+            if let Some((p, pkg)) = forward_port_conflict(
+                manifests.iter().map(|m| (&m.id, &m.interfaces)),
+                (&temp_manifest.id, &temp_manifest.interfaces),
+                &overrides,
+            ) {
+                return Err(Error::new(
+                    eyre!("Port {} is forwarded to package: {}", p, pkg),
+                    ErrorKind::LanPortConflict,
+                ));
+            }
 
             let pkg_archive_dir = ctx
                 .datadir
@@ -729,7 +857,39 @@ pub async fn download_install_s9pk(
                 .track_download_during(ctx.db.clone(), pkg_id, || async {
                     let mut progress_writer =
                         InstallProgressTracker::new(&mut dst, progress.clone());
-                    tokio::io::copy(&mut s9pk, &mut progress_writer).await?;
+                    let copied = tokio::io::copy(&mut s9pk, &mut progress_writer).await;
+                    if let Some(from_delta) = from_delta {
+                        let applied = async {
+                            copied?;
+                            if delta::signed_hash(&pkg_archive).await? != from_delta.expected_hash
+                            {
+                                return Err(Error::new(
+                                    eyre!("Reconstructed s9pk does not match the expected hash"),
+                                    ErrorKind::ValidateS9pk,
+                                ));
+                            }
+                            Ok(())
+                        }
+                        .await;
+                        if let Err(e) = applied {
+                            tracing::warn!(
+                                "Install {}@{}: Failed to apply delta, downloading the full s9pk: {}",
+                                pkg_id,
+                                version,
+                                e
+                            );
+                            tracing::debug!("{:?}", e);
+                            let mut s9pk = from_delta.fallback.await?;
+                            dst.set_len(0).await?;
+                            dst.seek(SeekFrom::Start(0)).await?;
+                            progress.downloaded.store(0, Ordering::SeqCst);
+                            let mut progress_writer =
+                                InstallProgressTracker::new(&mut dst, progress.clone());
+                            tokio::io::copy(&mut s9pk, &mut progress_writer).await?;
+                        }
+                    } else {
+                        copied?;
+                    }
                     progress.download_complete();
                     if let Some(complete) = download_complete {
                         complete.send(()).unwrap_or_default();
@@ -783,6 +943,7 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
     progress: Arc<InstallProgress>,
 ) -> Result<(), Error> {
     rdr.validate().await?;
+    crate::trust::check_signers(ctx.secret_store.acquire().await?.as_mut(), rdr.signers()).await?;
     rdr.validated();
     let developer_key = rdr.developer_key().clone();
     rdr.reset().await?;
@@ -987,6 +1148,35 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
                 &Default::default(),
             )
             .await?,
+            crashes: Default::default(),
+            restart_policy: match &prev {
+                PackageDataEntry::Updating(PackageDataEntryUpdating { installed, .. }) => {
+                    installed.status.restart_policy.clone()
+                }
+                _ => None,
+            },
+            resource_limits: match &prev {
+                PackageDataEntry::Updating(PackageDataEntryUpdating { installed, .. }) => {
+                    installed.status.resource_limits.clone()
+                }
+                _ => None,
+            },
+            // only for health checks and actions the new version still has
+            remediations: match &prev {
+                PackageDataEntry::Updating(PackageDataEntryUpdating { installed, .. }) => installed
+                    .status
+                    .remediations
+                    .iter()
+                    .filter(|(check_id, remediation)| {
+                        manifest.health_checks.0.contains_key(*check_id)
+                            && remediation
+                                .as_ref()
+                                .map_or(true, |r| r.validate(&manifest.actions).is_ok())
+                    })
+                    .map(|(check_id, remediation)| (check_id.clone(), remediation.clone()))
+                    .collect(),
+                _ => BTreeMap::new(),
+            },
         },
         marketplace_url,
         developer_key,
@@ -1019,9 +1209,24 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
     let mut to_cleanup = None;
 
     if let PackageDataEntry::Updating(PackageDataEntryUpdating {
-        installed: prev, ..
+        installed: prev,
+        static_files: prev_static_files,
+        ..
     }) = &prev
     {
+        if let Err(e) = rollback::snapshot(&ctx, prev, prev_static_files).await {
+            tracing::warn!(
+                "Update {}: Failed to snapshot {} for rollback: {}",
+                pkg_id,
+                prev.manifest.version,
+                e
+            );
+            tracing::debug!("{:?}", e);
+            let dir = crate::volume::rollback_dir(&ctx.datadir, pkg_id);
+            if tokio::fs::metadata(&dir).await.is_ok() {
+                tokio::fs::remove_dir_all(&dir).await?;
+            }
+        }
         let prev_is_configured = prev.status.configured;
         let prev_migration = prev
             .manifest
@@ -1299,6 +1504,62 @@ pub fn rebuild_from<'a>(
     .boxed()
 }
 
+/// Forwarded ports belong to a single package, and can not share a tcp port with the vhost proxy.
+/// Forwards are checked at the external port `overrides` remap them to, and not at all if disabled.
+fn forward_port_conflict<'a>(
+    installed: impl IntoIterator<Item = (&'a PackageId, &'a Interfaces)>,
+    (new_id, new): (&PackageId, &Interfaces),
+    overrides: &ForwardOverrides,
+) -> Option<(u16, PackageId)> {
+    let external = |pkg: &PackageId, iface: &InterfaceId, port: &Port| {
+        overrides
+            .get(&(pkg.clone(), iface.clone(), port.0))
+            .copied()
+            .unwrap_or(Some(port.0))
+    };
+    let mut forwarded = BTreeMap::new();
+    let mut lan = BTreeMap::new();
+    for (pkg, interfaces) in installed.into_iter().filter(|(id, _)| *id != new_id) {
+        for (id, iface) in &interfaces.0 {
+            for (p, forward) in iface.forward_config.iter().flatten() {
+                let Some(p) = external(pkg, id, p) else {
+                    continue;
+                };
+                for protocol in &forward.protocols {
+                    forwarded.insert((*protocol, p), pkg.clone());
+                }
+            }
+            for (p, _) in iface.lan_config.iter().flatten() {
+                lan.insert(p.0, pkg.clone());
+            }
+        }
+    }
+    for (id, iface) in &new.0 {
+        for (p, forward) in iface.forward_config.iter().flatten() {
+            let Some(p) = external(new_id, id, p) else {
+                continue;
+            };
+            for protocol in &forward.protocols {
+                if let Some(pkg) = forwarded.get(&(*protocol, p)).or_else(|| {
+                    if *protocol == ForwardProtocol::Tcp {
+                        lan.get(&p)
+                    } else {
+                        None
+                    }
+                }) {
+                    return Some((p, pkg.clone()));
+                }
+            }
+        }
+        for (p, _) in iface.lan_config.iter().flatten() {
+            if let Some(pkg) = forwarded.get(&(ForwardProtocol::Tcp, p.0)) {
+                return Some((p.0, pkg.clone()));
+            }
+        }
+    }
+    None
+}
+
 fn ssl_port_status(manifests: &Vec<Manifest>) -> BTreeMap<Port, (bool, PackageId)> {
     let mut ret = BTreeMap::new();
     for m in manifests {
@@ -1315,3 +1576,62 @@ fn ssl_port_status(manifests: &Vec<Manifest>) -> BTreeMap<Port, (bool, PackageId
     }
     ret
 }
+
+#[test]
+fn forward_conflicts_with_overrides() {
+    let interfaces = |forward: Value, lan: Value| -> Interfaces {
+        serde_json::from_value(json!({
+            "main": {
+                "name": "Main",
+                "description": "",
+                "tor-config": null,
+                "lan-config": lan,
+                "forward-config": forward,
+                "ui": false,
+                "protocols": ["tcp", "udp", "http"],
+            }
+        }))
+        .unwrap()
+    };
+    let game: PackageId = "game".parse().unwrap();
+    let other: PackageId = "other".parse().unwrap();
+    let main: InterfaceId = "main".parse().unwrap();
+    let game_ifaces = interfaces(
+        json!({ "25565": { "protocols": ["tcp"], "internal": 25565 } }),
+        Value::Null,
+    );
+    let same_port = interfaces(
+        json!({ "25565": { "protocols": ["tcp", "udp"], "internal": 25565 } }),
+        Value::Null,
+    );
+    let lan_port = interfaces(
+        Value::Null,
+        json!({ "8443": { "ssl": true, "internal": 80 } }),
+    );
+    let check = |new: &Interfaces, overrides: &ForwardOverrides| {
+        forward_port_conflict([(&game, &game_ifaces)], (&other, new), overrides)
+    };
+
+    let none = ForwardOverrides::new();
+    assert_eq!(check(&same_port, &none), Some((25565, game.clone())));
+    assert_eq!(check(&lan_port, &none), None);
+
+    // a remapped forward frees its manifest port, and takes the one it was moved to
+    let remapped = [((game.clone(), main.clone(), 25565), Some(8443))].into();
+    assert_eq!(check(&same_port, &remapped), None);
+    assert_eq!(check(&lan_port, &remapped), Some((8443, game.clone())));
+
+    // a disabled forward takes no port at all
+    let disabled = [((game.clone(), main.clone(), 25565), None)].into();
+    assert_eq!(check(&same_port, &disabled), None);
+
+    // the new package keeps its own overrides across updates
+    let moved_away = [((other.clone(), main.clone(), 25565), Some(25566))].into();
+    assert_eq!(check(&same_port, &moved_away), None);
+    let moved_onto = [((other.clone(), main.clone(), 25566), Some(25565))].into();
+    let other_port = interfaces(
+        json!({ "25566": { "protocols": ["tcp"], "internal": 25566 } }),
+        Value::Null,
+    );
+    assert_eq!(check(&other_port, &moved_onto), Some((25565, game)));
+}
@@ -0,0 +1,241 @@
+use std::path::{Path, PathBuf};
+
+use rpc_toolkit::command;
+use serde::Serialize;
+use tokio::process::Command;
+use tracing::instrument;
+
+use super::cleanup::{cleanup, remove_from_current_dependents_lists};
+use super::{unpack_s9pk, PKG_ARCHIVE_DIR};
+use crate::context::RpcContext;
+use crate::db::model::{
+    InstalledPackageInfo, PackageDataEntry, PackageDataEntryInstalled, StaticFiles,
+};
+use crate::dependencies::add_dependent_to_current_dependents_lists;
+use crate::prelude::*;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::s9pk::reader::S9pkReader;
+use crate::status::MainStatus;
+use crate::util::serde::IoFormat;
+use crate::util::{display_none, Invoke, Version};
+use crate::volume::{rollback_dir, PKG_VOLUME_DIR};
+
+/// Written last, so a snapshot without it is incomplete and ignored
+const ENTRY_FILE: &str = "package.cbor";
+
+/// Same layout as [PackageDataEntryInstalled], which is what the snapshot is read back as
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct SnapshotEntry<'a> {
+    static_files: &'a StaticFiles,
+    manifest: &'a Manifest,
+    installed: &'a InstalledPackageInfo,
+}
+
+fn archive_path(datadir: &Path, pkg_id: &PackageId, version: &Version) -> PathBuf {
+    datadir
+        .join(PKG_ARCHIVE_DIR)
+        .join(pkg_id)
+        .join(version.as_str())
+        .join(format!("{}.s9pk", pkg_id))
+}
+
+fn volumes_path(datadir: &Path, pkg_id: &PackageId) -> PathBuf {
+    datadir.join(PKG_VOLUME_DIR).join(pkg_id).join("data")
+}
+
+/// Copies, sharing extents where the filesystem supports it
+async fn cp(src: &Path, dst: &Path) -> Result<(), Error> {
+    Command::new("cp")
+        .arg("-a")
+        .arg("--reflink=auto")
+        .arg(src)
+        .arg(dst)
+        .invoke(ErrorKind::Filesystem)
+        .await?;
+    Ok(())
+}
+
+/// Bytes taken up by `path` and everything under it
+async fn disk_usage(path: &Path) -> Result<u64, Error> {
+    let out = Command::new("du")
+        .arg("-s")
+        .arg("--block-size=1")
+        .arg(path)
+        .invoke(ErrorKind::Filesystem)
+        .await?;
+    String::from_utf8(out)?
+        .split_whitespace()
+        .next()
+        .and_then(|size| size.parse().ok())
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("could not parse disk usage of {}", path.display()),
+                ErrorKind::ParseSysInfo,
+            )
+        })
+}
+
+/// Bytes free on the filesystem containing `path`
+async fn available_space(path: &Path) -> Result<u64, Error> {
+    let path = path.to_owned();
+    let stat = tokio::task::spawn_blocking(move || nix::sys::statvfs::statvfs(&path))
+        .await
+        .with_kind(ErrorKind::Unknown)?
+        .with_ctx(|_| (ErrorKind::Filesystem, "statvfs"))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// Captures everything needed to roll back to the currently installed version of a package.
+/// Only the most recent snapshot is kept.
+/// The installed version is stopped first, so its volumes are not copied while in use.
+/// If there is not enough free space for the copy, no snapshot is taken and the update goes ahead without one.
+#[instrument(skip_all)]
+pub async fn snapshot(
+    ctx: &RpcContext,
+    installed: &InstalledPackageInfo,
+    static_files: &StaticFiles,
+) -> Result<(), Error> {
+    let pkg_id = &installed.manifest.id;
+    let version = &installed.manifest.version;
+    if let Some(manager) = ctx.managers.get(&(pkg_id.clone(), version.clone())).await {
+        manager.exit().await;
+    }
+
+    tracing::info!("Update {}: Snapshotting {}", pkg_id, version);
+    // an older snapshot would roll back past this version, so it goes even if no new one is taken
+    let dir = rollback_dir(&ctx.datadir, pkg_id);
+    if tokio::fs::metadata(&dir).await.is_ok() {
+        tokio::fs::remove_dir_all(&dir).await?;
+    }
+
+    let volumes = volumes_path(&ctx.datadir, pkg_id);
+    let has_volumes = tokio::fs::metadata(&volumes).await.is_ok();
+    let archive = archive_path(&ctx.datadir, pkg_id, version);
+    let needed = disk_usage(&archive).await?
+        + if has_volumes {
+            disk_usage(&volumes).await?
+        } else {
+            0
+        };
+    let available = available_space(&ctx.datadir).await?;
+    if needed > available {
+        tracing::warn!(
+            "Update {}: Not snapshotting {}: it needs {} bytes, but only {} are free. It will not be possible to roll back this update.",
+            pkg_id,
+            version,
+            needed,
+            available
+        );
+        return Ok(());
+    }
+
+    tokio::fs::create_dir_all(&dir).await?;
+    if has_volumes {
+        cp(&volumes, &dir.join("data")).await?;
+    }
+    cp(&archive, &dir.join(format!("{}.s9pk", pkg_id))).await?;
+    tokio::fs::write(
+        dir.join(ENTRY_FILE),
+        IoFormat::Cbor.to_vec(&SnapshotEntry {
+            static_files,
+            manifest: &installed.manifest,
+            installed,
+        })?,
+    )
+    .await?;
+    tracing::info!("Update {}: Snapshotted {}", pkg_id, version);
+
+    Ok(())
+}
+
+/// Restores the s9pk, data volumes and configuration a package had before its last update.
+/// The package must be stopped. The snapshot is consumed.
+#[command(display(display_none), metadata(sync_db = true))]
+#[instrument(skip(ctx))]
+pub async fn rollback(#[context] ctx: RpcContext, #[arg] id: PackageId) -> Result<(), Error> {
+    let current: InstalledPackageInfo = {
+        let peek = ctx.db.peek().await;
+        let installed = peek
+            .as_package_data()
+            .as_idx(&id)
+            .or_not_found(&id)?
+            .expect_as_installed()?
+            .as_installed();
+        if !matches!(installed.as_status().as_main().de()?, MainStatus::Stopped) {
+            return Err(Error::new(
+                eyre!("{} must be stopped to roll it back", id),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        installed.de()?
+    };
+
+    let dir = rollback_dir(&ctx.datadir, &id);
+    let entry_path = dir.join(ENTRY_FILE);
+    if tokio::fs::metadata(&entry_path).await.is_err() {
+        return Err(Error::new(
+            eyre!("No rollback snapshot for {}", id),
+            ErrorKind::NotFound,
+        ));
+    }
+    let mut prev: PackageDataEntryInstalled =
+        IoFormat::Cbor.from_slice(&tokio::fs::read(&entry_path).await?)?;
+    let version = prev.manifest.version.clone();
+    tracing::info!(
+        "Rollback {}: {} -> {}",
+        id,
+        current.manifest.version,
+        version
+    );
+
+    let s9pk = dir.join(format!("{}.s9pk", id));
+    let mut rdr = S9pkReader::open(&s9pk, true).await?;
+    unpack_s9pk(&ctx.datadir, &prev.manifest, &mut rdr).await?;
+    let archive = archive_path(&ctx.datadir, &id, &version);
+    if tokio::fs::metadata(&archive).await.is_err() {
+        if let Some(parent) = archive.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        cp(&s9pk, &archive).await?;
+    }
+
+    let volumes = volumes_path(&ctx.datadir, &id);
+    let snapshot_volumes = dir.join("data");
+    if tokio::fs::metadata(&snapshot_volumes).await.is_ok() {
+        let discarded = volumes.with_file_name("data.discarded");
+        if tokio::fs::metadata(&discarded).await.is_ok() {
+            tokio::fs::remove_dir_all(&discarded).await?;
+        }
+        if tokio::fs::metadata(&volumes).await.is_ok() {
+            tokio::fs::rename(&volumes, &discarded).await?;
+        }
+        tokio::fs::rename(&snapshot_volumes, &volumes).await?;
+        if tokio::fs::metadata(&discarded).await.is_ok() {
+            tokio::fs::remove_dir_all(&discarded).await?;
+        }
+    }
+
+    prev.installed.status.main = MainStatus::Stopped;
+    prev.installed.current_dependents = current.current_dependents.clone();
+    let prev_dependencies = prev.installed.current_dependencies.clone();
+    let manifest = prev.manifest.clone();
+    ctx.db
+        .mutate(|db| {
+            remove_from_current_dependents_lists(db, &id, &current.current_dependencies)?;
+            db.as_package_data_mut()
+                .insert(&id, &PackageDataEntry::Installed(prev))?;
+            add_dependent_to_current_dependents_lists(db, &id, &prev_dependencies)
+        })
+        .await?;
+
+    if current.manifest.version != version {
+        cleanup(&ctx, &id, &current.manifest.version).await?;
+    }
+    ctx.managers.add(ctx.clone(), manifest).await?;
+
+    tokio::fs::remove_dir_all(&dir).await?;
+    tracing::info!("Rollback {}: Complete", id);
+
+    Ok(())
+}
@@ -2,6 +2,8 @@ pub const DEFAULT_MARKETPLACE: &str = "https://registry.start9.com";
 // pub const COMMUNITY_MARKETPLACE: &str = "https://community-registry.start9.com";
 pub const BUFFER_SIZE: usize = 1024;
 pub const HOST_IP: [u8; 4] = [172, 18, 0, 1];
+/// The docker bridge packages are attached to, with the address of the server on it
+pub const BRIDGE_SUBNET: &str = "172.18.0.1/24";
 pub const TARGET: &str = current_platform::CURRENT_PLATFORM;
 lazy_static::lazy_static! {
     pub static ref ARCH: &'static str = {
@@ -58,6 +60,7 @@ pub mod sound;
 pub mod ssh;
 pub mod status;
 pub mod system;
+pub mod trust;
 pub mod update;
 pub mod util;
 pub mod version;
@@ -85,6 +88,7 @@ pub fn echo(#[arg] message: String) -> Result<String, RpcError> {
     auth::auth,
     db::db,
     ssh::ssh,
+    trust::trust,
     net::wifi::wifi,
     disk::disk,
     notifications::notification,
@@ -117,10 +121,14 @@ pub fn server() -> Result<(), RpcError> {
     install::sideload,
     install::uninstall,
     install::list,
+    install::rollback::rollback,
     config::config,
     control::start,
     control::stop,
     control::restart,
+    control::restart_policy,
+    manager::health::health,
+    manager::resources::resources,
     logs::logs,
     properties::properties,
     dependencies::dependency,
@@ -133,6 +141,9 @@ pub fn package() -> Result<(), RpcError> {
 #[command(subcommands(
     version::git_info,
     s9pk::pack,
+    s9pk::convert,
+    s9pk::countersign,
+    s9pk::make_delta,
     developer::verify,
     developer::init,
     inspect::inspect,
@@ -1,16 +1,193 @@
-use models::OptionExt;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::Ipv4Addr;
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use models::{ActionId, OptionExt};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
 use tracing::instrument;
 
 use crate::context::RpcContext;
+use crate::notifications::NotificationLevel;
 use crate::prelude::*;
-use crate::s9pk::manifest::PackageId;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::status::health_check::{
+    HealthCheckId, HealthCheckResult, Remediation, RemediationAction,
+};
 use crate::status::MainStatus;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, Duration, IoFormat};
 use crate::Error;
 
-/// So, this is used for a service to run a health check cycle, go out and run the health checks, and store those in the db
+/// Results kept per health check. With a check every 15 seconds or so, this covers about an hour
+const HEALTH_HISTORY_LEN: usize = 256;
+pub const DEFAULT_FAILURE_GRACE_PERIOD: std::time::Duration =
+    std::time::Duration::from_secs(5 * 60);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthCheckRecord {
+    pub checked_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub result: HealthCheckResult,
+}
+
+#[derive(Default)]
+struct CheckHistory {
+    records: VecDeque<HealthCheckRecord>,
+    failing_since: Option<DateTime<Utc>>,
+    notified: bool,
+    consecutive_failures: u32,
+    /// Within the last day
+    remediations: VecDeque<DateTime<Utc>>,
+}
+impl CheckHistory {
+    /// Whether the remediation is due, in which case it is counted as performed
+    fn remediation_due(&mut self, remediation: &Remediation, now: DateTime<Utc>) -> bool {
+        if self.consecutive_failures < remediation.after {
+            return false;
+        }
+        while self
+            .remediations
+            .front()
+            .map_or(false, |t| now - *t >= chrono::Duration::days(1))
+        {
+            self.remediations.pop_front();
+        }
+        let cooled_down = self.remediations.back().map_or(true, |last| {
+            (now - *last).to_std().unwrap_or_default() >= *remediation.cooldown
+        });
+        if !cooled_down || self.remediations.len() >= remediation.max_per_day as usize {
+            return false;
+        }
+        self.remediations.push_back(now);
+        self.consecutive_failures = 0;
+        true
+    }
+
+    /// Number of times the check went from failing to succeeding or back
+    fn flaps(&self) -> usize {
+        self.records
+            .iter()
+            .filter_map(|r| match r.result {
+                HealthCheckResult::Success => Some(true),
+                HealthCheckResult::Failure { .. } => Some(false),
+                _ => None,
+            })
+            .fold((None, 0), |(prev, flaps), ok| match prev {
+                Some(prev) if prev != ok => (Some(ok), flaps + 1),
+                _ => (Some(ok), flaps),
+            })
+            .1
+    }
+}
+
+#[derive(Default)]
+struct PackageHealthHistory {
+    started: Option<DateTime<Utc>>,
+    checks: BTreeMap<HealthCheckId, CheckHistory>,
+}
+
+#[derive(Default)]
+struct Recorded {
+    /// Checks that just exceeded the failure grace period, with how long they have been failing
+    failing: Vec<(HealthCheckId, std::time::Duration)>,
+    remediations: Vec<(HealthCheckId, Remediation)>,
+}
+
+/// Recent health check results of every package, kept in memory
+pub struct HealthHistory {
+    failure_grace_period: std::time::Duration,
+    packages: Mutex<BTreeMap<PackageId, PackageHealthHistory>>,
+}
+impl HealthHistory {
+    pub fn new(failure_grace_period: std::time::Duration) -> Self {
+        Self {
+            failure_grace_period,
+            packages: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    async fn record(
+        &self,
+        manifest: &Manifest,
+        overrides: &BTreeMap<HealthCheckId, Option<Remediation>>,
+        started: DateTime<Utc>,
+        results: &BTreeMap<HealthCheckId, HealthCheckResult>,
+    ) -> Recorded {
+        let now = Utc::now();
+        let mut packages = self.packages.lock().await;
+        let package = packages.entry(manifest.id.clone()).or_default();
+        if package.started != Some(started) {
+            // failures from a previous run do not count towards the grace period or remediations
+            for check in package.checks.values_mut() {
+                check.failing_since = None;
+                check.notified = false;
+                check.consecutive_failures = 0;
+            }
+            package.started = Some(started);
+        }
+        package
+            .checks
+            .retain(|check_id, _| results.contains_key(check_id));
+        let mut recorded = Recorded::default();
+        for (check_id, result) in results {
+            let check = package.checks.entry(check_id.clone()).or_default();
+            check.records.push_back(HealthCheckRecord {
+                checked_at: now,
+                result: result.clone(),
+            });
+            while check.records.len() > HEALTH_HISTORY_LEN {
+                check.records.pop_front();
+            }
+            if let HealthCheckResult::Failure { .. } = result {
+                let since = *check.failing_since.get_or_insert(now);
+                let failing_for = (now - since).to_std().unwrap_or_default();
+                if !check.notified && failing_for >= self.failure_grace_period {
+                    check.notified = true;
+                    recorded.failing.push((check_id.clone(), failing_for));
+                }
+                check.consecutive_failures += 1;
+                let remediation = match overrides.get(check_id) {
+                    Some(overridden) => overridden.as_ref(),
+                    None => manifest
+                        .health_checks
+                        .0
+                        .get(check_id)
+                        .and_then(|c| c.remediation.as_ref()),
+                };
+                if let Some(remediation) = remediation {
+                    if check.remediation_due(remediation, now) {
+                        recorded
+                            .remediations
+                            .push((check_id.clone(), remediation.clone()));
+                    }
+                }
+            } else {
+                check.failing_since = None;
+                check.notified = false;
+                check.consecutive_failures = 0;
+            }
+        }
+        recorded
+    }
+
+    pub async fn remove(&self, id: &PackageId) {
+        self.packages.lock().await.remove(id);
+    }
+}
+
+/// So, this is used for a service to run a health check cycle, go out and run the health checks, and store those in the db.
+/// Returns the remediations that are due
 #[instrument(skip_all)]
-pub async fn check(ctx: &RpcContext, id: &PackageId) -> Result<(), Error> {
-    let (manifest, started) = {
+pub async fn check(
+    ctx: &RpcContext,
+    id: &PackageId,
+    ip: Option<Ipv4Addr>,
+) -> Result<Vec<(HealthCheckId, Remediation)>, Error> {
+    let (manifest, remediations, started) = {
         let peeked = ctx.db.peek().await;
         let pde = peeked
             .as_package_data()
@@ -19,22 +196,41 @@ pub async fn check(ctx: &RpcContext, id: &PackageId) -> Result<(), Error> {
             .expect_as_installed()?;
 
         let manifest = pde.as_installed().as_manifest().de()?;
+        let remediations = pde.as_installed().as_status().as_remediations().de()?;
 
         let started = pde.as_installed().as_status().as_main().de()?.started();
 
-        (manifest, started)
+        (manifest, remediations, started)
     };
 
-    let health_results = if let Some(started) = started {
+    let (health_results, started) = if let Some(started) = started {
         tracing::debug!("Checking health of {}", id);
-        manifest
-            .health_checks
-            .check_all(ctx, started, id, &manifest.version, &manifest.volumes)
-            .await?
+        (
+            manifest
+                .health_checks
+                .check_all(ctx, started, id, &manifest.version, &manifest.volumes, ip)
+                .await?,
+            started,
+        )
     } else {
-        return Ok(());
+        return Ok(Vec::new());
     };
 
+    let recorded = ctx
+        .health_history
+        .record(&manifest, &remediations, started, &health_results)
+        .await;
+    for (check_id, failing_for) in recorded.failing {
+        notify_failing(
+            ctx,
+            &manifest,
+            &check_id,
+            &health_results[&check_id],
+            failing_for,
+        )
+        .await;
+    }
+
     ctx.db
         .mutate(|v| {
             let pde = v
@@ -52,5 +248,500 @@ pub async fn check(ctx: &RpcContext, id: &PackageId) -> Result<(), Error> {
             }
             Ok(())
         })
+        .await?;
+
+    Ok(recorded.remediations)
+}
+
+/// Performs a remediation and records it in the audit trail
+#[instrument(skip_all)]
+pub async fn remediate(
+    ctx: &RpcContext,
+    manifest: &Manifest,
+    check_id: &HealthCheckId,
+    remediation: &RemediationAction,
+) {
+    tracing::warn!(
+        "Health check {} of {} keeps failing, performing {}",
+        check_id,
+        manifest.id,
+        remediation
+    );
+    let res = match remediation {
+        // the restart stops the health checks, so it happens after recording it
+        RemediationAction::Restart => Ok(()),
+        RemediationAction::Action { action_id } => match manifest.actions.0.get(action_id) {
+            Some(action) => action
+                .execute(
+                    ctx,
+                    &manifest.id,
+                    &manifest.version,
+                    action_id,
+                    &manifest.volumes,
+                    None,
+                )
+                .await
+                .map(|_| ()),
+            None => Err(Error::new(
+                eyre!("Action {} not found in manifest", action_id),
+                ErrorKind::NotFound,
+            )),
+        },
+    };
+    if let Err(e) = &res {
+        tracing::error!("Remediation of {} failed: {}", manifest.id, e);
+        tracing::debug!("{:?}", e);
+    }
+    let error = res.err().map(|e| e.to_string());
+    if let Err(e) = sqlx::query!(
+        "INSERT INTO health_remediations (package, health_check, remediation, error) VALUES ($1, $2, $3, $4)",
+        &*manifest.id,
+        check_id.to_string(),
+        remediation.to_string(),
+        error
+    )
+    .execute(&ctx.secret_store)
+    .await
+    {
+        tracing::error!("Failed to record remediation: {}", e);
+        tracing::debug!("{:?}", e);
+    }
+    if let Err(e) = ctx
+        .notification_manager
+        .notify(
+            ctx.db.clone(),
+            Some(manifest.id.clone()),
+            NotificationLevel::Warning,
+            "Service Remediated".to_owned(),
+            format!(
+                "A health check of {} kept failing, so the following was performed automatically: {}{}",
+                manifest.title,
+                remediation,
+                error.map_or_else(String::new, |e| format!(" (failed: {})", e))
+            ),
+            (),
+            None,
+        )
+        .await
+    {
+        tracing::error!("Failed to send remediation notification: {}", e);
+        tracing::debug!("{:?}", e);
+    }
+    if let RemediationAction::Restart = remediation {
+        if let Some(manager) = ctx
+            .managers
+            .get(&(manifest.id.clone(), manifest.version.clone()))
+            .await
+        {
+            manager.restart().await;
+        }
+    }
+}
+
+async fn notify_failing(
+    ctx: &RpcContext,
+    manifest: &Manifest,
+    check_id: &HealthCheckId,
+    result: &HealthCheckResult,
+    failing_for: std::time::Duration,
+) {
+    let name = manifest
+        .health_checks
+        .0
+        .get(check_id)
+        .map_or_else(|| check_id.to_string(), |c| c.name.clone());
+    if let Err(e) = ctx
+        .notification_manager
+        .notify(
+            ctx.db.clone(),
+            Some(manifest.id.clone()),
+            NotificationLevel::Warning,
+            "Health Check Failing".to_owned(),
+            format!(
+                "The \"{}\" health check of {} has been failing for {} minutes: {}",
+                name,
+                manifest.title,
+                failing_for.as_secs() / 60,
+                result
+            ),
+            (),
+            None,
+        )
         .await
+    {
+        tracing::error!("Failed to send health check notification: {}", e);
+        tracing::debug!("{:?}", e);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthCheckHistory {
+    pub failing_since: Option<DateTime<Utc>>,
+    /// Number of times the check went from failing to succeeding or back within the history
+    pub flaps: usize,
+    /// Oldest first
+    pub history: Vec<HealthCheckRecord>,
+}
+
+#[command(subcommands(history, remediations, remediation))]
+pub fn health() -> Result<(), Error> {
+    Ok(())
+}
+
+fn display_history(history: BTreeMap<HealthCheckId, HealthCheckHistory>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(history, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "HEALTH CHECK", "LATEST", "CHECKED AT", "FAILING SINCE", "FLAPS"]);
+    for (id, check) in history {
+        let latest = check.history.last();
+        table.add_row(row![
+            id,
+            latest.map_or_else(String::new, |r| r.result.to_string()),
+            latest.map_or_else(String::new, |r| r.checked_at.to_rfc3339()),
+            check
+                .failing_since
+                .map_or_else(String::new, |t| t.to_rfc3339()),
+            check.flaps,
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+/// Recent results of the health checks of a package, kept since the server started
+#[command(display(display_history))]
+#[instrument(skip_all)]
+pub async fn history(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<BTreeMap<HealthCheckId, HealthCheckHistory>, Error> {
+    ctx.db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(&id)
+        .or_not_found(&id)?
+        .expect_as_installed()?;
+    let packages = ctx.health_history.packages.lock().await;
+    Ok(packages
+        .get(&id)
+        .into_iter()
+        .flat_map(|p| p.checks.iter())
+        .map(|(check_id, check)| {
+            (
+                check_id.clone(),
+                HealthCheckHistory {
+                    failing_since: check.failing_since,
+                    flaps: check.flaps(),
+                    history: check.records.iter().cloned().collect(),
+                },
+            )
+        })
+        .collect())
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RemediationRecord {
+    pub health_check: HealthCheckId,
+    pub remediation: String,
+    pub performed_at: DateTime<Utc>,
+    pub error: Option<String>,
+}
+
+fn display_remediations(records: Vec<RemediationRecord>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(records, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "PERFORMED AT", "HEALTH CHECK", "REMEDIATION", "ERROR"]);
+    for record in records {
+        table.add_row(row![
+            record.performed_at.to_rfc3339(),
+            record.health_check,
+            record.remediation,
+            record.error.unwrap_or_default(),
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+/// Remediations performed automatically because of failing health checks, newest first
+#[command(display(display_remediations))]
+#[instrument(skip_all)]
+pub async fn remediations(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] limit: Option<u32>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<RemediationRecord>, Error> {
+    sqlx::query!(
+        "SELECT health_check, remediation, performed_at, error FROM health_remediations WHERE package = $1 ORDER BY id DESC LIMIT $2",
+        &*id,
+        limit.unwrap_or(50) as i64
+    )
+    .fetch_all(&ctx.secret_store)
+    .await?
+    .into_iter()
+    .map(|r| {
+        Ok(RemediationRecord {
+            health_check: r.health_check.parse()?,
+            remediation: r.remediation,
+            performed_at: DateTime::from_utc(r.performed_at, Utc),
+            error: r.error,
+        })
+    })
+    .collect()
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RemediationInfo {
+    /// The remediation in effect, if any
+    pub remediation: Option<Remediation>,
+    /// Whether it was set by the user rather than taken from the manifest
+    pub overridden: bool,
+}
+
+/// What the manager does on its own when a health check keeps failing
+#[command(subcommands(
+    get_remediation,
+    set_remediation,
+    disable_remediation,
+    clear_remediation
+))]
+pub fn remediation() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(rename = "get", display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn get_remediation(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(rename = "health-check")] health_check: HealthCheckId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<RemediationInfo, Error> {
+    let peek = ctx.db.peek().await;
+    let installed = peek
+        .as_package_data()
+        .as_idx(&id)
+        .or_not_found(&id)?
+        .expect_as_installed()?
+        .as_installed();
+    let check = installed
+        .as_manifest()
+        .as_health_checks()
+        .de()?
+        .0
+        .remove(&health_check)
+        .or_not_found(&health_check)?;
+    Ok(
+        match installed
+            .as_status()
+            .as_remediations()
+            .de()?
+            .remove(&health_check)
+        {
+            Some(remediation) => RemediationInfo {
+                remediation,
+                overridden: true,
+            },
+            None => RemediationInfo {
+                remediation: check.remediation,
+                overridden: false,
+            },
+        },
+    )
+}
+
+/// Options that are not passed keep the values of the remediation currently in effect
+#[command(rename = "set", display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn set_remediation(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(rename = "health-check")] health_check: HealthCheckId,
+    #[arg(long = "restart")] restart: bool,
+    #[arg(rename = "action-id", long = "action-id")] action_id: Option<ActionId>,
+    #[arg(long = "after")] after: Option<u32>,
+    #[arg(long = "cooldown")] cooldown: Option<Duration>,
+    #[arg(rename = "max-per-day", long = "max-per-day")] max_per_day: Option<u32>,
+) -> Result<(), Error> {
+    let action = match (restart, action_id) {
+        (true, Some(_)) => {
+            return Err(Error::new(
+                eyre!("--restart and --action-id are mutually exclusive"),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        (true, None) => Some(RemediationAction::Restart),
+        (false, Some(action_id)) => Some(RemediationAction::Action { action_id }),
+        (false, None) => None,
+    };
+    ctx.db
+        .mutate(|db| {
+            let installed = db
+                .as_package_data_mut()
+                .as_idx_mut(&id)
+                .or_not_found(&id)?
+                .as_installed_mut()
+                .or_not_found(&id)?;
+            let manifest = installed.as_manifest().de()?;
+            let check = manifest
+                .health_checks
+                .0
+                .get(&health_check)
+                .or_not_found(&health_check)?;
+            let mut remediations = installed.as_status().as_remediations().de()?;
+            let current = match remediations.get(&health_check) {
+                Some(overridden) => overridden.clone(),
+                None => check.remediation.clone(),
+            };
+            let mut remediation = match (current, action.clone()) {
+                (Some(mut remediation), action) => {
+                    if let Some(action) = action {
+                        remediation.action = action;
+                    }
+                    remediation
+                }
+                (None, Some(action)) => Remediation::new(action),
+                (None, None) => {
+                    return Err(Error::new(
+                        eyre!(
+                            "{} is not remediated, pass --restart or --action-id",
+                            health_check
+                        ),
+                        ErrorKind::InvalidRequest,
+                    ));
+                }
+            };
+            if let Some(after) = after {
+                remediation.after = after;
+            }
+            if let Some(cooldown) = cooldown.clone() {
+                remediation.cooldown = cooldown;
+            }
+            if let Some(max_per_day) = max_per_day {
+                remediation.max_per_day = max_per_day;
+            }
+            remediation
+                .validate(&manifest.actions)
+                .map_err(|e| Error::new(e.source, ErrorKind::InvalidRequest))?;
+            remediations.insert(health_check.clone(), Some(remediation));
+            installed
+                .as_status_mut()
+                .as_remediations_mut()
+                .ser(&remediations)
+        })
+        .await
+}
+
+/// Stop remediating a health check, even if the manifest says to
+#[command(rename = "disable", display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn disable_remediation(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(rename = "health-check")] health_check: HealthCheckId,
+) -> Result<(), Error> {
+    ctx.db
+        .mutate(|db| {
+            let installed = db
+                .as_package_data_mut()
+                .as_idx_mut(&id)
+                .or_not_found(&id)?
+                .as_installed_mut()
+                .or_not_found(&id)?;
+            installed
+                .as_manifest()
+                .as_health_checks()
+                .de()?
+                .0
+                .get(&health_check)
+                .or_not_found(&health_check)?;
+            let mut remediations = installed.as_status().as_remediations().de()?;
+            remediations.insert(health_check.clone(), None);
+            installed
+                .as_status_mut()
+                .as_remediations_mut()
+                .ser(&remediations)
+        })
+        .await
+}
+
+/// Go back to the remediation of the manifest
+#[command(rename = "clear", display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn clear_remediation(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(rename = "health-check")] health_check: HealthCheckId,
+) -> Result<(), Error> {
+    ctx.db
+        .mutate(|db| {
+            let status = db
+                .as_package_data_mut()
+                .as_idx_mut(&id)
+                .or_not_found(&id)?
+                .as_installed_mut()
+                .or_not_found(&id)?
+                .as_status_mut();
+            let mut remediations = status.as_remediations().de()?;
+            remediations.remove(&health_check);
+            status.as_remediations_mut().ser(&remediations)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn remediation_cooldown_and_daily_limit() {
+        let remediation = Remediation {
+            action: RemediationAction::Restart,
+            after: 2,
+            cooldown: std::time::Duration::from_secs(10 * 60).into(),
+            max_per_day: 3,
+        };
+        let start: DateTime<Utc> = "2023-11-01T00:00:00Z".parse().unwrap();
+        let at = |minutes: i64| start + chrono::Duration::minutes(minutes);
+        let mut check = CheckHistory::default();
+
+        check.consecutive_failures = 1;
+        assert!(!check.remediation_due(&remediation, at(0)));
+        check.consecutive_failures = 2;
+        assert!(check.remediation_due(&remediation, at(0)));
+        assert_eq!(check.consecutive_failures, 0);
+
+        // failures keep counting through the cooldown
+        check.consecutive_failures = 2;
+        assert!(!check.remediation_due(&remediation, at(5)));
+        assert!(check.remediation_due(&remediation, at(10)));
+        check.consecutive_failures = 2;
+        assert!(check.remediation_due(&remediation, at(20)));
+
+        // until the first of the day is a day old
+        check.consecutive_failures = 2;
+        assert!(!check.remediation_due(&remediation, at(60)));
+        assert!(!check.remediation_due(&remediation, at(24 * 60 - 1)));
+        assert!(check.remediation_due(&remediation, at(24 * 60)));
+    }
 }
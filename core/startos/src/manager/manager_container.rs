@@ -1,6 +1,7 @@
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use chrono::Utc;
 use models::OptionExt;
 use tokio::sync::watch;
 use tokio::sync::watch::Sender;
@@ -8,9 +9,10 @@ use tracing::instrument;
 
 use super::start_stop::StartStop;
 use super::{manager_seed, run_main, ManagerPersistentContainer, RunMainResult};
+use crate::notifications::NotificationLevel;
 use crate::prelude::*;
-use crate::procedure::NoOutput;
 use crate::s9pk::manifest::Manifest;
+use crate::status::restart::{RestartMode, RestartPolicy};
 use crate::status::MainStatus;
 use crate::util::NonDetachingJoinHandle;
 use crate::Error;
@@ -19,6 +21,9 @@ pub type ManageContainerOverride = Arc<watch::Sender<Option<Override>>>;
 
 pub type Override = MainStatus;
 
+/// A run of the main process lasting at least this long resets the consecutive crash count
+const STABLE_RUN: Duration = Duration::from_secs(10 * 60);
+
 pub struct OverrideGuard {
     override_main_status: Option<ManageContainerOverride>,
 }
@@ -58,10 +63,12 @@ impl ManageContainer {
             .0,
         );
         let override_main_status: ManageContainerOverride = Arc::new(watch::channel(None).0);
+        let crash_loop_status = Arc::new(watch::channel(None).0);
         let service = tokio::spawn(create_service_manager(
             desired_state.clone(),
             seed.clone(),
             current_state.clone(),
+            crash_loop_status.clone(),
             persistent_container,
         ))
         .into();
@@ -69,6 +76,7 @@ impl ManageContainer {
             desired_state.clone(),
             current_state.clone(),
             override_main_status.clone(),
+            crash_loop_status,
             seed.clone(),
         ))
         .into();
@@ -138,6 +146,7 @@ async fn create_service_manager(
     desired_state: Arc<Sender<StartStop>>,
     seed: Arc<manager_seed::ManagerSeed>,
     current_state: Arc<Sender<StartStop>>,
+    crash_loop_status: Arc<Sender<Option<MainStatus>>>,
     persistent_container: Arc<Option<super::persistent_container::PersistentContainer>>,
 ) {
     let mut desired_state_receiver = desired_state.subscribe();
@@ -172,6 +181,7 @@ async fn create_service_manager(
                 current_state.clone(),
                 desired_state.clone(),
                 seed.clone(),
+                crash_loop_status.clone(),
                 persistent_container.clone(),
                 &mut running_service,
             ),
@@ -189,15 +199,18 @@ async fn save_state(
     desired_state: Arc<Sender<StartStop>>,
     current_state: Arc<Sender<StartStop>>,
     override_main_status: ManageContainerOverride,
+    crash_loop_status: Arc<Sender<Option<MainStatus>>>,
     seed: Arc<manager_seed::ManagerSeed>,
 ) {
     let mut desired_state_receiver = desired_state.subscribe();
     let mut current_state_receiver = current_state.subscribe();
     let mut override_main_status_receiver = override_main_status.subscribe();
+    let mut crash_loop_status_receiver = crash_loop_status.subscribe();
     loop {
         let current: StartStop = *current_state_receiver.borrow();
         let desired: StartStop = *desired_state_receiver.borrow();
         let override_status = override_main_status_receiver.borrow().clone();
+        let crash_loop = crash_loop_status_receiver.borrow().clone();
         let status = match (override_status.clone(), current, desired) {
             (Some(status), _, _) => status,
             (_, StartStop::Start, StartStop::Start) => MainStatus::Running {
@@ -205,7 +218,7 @@ async fn save_state(
                 health: Default::default(),
             },
             (_, StartStop::Start, StartStop::Stop) => MainStatus::Stopping,
-            (_, StartStop::Stop, StartStop::Start) => MainStatus::Starting,
+            (_, StartStop::Stop, StartStop::Start) => crash_loop.unwrap_or(MainStatus::Starting),
             (_, StartStop::Stop, StartStop::Stop) => MainStatus::Stopped,
         };
 
@@ -222,7 +235,8 @@ async fn save_state(
         tokio::select! {
             _ = desired_state_receiver.changed() =>{},
             _ = current_state_receiver.changed() => {},
-            _ = override_main_status_receiver.changed() => {}
+            _ = override_main_status_receiver.changed() => {},
+            _ = crash_loop_status_receiver.changed() => {}
         }
     }
 }
@@ -231,6 +245,7 @@ fn starting_service(
     current_state: Arc<Sender<StartStop>>,
     desired_state: Arc<Sender<StartStop>>,
     seed: Arc<manager_seed::ManagerSeed>,
+    crash_loop_status: Arc<Sender<Option<MainStatus>>>,
     persistent_container: ManagerPersistentContainer,
     running_service: &mut Option<NonDetachingJoinHandle<()>>,
 ) {
@@ -242,7 +257,17 @@ fn starting_service(
     };
     let set_stopped = { move || current_state.send_modify(|x| *x = StartStop::Stop) };
     let running_main_loop = async move {
+        // An explicit start gives the service a fresh set of retries
+        if let Err(e) = reset_crashes(&seed).await {
+            tracing::error!(
+                "Could not reset crash count for {}: {}",
+                seed.manifest.id,
+                e
+            );
+            tracing::debug!("{:?}", e);
+        }
         while desired_state.borrow().is_start() {
+            let started = Instant::now();
             let result = run_main(
                 seed.clone(),
                 persistent_container.clone(),
@@ -250,28 +275,174 @@ fn starting_service(
             )
             .await;
             set_stopped();
-            run_main_log_result(result, seed.clone()).await;
+            if !handle_exit(
+                result,
+                started.elapsed(),
+                &seed,
+                &desired_state,
+                &crash_loop_status,
+            )
+            .await
+            {
+                desired_state.send_modify(|x| *x = StartStop::Stop);
+            }
         }
     };
     *running_service = Some(tokio::spawn(running_main_loop).into());
 }
 
-async fn run_main_log_result(result: RunMainResult, seed: Arc<manager_seed::ManagerSeed>) {
-    match result {
-        Ok(Ok(NoOutput)) => (), // restart
-        Ok(Err(e)) => {
+async fn reset_crashes(seed: &manager_seed::ManagerSeed) -> Result<(), Error> {
+    let id = &seed.manifest.id;
+    seed.ctx
+        .db
+        .mutate(|db| {
+            let Some(installed) = db
+                .as_package_data_mut()
+                .as_idx_mut(id)
+                .or_not_found(id)?
+                .as_installed_mut()
+            else {
+                return Ok(());
+            };
+            installed
+                .as_status_mut()
+                .as_crashes_mut()
+                .as_consecutive_mut()
+                .ser(&0)
+        })
+        .await
+}
+
+/// Records the exit of the main process, and waits out the backoff of the restart policy if it crashed.
+/// Returns whether the main process should be run again.
+async fn handle_exit(
+    result: RunMainResult,
+    ran_for: Duration,
+    seed: &manager_seed::ManagerSeed,
+    desired_state: &Sender<StartStop>,
+    crash_loop_status: &Sender<Option<MainStatus>>,
+) -> bool {
+    // the main process of a persistent container is killed when the user stops the service, which is no crash
+    if !desired_state.borrow().is_start() {
+        return false;
+    }
+    let id = &seed.manifest.id;
+    let exit_code = match &result {
+        Ok(Ok(_)) => None,
+        Ok(Err((code, _))) => {
             tracing::error!(
                 "The service {} has crashed with the following exit code: {}",
-                seed.manifest.id.clone(),
-                e.0
+                id,
+                code
             );
-
-            tokio::time::sleep(Duration::from_secs(15)).await;
+            Some(*code)
         }
         Err(e) => {
             tracing::error!("failed to start service: {}", e);
             tracing::debug!("{:?}", e);
+            None
         }
+    };
+    let crashed = result.map_or(true, |r| r.is_err());
+    let manifest = &seed.manifest;
+    let (policy, crashes) = match seed
+        .ctx
+        .db
+        .mutate(|db| {
+            let status = db
+                .as_package_data_mut()
+                .as_idx_mut(&manifest.id)
+                .or_not_found(&manifest.id)?
+                .as_installed_mut()
+                .or_not_found(&manifest.id)?
+                .as_status_mut();
+            let policy = status
+                .as_restart_policy()
+                .de()?
+                .unwrap_or_else(|| manifest.restart_policy.clone());
+            let mut crashes = status.as_crashes().de()?;
+            if crashed {
+                if ran_for >= STABLE_RUN {
+                    crashes.consecutive = 0;
+                }
+                crashes.consecutive += 1;
+                crashes.total += 1;
+                crashes.last = Some(Utc::now());
+                crashes.last_exit_code = exit_code;
+            } else {
+                crashes.consecutive = 0;
+            }
+            status.as_crashes_mut().ser(&crashes)?;
+            Ok((policy, crashes.consecutive))
+        })
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            tracing::error!("Could not record crash of {}: {}", id, e);
+            tracing::debug!("{:?}", e);
+            (manifest.restart_policy.clone(), 1)
+        }
+    };
+
+    if !crashed {
+        return policy.mode == RestartMode::Always;
+    }
+    if policy.gives_up(crashes) {
+        notify_gave_up(seed, &policy, crashes).await;
+        return false;
+    }
+
+    let delay = policy.delay(crashes);
+    crash_loop_status.send_replace(Some(MainStatus::CrashLooping {
+        crashes,
+        restart_at: Utc::now()
+            + chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::zero()),
+    }));
+    let mut desired = desired_state.subscribe();
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => (),
+        _ = async {
+            while desired.borrow().is_start() {
+                if desired.changed().await.is_err() {
+                    break;
+                }
+            }
+        } => (),
+    }
+    crash_loop_status.send_replace(None);
+    true
+}
+
+async fn notify_gave_up(seed: &manager_seed::ManagerSeed, policy: &RestartPolicy, crashes: u32) {
+    let manifest = &seed.manifest;
+    let message = if policy.mode == RestartMode::Never {
+        format!(
+            "{} crashed and will not be restarted automatically. Start it again once the problem is resolved.",
+            manifest.title
+        )
+    } else {
+        format!(
+            "{} crashed {} times in a row and was stopped. Start it again once the problem is resolved.",
+            manifest.title, crashes
+        )
+    };
+    if let Err(e) = seed
+        .ctx
+        .notification_manager
+        .notify(
+            seed.ctx.db.clone(),
+            Some(manifest.id.clone()),
+            NotificationLevel::Error,
+            "Service Stopped".to_owned(),
+            message,
+            (),
+            None,
+        )
+        .await
+    {
+        tracing::error!("Failed to send crash notification: {}", e);
+        tracing::debug!("{:?}", e);
     }
 }
 
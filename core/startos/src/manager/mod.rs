@@ -30,7 +30,7 @@ use crate::db::model::{CurrentDependencies, CurrentDependencyInfo};
 use crate::dependencies::{
     add_dependent_to_current_dependents_lists, compute_dependency_config_errs,
 };
-use crate::disk::mount::backup::BackupMountGuard;
+use crate::disk::mount::backup::{remove_staging, BackupMountGuard};
 use crate::disk::mount::guard::TmpMountGuard;
 use crate::install::cleanup::remove_from_current_dependents_lists;
 use crate::net::net_controller::NetService;
@@ -40,9 +40,9 @@ use crate::procedure::docker::{DockerContainer, DockerProcedure, LongRunning};
 use crate::procedure::{NoOutput, ProcedureName};
 use crate::s9pk::manifest::Manifest;
 use crate::status::MainStatus;
-use crate::util::docker::{get_container_ip, kill_container};
+use crate::util::docker::{get_container_ip, get_container_ipv6, kill_container};
 use crate::util::NonDetachingJoinHandle;
-use crate::volume::Volume;
+use crate::volume::{backup_staging_dir, Volume};
 use crate::Error;
 
 pub mod health;
@@ -50,6 +50,7 @@ mod manager_container;
 mod manager_map;
 pub mod manager_seed;
 mod persistent_container;
+pub mod resources;
 mod start_stop;
 mod transition_state;
 
@@ -272,12 +273,23 @@ impl Manager {
             let override_guard =
                 manage_container.set_override(get_status(peek, &seed.manifest).backing_up())?;
             manage_container.wait_for_desired(StartStop::Stop).await;
-            let backup_guard = backup_guard.lock().await;
-            let guard = backup_guard.mount_package_backup(&seed.manifest.id).await?;
+            let mut backup_guard = backup_guard.lock().await;
+            let staging = backup_staging_dir(&seed.ctx.datadir, &seed.manifest.id);
+            let guard = backup_guard
+                .stage_package_backup(&seed.manifest.id, &staging)
+                .await?;
 
             let return_value = seed.manifest.backup.create(seed.clone()).await;
             guard.unmount().await?;
+            let return_value = match return_value {
+                Ok(info) => backup_guard
+                    .commit_package_snapshot(&seed.manifest.id, &staging, info.timestamp)
+                    .await
+                    .map(|_| info),
+                Err(e) => Err(e),
+            };
             drop(backup_guard);
+            remove_staging(&staging).await?;
 
             let manifest_id = seed.manifest.id.clone();
             seed.ctx
@@ -655,6 +667,14 @@ async fn run_main(
     persistent_container: ManagerPersistentContainer,
     started: Arc<impl Fn()>,
 ) -> RunMainResult {
+    if let Err(e) = apply_resource_limits(&seed).await {
+        tracing::error!(
+            "Could not apply resource limits for {}: {}",
+            seed.manifest.id,
+            e
+        );
+        tracing::debug!("{:?}", e);
+    }
     let mut runtime = NonDetachingJoinHandle::from(tokio::spawn(start_up_image(seed.clone())));
     let ip = match persistent_container.is_some() {
         false => Some(match get_running_ip(&seed, &mut runtime).await {
@@ -673,7 +693,11 @@ async fn run_main(
         None
     };
 
-    let health = main_health_check_daemon(seed.clone());
+    let ip = match &*persistent_container {
+        Some(container) => container.ip.clone(),
+        None => watch::channel(ip).1,
+    };
+    let health = main_health_check_daemon(seed.clone(), ip);
     let res = tokio::select! {
         a = runtime => a.map_err(|_| Error::new(eyre!("Manager runtime panicked!"), crate::ErrorKind::Docker)).and_then(|a| a),
         _ = health => Err(Error::new(eyre!("Health check daemon exited!"), crate::ErrorKind::Unknown))
@@ -684,6 +708,11 @@ async fn run_main(
     res
 }
 
+async fn apply_resource_limits(seed: &ManagerSeed) -> Result<(), Error> {
+    let limits = resources::effective_limits(&seed.ctx.db.peek().await, &seed.manifest)?;
+    resources::apply(&seed.manifest.id, &limits).await
+}
+
 /// We want to start up the manifest, but in this case we want to know that we have generated the certificates.
 /// Note for _generated_certificate: Needed to know that before we start the state we have generated the certificate
 async fn start_up_image(seed: Arc<ManagerSeed>) -> Result<Result<NoOutput, (i32, String)>, Error> {
@@ -751,7 +780,11 @@ async fn add_network_for_main(
     let mut svc = seed
         .ctx
         .net_controller
-        .create_service(seed.manifest.id.clone(), ip)
+        .create_service(
+            seed.manifest.id.clone(),
+            ip,
+            get_container_ipv6(&seed.container_name).await?,
+        )
         .await?;
     // DEPRECATED
     let mut secrets = seed.ctx.secret_store.acquire().await?;
@@ -767,6 +800,10 @@ async fn add_network_for_main(
             )
             .await?;
         }
+        for (port, forward) in interface.forward_config.iter().flatten() {
+            svc.add_forward(tx.as_mut(), id.clone(), port.0, forward)
+                .await?;
+        }
         for (external, internal) in interface.tor_config.iter().flat_map(|t| &t.port_mapping) {
             svc.add_tor(tx.as_mut(), id.clone(), external.0, internal.0)
                 .await?;
@@ -787,16 +824,26 @@ async fn remove_network_for_main(svc: NetService) -> Result<(), Error> {
     svc.remove_all().await
 }
 
-async fn main_health_check_daemon(seed: Arc<ManagerSeed>) {
+/// `ip` is the LAN address of the service that probes are run against
+async fn main_health_check_daemon(seed: Arc<ManagerSeed>, ip: watch::Receiver<Option<Ipv4Addr>>) {
     tokio::time::sleep(Duration::from_secs(HEALTH_CHECK_GRACE_PERIOD_SECONDS)).await;
     loop {
-        if let Err(e) = health::check(&seed.ctx, &seed.manifest.id).await {
-            tracing::error!(
-                "Failed to run health check for {}: {}",
-                &seed.manifest.id,
-                e
-            );
-            tracing::debug!("{:?}", e);
+        let current_ip = *ip.borrow();
+        match health::check(&seed.ctx, &seed.manifest.id, current_ip).await {
+            Ok(remediations) => {
+                for (check_id, remediation) in remediations {
+                    health::remediate(&seed.ctx, &seed.manifest, &check_id, &remediation.action)
+                        .await;
+                }
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Failed to run health check for {}: {}",
+                    &seed.manifest.id,
+                    e
+                );
+                tracing::debug!("{:?}", e);
+            }
         }
         tokio::time::sleep(Duration::from_secs(HEALTH_CHECK_COOLDOWN_SECONDS)).await;
     }
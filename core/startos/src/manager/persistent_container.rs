@@ -1,3 +1,4 @@
+use std::net::Ipv4Addr;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -21,17 +22,20 @@ use crate::Error;
 pub struct PersistentContainer {
     _running_docker: NonDetachingJoinHandle<()>,
     pub rpc_client: Receiver<Arc<UnixRpcClient>>,
+    /// LAN address of the container while it is running
+    pub ip: Receiver<Option<Ipv4Addr>>,
 }
 
 impl PersistentContainer {
     #[instrument(skip_all)]
     pub async fn init(seed: &Arc<ManagerSeed>) -> Result<Option<Self>, Error> {
         Ok(if let Some(containers) = &seed.manifest.containers {
-            let (running_docker, rpc_client) =
+            let (running_docker, rpc_client, ip) =
                 spawn_persistent_container(seed.clone(), containers.main.clone()).await?;
             Some(Self {
                 _running_docker: running_docker,
                 rpc_client,
+                ip,
             })
         } else {
             None
@@ -46,8 +50,16 @@ impl PersistentContainer {
 pub async fn spawn_persistent_container(
     seed: Arc<ManagerSeed>,
     container: DockerContainer,
-) -> Result<(NonDetachingJoinHandle<()>, Receiver<Arc<UnixRpcClient>>), Error> {
+) -> Result<
+    (
+        NonDetachingJoinHandle<()>,
+        Receiver<Arc<UnixRpcClient>>,
+        Receiver<Option<Ipv4Addr>>,
+    ),
+    Error,
+> {
     let (send_inserter, inserter) = oneshot::channel();
+    let (send_ip, ip_recv) = watch::channel(None);
     Ok((
         tokio::task::spawn(async move {
             let mut inserter_send: Option<watch::Sender<Arc<UnixRpcClient>>> = None;
@@ -68,6 +80,7 @@ pub async fn spawn_persistent_container(
                         }
                     };
                     let svc = add_network_for_main(&seed, ip).await?;
+                    send_ip.send_replace(Some(ip));
 
                     if let Some(inserter_send) = inserter_send.as_mut() {
                         let _ = inserter_send.send(Arc::new(inserter));
@@ -83,6 +96,7 @@ pub async fn spawn_persistent_container(
                         a = runtime.running_output => a.map_err(|_| Error::new(eyre!("Manager runtime panicked!"), crate::ErrorKind::Docker)).map(|_| ()),
                     };
 
+                    send_ip.send_replace(None);
                     remove_network_for_main(svc).await?;
 
                     res
@@ -97,5 +111,6 @@ pub async fn spawn_persistent_container(
         })
         .into(),
         inserter.await.map_err(|_| Error::new(eyre!("Container handle dropped before inserter sent"), crate::ErrorKind::Unknown))?,
+        ip_recv,
     ))
 }
@@ -0,0 +1,405 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::prelude::*;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Invoke};
+
+/// The containers of every package run in a slice of their own under this one
+const CGROUP_ROOT: &str = "/sys/fs/cgroup/startos.slice";
+/// Lowest memory limit accepted, in MiB
+const MIN_MEMORY: u64 = 16;
+
+/// Limits on the resources the containers of a package can use together
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResourceLimits {
+    /// Share of CPU time relative to other packages, from 1 to 10000. Defaults to 100
+    #[serde(default)]
+    pub cpu_weight: Option<u16>,
+    /// Maximum number of CPU cores, which can be fractional
+    #[serde(default)]
+    pub cpu_quota: Option<f64>,
+    /// Maximum memory in MiB
+    #[serde(default)]
+    pub memory: Option<u64>,
+    /// Share of block IO relative to other packages, from 1 to 10000. Defaults to 100
+    #[serde(default)]
+    pub io_weight: Option<u16>,
+}
+impl ResourceLimits {
+    pub fn validate(&self) -> Result<(), Error> {
+        for (name, weight) in [
+            ("cpu-weight", self.cpu_weight),
+            ("io-weight", self.io_weight),
+        ] {
+            if weight.map_or(false, |w| !(1..=10000).contains(&w)) {
+                return Err(Error::new(
+                    eyre!("{} must be between 1 and 10000", name),
+                    ErrorKind::InvalidRequest,
+                ));
+            }
+        }
+        if self.cpu_quota.map_or(false, |q| q.is_nan() || q <= 0.0) {
+            return Err(Error::new(
+                eyre!("cpu-quota must be positive"),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        if self.memory.map_or(false, |m| m < MIN_MEMORY) {
+            return Err(Error::new(
+                eyre!("memory must be at least {} MiB", MIN_MEMORY),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The systemd slice the containers of a package run in
+pub fn slice_name(id: &PackageId) -> String {
+    // a dash separates the levels of the slice hierarchy, so it has to be escaped
+    format!("startos-{}.slice", id.replace('-', "\\x2d"))
+}
+
+/// The limits in effect for a package: the ones set by the user, or else the ones of the manifest
+pub fn effective_limits(db: &Peeked, manifest: &Manifest) -> Result<ResourceLimits, Error> {
+    Ok(db
+        .as_package_data()
+        .as_idx(&manifest.id)
+        .and_then(|pde| pde.as_installed())
+        .map(|installed| installed.as_status().as_resource_limits().de())
+        .transpose()?
+        .flatten()
+        .unwrap_or_else(|| manifest.resource_limits.clone()))
+}
+
+/// Applies the limits to the slice of the package, which affects running containers immediately
+#[instrument(skip_all)]
+pub async fn apply(id: &PackageId, limits: &ResourceLimits) -> Result<(), Error> {
+    // an empty assignment resets the property to its default
+    Command::new("systemctl")
+        .arg("set-property")
+        .arg("--runtime")
+        .arg(slice_name(id))
+        .arg(format!(
+            "CPUWeight={}",
+            limits
+                .cpu_weight
+                .map_or_else(String::new, |w| w.to_string())
+        ))
+        .arg(format!(
+            "CPUQuota={}",
+            limits
+                .cpu_quota
+                .map_or_else(String::new, |q| format!("{}%", (q * 100.0).round() as u64))
+        ))
+        .arg(format!(
+            "MemoryMax={}",
+            limits
+                .memory
+                .map_or_else(|| "infinity".to_owned(), |m| format!("{}M", m))
+        ))
+        .arg(format!(
+            "IOWeight={}",
+            limits.io_weight.map_or_else(String::new, |w| w.to_string())
+        ))
+        .invoke(ErrorKind::Systemd)
+        .await?;
+    Ok(())
+}
+
+/// Cumulative resource usage of the containers of a package
+#[derive(Clone, Debug, Default)]
+pub struct CgroupUsage {
+    pub cpu_usec: u64,
+    pub memory: u64,
+    pub memory_max: Option<u64>,
+    pub io_read: u64,
+    pub io_written: u64,
+}
+
+/// Parses a single value cgroup file such as memory.current, where "max" means no limit
+fn parse_u64(contents: &str) -> Result<Option<u64>, Error> {
+    match contents.trim() {
+        "max" => Ok(None),
+        n => Ok(Some(n.parse()?)),
+    }
+}
+
+/// Total CPU time in microseconds from cpu.stat
+fn parse_cpu_stat(cpu_stat: &str) -> Result<u64, Error> {
+    for line in cpu_stat.lines() {
+        if let Some(usec) = line.strip_prefix("usage_usec ") {
+            return Ok(usec.trim().parse()?);
+        }
+    }
+    Ok(0)
+}
+
+/// Bytes read and written summed over all devices in io.stat
+fn parse_io_stat(io_stat: &str) -> Result<(u64, u64), Error> {
+    let (mut read, mut written) = (0, 0);
+    for field in io_stat.split_whitespace() {
+        if let Some(bytes) = field.strip_prefix("rbytes=") {
+            read += bytes.parse::<u64>()?;
+        } else if let Some(bytes) = field.strip_prefix("wbytes=") {
+            written += bytes.parse::<u64>()?;
+        }
+    }
+    Ok((read, written))
+}
+
+async fn read_u64(path: &Path) -> Result<Option<u64>, Error> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+    parse_u64(&contents)
+}
+
+async fn read_usage(dir: &Path) -> Result<CgroupUsage, Error> {
+    let mut usage = CgroupUsage::default();
+    let cpu_stat = dir.join("cpu.stat");
+    usage.cpu_usec = parse_cpu_stat(
+        &tokio::fs::read_to_string(&cpu_stat)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, cpu_stat.display().to_string()))?,
+    )?;
+    usage.memory = read_u64(&dir.join("memory.current"))
+        .await?
+        .unwrap_or_default();
+    usage.memory_max = read_u64(&dir.join("memory.max")).await?;
+    // the io controller is not always enabled
+    if let Ok(io_stat) = tokio::fs::read_to_string(dir.join("io.stat")).await {
+        (usage.io_read, usage.io_written) = parse_io_stat(&io_stat)?;
+    }
+    Ok(usage)
+}
+
+/// Usage of every package that has had containers running since boot, leaving out those that can not be read
+pub async fn usage_all() -> Result<BTreeMap<PackageId, CgroupUsage>, Error> {
+    let mut res = BTreeMap::new();
+    let mut read_dir = match tokio::fs::read_dir(CGROUP_ROOT).await {
+        Ok(a) => a,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(res),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name();
+        let Some(id) = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("startos-"))
+            .and_then(|n| n.strip_suffix(".slice"))
+        else {
+            continue;
+        };
+        let Ok(id) = id.replace("\\x2d", "-").parse::<PackageId>() else {
+            continue;
+        };
+        // a slice can be removed while it is read, when its package stops
+        match read_usage(&entry.path()).await {
+            Ok(usage) => {
+                res.insert(id, usage);
+            }
+            Err(e) => {
+                tracing::warn!("Could not read resource usage of {}: {}", id, e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResourceLimitsInfo {
+    /// The limits in effect
+    pub limits: ResourceLimits,
+    /// Whether the limits were set by the user rather than taken from the manifest
+    pub overridden: bool,
+}
+
+#[command(subcommands(get_resources, set_resources, clear_resources))]
+pub fn resources() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(rename = "get", display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn get_resources(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<ResourceLimitsInfo, Error> {
+    let peek = ctx.db.peek().await;
+    let installed = peek
+        .as_package_data()
+        .as_idx(&id)
+        .or_not_found(&id)?
+        .expect_as_installed()?
+        .as_installed();
+    Ok(match installed.as_status().as_resource_limits().de()? {
+        Some(limits) => ResourceLimitsInfo {
+            limits,
+            overridden: true,
+        },
+        None => ResourceLimitsInfo {
+            limits: installed.as_manifest().as_resource_limits().de()?,
+            overridden: false,
+        },
+    })
+}
+
+/// Options that are not passed keep the values of the limits currently in effect.
+/// Passing 0 removes the limit
+#[command(rename = "set", display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn set_resources(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(long = "cpu-weight")] cpu_weight: Option<u16>,
+    #[arg(long = "cpu-quota")] cpu_quota: Option<f64>,
+    #[arg(long = "memory")] memory: Option<u64>,
+    #[arg(long = "io-weight")] io_weight: Option<u16>,
+) -> Result<(), Error> {
+    let limits = ctx
+        .db
+        .mutate(|db| {
+            let installed = db
+                .as_package_data_mut()
+                .as_idx_mut(&id)
+                .or_not_found(&id)?
+                .as_installed_mut()
+                .or_not_found(&id)?;
+            let mut limits = match installed.as_status().as_resource_limits().de()? {
+                Some(limits) => limits,
+                None => installed.as_manifest().as_resource_limits().de()?,
+            };
+            if let Some(cpu_weight) = cpu_weight {
+                limits.cpu_weight = Some(cpu_weight).filter(|w| *w != 0);
+            }
+            if let Some(cpu_quota) = cpu_quota {
+                limits.cpu_quota = Some(cpu_quota).filter(|q| *q != 0.0);
+            }
+            if let Some(memory) = memory {
+                limits.memory = Some(memory).filter(|m| *m != 0);
+            }
+            if let Some(io_weight) = io_weight {
+                limits.io_weight = Some(io_weight).filter(|w| *w != 0);
+            }
+            limits.validate()?;
+            installed
+                .as_status_mut()
+                .as_resource_limits_mut()
+                .ser(&Some(limits.clone()))?;
+            Ok(limits)
+        })
+        .await?;
+    apply(&id, &limits).await
+}
+
+/// Go back to the resource limits of the manifest
+#[command(rename = "clear", display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn clear_resources(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+) -> Result<(), Error> {
+    let limits = ctx
+        .db
+        .mutate(|db| {
+            let installed = db
+                .as_package_data_mut()
+                .as_idx_mut(&id)
+                .or_not_found(&id)?
+                .as_installed_mut()
+                .or_not_found(&id)?;
+            installed
+                .as_status_mut()
+                .as_resource_limits_mut()
+                .ser(&None)?;
+            installed.as_manifest().as_resource_limits().de()
+        })
+        .await?;
+    apply(&id, &limits).await
+}
+
+#[test]
+fn validate_limits() {
+    assert!(ResourceLimits::default().validate().is_ok());
+    assert!(ResourceLimits {
+        cpu_weight: Some(10000),
+        cpu_quota: Some(0.5),
+        memory: Some(MIN_MEMORY),
+        io_weight: Some(1),
+    }
+    .validate()
+    .is_ok());
+    for invalid in [
+        ResourceLimits {
+            cpu_weight: Some(0),
+            ..Default::default()
+        },
+        ResourceLimits {
+            io_weight: Some(10001),
+            ..Default::default()
+        },
+        ResourceLimits {
+            cpu_quota: Some(0.0),
+            ..Default::default()
+        },
+        ResourceLimits {
+            cpu_quota: Some(f64::NAN),
+            ..Default::default()
+        },
+        ResourceLimits {
+            memory: Some(MIN_MEMORY - 1),
+            ..Default::default()
+        },
+    ] {
+        assert!(invalid.validate().is_err(), "{:?}", invalid);
+    }
+}
+
+#[test]
+fn escape_slice_name() {
+    assert_eq!(
+        slice_name(&"bitcoind".parse().unwrap()),
+        "startos-bitcoind.slice"
+    );
+    assert_eq!(
+        slice_name(&"lnd-tools".parse().unwrap()),
+        "startos-lnd\\x2dtools.slice"
+    );
+}
+
+#[test]
+fn parse_cgroup_files() {
+    assert_eq!(
+        parse_cpu_stat("usage_usec 1234567\nuser_usec 1000000\nsystem_usec 234567\nnr_periods 0\n")
+            .unwrap(),
+        1234567
+    );
+    assert_eq!(parse_cpu_stat("").unwrap(), 0);
+    assert_eq!(parse_u64("104857600\n").unwrap(), Some(104857600));
+    assert_eq!(parse_u64("max\n").unwrap(), None);
+    assert!(parse_u64("lots\n").is_err());
+    assert_eq!(
+        parse_io_stat(
+            "259:0 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0\n\
+             8:0 rbytes=100 wbytes=0 rios=1 wios=0 dbytes=0 dios=0\n"
+        )
+        .unwrap(),
+        (4196, 8192)
+    );
+    assert_eq!(parse_io_stat("").unwrap(), (0, 0));
+}
@@ -18,6 +18,7 @@ impl From<MainStatus> for StartStop {
             MainStatus::Restarting => StartStop::Start,
             MainStatus::Stopping => StartStop::Stop,
             MainStatus::Starting => StartStop::Start,
+            MainStatus::CrashLooping { .. } => StartStop::Start,
             MainStatus::Running {
                 started: _,
                 health: _,
@@ -19,6 +19,7 @@ use rpc_toolkit::yajrc::RpcMethod;
 use rpc_toolkit::Metadata;
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
+use sqlx::PgPool;
 use tokio::sync::Mutex;
 
 use crate::context::RpcContext;
@@ -92,9 +93,19 @@ impl HasValidSession {
     }
 
     pub async fn from_session(session: &HashSessionToken, ctx: &RpcContext) -> Result<Self, Error> {
-        let session_hash = session.hashed();
+        Self::from_secret_store(session, &ctx.secret_store).await
+    }
+
+    pub async fn from_secret_store(
+        session: &HashSessionToken,
+        secrets: &PgPool,
+    ) -> Result<Self, Error> {
+        Self::from_session_hash(session.hashed(), secrets).await
+    }
+
+    pub async fn from_session_hash(session_hash: &str, secrets: &PgPool) -> Result<Self, Error> {
         let session = sqlx::query!("UPDATE session SET last_active = CURRENT_TIMESTAMP WHERE id = $1 AND logged_out IS NULL OR logged_out > CURRENT_TIMESTAMP", session_hash)
-            .execute(ctx.secret_store.acquire().await?.as_mut())
+            .execute(secrets.acquire().await?.as_mut())
             .await?;
         if session.rows_affected() == 0 {
             return Err(Error::new(
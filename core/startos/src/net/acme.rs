@@ -0,0 +1,886 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use chrono::Utc;
+use clap::ArgMatches;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509ReqBuilder, X509};
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use reqwest::{Certificate, Client};
+use rpc_toolkit::command;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tokio::sync::RwLock;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::net::ssl::generate_key;
+use crate::prelude::*;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// Served by the main web server on port 80
+pub const HTTP_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+const RENEW_WITHIN_DAYS: u32 = 30;
+const RENEWAL_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLLS: usize = 60;
+
+fn b64(data: &[u8]) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(data)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChallengeType {
+    #[serde(rename = "http-01")]
+    Http01,
+}
+impl ChallengeType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChallengeType::Http01 => "http-01",
+        }
+    }
+}
+impl std::fmt::Display for ChallengeType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+impl std::str::FromStr for ChallengeType {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http-01" => Ok(ChallengeType::Http01),
+            _ => Err(Error::new(
+                eyre!("Must be \"http-01\"."),
+                ErrorKind::Deserialization,
+            )),
+        }
+    }
+}
+
+/// Responses to challenges in progress, served by the main web server.
+/// DNS-01 is not supported: it would require the TXT record to be published on a DNS server
+/// reachable by the CA, and this server only answers DNS queries locally.
+#[derive(Default)]
+pub struct AcmeChallenges {
+    http: RwLock<BTreeMap<String, String>>,
+}
+impl AcmeChallenges {
+    pub async fn http_response(&self, token: &str) -> Option<String> {
+        self.http.read().await.get(token).cloned()
+    }
+    async fn publish(&self, ty: ChallengeType, token: &str, key_auth: &str) {
+        match ty {
+            ChallengeType::Http01 => {
+                self.http
+                    .write()
+                    .await
+                    .insert(token.to_owned(), key_auth.to_owned());
+            }
+        }
+    }
+    async fn unpublish(&self, ty: ChallengeType, token: &str) {
+        match ty {
+            ChallengeType::Http01 => {
+                self.http.write().await.remove(token);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AcmeCert {
+    pub key: PKey<Private>,
+    /// Leaf first
+    pub fullchain: Vec<X509>,
+}
+impl AcmeCert {
+    fn from_pem(key: &str, fullchain: &str) -> Result<Self, Error> {
+        let fullchain = X509::stack_from_pem(fullchain.as_bytes())?;
+        if fullchain.is_empty() {
+            return Err(Error::new(
+                eyre!("Certificate chain is empty"),
+                ErrorKind::OpenSsl,
+            ));
+        }
+        Ok(AcmeCert {
+            key: PKey::private_key_from_pem(key.as_bytes())?,
+            fullchain,
+        })
+    }
+    fn needs_renewal(&self) -> Result<bool, Error> {
+        Ok(self.fullchain[0]
+            .not_after()
+            .compare(Asn1Time::days_from_now(RENEW_WITHIN_DAYS)?.as_ref())?
+            == Ordering::Less)
+    }
+}
+
+/// Certificates issued by ACME for public domains.
+/// `.local` and `.onion` addresses are always served certificates signed by the local CA.
+pub struct AcmeManager {
+    client: Client,
+    certs: RwLock<BTreeMap<String, Arc<AcmeCert>>>,
+    challenges: Arc<AcmeChallenges>,
+}
+impl AcmeManager {
+    /// `root_ca` is trusted in addition to the system roots when connecting to an ACME directory,
+    /// e.g. to test against Pebble
+    #[instrument(skip_all)]
+    pub async fn init(secrets: &PgPool, root_ca: Option<&Path>) -> Result<Self, Error> {
+        let mut client = Client::builder();
+        if let Some(root_ca) = root_ca {
+            let pem = tokio::fs::read(root_ca)
+                .await
+                .with_ctx(|_| (ErrorKind::Filesystem, root_ca.display().to_string()))?;
+            client = client
+                .add_root_certificate(Certificate::from_pem(&pem).with_kind(ErrorKind::OpenSsl)?);
+        }
+        let mut certs = BTreeMap::new();
+        for row in sqlx::query!("SELECT domain, key_pem, fullchain_pem FROM acme_domains")
+            .fetch_all(secrets)
+            .await?
+        {
+            if let (Some(key), Some(fullchain)) = (row.key_pem, row.fullchain_pem) {
+                certs.insert(row.domain, Arc::new(AcmeCert::from_pem(&key, &fullchain)?));
+            }
+        }
+        Ok(Self {
+            client: client.build().with_kind(ErrorKind::Network)?,
+            certs: RwLock::new(certs),
+            challenges: Arc::new(AcmeChallenges::default()),
+        })
+    }
+
+    pub fn challenges(&self) -> Arc<AcmeChallenges> {
+        self.challenges.clone()
+    }
+
+    pub async fn cert(&self, domain: &str) -> Option<Arc<AcmeCert>> {
+        self.certs
+            .read()
+            .await
+            .get(&domain.to_ascii_lowercase())
+            .cloned()
+    }
+
+    #[instrument(skip(self, secrets))]
+    pub async fn issue(&self, secrets: &PgPool, domain: &str) -> Result<(), Error> {
+        let settings = sqlx::query!(
+            "SELECT directory_url, challenge, contact FROM acme_domains WHERE domain = $1",
+            domain
+        )
+        .fetch_optional(secrets)
+        .await?
+        .or_not_found(domain)?;
+        let challenge: ChallengeType = settings.challenge.parse()?;
+
+        let account = sqlx::query!(
+            "SELECT key_pem, kid FROM acme_accounts WHERE directory_url = $1",
+            settings.directory_url
+        )
+        .fetch_optional(secrets)
+        .await?;
+        let mut client = match account {
+            Some(account) => {
+                AcmeClient::new(
+                    self.client.clone(),
+                    &settings.directory_url,
+                    PKey::private_key_from_pem(account.key_pem.as_bytes())?,
+                    Some(account.kid),
+                )
+                .await?
+            }
+            None => {
+                let key = generate_key()?;
+                let mut client = AcmeClient::new(
+                    self.client.clone(),
+                    &settings.directory_url,
+                    key.clone(),
+                    None,
+                )
+                .await?;
+                let kid = client.register(settings.contact.as_deref()).await?;
+                let key_pem = String::from_utf8(key.private_key_to_pem_pkcs8()?)?;
+                sqlx::query!(
+                    "INSERT INTO acme_accounts (directory_url, key_pem, kid) VALUES ($1, $2, $3) ON CONFLICT (directory_url) DO UPDATE SET key_pem = EXCLUDED.key_pem, kid = EXCLUDED.kid",
+                    settings.directory_url,
+                    key_pem,
+                    kid,
+                )
+                .execute(secrets)
+                .await?;
+                client
+            }
+        };
+
+        let cert = client.order(domain, challenge, &self.challenges).await?;
+        let key_pem = String::from_utf8(cert.key.private_key_to_pem_pkcs8()?)?;
+        let mut fullchain_pem = Vec::new();
+        for c in &cert.fullchain {
+            fullchain_pem.extend(c.to_pem()?);
+        }
+        let fullchain_pem = String::from_utf8(fullchain_pem)?;
+        sqlx::query!(
+            "UPDATE acme_domains SET key_pem = $2, fullchain_pem = $3 WHERE domain = $1",
+            domain,
+            key_pem,
+            fullchain_pem,
+        )
+        .execute(secrets)
+        .await?;
+        self.certs
+            .write()
+            .await
+            .insert(domain.to_owned(), Arc::new(cert));
+        tracing::info!("Issued ACME certificate for {}", domain);
+
+        Ok(())
+    }
+
+    /// Issues certificates for every domain that is missing one or whose certificate expires soon
+    #[instrument(skip_all)]
+    pub async fn renew(&self, secrets: &PgPool) -> Result<(), Error> {
+        let domains = sqlx::query!("SELECT domain FROM acme_domains")
+            .fetch_all(secrets)
+            .await?;
+        let mut errors = ErrorCollection::new();
+        for row in domains {
+            let due = match self.cert(&row.domain).await {
+                Some(cert) => cert.needs_renewal()?,
+                None => true,
+            };
+            if due {
+                if let Err(e) = self.issue(secrets, &row.domain).await {
+                    errors.handle::<(), _>(Err(Error::new(
+                        eyre!("{}: {}", row.domain, e.source),
+                        e.kind,
+                    )));
+                }
+            }
+        }
+        errors.into_result()
+    }
+
+    async fn remove(&self, domain: &str) {
+        self.certs.write().await.remove(domain);
+    }
+}
+
+/// Keeps ACME certificates up to date until the server shuts down
+pub async fn renewal(ctx: RpcContext) {
+    let mut shutdown = ctx.shutdown.subscribe();
+    loop {
+        tokio::select! {
+            res = ctx.net_controller.acme.renew(&ctx.secret_store) => {
+                if let Err(e) = res {
+                    tracing::error!("Error renewing ACME certificates: {}", e);
+                    tracing::debug!("{:?}", e);
+                }
+            }
+            _ = shutdown.recv() => break,
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(RENEWAL_INTERVAL) => (),
+            _ = shutdown.recv() => break,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    ty: String,
+    #[serde(default)]
+    detail: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pending,
+    Ready,
+    Processing,
+    Valid,
+    Invalid,
+    Expired,
+    Deactivated,
+    Revoked,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Order {
+    status: Status,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: Status,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    ty: String,
+    url: String,
+    token: String,
+}
+
+/// A minimal RFC 8555 client, authenticated with an account key on P-256
+struct AcmeClient {
+    client: Client,
+    directory: Directory,
+    key: PKey<Private>,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+impl AcmeClient {
+    async fn new(
+        client: Client,
+        directory_url: &str,
+        key: PKey<Private>,
+        kid: Option<String>,
+    ) -> Result<Self, Error> {
+        let directory = client
+            .get(directory_url)
+            .send()
+            .await
+            .with_kind(ErrorKind::Acme)?
+            .error_for_status()
+            .with_kind(ErrorKind::Acme)?
+            .json()
+            .await
+            .with_kind(ErrorKind::Acme)?;
+        Ok(Self {
+            client,
+            directory,
+            key,
+            kid,
+            nonce: None,
+        })
+    }
+
+    async fn nonce(&mut self) -> Result<String, Error> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let res = self
+            .client
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .with_kind(ErrorKind::Acme)?;
+        replay_nonce(&res)
+            .ok_or_else(|| Error::new(eyre!("ACME server did not issue a nonce"), ErrorKind::Acme))
+    }
+
+    fn jwk(&self) -> Result<Value, Error> {
+        let ec = self.key.ec_key()?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        ec.public_key().affine_coordinates_gfp(
+            ec.group(),
+            &mut x,
+            &mut y,
+            &mut BigNumContext::new()?,
+        )?;
+        Ok(json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": b64(&x.to_vec_padded(32)?),
+            "y": b64(&y.to_vec_padded(32)?),
+        }))
+    }
+
+    /// RFC 7638: the members of the JWK in lexicographic order, without whitespace
+    fn thumbprint(&self) -> Result<String, Error> {
+        let jwk = self.jwk()?;
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+            jwk["x"], jwk["y"]
+        );
+        Ok(b64(&sha256(canonical.as_bytes())))
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut signer = openssl::sign::Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(data)?;
+        let sig = EcdsaSig::from_der(&signer.sign_to_vec()?)?;
+        let mut res = sig.r().to_vec_padded(32)?;
+        res.extend(sig.s().to_vec_padded(32)?);
+        Ok(res)
+    }
+
+    /// A JWS signed POST. Without a payload, this is a POST-as-GET.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<reqwest::Response, Error> {
+        let mut retried = false;
+        loop {
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": self.nonce().await?,
+                "url": url,
+            });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk()?,
+            }
+            let protected =
+                b64(&serde_json::to_vec(&protected).with_kind(ErrorKind::Serialization)?);
+            let payload = match payload {
+                Some(payload) => {
+                    b64(&serde_json::to_vec(payload).with_kind(ErrorKind::Serialization)?)
+                }
+                None => String::new(),
+            };
+            let signature = b64(&self.sign(format!("{}.{}", protected, payload).as_bytes())?);
+            let res = self
+                .client
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(
+                    serde_json::to_vec(&json!({
+                        "protected": protected,
+                        "payload": payload,
+                        "signature": signature,
+                    }))
+                    .with_kind(ErrorKind::Serialization)?,
+                )
+                .send()
+                .await
+                .with_kind(ErrorKind::Acme)?;
+            self.nonce = replay_nonce(&res);
+            if res.status().is_success() {
+                return Ok(res);
+            }
+            let status = res.status();
+            let problem: Problem = res.json().await.with_kind(ErrorKind::Acme)?;
+            if problem.ty == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(Error::new(
+                eyre!("{} ({}): {}", problem.ty, status, problem.detail),
+                ErrorKind::Acme,
+            ));
+        }
+    }
+
+    async fn post_json<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<T, Error> {
+        self.post(url, payload)
+            .await?
+            .json()
+            .await
+            .with_kind(ErrorKind::Acme)
+    }
+
+    /// Creates an account, agreeing to the terms of service. Returns the account URL.
+    async fn register(&mut self, contact: Option<&str>) -> Result<String, Error> {
+        let url = self.directory.new_account.clone();
+        let res = self
+            .post(
+                &url,
+                Some(&json!({
+                    "termsOfServiceAgreed": true,
+                    "contact": contact
+                        .into_iter()
+                        .map(|c| format!("mailto:{}", c))
+                        .collect::<Vec<_>>(),
+                })),
+            )
+            .await?;
+        let kid = location(&res)?;
+        self.kid = Some(kid.clone());
+        Ok(kid)
+    }
+
+    #[instrument(skip(self, challenges))]
+    async fn order(
+        &mut self,
+        domain: &str,
+        challenge_type: ChallengeType,
+        challenges: &AcmeChallenges,
+    ) -> Result<AcmeCert, Error> {
+        let url = self.directory.new_order.clone();
+        let res = self
+            .post(
+                &url,
+                Some(&json!({
+                    "identifiers": [{ "type": "dns", "value": domain }],
+                })),
+            )
+            .await?;
+        let order_url = location(&res)?;
+        let order: Order = res.json().await.with_kind(ErrorKind::Acme)?;
+
+        for authz_url in &order.authorizations {
+            let authz: Authorization = self.post_json(authz_url, None).await?;
+            if authz.status == Status::Valid {
+                continue;
+            }
+            let challenge = authz
+                .challenges
+                .into_iter()
+                .find(|c| c.ty == challenge_type.as_str())
+                .ok_or_else(|| {
+                    Error::new(
+                        eyre!(
+                            "ACME server does not offer {} for {}",
+                            challenge_type,
+                            domain
+                        ),
+                        ErrorKind::Acme,
+                    )
+                })?;
+            let key_auth = format!("{}.{}", challenge.token, self.thumbprint()?);
+            challenges
+                .publish(challenge_type, &challenge.token, &key_auth)
+                .await;
+            let res = async {
+                self.post(&challenge.url, Some(&json!({}))).await?;
+                self.poll(authz_url, |authz: &Authorization| authz.status)
+                    .await
+            }
+            .await;
+            challenges.unpublish(challenge_type, &challenge.token).await;
+            res?;
+        }
+
+        let key = generate_key()?;
+        let mut csr = X509ReqBuilder::new()?;
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_text("CN", domain)?;
+        csr.set_subject_name(&name.build())?;
+        csr.set_pubkey(&key)?;
+        let mut extensions = Stack::new()?;
+        extensions.push(
+            SubjectAlternativeName::new()
+                .dns(domain)
+                .build(&csr.x509v3_context(None))?,
+        )?;
+        csr.add_extensions(&extensions)?;
+        csr.sign(&key, MessageDigest::sha256())?;
+        self.post(
+            &order.finalize,
+            Some(&json!({ "csr": b64(&csr.build().to_der()?) })),
+        )
+        .await?;
+
+        let mut certificate = None;
+        self.poll(&order_url, |order: &Order| {
+            certificate = order.certificate.clone();
+            order.status
+        })
+        .await?;
+        let certificate = certificate.ok_or_else(|| {
+            Error::new(
+                eyre!("ACME server did not issue a certificate"),
+                ErrorKind::Acme,
+            )
+        })?;
+        let fullchain = self
+            .post(&certificate, None)
+            .await?
+            .text()
+            .await
+            .with_kind(ErrorKind::Acme)?;
+
+        Ok(AcmeCert {
+            fullchain: X509::stack_from_pem(fullchain.as_bytes())?,
+            key,
+        })
+    }
+
+    /// POST-as-GETs a resource until its status is valid
+    async fn poll<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        mut status: impl FnMut(&T) -> Status,
+    ) -> Result<(), Error> {
+        for _ in 0..MAX_POLLS {
+            let res: T = self.post_json(url, None).await?;
+            match status(&res) {
+                Status::Valid => return Ok(()),
+                Status::Pending | Status::Ready | Status::Processing => {
+                    tokio::time::sleep(POLL_INTERVAL).await
+                }
+                s => return Err(Error::new(eyre!("{} is {:?}", url, s), ErrorKind::Acme)),
+            }
+        }
+        Err(Error::new(
+            eyre!("Timed out waiting for {}", url),
+            ErrorKind::Timeout,
+        ))
+    }
+}
+
+fn replay_nonce(res: &reqwest::Response) -> Option<String> {
+    res.headers()
+        .get("Replay-Nonce")
+        .and_then(|n| n.to_str().ok())
+        .map(|n| n.to_owned())
+}
+
+fn location(res: &reqwest::Response) -> Result<String, Error> {
+    res.headers()
+        .get(LOCATION)
+        .and_then(|l| l.to_str().ok())
+        .map(|l| l.to_owned())
+        .ok_or_else(|| Error::new(eyre!("ACME response missing Location"), ErrorKind::Acme))
+}
+
+/// ACME can only issue certificates for public DNS names
+pub fn validate_domain(domain: &str) -> Result<String, Error> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let public = domain.parse::<IpAddr>().is_err()
+        && domain.contains('.')
+        && ![".local", ".onion", ".embassy"]
+            .iter()
+            .any(|tld| domain.ends_with(tld))
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !public {
+        return Err(Error::new(
+            eyre!(
+                "{} is not a public domain: .local and .onion addresses use certificates from the local CA",
+                domain
+            ),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    Ok(domain)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AcmeDomainInfo {
+    pub domain: String,
+    pub directory_url: String,
+    pub challenge: ChallengeType,
+    pub created_at: String,
+    pub expires: Option<String>,
+}
+
+#[command(subcommands(add, remove, renew, list))]
+pub fn acme() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Requests a certificate for a domain, and keeps it renewed
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] domain: String,
+    #[arg(long = "directory-url", rename = "directory-url")] directory_url: Option<String>,
+    #[arg] contact: Option<String>,
+    #[arg] challenge: Option<ChallengeType>,
+) -> Result<(), Error> {
+    let domain = validate_domain(&domain)?;
+    let directory_url = directory_url.unwrap_or_else(|| LETS_ENCRYPT_DIRECTORY.to_owned());
+    let challenge = challenge.unwrap_or(ChallengeType::Http01).to_string();
+    let created_at = Utc::now().to_rfc3339();
+    let n = sqlx::query!(
+        "INSERT INTO acme_domains (domain, directory_url, challenge, contact, created_at) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (domain) DO NOTHING",
+        domain,
+        directory_url,
+        challenge,
+        contact,
+        created_at,
+    )
+    .execute(&ctx.secret_store)
+    .await?
+    .rows_affected();
+    if n == 0 {
+        return Err(Error::new(
+            eyre!("Duplicate ACME domain: {}", domain),
+            ErrorKind::Duplicate,
+        ));
+    }
+    if let Err(e) = ctx
+        .net_controller
+        .acme
+        .issue(&ctx.secret_store, &domain)
+        .await
+    {
+        sqlx::query!("DELETE FROM acme_domains WHERE domain = $1", domain)
+            .execute(&ctx.secret_store)
+            .await?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn remove(#[context] ctx: RpcContext, #[arg] domain: String) -> Result<(), Error> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let n = sqlx::query!("DELETE FROM acme_domains WHERE domain = $1", domain)
+        .execute(&ctx.secret_store)
+        .await?
+        .rows_affected();
+    if n == 0 {
+        return Err(Error::new(
+            eyre!("ACME Domain Not Found"),
+            ErrorKind::NotFound,
+        ));
+    }
+    ctx.net_controller.acme.remove(&domain).await;
+    Ok(())
+}
+
+/// Reissues the certificate for a domain, whether or not it is due
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn renew(#[context] ctx: RpcContext, #[arg] domain: String) -> Result<(), Error> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    ctx.net_controller
+        .acme
+        .issue(&ctx.secret_store, &domain)
+        .await
+}
+
+fn display_acme_domains(all: Vec<AcmeDomainInfo>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(all, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "DOMAIN", "CHALLENGE", "EXPIRES", "DIRECTORY"]);
+    for info in all {
+        table.add_row(row![
+            &info.domain,
+            info.challenge.as_str(),
+            info.expires.as_deref().unwrap_or("N/A"),
+            &info.directory_url,
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_acme_domains))]
+#[instrument(skip_all)]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<AcmeDomainInfo>, Error> {
+    let rows =
+        sqlx::query!("SELECT domain, directory_url, challenge, created_at FROM acme_domains")
+            .fetch_all(&ctx.secret_store)
+            .await?;
+    let mut res = Vec::with_capacity(rows.len());
+    for row in rows {
+        res.push(AcmeDomainInfo {
+            expires: ctx
+                .net_controller
+                .acme
+                .cert(&row.domain)
+                .await
+                .map(|cert| cert.fullchain[0].not_after().to_string()),
+            challenge: row.challenge.parse()?,
+            domain: row.domain,
+            directory_url: row.directory_url,
+            created_at: row.created_at,
+        });
+    }
+    Ok(res)
+}
+
+/// Run against Pebble (https://github.com/letsencrypt/pebble) with `PEBBLE_VA_ALWAYS_VALID=1`:
+/// `PEBBLE_DIRECTORY=https://localhost:14000/dir cargo test -- --ignored acme_pebble`
+#[tokio::test]
+#[ignore]
+async fn acme_pebble() {
+    let directory = std::env::var("PEBBLE_DIRECTORY")
+        .unwrap_or_else(|_| "https://localhost:14000/dir".to_owned());
+    let http = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let mut client = AcmeClient::new(http, &directory, generate_key().unwrap(), None)
+        .await
+        .unwrap();
+    client.register(Some("admin@example.com")).await.unwrap();
+    let challenges = AcmeChallenges::default();
+    let cert = client
+        .order("test.example.com", ChallengeType::Http01, &challenges)
+        .await
+        .unwrap();
+    assert!(!cert.needs_renewal().unwrap());
+    assert!(cert.fullchain[0]
+        .subject_alt_names()
+        .unwrap()
+        .iter()
+        .any(|san| san.dnsname() == Some("test.example.com")));
+    assert!(challenges.http.read().await.is_empty());
+}
+
+#[test]
+fn validate_domains() {
+    assert_eq!(
+        validate_domain("Example.COM.").unwrap(),
+        "example.com".to_owned()
+    );
+    assert_eq!(
+        validate_domain("a-b.sub.example.com").unwrap(),
+        "a-b.sub.example.com".to_owned()
+    );
+    for domain in [
+        "localhost",
+        "192.168.1.1",
+        "::1",
+        "adjective-noun.local",
+        "xyz.onion",
+        "package.embassy",
+        "a..example.com",
+        "under_score.example.com",
+        format!("{}.example.com", "a".repeat(64)).as_str(),
+    ] {
+        assert!(validate_domain(domain).is_err(), "{}", domain);
+    }
+}
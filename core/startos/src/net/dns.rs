@@ -1,71 +1,437 @@
 use std::borrow::Borrow;
-use std::collections::BTreeMap;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
 use std::sync::{Arc, Weak};
 use std::time::Duration;
 
-use color_eyre::eyre::eyre;
 use futures::TryFutureExt;
 use helpers::NonDetachingJoinHandle;
+use ipnet::Ipv4Net;
 use models::PackageId;
-use tokio::net::{TcpListener, UdpSocket};
+use openssl::x509::X509;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::Url;
+use rpc_toolkit::command;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::process::Command;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
+use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
 use tracing::instrument;
 use trust_dns_server::authority::MessageResponseBuilder;
-use trust_dns_server::proto::op::{Header, ResponseCode};
-use trust_dns_server::proto::rr::{Name, Record, RecordType};
+use trust_dns_server::proto::op::{Header, Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_server::proto::rr::rdata::{A, AAAA, SOA};
+use trust_dns_server::proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_server::proto::serialize::binary::BinEncodable;
 use trust_dns_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
 use trust_dns_server::ServerFuture;
 
-use crate::util::Invoke;
-use crate::{Error, ErrorKind, ResultExt};
+use crate::context::RpcContext;
+use crate::net::acme::validate_domain;
+use crate::prelude::*;
+use crate::util::serde::{deserialize_from_str, display_serializable, serialize_display, IoFormat};
+use crate::util::{display_none, Invoke};
+use crate::HOST_IP;
+
+/// `resolv.conf` listing the upstream servers of systemd-resolved, rather than its stub listener
+const UPSTREAM_RESOLV_CONF: &[&str] = &["/run/systemd/resolve/resolv.conf", "/etc/resolv.conf"];
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+/// Trust anchors for DNS over TLS
+const CA_BUNDLE: &str = "/etc/ssl/certs/ca-certificates.crt";
+const DNS_MESSAGE: &str = "application/dns-message";
+const BLOCKLIST_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+type Services = BTreeMap<Option<PackageId>, BTreeMap<IpAddr, Weak<()>>>;
+
+/// A server that queries outside of `.embassy` are forwarded to
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DnsUpstream {
+    /// `udp://<ip>[:port]`, retried over TCP if the response is truncated
+    Plain(SocketAddr),
+    /// `tls://<host>[:port]`: DNS over TLS (RFC 7858)
+    Tls { host: String, port: u16 },
+    /// `https://<host>/<path>`: DNS over HTTPS (RFC 8484)
+    Https(Url),
+}
+impl FromStr for DnsUpstream {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url: Url = s.parse().with_kind(ErrorKind::ParseUrl)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::new(eyre!("{} has no host", url), ErrorKind::ParseUrl))?
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        match url.scheme() {
+            "udp" => Ok(DnsUpstream::Plain(SocketAddr::new(
+                host.parse().with_kind(ErrorKind::ParseUrl)?,
+                url.port().unwrap_or(53),
+            ))),
+            "tls" => Ok(DnsUpstream::Tls {
+                host: host.to_owned(),
+                port: url.port().unwrap_or(853),
+            }),
+            "https" => Ok(DnsUpstream::Https(url)),
+            scheme => Err(Error::new(
+                eyre!("Unsupported DNS Upstream Scheme: {}", scheme),
+                ErrorKind::ParseUrl,
+            )),
+        }
+    }
+}
+impl fmt::Display for DnsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsUpstream::Plain(addr) => write!(f, "udp://{}", addr),
+            DnsUpstream::Tls { host, port } => write!(f, "tls://{}:{}", host, port),
+            DnsUpstream::Https(url) => write!(f, "{}", url),
+        }
+    }
+}
+impl<'de> Deserialize<'de> for DnsUpstream {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_from_str(deserializer)
+    }
+}
+impl Serialize for DnsUpstream {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_display(self, serializer)
+    }
+}
+
+/// Overrides of the blocklists for the containers of one package.
+/// A rule applies to a domain and all of its subdomains, and the most specific rule wins.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, HasModel)]
+#[serde(rename_all = "kebab-case")]
+#[model = "Model<Self>"]
+pub struct DnsRules {
+    pub allow: BTreeSet<String>,
+    pub deny: BTreeSet<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, HasModel)]
+#[serde(rename_all = "kebab-case")]
+#[model = "Model<Self>"]
+pub struct DnsSettings {
+    /// Tried in order. If empty, the upstreams of the host are used
+    pub upstreams: Vec<DnsUpstream>,
+    /// Hosts-format files of domains to block, refreshed daily
+    pub blocklists: BTreeSet<Url>,
+    pub packages: BTreeMap<PackageId, DnsRules>,
+}
+
+#[derive(Default)]
+struct ResolverConfig {
+    upstreams: Vec<DnsUpstream>,
+    blocklists: BTreeMap<Url, BTreeSet<String>>,
+    packages: BTreeMap<PackageId, DnsRules>,
+    /// The WireGuard subnet, with the address of the server
+    tunnel: Option<Ipv4Net>,
+    /// `.local` names served by the vhost proxy
+    local: BTreeMap<String, Weak<()>>,
+}
+impl ResolverConfig {
+    /// WireGuard peers can not use mDNS, so `.local` names served by the vhost proxy are answered with
+    /// the address of the server in the tunnel
+    fn resolve_tunnel(&self, name: &str, src: IpAddr) -> Option<Ipv4Addr> {
+        let tunnel = self.tunnel?;
+        match src {
+            IpAddr::V4(src) if tunnel.contains(&src) => (),
+            _ => return None,
+        }
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.local
+            .get(&name)
+            .filter(|rc| rc.strong_count() > 0)
+            .map(|_| tunnel.addr())
+    }
+
+    fn is_blocked(&self, name: &str, package: Option<&PackageId>) -> bool {
+        if let Some(rules) = package.and_then(|pkg| self.packages.get(pkg)) {
+            for domain in parents(name) {
+                if rules.allow.contains(domain) {
+                    return false;
+                }
+                if rules.deny.contains(domain) {
+                    return true;
+                }
+            }
+        }
+        parents(name).any(|domain| {
+            self.blocklists
+                .values()
+                .any(|blocked| blocked.contains(domain))
+        })
+    }
+}
+
+/// `a.b.c`, `b.c`, `c`
+fn parents(name: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(name), |name| {
+        name.split_once('.').map(|(_, parent)| parent)
+    })
+}
+
+/// Domains listed in a hosts file (`0.0.0.0 ads.example.com`). Lines of bare domains are accepted as well.
+pub fn parse_hosts(hosts: &str) -> BTreeSet<String> {
+    let mut res = BTreeSet::new();
+    for line in hosts.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut words = line.split_whitespace().peekable();
+        if words.peek().map_or(false, |w| w.parse::<IpAddr>().is_ok()) {
+            words.next();
+        }
+        res.extend(
+            words
+                .filter_map(|w| validate_domain(w).ok())
+                .filter(|d| d != "localhost.localdomain"),
+        );
+    }
+    res
+}
+
+async fn fetch_blocklist(client: &reqwest::Client, url: &Url) -> Result<BTreeSet<String>, Error> {
+    let hosts = if url.scheme() == "file" {
+        let path = url
+            .to_file_path()
+            .map_err(|_| Error::new(eyre!("Invalid File URL: {}", url), ErrorKind::ParseUrl))?;
+        tokio::fs::read_to_string(&path)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?
+    } else {
+        client
+            .get(url.clone())
+            .send()
+            .await
+            .with_kind(ErrorKind::Network)?
+            .error_for_status()
+            .with_kind(ErrorKind::Network)?
+            .text()
+            .await
+            .with_kind(ErrorKind::Network)?
+    };
+    Ok(parse_hosts(&hosts))
+}
+
+async fn system_roots() -> Result<RootCertStore, Error> {
+    let pem = tokio::fs::read(CA_BUNDLE)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, CA_BUNDLE))?;
+    let mut store = RootCertStore::empty();
+    for cert in X509::stack_from_pem(&pem)? {
+        if let Err(e) = store.add(&Certificate(cert.to_der()?)) {
+            tracing::debug!("Skipping CA certificate: {}", e);
+        }
+    }
+    Ok(store)
+}
 
 pub struct DnsController {
-    services: Weak<RwLock<BTreeMap<Option<PackageId>, BTreeMap<Ipv4Addr, Weak<()>>>>>,
+    services: Weak<RwLock<Services>>,
+    config: Arc<RwLock<ResolverConfig>>,
+    resolver: Resolver,
     #[allow(dead_code)]
     dns_server: NonDetachingJoinHandle<Result<(), Error>>,
+    tunnel_server: Mutex<Option<NonDetachingJoinHandle<Result<(), Error>>>>,
 }
 
+#[derive(Clone)]
 struct Resolver {
-    services: Arc<RwLock<BTreeMap<Option<PackageId>, BTreeMap<Ipv4Addr, Weak<()>>>>>,
+    services: Arc<RwLock<Services>>,
+    config: Arc<RwLock<ResolverConfig>>,
+    /// Our own addresses, which must never be used as an upstream
+    bind: Vec<SocketAddr>,
+    tls: Arc<ClientConfig>,
+    client: reqwest::Client,
 }
 impl Resolver {
-    async fn resolve(&self, name: &Name) -> Option<Vec<Ipv4Addr>> {
-        match name.iter().next_back() {
-            Some(b"embassy") => {
-                if let Some(pkg) = name.iter().rev().skip(1).next() {
-                    if let Some(ip) = self.services.read().await.get(&Some(
-                        std::str::from_utf8(pkg)
-                            .unwrap_or_default()
-                            .parse()
-                            .unwrap_or_default(),
-                    )) {
-                        Some(
-                            ip.iter()
-                                .filter(|(_, rc)| rc.strong_count() > 0)
-                                .map(|(ip, _)| *ip)
-                                .collect(),
-                        )
-                    } else {
-                        None
-                    }
-                } else if let Some(ip) = self.services.read().await.get(&None) {
-                    Some(
-                        ip.iter()
-                            .filter(|(_, rc)| rc.strong_count() > 0)
-                            .map(|(ip, _)| *ip)
-                            .collect(),
-                    )
+    async fn resolve_tunnel(&self, name: &Name, src: IpAddr) -> Option<Ipv4Addr> {
+        self.config
+            .read()
+            .await
+            .resolve_tunnel(&name.to_ascii(), src)
+    }
+
+    /// Addresses of a name in the `.embassy` TLD, or `None` if it does not exist
+    async fn resolve(&self, name: &Name) -> Option<Vec<IpAddr>> {
+        let pkg = match name.iter().rev().skip(1).next() {
+            Some(pkg) => Some(std::str::from_utf8(pkg).ok()?.parse().ok()?),
+            None => None,
+        };
+        self.services.read().await.get(&pkg).map(|ips| {
+            ips.iter()
+                .filter(|(_, rc)| rc.strong_count() > 0)
+                .map(|(ip, _)| *ip)
+                .collect()
+        })
+    }
+
+    /// Whether a query from `src` for a name outside of `.embassy` should be refused
+    async fn is_blocked(&self, name: &Name, src: IpAddr) -> bool {
+        let name = name.to_ascii().trim_end_matches('.').to_ascii_lowercase();
+        let package = self
+            .services
+            .read()
+            .await
+            .iter()
+            .find(|(_, ips)| ips.get(&src).map_or(false, |rc| rc.strong_count() > 0))
+            .and_then(|(pkg, _)| pkg.clone());
+        self.config.read().await.is_blocked(&name, package.as_ref())
+    }
+
+    async fn system_upstreams(&self) -> Vec<SocketAddr> {
+        for path in UPSTREAM_RESOLV_CONF {
+            let Ok(conf) = tokio::fs::read_to_string(path).await else {
+                continue;
+            };
+            let upstreams = conf
+                .lines()
+                .filter_map(|l| l.trim().strip_prefix("nameserver"))
+                .filter_map(|ip| IpAddr::from_str(ip.trim()).ok())
+                .map(|ip| SocketAddr::new(ip, 53))
+                .filter(|addr| !self.bind.contains(addr))
+                .collect::<Vec<_>>();
+            if !upstreams.is_empty() {
+                return upstreams;
+            }
+        }
+        Vec::new()
+    }
+
+    async fn exchange(
+        &self,
+        upstream: &DnsUpstream,
+        bytes: &[u8],
+        id: u16,
+    ) -> Result<Message, Error> {
+        match upstream {
+            DnsUpstream::Plain(addr) => {
+                let res = forward_udp(bytes, id, *addr).await?;
+                if res.truncated() {
+                    exchange_stream(TcpStream::connect(addr).await?, bytes).await
                 } else {
-                    None
+                    Ok(res)
                 }
             }
-            _ => None,
+            DnsUpstream::Tls { host, port } => {
+                let stream = TlsConnector::from(self.tls.clone())
+                    .connect(
+                        ServerName::try_from(host.as_str()).with_kind(ErrorKind::OpenSsl)?,
+                        TcpStream::connect((host.as_str(), *port)).await?,
+                    )
+                    .await
+                    .with_kind(ErrorKind::OpenSsl)?;
+                exchange_stream(stream, bytes).await
+            }
+            DnsUpstream::Https(url) => {
+                let res = self
+                    .client
+                    .post(url.clone())
+                    .header(CONTENT_TYPE, DNS_MESSAGE)
+                    .header(ACCEPT, DNS_MESSAGE)
+                    .body(bytes.to_vec())
+                    .send()
+                    .await
+                    .with_kind(ErrorKind::Network)?
+                    .error_for_status()
+                    .with_kind(ErrorKind::Network)?
+                    .bytes()
+                    .await
+                    .with_kind(ErrorKind::Network)?;
+                Message::from_vec(&res).with_kind(ErrorKind::Network)
+            }
+        }
+    }
+
+    /// Asks each upstream in turn
+    async fn forward(&self, query: &Query) -> Result<Message, Error> {
+        let mut msg = Message::new();
+        msg.set_id(rand::random())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(query.clone());
+        let bytes = msg.to_vec().with_kind(ErrorKind::Network)?;
+        let mut upstreams = self.config.read().await.upstreams.clone();
+        if upstreams.is_empty() {
+            upstreams = self
+                .system_upstreams()
+                .await
+                .into_iter()
+                .map(DnsUpstream::Plain)
+                .collect();
+        }
+        let mut errors = Vec::new();
+        for upstream in upstreams {
+            match tokio::time::timeout(UPSTREAM_TIMEOUT, self.exchange(&upstream, &bytes, msg.id()))
+                .await
+            {
+                Ok(Ok(res)) => return Ok(res),
+                Ok(Err(e)) => errors.push(format!("{}: {}", upstream, e)),
+                Err(_) => errors.push(format!("{}: timed out", upstream)),
+            }
+        }
+        Err(Error::new(
+            eyre!("No upstream DNS server answered: [{}]", errors.join(", ")),
+            ErrorKind::Network,
+        ))
+    }
+}
+
+async fn forward_udp(bytes: &[u8], id: u16, upstream: SocketAddr) -> Result<Message, Error> {
+    let socket = UdpSocket::bind(match upstream {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0)),
+    })
+    .await?;
+    socket.connect(upstream).await?;
+    socket.send(bytes).await?;
+    let mut buf = vec![0; 4096];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        if let Ok(res) = Message::from_vec(&buf[..len]) {
+            if res.id() == id && res.message_type() == MessageType::Response {
+                return Ok(res);
+            }
         }
     }
 }
 
+/// Sends a query with the length prefix used over TCP and TLS
+async fn exchange_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    bytes: &[u8],
+) -> Result<Message, Error> {
+    stream.write_u16(bytes.len() as u16).await?;
+    stream.write_all(bytes).await?;
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+    Message::from_vec(&buf).with_kind(ErrorKind::Network)
+}
+
+/// Lets clients cache negative answers for `.embassy`, but not for long since packages come and go
+fn embassy_soa() -> Record {
+    let embassy = Name::from_ascii("embassy.").unwrap();
+    Record::from_rdata(
+        embassy.clone(),
+        0,
+        RData::SOA(SOA::new(
+            embassy.clone(),
+            Name::from_ascii("hostmaster.embassy.").unwrap(),
+            0,
+            3600,
+            600,
+            86400,
+            5,
+        )),
+    )
+}
+
 #[async_trait::async_trait]
 impl RequestHandler for Resolver {
     async fn handle_request<R: ResponseHandler>(
@@ -74,66 +440,83 @@ impl RequestHandler for Resolver {
         mut response_handle: R,
     ) -> ResponseInfo {
         let query = request.request_info().query;
-        if let Some(ip) = self.resolve(query.name().borrow()).await {
-            match query.query_type() {
-                RecordType::A => {
+        let name: &Name = query.name().borrow();
+        let builder = MessageResponseBuilder::from_message_request(&*request);
+        let mut header = Header::response_from_request(request.header());
+        header.set_recursion_available(true);
+        if let Some(ip) = self.resolve_tunnel(name, request.src().ip()).await {
+            header.set_authoritative(true);
+            let answers = match query.query_type() {
+                RecordType::A | RecordType::ANY => {
+                    vec![Record::from_rdata(name.clone(), 0, RData::A(A(ip)))]
+                }
+                _ => Vec::new(),
+            };
+            response_handle
+                .send_response(builder.build(header, &answers, [], [], []))
+                .await
+        } else if name.iter().next_back() == Some(b"embassy") {
+            header.set_authoritative(true);
+            match self.resolve(name).await {
+                Some(ips) => {
+                    let answers = ips
+                        .into_iter()
+                        .filter_map(|ip| match (ip, query.query_type()) {
+                            (IpAddr::V4(ip), RecordType::A | RecordType::ANY) => {
+                                Some(RData::A(A(ip)))
+                            }
+                            (IpAddr::V6(ip), RecordType::AAAA | RecordType::ANY) => {
+                                Some(RData::AAAA(AAAA(ip)))
+                            }
+                            _ => None,
+                        })
+                        .map(|rdata| Record::from_rdata(name.clone(), 0, rdata))
+                        .collect::<Vec<_>>();
+                    // NODATA: the name exists, but has no records of this type
+                    let soa = if answers.is_empty() {
+                        vec![embassy_soa()]
+                    } else {
+                        Vec::new()
+                    };
                     response_handle
-                        .send_response(
-                            MessageResponseBuilder::from_message_request(&*request).build(
-                                Header::response_from_request(request.header()),
-                                &ip.into_iter()
-                                    .map(|ip| {
-                                        Record::from_rdata(
-                                            request.request_info().query.name().to_owned().into(),
-                                            0,
-                                            trust_dns_server::proto::rr::RData::A(ip.into()),
-                                        )
-                                    })
-                                    .collect::<Vec<_>>(),
-                                [],
-                                [],
-                                [],
-                            ),
-                        )
+                        .send_response(builder.build(header, &answers, [], &soa, []))
                         .await
                 }
-                a => {
-                    if a != RecordType::AAAA {
-                        tracing::warn!(
-                            "Non A-Record requested for {}: {:?}",
-                            query.name(),
-                            query.query_type()
-                        );
-                    }
-                    let mut res = Header::response_from_request(request.header());
-                    res.set_response_code(ResponseCode::NXDomain);
+                None => {
+                    header.set_response_code(ResponseCode::NXDomain);
                     response_handle
-                        .send_response(
-                            MessageResponseBuilder::from_message_request(&*request).build(
-                                res.into(),
-                                [],
-                                [],
-                                [],
-                                [],
-                            ),
-                        )
+                        .send_response(builder.build(header, [], [], &[embassy_soa()], []))
                         .await
                 }
             }
-        } else {
-            let mut res = Header::response_from_request(request.header());
-            res.set_response_code(ResponseCode::NXDomain);
+        } else if self.is_blocked(name, request.src().ip()).await {
+            header.set_response_code(ResponseCode::NXDomain);
             response_handle
-                .send_response(
-                    MessageResponseBuilder::from_message_request(&*request).build(
-                        res.into(),
-                        [],
-                        [],
-                        [],
-                        [],
-                    ),
-                )
+                .send_response(builder.build(header, [], [], [], []))
                 .await
+        } else {
+            match self.forward(query.original()).await {
+                Ok(res) => {
+                    header.set_response_code(res.response_code());
+                    response_handle
+                        .send_response(builder.build(
+                            header,
+                            res.answers(),
+                            res.name_servers(),
+                            [],
+                            res.additionals(),
+                        ))
+                        .await
+                }
+                Err(e) => {
+                    tracing::warn!("Error forwarding DNS query for {}: {}", name, e);
+                    tracing::debug!("{:?}", e);
+                    header.set_response_code(ResponseCode::ServFail);
+                    response_handle
+                        .send_response(builder.build(header, [], [], [], []))
+                        .await
+                }
+            }
         }
         .unwrap_or_else(|e| {
             tracing::error!("{}", e);
@@ -148,11 +531,32 @@ impl RequestHandler for Resolver {
 impl DnsController {
     #[instrument(skip_all)]
     pub async fn init(bind: &[SocketAddr]) -> Result<Self, Error> {
+        // packages query the server on the bridge directly, so their rules apply by container address
+        let bridge = SocketAddr::from((HOST_IP, 53));
         let services = Arc::new(RwLock::new(BTreeMap::new()));
+        let config = Arc::new(RwLock::new(ResolverConfig::default()));
 
-        let mut server = ServerFuture::new(Resolver {
+        let roots = match system_roots().await {
+            Ok(roots) => roots,
+            Err(e) => {
+                tracing::warn!("Could not load CA certificates for DNS over TLS: {}", e);
+                tracing::debug!("{:?}", e);
+                RootCertStore::empty()
+            }
+        };
+        let resolver = Resolver {
             services: services.clone(),
-        });
+            config: config.clone(),
+            bind: bind.iter().copied().chain([bridge]).collect(),
+            tls: Arc::new(
+                ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+            ),
+            client: reqwest::Client::new(),
+        };
+        let mut server = ServerFuture::new(resolver.clone());
         server.register_listener(
             TcpListener::bind(bind)
                 .await
@@ -160,7 +564,24 @@ impl DnsController {
             Duration::from_secs(30),
         );
         server.register_socket(UdpSocket::bind(bind).await.with_kind(ErrorKind::Network)?);
+        if !bind
+            .iter()
+            .any(|addr| *addr == bridge || addr.ip().is_unspecified())
+        {
+            server.register_listener(
+                TcpListener::bind(bridge)
+                    .await
+                    .with_kind(ErrorKind::Network)?,
+                Duration::from_secs(30),
+            );
+            server.register_socket(
+                UdpSocket::bind(bridge)
+                    .await
+                    .with_kind(ErrorKind::Network)?,
+            );
+        }
 
+        // `.embassy` names for the server itself
         Command::new("resolvectl")
             .arg("dns")
             .arg("br-start9")
@@ -183,11 +604,77 @@ impl DnsController {
 
         Ok(Self {
             services: Arc::downgrade(&services),
+            config,
+            resolver,
             dns_server,
+            tunnel_server: Mutex::new(None),
         })
     }
 
-    pub async fn add(&self, pkg_id: Option<PackageId>, ip: Ipv4Addr) -> Result<Arc<()>, Error> {
+    /// Serves WireGuard peers on the address of the server in `tunnel`, which must already be assigned
+    pub async fn set_tunnel(&self, tunnel: Option<Ipv4Net>) -> Result<(), Error> {
+        let mut tunnel_server = self.tunnel_server.lock().await;
+        *tunnel_server = None;
+        self.config.write().await.tunnel = tunnel;
+        if let Some(tunnel) = tunnel {
+            let bind = SocketAddr::from((tunnel.addr(), 53));
+            let mut server = ServerFuture::new(self.resolver.clone());
+            server.register_listener(
+                TcpListener::bind(bind)
+                    .await
+                    .with_kind(ErrorKind::Network)?,
+                Duration::from_secs(30),
+            );
+            server.register_socket(UdpSocket::bind(bind).await.with_kind(ErrorKind::Network)?);
+            *tunnel_server = Some(
+                tokio::spawn(
+                    server
+                        .block_until_done()
+                        .map_err(|e| Error::new(e, ErrorKind::Network)),
+                )
+                .into(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Applies the upstreams and package rules of `settings`, and drops blocklists it no longer lists
+    pub async fn configure(&self, settings: &DnsSettings) {
+        let mut config = self.config.write().await;
+        config.upstreams = settings.upstreams.clone();
+        config.packages = settings.packages.clone();
+        config
+            .blocklists
+            .retain(|url, _| settings.blocklists.contains(url));
+    }
+
+    pub async fn set_blocklist(&self, url: Url, domains: BTreeSet<String>) {
+        self.config.write().await.blocklists.insert(url, domains);
+    }
+
+    /// Resolves a `.local` name for WireGuard peers, while the returned rc is held
+    pub async fn add_local(&self, name: String) -> Arc<()> {
+        let mut config = self.config.write().await;
+        if let Some(rc) = config.local.get(&name).and_then(Weak::upgrade) {
+            return rc;
+        }
+        let rc = Arc::new(());
+        config.local.insert(name, Arc::downgrade(&rc));
+        rc
+    }
+
+    pub async fn gc_local(&self, name: &str) {
+        let mut config = self.config.write().await;
+        if config
+            .local
+            .get(name)
+            .map_or(false, |rc| rc.strong_count() == 0)
+        {
+            config.local.remove(name);
+        }
+    }
+
+    pub async fn add(&self, pkg_id: Option<PackageId>, ip: IpAddr) -> Result<Arc<()>, Error> {
         if let Some(services) = Weak::upgrade(&self.services) {
             let mut writable = services.write().await;
             let mut ips = writable.remove(&pkg_id).unwrap_or_default();
@@ -207,7 +694,7 @@ impl DnsController {
         }
     }
 
-    pub async fn gc(&self, pkg_id: Option<PackageId>, ip: Ipv4Addr) -> Result<(), Error> {
+    pub async fn gc(&self, pkg_id: Option<PackageId>, ip: IpAddr) -> Result<(), Error> {
         if let Some(services) = Weak::upgrade(&self.services) {
             let mut writable = services.write().await;
             let mut ips = writable.remove(&pkg_id).unwrap_or_default();
@@ -226,3 +713,264 @@ impl DnsController {
         }
     }
 }
+
+/// Loads the DNS settings at startup, and refreshes the blocklists daily
+pub async fn refresh(ctx: RpcContext) {
+    loop {
+        if let Err(e) = reload(&ctx, true).await {
+            tracing::error!("Error refreshing DNS settings: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        tokio::time::sleep(BLOCKLIST_REFRESH_INTERVAL).await;
+    }
+}
+
+/// Applies the settings in the db, fetching every blocklist if `fetch` is set
+async fn reload(ctx: &RpcContext, fetch: bool) -> Result<(), Error> {
+    let settings = ctx.db.peek().await.as_server_info().as_dns().de()?;
+    ctx.net_controller.dns.configure(&settings).await;
+    if fetch {
+        for url in settings.blocklists {
+            match fetch_blocklist(&ctx.client, &url).await {
+                Ok(domains) => ctx.net_controller.dns.set_blocklist(url, domains).await,
+                Err(e) => {
+                    tracing::warn!("Error fetching DNS blocklist {}: {}", url, e);
+                    tracing::debug!("{:?}", e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn update_settings(
+    ctx: &RpcContext,
+    f: impl FnOnce(&mut DnsSettings) -> Result<(), Error> + Send + std::panic::UnwindSafe,
+) -> Result<(), Error> {
+    ctx.db
+        .mutate(|db| {
+            let mut settings = db.as_server_info().as_dns().de()?;
+            f(&mut settings)?;
+            db.as_server_info_mut().as_dns_mut().ser(&settings)
+        })
+        .await?;
+    reload(ctx, false).await
+}
+
+/// Resolution of names outside of `.embassy` for the server and its packages
+#[command(subcommands(get_dns, upstream, blocklist, package))]
+pub fn dns() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(rename = "get", display(display_serializable))]
+pub async fn get_dns(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<DnsSettings, Error> {
+    ctx.db.peek().await.as_server_info().as_dns().de()
+}
+
+/// Servers to forward to: `udp://<ip>`, `tls://<host>` or `https://<host>/<path>`
+#[command(subcommands(add_upstream, remove_upstream))]
+pub fn upstream() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(rename = "add", display(display_none))]
+#[instrument(skip_all)]
+pub async fn add_upstream(
+    #[context] ctx: RpcContext,
+    #[arg] upstream: DnsUpstream,
+) -> Result<(), Error> {
+    update_settings(&ctx, |settings| {
+        if settings.upstreams.contains(&upstream) {
+            return Err(Error::new(
+                eyre!("{} is already an upstream", upstream),
+                ErrorKind::Duplicate,
+            ));
+        }
+        settings.upstreams.push(upstream);
+        Ok(())
+    })
+    .await
+}
+
+#[command(rename = "remove", display(display_none))]
+#[instrument(skip_all)]
+pub async fn remove_upstream(
+    #[context] ctx: RpcContext,
+    #[arg] upstream: DnsUpstream,
+) -> Result<(), Error> {
+    update_settings(&ctx, |settings| {
+        let len = settings.upstreams.len();
+        settings.upstreams.retain(|u| u != &upstream);
+        if settings.upstreams.len() == len {
+            return Err(Error::new(
+                eyre!("DNS Upstream Not Found"),
+                ErrorKind::NotFound,
+            ));
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Hosts-format files of domains that resolve to NXDOMAIN, by `http(s)://` or `file://` URL
+#[command(subcommands(add_blocklist, remove_blocklist))]
+pub fn blocklist() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(rename = "add", display(display_none))]
+#[instrument(skip_all)]
+pub async fn add_blocklist(#[context] ctx: RpcContext, #[arg] url: Url) -> Result<(), Error> {
+    let domains = fetch_blocklist(&ctx.client, &url).await?;
+    let added = url.clone();
+    update_settings(&ctx, |settings| {
+        if !settings.blocklists.insert(added) {
+            return Err(Error::new(
+                eyre!("Blocklist already added"),
+                ErrorKind::Duplicate,
+            ));
+        }
+        Ok(())
+    })
+    .await?;
+    ctx.net_controller.dns.set_blocklist(url, domains).await;
+    Ok(())
+}
+
+#[command(rename = "remove", display(display_none))]
+#[instrument(skip_all)]
+pub async fn remove_blocklist(#[context] ctx: RpcContext, #[arg] url: Url) -> Result<(), Error> {
+    update_settings(&ctx, |settings| {
+        if !settings.blocklists.remove(&url) {
+            return Err(Error::new(
+                eyre!("Blocklist Not Found"),
+                ErrorKind::NotFound,
+            ));
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Per-package overrides of the blocklists
+#[command(subcommands(allow, deny, clear))]
+pub fn package() -> Result<(), Error> {
+    Ok(())
+}
+
+async fn set_rule(
+    ctx: &RpcContext,
+    package: PackageId,
+    domain: String,
+    allow: Option<bool>,
+) -> Result<(), Error> {
+    let domain = validate_domain(&domain)?;
+    if allow.is_some() {
+        ctx.db
+            .peek()
+            .await
+            .as_package_data()
+            .as_idx(&package)
+            .or_not_found(&package)?;
+    }
+    update_settings(ctx, move |settings| {
+        let rules = settings.packages.entry(package.clone()).or_default();
+        rules.allow.remove(&domain);
+        rules.deny.remove(&domain);
+        match allow {
+            Some(true) => rules.allow.insert(domain),
+            Some(false) => rules.deny.insert(domain),
+            None => false,
+        };
+        if rules.allow.is_empty() && rules.deny.is_empty() {
+            settings.packages.remove(&package);
+        }
+        Ok(())
+    })
+    .await
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn allow(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] domain: String,
+) -> Result<(), Error> {
+    set_rule(&ctx, package, domain, Some(true)).await
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn deny(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] domain: String,
+) -> Result<(), Error> {
+    set_rule(&ctx, package, domain, Some(false)).await
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn clear(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] domain: String,
+) -> Result<(), Error> {
+    set_rule(&ctx, package, domain, None).await
+}
+
+#[test]
+fn blocklist_rules() {
+    let mut config = ResolverConfig::default();
+    config.blocklists.insert(
+        "file:///blocklist".parse().unwrap(),
+        parse_hosts(
+            "# comment\n127.0.0.1 localhost\n0.0.0.0 ads.example.com tracker.example.org # inline\nbare.example.net\n",
+        ),
+    );
+    let pkg: PackageId = "bitcoind".parse().unwrap();
+    config.packages.insert(
+        pkg.clone(),
+        DnsRules {
+            allow: ["cdn.ads.example.com".to_owned()].into_iter().collect(),
+            deny: ["example.com".to_owned()].into_iter().collect(),
+        },
+    );
+    assert!(config.is_blocked("x.ads.example.com", None));
+    assert!(config.is_blocked("bare.example.net", None));
+    assert!(!config.is_blocked("example.com", None));
+    assert!(!config.is_blocked("localhost", None));
+    assert!(config.is_blocked("example.com", Some(&pkg)));
+    assert!(!config.is_blocked("cdn.ads.example.com", Some(&pkg)));
+    assert!(config.is_blocked("tracker.example.org", Some(&pkg)));
+}
+
+#[test]
+fn tunnel_names() {
+    let mut config = ResolverConfig::default();
+    let peer: IpAddr = Ipv4Addr::new(10, 59, 0, 2).into();
+    let served = Arc::new(());
+    config
+        .local
+        .insert("abcdef.local".into(), Arc::downgrade(&served));
+    assert_eq!(config.resolve_tunnel("abcdef.local.", peer), None);
+
+    config.tunnel = Some("10.59.0.1/24".parse().unwrap());
+    let server = Some(Ipv4Addr::new(10, 59, 0, 1));
+    assert_eq!(config.resolve_tunnel("abcdef.local.", peer), server);
+    assert_eq!(config.resolve_tunnel("ABCDEF.local", peer), server);
+    assert_eq!(config.resolve_tunnel("printer.local.", peer), None);
+    assert_eq!(
+        config.resolve_tunnel("abcdef.local.", Ipv4Addr::new(192, 168, 1, 2).into()),
+        None
+    );
+    drop(served);
+    assert_eq!(config.resolve_tunnel("abcdef.local.", peer), None);
+}
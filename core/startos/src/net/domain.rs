@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use chrono::Utc;
+use clap::ArgMatches;
+use models::{InterfaceId, PackageId};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::net::acme::validate_domain;
+use crate::net::keys::Key;
+use crate::net::vhost::AlpnInfo;
+use crate::prelude::*;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+
+/// A LAN binding of a running package, which custom domains for the same interface and port are proxied to
+pub(super) struct LanTarget {
+    pub key: Key,
+    pub target: SocketAddr,
+    pub connect_ssl: Result<(), AlpnInfo>,
+    pub rcs: BTreeMap<String, Arc<()>>,
+}
+
+/// Custom domains, each bound to an interface of a package on one of its LAN ports.
+/// The vhost proxy serves them on that port while the package is running.
+#[derive(Default)]
+pub struct Domains {
+    pub(super) bindings: BTreeMap<(String, u16), (PackageId, InterfaceId)>,
+    pub(super) active: BTreeMap<(PackageId, InterfaceId, u16), LanTarget>,
+}
+impl Domains {
+    #[instrument(skip_all)]
+    pub async fn load(secrets: &PgPool) -> Result<Self, Error> {
+        let mut bindings = BTreeMap::new();
+        for row in sqlx::query!("SELECT hostname, port, package, interface FROM domain_bindings")
+            .fetch_all(secrets)
+            .await?
+        {
+            bindings.insert(
+                (row.hostname, row.port as u16),
+                (row.package.parse()?, row.interface.parse()?),
+            );
+        }
+        Ok(Self {
+            bindings,
+            active: BTreeMap::new(),
+        })
+    }
+
+    pub(super) fn hostnames(
+        &self,
+        package: &PackageId,
+        interface: &InterfaceId,
+        port: u16,
+    ) -> Vec<String> {
+        self.bindings
+            .iter()
+            .filter(|((_, p), (pkg, iface))| *p == port && pkg == package && iface == interface)
+            .map(|((hostname, _), _)| hostname.clone())
+            .collect()
+    }
+
+    /// The custom domains of a package interface, on any port
+    pub(super) fn all_hostnames(
+        &self,
+        package: &PackageId,
+        interface: &InterfaceId,
+    ) -> BTreeSet<String> {
+        self.bindings
+            .iter()
+            .filter(|(_, (pkg, iface))| pkg == package && iface == interface)
+            .map(|((hostname, _), _)| hostname.clone())
+            .collect()
+    }
+
+    /// URLs of every custom domain
+    pub(super) fn clearnet_addresses(&self) -> Vec<String> {
+        self.bindings
+            .keys()
+            .map(|(hostname, port)| match port {
+                443 => format!("https://{}", hostname),
+                port => format!("https://{}:{}", hostname, port),
+            })
+            .collect()
+    }
+
+    /// Fails if the hostname is already bound on that port
+    pub(super) fn bind(
+        &mut self,
+        hostname: String,
+        port: u16,
+        package: PackageId,
+        interface: InterfaceId,
+    ) -> Result<(), Error> {
+        if let Some((package, interface)) = self.bindings.get(&(hostname.clone(), port)) {
+            return Err(Error::new(
+                eyre!(
+                    "{}:{} is already bound to {}/{}",
+                    hostname,
+                    port,
+                    package,
+                    interface
+                ),
+                ErrorKind::Duplicate,
+            ));
+        }
+        self.bindings.insert((hostname, port), (package, interface));
+        Ok(())
+    }
+}
+
+/// Publishes the custom domains to `server-info.connection-addresses.clearnet`
+pub async fn sync_clearnet_addresses(ctx: &RpcContext) -> Result<(), Error> {
+    let clearnet = ctx.net_controller.clearnet_addresses().await;
+    ctx.db
+        .mutate(|db| {
+            db.as_server_info_mut()
+                .as_connection_addresses_mut()
+                .as_clearnet_mut()
+                .ser(&clearnet)
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DomainBinding {
+    pub hostname: String,
+    pub port: u16,
+    pub package: PackageId,
+    pub interface: InterfaceId,
+    pub created_at: String,
+}
+
+/// Custom hostnames for package interfaces. Use `net.acme.add` for a publicly trusted certificate,
+/// otherwise they are served with a certificate from the local CA.
+#[command(subcommands(bind, unbind, list))]
+pub fn domain() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn bind(
+    #[context] ctx: RpcContext,
+    #[arg] hostname: String,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] port: u16,
+) -> Result<(), Error> {
+    let hostname = validate_domain(&hostname)?;
+    let manifest = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(&package)
+        .and_then(|pde| pde.as_installed())
+        .map(|i| i.as_manifest().de())
+        .transpose()?
+        .or_not_found(&package)?;
+    let lan_config = manifest
+        .interfaces
+        .0
+        .get(&interface)
+        .or_not_found(&interface)?
+        .lan_config
+        .as_ref();
+    if !lan_config
+        .into_iter()
+        .flatten()
+        .any(|(external, _)| external.0 == port)
+    {
+        return Err(Error::new(
+            eyre!(
+                "{}/{} is not served on LAN port {}",
+                package,
+                interface,
+                port
+            ),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+
+    let created_at = Utc::now().to_rfc3339();
+    let n = sqlx::query!(
+        "INSERT INTO domain_bindings (hostname, port, package, interface, created_at) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (hostname, port) DO NOTHING",
+        hostname,
+        port as i32,
+        &*package,
+        &*interface,
+        created_at,
+    )
+    .execute(&ctx.secret_store)
+    .await?
+    .rows_affected();
+    if n == 0 {
+        return Err(Error::new(
+            eyre!("{}:{} is already bound", hostname, port),
+            ErrorKind::Duplicate,
+        ));
+    }
+    if let Err(e) = ctx
+        .net_controller
+        .bind_domain(hostname.clone(), port, package, interface)
+        .await
+    {
+        sqlx::query!(
+            "DELETE FROM domain_bindings WHERE hostname = $1 AND port = $2",
+            hostname,
+            port as i32
+        )
+        .execute(&ctx.secret_store)
+        .await?;
+        return Err(e);
+    }
+    sync_clearnet_addresses(&ctx).await
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn unbind(
+    #[context] ctx: RpcContext,
+    #[arg] hostname: String,
+    #[arg] port: u16,
+) -> Result<(), Error> {
+    let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+    let n = sqlx::query!(
+        "DELETE FROM domain_bindings WHERE hostname = $1 AND port = $2",
+        hostname,
+        port as i32
+    )
+    .execute(&ctx.secret_store)
+    .await?
+    .rows_affected();
+    if n == 0 {
+        return Err(Error::new(
+            eyre!("Domain Binding Not Found"),
+            ErrorKind::NotFound,
+        ));
+    }
+    ctx.net_controller.unbind_domain(hostname, port).await?;
+    sync_clearnet_addresses(&ctx).await
+}
+
+fn display_domain_bindings(all: Vec<DomainBinding>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(all, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "HOSTNAME", "PORT", "PACKAGE", "INTERFACE"]);
+    for binding in all {
+        table.add_row(row![
+            &binding.hostname,
+            binding.port,
+            &*binding.package,
+            &*binding.interface
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_domain_bindings))]
+#[instrument(skip_all)]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<DomainBinding>, Error> {
+    sqlx::query!("SELECT hostname, port, package, interface, created_at FROM domain_bindings")
+        .fetch_all(&ctx.secret_store)
+        .await?
+        .into_iter()
+        .map(|r| {
+            Ok(DomainBinding {
+                hostname: r.hostname,
+                port: r.port as u16,
+                package: r.package.parse()?,
+                interface: r.interface.parse()?,
+                created_at: r.created_at,
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn binding_conflicts() {
+    let package: PackageId = "test".parse().unwrap();
+    let other: PackageId = "other".parse().unwrap();
+    let interface: InterfaceId = "main".parse().unwrap();
+    let mut domains = Domains::default();
+    domains
+        .bind(
+            "example.com".into(),
+            443,
+            package.clone(),
+            interface.clone(),
+        )
+        .unwrap();
+    // the same hostname can serve another package on another port
+    domains
+        .bind("example.com".into(), 8443, other.clone(), interface.clone())
+        .unwrap();
+    assert!(domains
+        .bind("example.com".into(), 443, other.clone(), interface.clone())
+        .is_err());
+    assert!(domains
+        .bind(
+            "example.com".into(),
+            443,
+            package.clone(),
+            interface.clone()
+        )
+        .is_err());
+    assert_eq!(
+        domains.hostnames(&package, &interface, 443),
+        vec!["example.com".to_owned()]
+    );
+    assert!(domains.hostnames(&package, &interface, 8443).is_empty());
+    assert_eq!(
+        domains.all_hostnames(&other, &interface),
+        BTreeSet::from(["example.com".to_owned()])
+    );
+
+    assert_eq!(
+        domains.clearnet_addresses(),
+        vec![
+            "https://example.com".to_owned(),
+            "https://example.com:8443".to_owned()
+        ]
+    );
+
+    domains.bindings.remove(&("example.com".to_owned(), 443));
+    domains
+        .bind("example.com".into(), 443, other.clone(), interface)
+        .unwrap();
+}
@@ -0,0 +1,471 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::net::SocketAddrV4;
+use std::sync::{Arc, Weak};
+
+use clap::ArgMatches;
+use itertools::Itertools;
+use models::{InterfaceId, PackageId};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::net::interface::ForwardProtocol;
+use crate::net::vhost::port_allowed;
+use crate::net::wireguard::BRIDGE_IFACE;
+use crate::prelude::*;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Invoke};
+
+const NFT_TABLE: &str = "startos-forward";
+
+struct Forward {
+    protocols: BTreeSet<ForwardProtocol>,
+    target: SocketAddrV4,
+    /// `None` if disabled
+    external: Option<u16>,
+    rc: Weak<()>,
+}
+
+/// Remapped and disabled forwards, by the package, interface and port of the manifest
+pub type ForwardOverrides = BTreeMap<(PackageId, InterfaceId, u16), Option<u16>>;
+
+/// DNATs LAN ports straight to package containers, keyed by the package, interface and port of the manifest
+pub struct ForwardController {
+    /// Interfaces forwarded ports are served on, leaving out the container bridge and the WireGuard tunnel
+    lan_ifaces: Vec<String>,
+    forwards: Mutex<BTreeMap<(PackageId, InterfaceId, u16), Forward>>,
+}
+impl ForwardController {
+    /// Clears the rules left over by a previous run
+    #[instrument(skip_all)]
+    pub async fn init(lan_ifaces: Vec<String>) -> Self {
+        if let Err(e) = sync(&lan_ifaces, &BTreeMap::new()).await {
+            tracing::error!("Error clearing port forwards: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        Self {
+            lan_ifaces,
+            forwards: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    #[instrument(skip_all)]
+    pub async fn add(
+        &self,
+        package: PackageId,
+        interface: InterfaceId,
+        port: u16,
+        protocols: BTreeSet<ForwardProtocol>,
+        target: SocketAddrV4,
+        external: Option<u16>,
+    ) -> Result<Arc<()>, Error> {
+        let mut forwards = self.forwards.lock().await;
+        forwards.retain(|_, f| f.rc.strong_count() > 0);
+        let key = (package, interface, port);
+        if let Some(external) = external {
+            check_conflict(&forwards, &key, &protocols, external)?;
+        }
+        let rc = forwards
+            .get(&key)
+            .and_then(|f| f.rc.upgrade())
+            .unwrap_or_else(|| Arc::new(()));
+        forwards.insert(
+            key,
+            Forward {
+                protocols,
+                target,
+                external,
+                rc: Arc::downgrade(&rc),
+            },
+        );
+        sync(&self.lan_ifaces, &forwards).await?;
+        Ok(rc)
+    }
+
+    #[instrument(skip_all)]
+    pub async fn gc(
+        &self,
+        package: &PackageId,
+        interface: &InterfaceId,
+        port: u16,
+    ) -> Result<(), Error> {
+        let mut forwards = self.forwards.lock().await;
+        let key = (package.clone(), interface.clone(), port);
+        if forwards
+            .get(&key)
+            .map_or(false, |f| f.rc.strong_count() == 0)
+        {
+            forwards.remove(&key);
+            sync(&self.lan_ifaces, &forwards).await?;
+        }
+        Ok(())
+    }
+
+    /// Moves a forward of a running package to another external port, or disables it if `None`
+    pub async fn remap(
+        &self,
+        package: &PackageId,
+        interface: &InterfaceId,
+        port: u16,
+        external: Option<u16>,
+    ) -> Result<(), Error> {
+        let mut forwards = self.forwards.lock().await;
+        forwards.retain(|_, f| f.rc.strong_count() > 0);
+        let key = (package.clone(), interface.clone(), port);
+        let Some(protocols) = forwards.get(&key).map(|f| f.protocols.clone()) else {
+            return Ok(());
+        };
+        if let Some(external) = external {
+            check_conflict(&forwards, &key, &protocols, external)?;
+        }
+        if let Some(forward) = forwards.get_mut(&key) {
+            forward.external = external;
+        }
+        sync(&self.lan_ifaces, &forwards).await
+    }
+
+    async fn active(&self) -> BTreeSet<(PackageId, InterfaceId, u16)> {
+        self.forwards
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, f)| f.rc.strong_count() > 0 && f.external.is_some())
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+}
+
+fn check_conflict(
+    forwards: &BTreeMap<(PackageId, InterfaceId, u16), Forward>,
+    key: &(PackageId, InterfaceId, u16),
+    protocols: &BTreeSet<ForwardProtocol>,
+    external: u16,
+) -> Result<(), Error> {
+    if let Some(((package, interface, _), _)) = forwards.iter().find(|(k, f)| {
+        *k != key && f.external == Some(external) && !f.protocols.is_disjoint(protocols)
+    }) {
+        return Err(Error::new(
+            eyre!(
+                "Port {} is already forwarded to {}/{}",
+                external,
+                package,
+                interface
+            ),
+            ErrorKind::LanPortConflict,
+        ));
+    }
+    Ok(())
+}
+
+/// Replaces the nftables table with one for `forwards`, atomically
+async fn sync(
+    lan_ifaces: &[String],
+    forwards: &BTreeMap<(PackageId, InterfaceId, u16), Forward>,
+) -> Result<(), Error> {
+    let ifaces = lan_ifaces.iter().map(|i| format!("\"{i}\"")).join(", ");
+    let mut rules = String::new();
+    for forward in forwards.values().filter(|f| f.rc.strong_count() > 0) {
+        let Some(external) = forward.external else {
+            continue;
+        };
+        for protocol in &forward.protocols {
+            writeln!(
+                rules,
+                "        iifname {{ {} }} fib daddr type local {} dport {} dnat to {}",
+                ifaces, protocol, external, forward.target
+            )
+            .unwrap();
+        }
+    }
+    let script = format!(
+        "table ip {NFT_TABLE}\n\
+         delete table ip {NFT_TABLE}\n\
+         table ip {NFT_TABLE} {{\n    \
+         chain prerouting {{\n        \
+         type nat hook prerouting priority dstnat; policy accept;\n\
+         {rules}    }}\n\
+         }}\n"
+    );
+    Command::new("nft")
+        .arg("-f")
+        .arg("-")
+        .input(Some(&mut std::io::Cursor::new(script.into_bytes())))
+        .invoke(ErrorKind::Network)
+        .await?;
+    if !rules.is_empty() {
+        allow_forwarding().await?;
+    }
+    Ok(())
+}
+
+/// Docker drops forwarded traffic that is not its own, so forwarded connections must be let through to the containers
+async fn allow_forwarding() -> Result<(), Error> {
+    let rule = [
+        "FORWARD",
+        "-o",
+        BRIDGE_IFACE,
+        "-m",
+        "conntrack",
+        "--ctstate",
+        "DNAT",
+        "-j",
+        "ACCEPT",
+    ];
+    if Command::new("iptables")
+        .arg("-C")
+        .args(rule)
+        .invoke(ErrorKind::Network)
+        .await
+        .is_err()
+    {
+        Command::new("iptables")
+            .arg("-I")
+            .args(rule)
+            .invoke(ErrorKind::Network)
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn overrides(secrets: impl sqlx::PgExecutor<'_>) -> Result<ForwardOverrides, Error> {
+    sqlx::query!("SELECT package, interface, port, external FROM port_forward_overrides")
+        .fetch_all(secrets)
+        .await?
+        .into_iter()
+        .map(|r| {
+            Ok((
+                (r.package.parse()?, r.interface.parse()?, r.port as u16),
+                r.external.map(|e| e as u16),
+            ))
+        })
+        .collect()
+}
+
+/// The external port of a forward: the port from the manifest unless it was remapped or disabled
+pub async fn external_port(
+    secrets: impl sqlx::PgExecutor<'_>,
+    package: &PackageId,
+    interface: &InterfaceId,
+    port: u16,
+) -> Result<Option<u16>, Error> {
+    Ok(sqlx::query!(
+        "SELECT external FROM port_forward_overrides WHERE package = $1 AND interface = $2 AND port = $3",
+        &**package,
+        &**interface,
+        port as i32
+    )
+    .fetch_optional(secrets)
+    .await?
+    .map_or(Some(port), |r| r.external.map(|e| e as u16)))
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PortForward {
+    pub package: PackageId,
+    pub interface: InterfaceId,
+    pub port: u16,
+    pub protocols: BTreeSet<ForwardProtocol>,
+    pub internal: u16,
+    pub external: Option<u16>,
+    pub active: bool,
+}
+
+async fn port_forwards(ctx: &RpcContext) -> Result<Vec<PortForward>, Error> {
+    let overrides = overrides(&ctx.secret_store).await?;
+    let active = ctx.net_controller.forward.active().await;
+    let mut res = Vec::new();
+    for (package, pde) in ctx.db.peek().await.as_package_data().as_entries()? {
+        let Some(installed) = pde.as_installed() else {
+            continue;
+        };
+        for (interface, iface) in installed.as_manifest().as_interfaces().de()?.0 {
+            for (port, forward) in iface.forward_config.into_iter().flatten() {
+                let key = (package.clone(), interface.clone(), port.0);
+                res.push(PortForward {
+                    external: overrides.get(&key).copied().unwrap_or(Some(port.0)),
+                    active: active.contains(&key),
+                    package: key.0,
+                    interface: key.1,
+                    port: port.0,
+                    protocols: forward.protocols,
+                    internal: forward.internal,
+                });
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// LAN ports forwarded straight to packages, for protocols the vhost proxy can not serve
+#[command(subcommands(list, remap, disable, reset))]
+pub fn forward() -> Result<(), Error> {
+    Ok(())
+}
+
+fn display_forwards(all: Vec<PortForward>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(all, matches);
+    }
+
+    let mut table = Table::new();
+    table
+        .add_row(row![bc => "PACKAGE", "INTERFACE", "PROTOCOLS", "EXTERNAL", "INTERNAL", "ACTIVE"]);
+    for forward in all {
+        table.add_row(row![
+            &*forward.package,
+            &*forward.interface,
+            forward.protocols.iter().join(", "),
+            forward
+                .external
+                .map_or("disabled".to_owned(), |p| p.to_string()),
+            forward.internal,
+            forward.active
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_forwards))]
+#[instrument(skip_all)]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<PortForward>, Error> {
+    port_forwards(&ctx).await
+}
+
+async fn set_external(
+    ctx: &RpcContext,
+    package: PackageId,
+    interface: InterfaceId,
+    port: u16,
+    external: Option<u16>,
+) -> Result<(), Error> {
+    let forwards = port_forwards(ctx).await?;
+    let forward = forwards
+        .iter()
+        .find(|f| f.package == package && f.interface == interface && f.port == port)
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("{}/{} does not forward port {}", package, interface, port),
+                ErrorKind::NotFound,
+            )
+        })?;
+    if let Some(external) = external {
+        if !port_allowed(external) {
+            return Err(Error::new(
+                eyre!("Port {} may not be forwarded", external),
+                ErrorKind::LanPortConflict,
+            ));
+        }
+        if let Some(other) = forwards.iter().find(|f| {
+            f.external == Some(external)
+                && !(f.package == package && f.interface == interface && f.port == port)
+                && !f.protocols.is_disjoint(&forward.protocols)
+        }) {
+            return Err(Error::new(
+                eyre!(
+                    "Port {} is already forwarded to {}/{}",
+                    external,
+                    other.package,
+                    other.interface
+                ),
+                ErrorKind::LanPortConflict,
+            ));
+        }
+        if forward.protocols.contains(&ForwardProtocol::Tcp) {
+            for (id, pde) in ctx.db.peek().await.as_package_data().as_entries()? {
+                let Some(installed) = pde.as_installed() else {
+                    continue;
+                };
+                if installed
+                    .as_manifest()
+                    .as_interfaces()
+                    .de()?
+                    .0
+                    .values()
+                    .flat_map(|iface| iface.lan_config.iter().flatten())
+                    .any(|(lan, _)| lan.0 == external)
+                {
+                    return Err(Error::new(
+                        eyre!("Port {} is a LAN port of {}", external, id),
+                        ErrorKind::LanPortConflict,
+                    ));
+                }
+            }
+        }
+    }
+    // the override is only committed once the forward controller has accepted it
+    let mut tx = ctx.secret_store.begin().await?;
+    if external == Some(port) {
+        sqlx::query!(
+            "DELETE FROM port_forward_overrides WHERE package = $1 AND interface = $2 AND port = $3",
+            &*package,
+            &*interface,
+            port as i32
+        )
+        .execute(&mut *tx)
+        .await?;
+    } else {
+        sqlx::query!(
+            "INSERT INTO port_forward_overrides (package, interface, port, external) VALUES ($1, $2, $3, $4) ON CONFLICT (package, interface, port) DO UPDATE SET external = $4",
+            &*package,
+            &*interface,
+            port as i32,
+            external.map(|e| e as i32)
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    ctx.net_controller
+        .forward
+        .remap(&package, &interface, port, external)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Forwards a port of a package from another LAN port than its manifest declares
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn remap(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] port: u16,
+    #[arg] external: u16,
+) -> Result<(), Error> {
+    set_external(&ctx, package, interface, port, Some(external)).await
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn disable(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] port: u16,
+) -> Result<(), Error> {
+    set_external(&ctx, package, interface, port, None).await
+}
+
+/// Forwards a port of a package from the LAN port its manifest declares
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn reset(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] port: u16,
+) -> Result<(), Error> {
+    set_external(&ctx, package, interface, port, Some(port)).await
+}
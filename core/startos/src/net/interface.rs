@@ -1,4 +1,5 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 
 use indexmap::IndexSet;
 pub use models::InterfaceId;
@@ -8,6 +9,7 @@ use tracing::instrument;
 
 use crate::db::model::{InterfaceAddressMap, InterfaceAddresses};
 use crate::net::keys::Key;
+use crate::net::vhost::port_allowed;
 use crate::s9pk::manifest::PackageId;
 use crate::util::serde::Port;
 use crate::{Error, ResultExt};
@@ -66,6 +68,8 @@ pub struct Interface {
     pub description: String,
     pub tor_config: Option<TorConfig>,
     pub lan_config: Option<BTreeMap<Port, LanPortConfig>>,
+    #[serde(default)]
+    pub forward_config: Option<BTreeMap<Port, ForwardConfig>>,
     pub ui: bool,
     pub protocols: IndexSet<String>,
 }
@@ -81,6 +85,32 @@ impl Interface {
         if self.ui && !(self.protocols.contains("http") || self.protocols.contains("https")) {
             color_eyre::eyre::bail!("must support http or https to serve a ui");
         }
+        for (external, forward) in self.forward_config.iter().flatten() {
+            if !port_allowed(external.0) {
+                color_eyre::eyre::bail!("port {} may not be forwarded", external.0);
+            }
+            if forward.protocols.is_empty() {
+                color_eyre::eyre::bail!("port {} is forwarded without a protocol", external.0);
+            }
+            for protocol in &forward.protocols {
+                if !self.protocols.contains(protocol.as_str()) {
+                    color_eyre::eyre::bail!(
+                        "must support {} to forward port {}",
+                        protocol,
+                        external.0
+                    );
+                }
+            }
+            if forward.protocols.contains(&ForwardProtocol::Tcp)
+                && self
+                    .lan_config
+                    .iter()
+                    .flatten()
+                    .any(|(lan, _)| lan == external)
+            {
+                color_eyre::eyre::bail!("tcp port {} is also a lan port", external.0);
+            }
+        }
         Ok(())
     }
 }
@@ -120,3 +150,31 @@ impl<'de> Deserialize<'de> for LanPortConfig {
         })
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardProtocol {
+    Tcp,
+    Udp,
+}
+impl ForwardProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+        }
+    }
+}
+impl fmt::Display for ForwardProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A LAN port forwarded as is to the package, for protocols the vhost proxy can not serve
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ForwardConfig {
+    pub protocols: BTreeSet<ForwardProtocol>,
+    pub internal: u16,
+}
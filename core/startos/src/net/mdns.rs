@@ -1,100 +1,615 @@
-use std::collections::BTreeMap;
-use std::net::Ipv4Addr;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
 use std::sync::{Arc, Weak};
+use std::time::Duration;
 
 use color_eyre::eyre::eyre;
-use tokio::process::{Child, Command};
-use tokio::sync::Mutex;
+use futures::TryStreamExt;
+use helpers::NonDetachingJoinHandle;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::RwLock;
 use tracing::instrument;
+use trust_dns_server::proto::op::{Message, MessageType, OpCode, Query};
+use trust_dns_server::proto::rr::rdata::{A, AAAA, PTR, SRV, TXT};
+use trust_dns_server::proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_server::proto::serialize::binary::BinEncodable;
 
-use crate::util::Invoke;
-use crate::{Error, ResultExt};
+use crate::net::utils::{
+    get_iface_ipv4_addr, get_iface_ipv6_addr, iface_is_physical, list_interfaces,
+};
+use crate::{Error, ErrorKind, ResultExt};
 
+const MDNS_PORT: u16 = 5353;
+const MDNS_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+/// RFC 6762 recommends 120s for records containing a host name, and 75 minutes for everything else
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+/// Responses to legacy unicast queries must not be cached for longer than this
+const LEGACY_TTL: u32 = 10;
+const HTTP_SERVICE: &str = "_http._tcp.local.";
+const HTTPS_SERVICE: &str = "_https._tcp.local.";
+const SERVICE_ENUMERATION: &str = "_services._dns-sd._udp.local.";
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const RESOLVE_ATTEMPTS: usize = 3;
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn name(s: &str) -> Result<Name, Error> {
+    Name::from_str(s).with_kind(ErrorKind::Network)
+}
+
+/// Resolves a `.local` name with a one-shot query (RFC 6762 §5.1)
+#[instrument]
 pub async fn resolve_mdns(hostname: &str) -> Result<Ipv4Addr, Error> {
-    Ok(String::from_utf8(
-        Command::new("avahi-resolve-host-name")
-            .kill_on_drop(true)
-            .arg("-4")
-            .arg(hostname)
-            .invoke(crate::ErrorKind::Network)
-            .await?,
-    )?
-    .split_once("\t")
-    .ok_or_else(|| {
-        Error::new(
-            eyre!("Failed to resolve hostname: {}", hostname),
-            crate::ErrorKind::Network,
-        )
-    })?
-    .1
-    .trim()
-    .parse()?)
-}
-
-pub struct MdnsController(Mutex<MdnsControllerInner>);
-impl MdnsController {
-    pub async fn init() -> Result<Self, Error> {
-        Ok(MdnsController(Mutex::new(
-            MdnsControllerInner::init().await?,
-        )))
+    let host = name(&format!("{}.", hostname.trim_end_matches('.')))?;
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+    let mut query = Message::new();
+    query
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .add_query(Query::query(host.clone(), RecordType::A));
+    let query = query.to_vec().with_kind(ErrorKind::Network)?;
+    let mut buf = vec![0; 9000];
+    for _ in 0..RESOLVE_ATTEMPTS {
+        socket
+            .send_to(&query, SocketAddr::from((MDNS_V4, MDNS_PORT)))
+            .await?;
+        let res = tokio::time::timeout(RESOLVE_TIMEOUT, async {
+            loop {
+                let (len, _) = socket.recv_from(&mut buf).await?;
+                let Ok(msg) = Message::from_vec(&buf[..len]) else {
+                    continue;
+                };
+                if let Some(ip) = msg
+                    .answers()
+                    .iter()
+                    .filter(|r| r.name() == &host)
+                    .find_map(|r| match r.data() {
+                        Some(RData::A(ip)) => Some(ip.0),
+                        _ => None,
+                    })
+                {
+                    return Ok::<_, Error>(ip);
+                }
+            }
+        })
+        .await;
+        if let Ok(res) = res {
+            return res;
+        }
     }
-    pub async fn add(&self, alias: String) -> Result<Arc<()>, Error> {
-        self.0.lock().await.add(alias).await
+    Err(Error::new(
+        eyre!("Failed to resolve hostname: {}", hostname),
+        ErrorKind::Network,
+    ))
+}
+
+/// A DNS-SD instance of `_http._tcp`, or `_https._tcp` for TLS ports
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ServiceKey {
+    host: String,
+    port: u16,
+    ssl: bool,
+}
+impl ServiceKey {
+    fn service(&self) -> &'static str {
+        if self.ssl {
+            HTTPS_SERVICE
+        } else {
+            HTTP_SERVICE
+        }
     }
-    pub async fn gc(&self, alias: String) -> Result<(), Error> {
-        self.0.lock().await.gc(alias).await
+}
+
+#[derive(Default)]
+struct Records {
+    /// Host names, e.g. `<base32>.local`
+    aliases: BTreeMap<String, Weak<()>>,
+    services: BTreeMap<ServiceKey, (String, Weak<()>)>,
+    addrs: BTreeSet<IpAddr>,
+}
+impl Records {
+    fn live_aliases(&self) -> impl Iterator<Item = &String> {
+        self.aliases
+            .iter()
+            .filter(|(_, rc)| rc.strong_count() > 0)
+            .map(|(alias, _)| alias)
+    }
+    fn live_services(&self) -> impl Iterator<Item = (&ServiceKey, &String)> {
+        self.services
+            .iter()
+            .filter(|(_, (_, rc))| rc.strong_count() > 0)
+            .map(|(key, (instance, _))| (key, instance))
+    }
+
+    fn address_records(&self, alias: &str, ty: RecordType) -> Result<Vec<Record>, Error> {
+        let host = name(&format!("{}.", alias))?;
+        Ok(self
+            .addrs
+            .iter()
+            .filter_map(|ip| match (ip, ty) {
+                (IpAddr::V4(ip), RecordType::A | RecordType::ANY) => Some(RData::A(A(*ip))),
+                (IpAddr::V6(ip), RecordType::AAAA | RecordType::ANY) => {
+                    Some(RData::AAAA(AAAA(*ip)))
+                }
+                _ => None,
+            })
+            .map(|rdata| Record::from_rdata(host.clone(), HOST_TTL, rdata))
+            .collect())
+    }
+
+    fn service_records(
+        &self,
+        key: &ServiceKey,
+        instance: &str,
+        ty: RecordType,
+    ) -> Result<Vec<Record>, Error> {
+        let full = instance_name(instance, key.service())?;
+        let mut res = Vec::new();
+        if matches!(ty, RecordType::SRV | RecordType::ANY) {
+            res.push(Record::from_rdata(
+                full.clone(),
+                HOST_TTL,
+                RData::SRV(SRV::new(0, 0, key.port, name(&format!("{}.", key.host))?)),
+            ));
+        }
+        if matches!(ty, RecordType::TXT | RecordType::ANY) {
+            res.push(Record::from_rdata(
+                full,
+                SERVICE_TTL,
+                RData::TXT(TXT::new(vec!["path=/".into()])),
+            ));
+        }
+        Ok(res)
+    }
+
+    /// Answers and additional records for a question
+    fn answer(&self, query: &Query) -> Result<(Vec<Record>, Vec<Record>), Error> {
+        let ty = query.query_type();
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+        for alias in self.live_aliases() {
+            if query.name() == &name(&format!("{}.", alias))? {
+                answers.extend(self.address_records(alias, ty)?);
+            }
+        }
+        if query.name() == &name(SERVICE_ENUMERATION)?
+            && matches!(ty, RecordType::PTR | RecordType::ANY)
+        {
+            let services = self
+                .live_services()
+                .map(|(key, _)| key.service())
+                .collect::<BTreeSet<_>>();
+            for service in services {
+                answers.push(Record::from_rdata(
+                    name(SERVICE_ENUMERATION)?,
+                    SERVICE_TTL,
+                    RData::PTR(PTR(name(service)?)),
+                ));
+            }
+        }
+        for (key, instance) in self.live_services() {
+            if query.name() == &name(key.service())?
+                && matches!(ty, RecordType::PTR | RecordType::ANY)
+            {
+                answers.push(Record::from_rdata(
+                    name(key.service())?,
+                    SERVICE_TTL,
+                    RData::PTR(PTR(instance_name(instance, key.service())?)),
+                ));
+                additionals.extend(self.service_records(key, instance, RecordType::ANY)?);
+                additionals.extend(self.address_records(&key.host, RecordType::ANY)?);
+            } else if query.name() == &instance_name(instance, key.service())? {
+                answers.extend(self.service_records(key, instance, ty)?);
+                additionals.extend(self.address_records(&key.host, RecordType::ANY)?);
+            }
+        }
+        Ok((answers, additionals))
+    }
+
+    /// Records to multicast when an alias is added or removed
+    fn alias_announcement(&self, alias: &str) -> Result<Vec<Record>, Error> {
+        self.address_records(alias, RecordType::ANY)
+    }
+    /// Records to multicast when a service is added or removed, including its PTR
+    fn service_announcement(&self, key: &ServiceKey, instance: &str) -> Result<Vec<Record>, Error> {
+        let mut res = vec![Record::from_rdata(
+            name(key.service())?,
+            SERVICE_TTL,
+            RData::PTR(PTR(instance_name(instance, key.service())?)),
+        )];
+        res.extend(self.service_records(key, instance, RecordType::ANY)?);
+        Ok(res)
+    }
+    fn announcement(&self) -> Result<Vec<Record>, Error> {
+        let mut res = Vec::new();
+        for alias in self.live_aliases() {
+            res.extend(self.alias_announcement(alias)?);
+        }
+        for (key, instance) in self.live_services() {
+            res.extend(self.service_announcement(key, instance)?);
+        }
+        Ok(res)
+    }
+}
+
+fn instance_name(instance: &str, service: &str) -> Result<Name, Error> {
+    let service = name(service)?;
+    Name::from_labels(std::iter::once(instance.as_bytes()).chain(service.iter()))
+        .with_kind(ErrorKind::Network)
+}
+
+fn response(answers: Vec<Record>, additionals: Vec<Record>) -> Message {
+    let mut msg = Message::new();
+    msg.set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Query)
+        .set_authoritative(true)
+        .add_answers(answers)
+        .add_additionals(additionals);
+    msg
+}
+
+fn goodbye(mut records: Vec<Record>) -> Message {
+    for record in &mut records {
+        record.set_ttl(0);
+    }
+    response(records, Vec::new())
+}
+
+fn bind_multicast(domain: Domain) -> Result<UdpSocket, Error> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    // avahi-daemon still answers for the hostname of the server
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    if domain == Domain::IPV6 {
+        socket.set_only_v6(true)?;
+        socket.set_multicast_loop_v6(true)?;
+        socket.set_multicast_hops_v6(255)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    } else {
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_multicast_ttl_v4(255)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
     }
+    Ok(UdpSocket::from_std(socket.into())?)
 }
 
-pub struct MdnsControllerInner {
-    alias_cmd: Option<Child>,
-    services: BTreeMap<String, Weak<()>>,
+fn if_index(iface: &str) -> Option<u32> {
+    let iface = CString::new(iface).ok()?;
+    match unsafe { libc::if_nametoindex(iface.as_ptr()) } {
+        0 => None,
+        idx => Some(idx),
+    }
+}
+
+struct Sockets {
+    v4: UdpSocket,
+    v6: Option<UdpSocket>,
+}
+impl Sockets {
+    async fn multicast(&self, msg: &Message) -> Result<(), Error> {
+        if msg.answers().is_empty() {
+            return Ok(());
+        }
+        let bytes = msg.to_vec().with_kind(ErrorKind::Network)?;
+        self.v4
+            .send_to(&bytes, SocketAddr::from((MDNS_V4, MDNS_PORT)))
+            .await?;
+        if let Some(v6) = &self.v6 {
+            v6.send_to(&bytes, SocketAddr::from((MDNS_V6, MDNS_PORT)))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Joins the mDNS group on every physical interface, and returns their addresses
+    async fn refresh(&self) -> Result<BTreeSet<IpAddr>, Error> {
+        let mut addrs = BTreeSet::new();
+        let mut ifaces = list_interfaces();
+        while let Some(iface) = ifaces.try_next().await? {
+            if !iface_is_physical(&iface).await {
+                continue;
+            }
+            if let Some((ip, _)) = get_iface_ipv4_addr(&iface).await? {
+                // fails with EADDRINUSE if we have already joined
+                self.v4.join_multicast_v4(MDNS_V4, ip).ok();
+                addrs.insert(ip.into());
+            }
+            if let Some((ip, _)) = get_iface_ipv6_addr(&iface).await? {
+                if let (Some(v6), Some(idx)) = (&self.v6, if_index(&iface)) {
+                    v6.join_multicast_v6(&MDNS_V6, idx).ok();
+                }
+                addrs.insert(ip.into());
+            }
+        }
+        Ok(addrs)
+    }
+}
+
+async fn respond(
+    socket: &UdpSocket,
+    records: &RwLock<Records>,
+    buf: &[u8],
+    from: SocketAddr,
+    to: SocketAddr,
+) -> Result<(), Error> {
+    let Ok(query) = Message::from_vec(buf) else {
+        return Ok(());
+    };
+    if query.message_type() != MessageType::Query || query.op_code() != OpCode::Query {
+        return Ok(());
+    }
+    let records = records.read().await;
+    let mut answers = Vec::new();
+    let mut additionals = Vec::new();
+    for q in query.queries() {
+        let (ans, add) = records.answer(q)?;
+        answers.extend(ans);
+        additionals.extend(add);
+    }
+    drop(records);
+    if answers.is_empty() {
+        return Ok(());
+    }
+    if from.port() == MDNS_PORT {
+        let bytes = response(answers, additionals)
+            .to_vec()
+            .with_kind(ErrorKind::Network)?;
+        socket.send_to(&bytes, to).await?;
+    } else {
+        // legacy unicast: reply like a unicast DNS server would (RFC 6762 §6.7)
+        for record in answers.iter_mut().chain(additionals.iter_mut()) {
+            record.set_ttl(record.ttl().min(LEGACY_TTL));
+        }
+        let mut res = response(answers, additionals);
+        res.set_id(query.id()).add_queries(query.queries().to_vec());
+        let bytes = res.to_vec().with_kind(ErrorKind::Network)?;
+        socket.send_to(&bytes, from).await?;
+    }
+    Ok(())
 }
 
-impl MdnsControllerInner {
+async fn serve(socket: &UdpSocket, records: &RwLock<Records>, group: SocketAddr) {
+    let mut buf = vec![0; 9000];
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((len, from)) => {
+                if let Err(e) = respond(socket, records, &buf[..len], from, group).await {
+                    tracing::debug!("Error responding to mDNS query from {}: {}", from, e);
+                }
+            }
+            Err(e) => {
+                tracing::error!("Error receiving mDNS query: {}", e);
+                tracing::debug!("{:?}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// Answers mDNS queries for the `.local` addresses of package interfaces, and advertises them with DNS-SD
+pub struct MdnsController {
+    records: Arc<RwLock<Records>>,
+    sockets: Arc<Sockets>,
+    _thread: NonDetachingJoinHandle<()>,
+}
+impl MdnsController {
     #[instrument(skip_all)]
-    async fn init() -> Result<Self, Error> {
-        let mut res = MdnsControllerInner {
-            alias_cmd: None,
-            services: BTreeMap::new(),
+    pub async fn init() -> Result<Self, Error> {
+        let sockets = Arc::new(Sockets {
+            v4: bind_multicast(Domain::IPV4).with_kind(ErrorKind::Network)?,
+            v6: match bind_multicast(Domain::IPV6) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    tracing::warn!("mDNS is unavailable over IPv6: {}", e);
+                    None
+                }
+            },
+        });
+        let records = Arc::new(RwLock::new(Records::default()));
+        records.write().await.addrs = sockets.refresh().await?;
+        let thread = {
+            let records = records.clone();
+            let sockets = sockets.clone();
+            tokio::spawn(async move {
+                let refresh = async {
+                    loop {
+                        tokio::time::sleep(REFRESH_INTERVAL).await;
+                        match sockets.refresh().await {
+                            Ok(addrs) => {
+                                let mut records = records.write().await;
+                                if records.addrs != addrs {
+                                    records.addrs = addrs;
+                                    if let Err(e) = async {
+                                        sockets
+                                            .multicast(&response(
+                                                records.announcement()?,
+                                                Vec::new(),
+                                            ))
+                                            .await
+                                    }
+                                    .await
+                                    {
+                                        tracing::error!("Error announcing mDNS records: {}", e);
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                tracing::error!("Error refreshing mDNS interfaces: {}", e);
+                                tracing::debug!("{:?}", e);
+                            }
+                        }
+                    }
+                };
+                let v4 = serve(
+                    &sockets.v4,
+                    &records,
+                    SocketAddrV4::new(MDNS_V4, MDNS_PORT).into(),
+                );
+                let v6 = async {
+                    match &sockets.v6 {
+                        Some(v6) => {
+                            serve(
+                                v6,
+                                &records,
+                                SocketAddrV6::new(MDNS_V6, MDNS_PORT, 0, 0).into(),
+                            )
+                            .await
+                        }
+                        None => futures::future::pending().await,
+                    }
+                };
+                tokio::join!(refresh, v4, v6);
+            })
+            .into()
         };
-        res.sync().await?;
-        Ok(res)
+        Ok(Self {
+            records,
+            sockets,
+            _thread: thread,
+        })
     }
-    #[instrument(skip_all)]
-    async fn sync(&mut self) -> Result<(), Error> {
-        if let Some(mut cmd) = self.alias_cmd.take() {
-            cmd.kill().await.with_kind(crate::ErrorKind::Network)?;
-        }
-        self.alias_cmd = Some(
-            Command::new("avahi-alias")
-                .kill_on_drop(true)
-                .args(
-                    self.services
-                        .iter()
-                        .filter(|(_, rc)| rc.strong_count() > 0)
-                        .map(|(s, _)| s),
-                )
-                .spawn()?,
-        );
+
+    pub async fn add(&self, alias: String) -> Result<Arc<()>, Error> {
+        let mut records = self.records.write().await;
+        if let Some(rc) = records.aliases.get(&alias).and_then(Weak::upgrade) {
+            return Ok(rc);
+        }
+        let rc = Arc::new(());
+        records.aliases.insert(alias.clone(), Arc::downgrade(&rc));
+        self.sockets
+            .multicast(&response(records.alias_announcement(&alias)?, Vec::new()))
+            .await?;
+        Ok(rc)
+    }
+
+    pub async fn gc(&self, alias: String) -> Result<(), Error> {
+        let mut records = self.records.write().await;
+        if let Some(rc) = records.aliases.get(&alias) {
+            if rc.strong_count() == 0 {
+                records.aliases.remove(&alias);
+                self.sockets
+                    .multicast(&goodbye(records.alias_announcement(&alias)?))
+                    .await?;
+            }
+        }
         Ok(())
     }
-    async fn add(&mut self, alias: String) -> Result<Arc<()>, Error> {
-        let rc = if let Some(rc) = Weak::upgrade(&self.services.remove(&alias).unwrap_or_default())
-        {
-            rc
-        } else {
-            Arc::new(())
-        };
-        self.services.insert(alias, Arc::downgrade(&rc));
-        self.sync().await?;
+
+    /// Advertises `host:port` as an instance of `_http._tcp`, or `_https._tcp` if `ssl`, for service browsers.
+    /// Instance names must be unique on the network.
+    pub async fn add_service(
+        &self,
+        instance: String,
+        host: String,
+        port: u16,
+        ssl: bool,
+    ) -> Result<Arc<()>, Error> {
+        let key = ServiceKey { host, port, ssl };
+        let mut records = self.records.write().await;
+        if let Some(rc) = records.services.get(&key).and_then(|(_, rc)| rc.upgrade()) {
+            return Ok(rc);
+        }
+        let rc = Arc::new(());
+        records
+            .services
+            .insert(key.clone(), (instance.clone(), Arc::downgrade(&rc)));
+        self.sockets
+            .multicast(&response(
+                records.service_announcement(&key, &instance)?,
+                Vec::new(),
+            ))
+            .await?;
         Ok(rc)
     }
-    async fn gc(&mut self, alias: String) -> Result<(), Error> {
-        if let Some(rc) = Weak::upgrade(&self.services.remove(&alias).unwrap_or_default()) {
-            self.services.insert(alias, Arc::downgrade(&rc));
+
+    pub async fn gc_service(&self, host: String, port: u16, ssl: bool) -> Result<(), Error> {
+        let key = ServiceKey { host, port, ssl };
+        let mut records = self.records.write().await;
+        if let Some((instance, rc)) = records.services.get(&key) {
+            if rc.strong_count() == 0 {
+                let instance = instance.clone();
+                records.services.remove(&key);
+                self.sockets
+                    .multicast(&goodbye(records.service_announcement(&key, &instance)?))
+                    .await?;
+            }
         }
-        self.sync().await?;
         Ok(())
     }
 }
+
+#[test]
+fn answers_service_browsing() {
+    let mut records = Records::default();
+    records.addrs.insert(Ipv4Addr::new(192, 168, 1, 2).into());
+    let alias = Arc::new(());
+    let service = Arc::new(());
+    records
+        .aliases
+        .insert("abcdef.local".into(), Arc::downgrade(&alias));
+    records.services.insert(
+        ServiceKey {
+            host: "abcdef.local".into(),
+            port: 443,
+            ssl: true,
+        },
+        ("bitcoind (main:443)".into(), Arc::downgrade(&service)),
+    );
+    records.services.insert(
+        ServiceKey {
+            host: "abcdef.local".into(),
+            port: 8080,
+            ssl: false,
+        },
+        ("bitcoind (main:8080)".into(), Arc::downgrade(&service)),
+    );
+
+    let (answers, _) = records
+        .answer(&Query::query(
+            name(SERVICE_ENUMERATION).unwrap(),
+            RecordType::PTR,
+        ))
+        .unwrap();
+    assert_eq!(answers.len(), 2);
+
+    let (answers, additionals) = records
+        .answer(&Query::query(name(HTTPS_SERVICE).unwrap(), RecordType::PTR))
+        .unwrap();
+    assert_eq!(answers.len(), 1);
+    assert!(
+        matches!(answers[0].data(), Some(RData::PTR(ptr)) if ptr.0 == instance_name("bitcoind (main:443)", HTTPS_SERVICE).unwrap())
+    );
+    assert!(additionals
+        .iter()
+        .any(|r| matches!(r.data(), Some(RData::SRV(srv)) if srv.port() == 443)));
+    assert!(additionals
+        .iter()
+        .any(|r| matches!(r.data(), Some(RData::A(ip)) if ip.0 == Ipv4Addr::new(192, 168, 1, 2))));
+
+    let (answers, _) = records
+        .answer(&Query::query(
+            name("abcdef.local.").unwrap(),
+            RecordType::AAAA,
+        ))
+        .unwrap();
+    assert!(answers.is_empty());
+
+    let (answers, _) = records
+        .answer(&Query::query(name(HTTP_SERVICE).unwrap(), RecordType::PTR))
+        .unwrap();
+    assert!(
+        matches!(&answers[..], [answer] if matches!(answer.data(), Some(RData::PTR(ptr)) if ptr.0 == instance_name("bitcoind (main:8080)", HTTP_SERVICE).unwrap()))
+    );
+
+    drop(service);
+    let (answers, _) = records
+        .answer(&Query::query(name(HTTPS_SERVICE).unwrap(), RecordType::PTR))
+        .unwrap();
+    assert!(answers.is_empty());
+}
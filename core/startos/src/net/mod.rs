@@ -6,12 +6,16 @@ use rpc_toolkit::command;
 
 use crate::Error;
 
+pub mod acme;
 pub mod dhcp;
 pub mod dns;
+pub mod domain;
+pub mod forward;
 pub mod interface;
 pub mod keys;
 pub mod mdns;
 pub mod net_controller;
+pub mod proxy;
 pub mod ssl;
 pub mod static_server;
 pub mod tor;
@@ -19,10 +23,22 @@ pub mod utils;
 pub mod vhost;
 pub mod web_server;
 pub mod wifi;
+pub mod wireguard;
 
 pub const PACKAGE_CERT_PATH: &str = "/var/lib/embassy/ssl";
 
-#[command(subcommands(tor::tor, dhcp::dhcp, ssl::ssl, keys::rotate_key))]
+#[command(subcommands(
+    tor::tor,
+    dhcp::dhcp,
+    ssl::ssl,
+    acme::acme,
+    domain::domain,
+    proxy::proxy,
+    forward::forward,
+    dns::dns,
+    wireguard::wireguard,
+    keys::rotate_key
+))]
 pub fn net() -> Result<(), Error> {
     Ok(())
 }
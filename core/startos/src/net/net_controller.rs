@@ -1,19 +1,25 @@
 use std::collections::BTreeMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 use std::sync::{Arc, Weak};
 
 use color_eyre::eyre::eyre;
 use models::InterfaceId;
-use sqlx::PgExecutor;
+use sqlx::{PgExecutor, PgPool};
+use tokio::sync::Mutex;
 use tracing::instrument;
 
 use crate::error::ErrorCollection;
 use crate::hostname::Hostname;
+use crate::net::acme::AcmeManager;
 use crate::net::dns::DnsController;
+use crate::net::domain::{Domains, LanTarget};
+use crate::net::forward::{external_port, ForwardController};
+use crate::net::interface::ForwardConfig;
 use crate::net::keys::Key;
 use crate::net::mdns::MdnsController;
+use crate::net::proxy::ProxyController;
 use crate::net::ssl::{export_cert, export_key, SslManager};
-use crate::net::tor::TorController;
+use crate::net::tor::{authorized_clients, TorController};
 use crate::net::vhost::{AlpnInfo, VHostController};
 use crate::s9pk::manifest::PackageId;
 use crate::volume::cert_dir;
@@ -24,7 +30,11 @@ pub struct NetController {
     pub(super) mdns: MdnsController,
     pub(super) vhost: VHostController,
     pub(super) dns: DnsController,
+    pub(super) proxy: Arc<ProxyController>,
+    pub(super) forward: ForwardController,
     pub(super) ssl: Arc<SslManager>,
+    pub(super) acme: Arc<AcmeManager>,
+    pub(super) domains: Mutex<Domains>,
     pub(super) os_bindings: Vec<Arc<()>>,
 }
 
@@ -35,16 +45,25 @@ impl NetController {
         tor_socks: SocketAddr,
         dns_bind: &[SocketAddr],
         ssl: SslManager,
+        acme: AcmeManager,
+        secrets: &PgPool,
         hostname: &Hostname,
         os_key: &Key,
+        lan_ifaces: Vec<String>,
     ) -> Result<Self, Error> {
         let ssl = Arc::new(ssl);
+        let acme = Arc::new(acme);
+        let proxy = Arc::new(ProxyController::load(secrets, hostname).await?);
         let mut res = Self {
             tor: TorController::new(tor_control, tor_socks),
             mdns: MdnsController::init().await?,
-            vhost: VHostController::new(ssl.clone()),
+            vhost: VHostController::new(ssl.clone(), acme.clone(), proxy.clone()),
             dns: DnsController::init(dns_bind).await?,
+            proxy,
+            forward: ForwardController::init(lan_ifaces).await,
             ssl,
+            acme,
+            domains: Mutex::new(Domains::load(secrets).await?),
             os_bindings: Vec::new(),
         };
         res.add_os_bindings(hostname, os_key).await?;
@@ -116,6 +135,8 @@ impl NetController {
                 )
                 .await?,
         );
+        self.os_bindings
+            .push(self.dns.add_local(hostname.local_domain_name()).await);
 
         // Tor (http)
         self.os_bindings.push(
@@ -150,17 +171,23 @@ impl NetController {
         self: &Arc<Self>,
         package: PackageId,
         ip: Ipv4Addr,
+        ipv6: Option<Ipv6Addr>,
     ) -> Result<NetService, Error> {
-        let dns = self.dns.add(Some(package.clone()), ip).await?;
+        let mut dns = vec![self.dns.add(Some(package.clone()), ip.into()).await?];
+        if let Some(ipv6) = ipv6 {
+            dns.push(self.dns.add(Some(package.clone()), ipv6.into()).await?);
+        }
 
         Ok(NetService {
             shutdown: false,
             id: package,
             ip,
+            ipv6,
             dns,
             controller: Arc::downgrade(self),
             tor: BTreeMap::new(),
             lan: BTreeMap::new(),
+            forward: BTreeMap::new(),
         })
     }
 
@@ -170,13 +197,34 @@ impl NetController {
         external: u16,
         target: SocketAddr,
     ) -> Result<Vec<Arc<()>>, Error> {
-        let mut rcs = Vec::with_capacity(1);
+        let mut rcs = Vec::with_capacity(3);
+        // through the proxy, so gated interfaces are gated over Tor too
+        let target = match key.interface() {
+            Some((package, interface)) => {
+                rcs.push(
+                    self.proxy
+                        .add_target(package.clone(), interface.clone(), target)
+                        .await?,
+                );
+                let (addr, rc) = self
+                    .proxy
+                    .add_tor(package, interface, external, target)
+                    .await?;
+                rcs.push(rc);
+                addr
+            }
+            None => target,
+        };
         rcs.push(self.tor.add(key.tor_key(), external, target).await?);
         Ok(rcs)
     }
 
     async fn remove_tor(&self, key: &Key, external: u16, rcs: Vec<Arc<()>>) -> Result<(), Error> {
         drop(rcs);
+        if let Some((package, interface)) = key.interface() {
+            self.proxy.gc_tor().await;
+            self.proxy.gc_targets(&package, &interface).await?;
+        }
         self.tor.gc(Some(key.tor_key()), Some(external)).await
     }
 
@@ -187,7 +235,7 @@ impl NetController {
         target: SocketAddr,
         connect_ssl: Result<(), AlpnInfo>,
     ) -> Result<Vec<Arc<()>>, Error> {
-        let mut rcs = Vec::with_capacity(2);
+        let mut rcs = Vec::with_capacity(5);
         rcs.push(
             self.vhost
                 .add(
@@ -195,29 +243,153 @@ impl NetController {
                     Some(key.local_address()),
                     external,
                     target.into(),
-                    connect_ssl,
+                    connect_ssl.clone(),
                 )
                 .await?,
         );
-        rcs.push(self.mdns.add(key.base_address()).await?);
+        rcs.push(self.mdns.add(key.local_address()).await?);
+        rcs.push(self.dns.add_local(key.local_address()).await);
+        if let Some((package, interface)) = key.interface() {
+            rcs.push(
+                self.proxy
+                    .add_target(package.clone(), interface.clone(), target)
+                    .await?,
+            );
+            // the vhost serves every LAN port over TLS
+            rcs.push(
+                self.mdns
+                    .add_service(
+                        format!("{} ({}:{})", package, interface, external),
+                        key.local_address(),
+                        external,
+                        true,
+                    )
+                    .await?,
+            );
+            let mut domains = self.domains.lock().await;
+            let mut lan = LanTarget {
+                key: key.clone(),
+                target,
+                connect_ssl,
+                rcs: BTreeMap::new(),
+            };
+            for hostname in domains.hostnames(&package, &interface, external) {
+                lan.rcs.insert(
+                    hostname.clone(),
+                    self.vhost
+                        .add(
+                            key.clone(),
+                            Some(hostname),
+                            external,
+                            target,
+                            lan.connect_ssl.clone(),
+                        )
+                        .await?,
+                );
+            }
+            domains.active.insert((package, interface, external), lan);
+        }
         Ok(rcs)
     }
 
     async fn remove_lan(&self, key: &Key, external: u16, rcs: Vec<Arc<()>>) -> Result<(), Error> {
         drop(rcs);
-        self.mdns.gc(key.base_address()).await?;
+        if let Some((package, interface)) = key.interface() {
+            let lan = self
+                .domains
+                .lock()
+                .await
+                .active
+                .remove(&(package, interface, external));
+            for (hostname, rc) in lan.into_iter().flat_map(|lan| lan.rcs) {
+                drop(rc);
+                self.vhost.gc(Some(hostname), external).await?;
+            }
+            self.proxy.gc_targets(&package, &interface).await?;
+        }
+        self.mdns.gc(key.local_address()).await?;
+        self.dns.gc_local(&key.local_address()).await;
+        self.mdns
+            .gc_service(key.local_address(), external, true)
+            .await?;
         self.vhost.gc(Some(key.local_address()), external).await
     }
+
+    /// Serves a custom domain on a LAN port of a package interface, immediately if the package is running
+    pub async fn bind_domain(
+        &self,
+        hostname: String,
+        port: u16,
+        package: PackageId,
+        interface: InterfaceId,
+    ) -> Result<(), Error> {
+        let mut domains = self.domains.lock().await;
+        domains.bind(hostname.clone(), port, package.clone(), interface.clone())?;
+        if let Some(lan) = domains.active.get_mut(&(package, interface, port)) {
+            match self
+                .vhost
+                .add(
+                    lan.key.clone(),
+                    Some(hostname.clone()),
+                    port,
+                    lan.target,
+                    lan.connect_ssl.clone(),
+                )
+                .await
+            {
+                Ok(rc) => {
+                    lan.rcs.insert(hostname, rc);
+                }
+                Err(e) => {
+                    domains.bindings.remove(&(hostname, port));
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn unbind_domain(&self, hostname: String, port: u16) -> Result<(), Error> {
+        let mut domains = self.domains.lock().await;
+        if let Some((package, interface)) = domains.bindings.remove(&(hostname.clone(), port)) {
+            if let Some(lan) = domains.active.get_mut(&(package, interface, port)) {
+                if lan.rcs.remove(&hostname).is_some() {
+                    self.vhost.gc(Some(hostname), port).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn clearnet_addresses(&self) -> Vec<String> {
+        self.domains.lock().await.clearnet_addresses()
+    }
+
+    /// Forgets the custom domains of an uninstalled package
+    pub async fn remove_domains(&self, package: &PackageId) {
+        self.domains
+            .lock()
+            .await
+            .bindings
+            .retain(|_, (pkg, _)| pkg != package);
+    }
+
+    /// Forgets the proxy settings of an uninstalled package
+    pub async fn remove_proxy_settings(&self, package: &PackageId) {
+        self.proxy.remove_package(package).await
+    }
 }
 
 pub struct NetService {
     shutdown: bool,
     id: PackageId,
     ip: Ipv4Addr,
-    dns: Arc<()>,
+    ipv6: Option<Ipv6Addr>,
+    dns: Vec<Arc<()>>,
     controller: Weak<NetController>,
     tor: BTreeMap<(InterfaceId, u16), (Key, Vec<Arc<()>>)>,
     lan: BTreeMap<(InterfaceId, u16), (Key, Vec<Arc<()>>)>,
+    forward: BTreeMap<(InterfaceId, u16), Arc<()>>,
 }
 impl NetService {
     fn net_controller(&self) -> Result<Arc<NetController>, Error> {
@@ -239,7 +411,9 @@ impl NetService {
         for<'a> &'a mut Ex: PgExecutor<'a>,
     {
         let key = Key::for_interface(secrets, Some((self.id.clone(), id.clone()))).await?;
+        let clients = authorized_clients(&mut *secrets, &self.id, &id).await?;
         let ctrl = self.net_controller()?;
+        ctrl.tor.set_client_auth(key.tor_key(), clients).await?;
         let tor_idx = (id, external);
         let mut tor = self
             .tor
@@ -298,6 +472,32 @@ impl NetService {
         }
         Ok(())
     }
+    pub async fn add_forward<Ex>(
+        &mut self,
+        secrets: &mut Ex,
+        id: InterfaceId,
+        port: u16,
+        config: &ForwardConfig,
+    ) -> Result<(), Error>
+    where
+        for<'a> &'a mut Ex: PgExecutor<'a>,
+    {
+        let external = external_port(&mut *secrets, &self.id, &id, port).await?;
+        let ctrl = self.net_controller()?;
+        let rc = ctrl
+            .forward
+            .add(
+                self.id.clone(),
+                id.clone(),
+                port,
+                config.protocols.clone(),
+                SocketAddrV4::new(self.ip, config.internal),
+                external,
+            )
+            .await?;
+        self.forward.insert((id, port), rc);
+        Ok(())
+    }
     pub async fn export_cert<Ex>(
         &self,
         secrets: &mut Ex,
@@ -309,7 +509,8 @@ impl NetService {
     {
         let key = Key::for_interface(secrets, Some((self.id.clone(), id.clone()))).await?;
         let ctrl = self.net_controller()?;
-        let cert = ctrl.ssl.with_certs(key, ip).await?;
+        let domains = ctrl.domains.lock().await.all_hostnames(&self.id, id);
+        let cert = ctrl.ssl.with_certs(key, ip, &domains).await?;
         let cert_dir = cert_dir(&self.id, id);
         tokio::fs::create_dir_all(&cert_dir).await?;
         export_key(
@@ -334,8 +535,15 @@ impl NetService {
             for ((_, external), (key, rcs)) in std::mem::take(&mut self.tor) {
                 errors.handle(ctrl.remove_tor(&key, external, rcs).await);
             }
+            for ((id, port), rc) in std::mem::take(&mut self.forward) {
+                drop(rc);
+                errors.handle(ctrl.forward.gc(&self.id, &id, port).await);
+            }
             std::mem::take(&mut self.dns);
-            errors.handle(ctrl.dns.gc(Some(self.id.clone()), self.ip).await);
+            errors.handle(ctrl.dns.gc(Some(self.id.clone()), self.ip.into()).await);
+            if let Some(ipv6) = self.ipv6 {
+                errors.handle(ctrl.dns.gc(Some(self.id.clone()), ipv6.into()).await);
+            }
             errors.into_result()
         } else {
             tracing::warn!("NetService dropped after NetController is shutdown");
@@ -357,10 +565,12 @@ impl Drop for NetService {
                     shutdown: true,
                     id: Default::default(),
                     ip: Ipv4Addr::new(0, 0, 0, 0),
+                    ipv6: None,
                     dns: Default::default(),
                     controller: Default::default(),
                     tor: Default::default(),
                     lan: Default::default(),
+                    forward: Default::default(),
                 },
             );
             tokio::spawn(async move { svc.remove_all().await.unwrap() });
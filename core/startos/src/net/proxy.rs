@@ -0,0 +1,1438 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::Infallible;
+use std::fmt::Write as _;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use clap::ArgMatches;
+use helpers::NonDetachingJoinHandle;
+use http::header::{
+    HeaderName, AUTHORIZATION, CACHE_CONTROL, CONTENT_SECURITY_POLICY, CONTENT_TYPE, COOKIE, HOST,
+    LOCATION, ORIGIN, RETRY_AFTER, SET_COOKIE, UPGRADE, WWW_AUTHENTICATE, X_FRAME_OPTIONS,
+};
+use http::request::Parts as RequestParts;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use hyper::client::conn::SendRequest;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::upgrade::OnUpgrade;
+use hyper::Body;
+use models::{InterfaceId, PackageId};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::Command;
+use tokio::sync::{Mutex, RwLock};
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::hostname::Hostname;
+use crate::middleware::auth::{HasValidSession, HashSessionToken};
+use crate::net::wireguard::WIREGUARD_IFACE;
+use crate::prelude::*;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Invoke};
+
+/// Clients whose rate limit bucket has refilled are forgotten once this many are tracked
+const MAX_TRACKED_CLIENTS: usize = 4096;
+/// Path on a session gated host where the StartOS UI hands a login back to the proxy
+const HANDOFF_PATH: &str = "/.startos/handoff";
+/// Cookie standing in for the StartOS session on a gated host, which never reaches the package
+const PROXY_SESSION_COOKIE: &str = "startos-proxy-session";
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(300);
+const MAX_PENDING_LOGINS: usize = 4096;
+/// Proxy cookies must be renewed through the StartOS UI once this old, even if the StartOS session is still valid
+const SESSION_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_SESSIONS: usize = 4096;
+const NFT_TABLE: &str = "startos-proxy";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(tag = "type")]
+pub enum ProxyAuth {
+    None,
+    Basic {
+        username: String,
+        #[serde(skip_serializing, default)]
+        password_hash: String,
+    },
+    /// Requires the session cookie of a user logged in to StartOS
+    Session,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RateLimit {
+    pub requests: u32,
+    /// Seconds
+    pub period: u32,
+}
+
+/// HTTP handling applied in front of a package interface on the LAN, custom domains and Tor, instead of passing its
+/// streams through untouched
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProxySettings {
+    pub forwarded_headers: bool,
+    pub auth: ProxyAuth,
+    pub rate_limit: Option<RateLimit>,
+}
+impl ProxySettings {
+    /// Whether the settings restrict access, so the interface must not be reachable around the proxy
+    fn gates(&self) -> bool {
+        !matches!(self.auth, ProxyAuth::None) || self.rate_limit.is_some()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct RateLimiter {
+    limit: RateLimit,
+    clients: std::sync::Mutex<BTreeMap<IpAddr, Bucket>>,
+}
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            clients: std::sync::Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Takes a request from the token bucket of `client`, or returns how long until one is available
+    fn throttle(&self, client: IpAddr) -> Option<Duration> {
+        let capacity = self.limit.requests as f64;
+        let rate = capacity / self.limit.period.max(1) as f64;
+        let now = Instant::now();
+        let refill = |bucket: &Bucket| {
+            (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity)
+        };
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_TRACKED_CLIENTS {
+            clients.retain(|_, bucket| refill(bucket) < capacity);
+        }
+        let bucket = clients.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+fn random_token() -> String {
+    base32::encode(
+        base32::Alphabet::RFC4648 { padding: false },
+        &rand::random::<[u8; 16]>(),
+    )
+    .to_lowercase()
+}
+
+struct Login {
+    host: String,
+    path: String,
+    /// Hash of the StartOS session, once the StartOS UI has confirmed it
+    session: Option<String>,
+    started: Instant,
+}
+
+/// A proxy cookie: the host it was issued for and the hash of its StartOS session
+struct ProxySession {
+    host: String,
+    session: String,
+    issued: Instant,
+}
+
+/// Session gating can not see the StartOS session cookie, since it is scoped to the StartOS host.
+/// Instead, a gated host sends the browser to the StartOS UI, which checks the session and sends
+/// it back with a one-time code that the proxy exchanges for a cookie of its own, once the user has confirmed the
+/// login there.
+#[derive(Default)]
+struct Logins {
+    /// Logins started on a gated host, by state
+    pending: BTreeMap<String, Login>,
+    /// Logins confirmed by the StartOS UI, by code
+    confirmed: BTreeMap<String, Login>,
+    /// Proxy cookies, by token
+    sessions: BTreeMap<String, ProxySession>,
+}
+impl Logins {
+    fn prune(logins: &mut BTreeMap<String, Login>) {
+        logins.retain(|_, login| login.started.elapsed() < LOGIN_TIMEOUT);
+        while logins.len() >= MAX_PENDING_LOGINS {
+            logins.pop_first();
+        }
+    }
+
+    fn start(&mut self, host: String, path: String) -> String {
+        Self::prune(&mut self.pending);
+        let state = random_token();
+        self.pending.insert(
+            state.clone(),
+            Login {
+                host,
+                path,
+                session: None,
+                started: Instant::now(),
+            },
+        );
+        state
+    }
+
+    /// Returns the host a pending login was started on
+    fn pending(&self, state: &str) -> Option<&str> {
+        self.pending
+            .get(state)
+            .filter(|l| l.started.elapsed() < LOGIN_TIMEOUT)
+            .map(|l| l.host.as_str())
+    }
+
+    /// Returns the host to send the browser back to, and the code to exchange there
+    fn confirm(&mut self, state: &str, session: &str) -> Option<(String, String)> {
+        let mut login = self
+            .pending
+            .remove(state)
+            .filter(|l| l.started.elapsed() < LOGIN_TIMEOUT)?;
+        login.session = Some(session.to_owned());
+        let host = login.host.clone();
+        Self::prune(&mut self.confirmed);
+        let code = random_token();
+        self.confirmed.insert(code.clone(), login);
+        Some((host, code))
+    }
+
+    /// Returns the proxy cookie token, and the path the login was started from
+    fn finish(&mut self, code: &str, host: &str) -> Option<(String, String)> {
+        let login = self
+            .confirmed
+            .remove(code)
+            .filter(|l| l.started.elapsed() < LOGIN_TIMEOUT && l.host == host)?;
+        let session = login.session?;
+        self.sessions
+            .retain(|_, session| session.issued.elapsed() < SESSION_TIMEOUT);
+        while self.sessions.len() >= MAX_SESSIONS {
+            self.sessions.pop_first();
+        }
+        let token = random_token();
+        self.sessions.insert(
+            token.clone(),
+            ProxySession {
+                host: login.host,
+                session,
+                issued: Instant::now(),
+            },
+        );
+        Some((token, login.path))
+    }
+
+    /// Returns the hash of the StartOS session of a proxy cookie, forgetting it once expired
+    fn session(&mut self, token: &str, host: &str) -> Option<String> {
+        let session = self.sessions.get(token)?;
+        if session.issued.elapsed() >= SESSION_TIMEOUT {
+            self.sessions.remove(token);
+            return None;
+        }
+        Some(session)
+            .filter(|s| s.host == host)
+            .map(|s| s.session.clone())
+    }
+}
+
+struct SessionGate {
+    /// StartOS UI host, where logins are confirmed
+    hostname: String,
+    secrets: PgPool,
+    logins: std::sync::Mutex<Logins>,
+}
+
+pub struct HttpProxy {
+    settings: ProxySettings,
+    gate: Arc<SessionGate>,
+    limiter: Option<RateLimiter>,
+}
+impl HttpProxy {
+    fn new(settings: ProxySettings, gate: Arc<SessionGate>) -> Self {
+        Self {
+            limiter: settings.rate_limit.clone().map(RateLimiter::new),
+            settings,
+            gate,
+        }
+    }
+
+    /// Serves HTTP/1.1 on `client`, forwarding the requests that pass the configured checks to `target`.
+    /// `client_addr` is `None` for connections through Tor, which hides it.
+    /// `name` is the TLS server name the connection was routed by, if the interface is bound to it.
+    pub async fn serve<C, T>(
+        self: Arc<Self>,
+        client_addr: Option<IpAddr>,
+        name: Option<String>,
+        client: C,
+        target: T,
+    ) -> std::io::Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + Send,
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (sender, conn) = hyper::client::conn::handshake(target)
+            .await
+            .map_err(io_error)?;
+        let _conn: NonDetachingJoinHandle<()> = tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                tracing::trace!("HttpProxy: target connection failed: {e}");
+                tracing::trace!("{e:?}");
+            }
+        })
+        .into();
+        let conn = Arc::new(ProxyConnection {
+            proxy: self,
+            client: client_addr,
+            name,
+            target: Mutex::new(sender),
+            verified: std::sync::Mutex::new(None),
+            upgrade: std::sync::Mutex::new(None),
+        });
+        let parts = Http::new()
+            .http1_only(true)
+            .serve_connection(
+                client,
+                service_fn({
+                    let conn = conn.clone();
+                    move |req| {
+                        let conn = conn.clone();
+                        async move { Ok::<_, Infallible>(conn.handle(req).await) }
+                    }
+                }),
+            )
+            .without_shutdown()
+            .await
+            .map_err(io_error)?;
+        let upgrade = conn.upgrade.lock().unwrap().take();
+        if let Some(upgrade) = upgrade {
+            let mut upgraded = upgrade.await.map_err(io_error)?;
+            let mut client = parts.io;
+            upgraded.write_all(&parts.read_buf).await?;
+            tokio::io::copy_bidirectional(&mut client, &mut upgraded).await?;
+        }
+        Ok(())
+    }
+}
+
+fn io_error(e: hyper::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e)
+}
+
+fn response(status: StatusCode, message: &str) -> Response<Body> {
+    let mut res = Response::new(Body::from(message.to_owned()));
+    *res.status_mut() = status;
+    res
+}
+
+fn redirect(status: StatusCode, location: &str) -> Response<Body> {
+    let mut res = response(status, "");
+    if let Ok(location) = HeaderValue::from_str(location) {
+        res.headers_mut().insert(LOCATION, location);
+    }
+    res
+}
+
+/// Removes the cookie `name` from the request, returning its value
+fn take_cookie(headers: &mut HeaderMap, name: &str) -> Option<String> {
+    let mut value = None;
+    let mut rest = Vec::new();
+    for header in headers.get_all(COOKIE) {
+        let Ok(cookies) = header.to_str() else {
+            rest.push(header.clone());
+            continue;
+        };
+        let mut kept = Vec::new();
+        for cookie in cookies.split(';').map(str::trim).filter(|c| !c.is_empty()) {
+            match cookie.split_once('=') {
+                Some((n, v)) if n == name => value = Some(v.to_owned()),
+                _ => kept.push(cookie),
+            }
+        }
+        if !kept.is_empty() {
+            rest.extend(HeaderValue::from_str(&kept.join("; ")).ok());
+        }
+    }
+    headers.remove(COOKIE);
+    for header in rest {
+        headers.append(COOKIE, header);
+    }
+    value
+}
+
+struct ProxyConnection {
+    proxy: Arc<HttpProxy>,
+    client: Option<IpAddr>,
+    name: Option<String>,
+    target: Mutex<SendRequest<Body>>,
+    /// Basic credentials already checked on this connection, since the password hash is slow to verify
+    verified: std::sync::Mutex<Option<HeaderValue>>,
+    /// The target side of a connection upgrade, completed once the client side is released by hyper
+    upgrade: std::sync::Mutex<Option<OnUpgrade>>,
+}
+impl ProxyConnection {
+    async fn handle(&self, mut req: Request<Body>) -> Response<Body> {
+        if let Some(retry) = self
+            .proxy
+            .limiter
+            .as_ref()
+            // Tor clients all share one bucket
+            .and_then(|l| l.throttle(self.client.unwrap_or(Ipv4Addr::LOCALHOST.into())))
+        {
+            let mut res = response(StatusCode::TOO_MANY_REQUESTS, "Too Many Requests");
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry.as_secs() + 1));
+            return res;
+        }
+        match &self.proxy.settings.auth {
+            ProxyAuth::None => (),
+            ProxyAuth::Basic {
+                username,
+                password_hash,
+            } => {
+                // the credentials are for the proxy, not the package
+                let authorization = req.headers_mut().remove(AUTHORIZATION);
+                if !self
+                    .check_basic(authorization, username, password_hash)
+                    .await
+                {
+                    let mut res = response(StatusCode::UNAUTHORIZED, "Unauthorized");
+                    res.headers_mut().insert(
+                        WWW_AUTHENTICATE,
+                        HeaderValue::from_static("Basic realm=\"StartOS\", charset=\"UTF-8\""),
+                    );
+                    return res;
+                }
+            }
+            // logins are handed off by the StartOS UI, which Tor clients reach at another host
+            ProxyAuth::Session if self.client.is_none() => {
+                return response(
+                    StatusCode::FORBIDDEN,
+                    "This service is only available on the LAN",
+                );
+            }
+            ProxyAuth::Session => {
+                // logins are handed back to the host, so it must be one the interface is bound to
+                let Some(host) = req
+                    .headers()
+                    .get(HOST)
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(|h| self.bound_host(h))
+                else {
+                    return response(StatusCode::MISDIRECTED_REQUEST, "Misdirected Request");
+                };
+                if req.uri().path() == HANDOFF_PATH {
+                    return self.finish_login(&req, &host);
+                }
+                if !self.check_session(&mut req, &host).await {
+                    return self.start_login(&req, host);
+                }
+            }
+        }
+        if self.proxy.settings.forwarded_headers {
+            let host = req.headers().get(HOST).cloned();
+            let headers = req.headers_mut();
+            // replaced rather than appended to, since this proxy is the edge
+            let forwarded_for = HeaderName::from_static("x-forwarded-for");
+            match self.client {
+                Some(client) => {
+                    headers.insert(
+                        forwarded_for,
+                        HeaderValue::from_str(&client.to_string()).unwrap(),
+                    );
+                }
+                None => {
+                    headers.remove(forwarded_for);
+                }
+            }
+            headers.insert(
+                HeaderName::from_static("x-forwarded-proto"),
+                HeaderValue::from_static(if self.client.is_some() {
+                    "https"
+                } else {
+                    "http"
+                }),
+            );
+            if let Some(host) = host {
+                headers.insert(HeaderName::from_static("x-forwarded-host"), host);
+            }
+        }
+        let upgrade = req.headers().contains_key(UPGRADE);
+        let res = {
+            let mut target = self.target.lock().await;
+            if let Err(e) = futures::future::poll_fn(|cx| target.poll_ready(cx)).await {
+                tracing::debug!("HttpProxy: target unavailable: {e}");
+                return response(StatusCode::BAD_GATEWAY, "Bad Gateway");
+            }
+            target.send_request(req)
+        };
+        match res.await {
+            Ok(mut res) => {
+                if upgrade && res.status() == StatusCode::SWITCHING_PROTOCOLS {
+                    *self.upgrade.lock().unwrap() = Some(hyper::upgrade::on(&mut res));
+                }
+                res
+            }
+            Err(e) => {
+                tracing::debug!("HttpProxy: request to target failed: {e}");
+                response(StatusCode::BAD_GATEWAY, "Bad Gateway")
+            }
+        }
+    }
+
+    /// Returns `host` if it names the server name of the connection, with at most a port
+    fn bound_host(&self, host: &str) -> Option<String> {
+        let name = self.name.as_deref()?;
+        let (host_name, port) = match host.split_once(':') {
+            Some((host_name, port)) => (host_name, Some(port)),
+            None => (host, None),
+        };
+        if !host_name.eq_ignore_ascii_case(name)
+            || port.map_or(false, |p| p.parse::<u16>().is_err())
+        {
+            return None;
+        }
+        Some(host.to_owned())
+    }
+
+    async fn check_basic(
+        &self,
+        authorization: Option<HeaderValue>,
+        username: &str,
+        password_hash: &str,
+    ) -> bool {
+        let Some(authorization) = authorization else {
+            return false;
+        };
+        if self.verified.lock().unwrap().as_ref() == Some(&authorization) {
+            return true;
+        }
+        let credentials = authorization
+            .to_str()
+            .ok()
+            .and_then(|a| a.strip_prefix("Basic "))
+            .and_then(|c| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(c.trim())
+                    .ok()
+            })
+            .and_then(|c| String::from_utf8(c).ok());
+        let Some((user, password)) = credentials.as_deref().and_then(|c| c.split_once(':')) else {
+            return false;
+        };
+        if user != username {
+            return false;
+        }
+        let hash = password_hash.to_owned();
+        let password = password.to_owned();
+        let valid = tokio::task::spawn_blocking(move || {
+            argon2::verify_encoded(&hash, password.as_bytes()).unwrap_or(false)
+        })
+        .await
+        .unwrap_or(false);
+        if valid {
+            *self.verified.lock().unwrap() = Some(authorization);
+        }
+        valid
+    }
+
+    async fn check_session(&self, req: &mut Request<Body>, host: &str) -> bool {
+        // the StartOS session is for the proxy, not the package
+        let Some(token) = take_cookie(req.headers_mut(), PROXY_SESSION_COOKIE) else {
+            return false;
+        };
+        let gate = &self.proxy.gate;
+        let Some(session) = gate.logins.lock().unwrap().session(&token, host) else {
+            return false;
+        };
+        if HasValidSession::from_session_hash(&session, &gate.secrets)
+            .await
+            .is_ok()
+        {
+            true
+        } else {
+            gate.logins.lock().unwrap().sessions.remove(&token);
+            false
+        }
+    }
+
+    /// Sends the browser to the StartOS UI to confirm its session
+    fn start_login(&self, req: &Request<Body>, host: String) -> Response<Body> {
+        if req.method() != Method::GET || req.headers().contains_key(UPGRADE) {
+            return response(
+                StatusCode::UNAUTHORIZED,
+                "Log in to StartOS to access this service",
+            );
+        }
+        // a path starting with "//" would send the browser to another host after login
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .filter(|p| p.starts_with('/') && !p.starts_with("//"))
+            .unwrap_or("/")
+            .to_owned();
+        let gate = &self.proxy.gate;
+        let state = gate.logins.lock().unwrap().start(host, path);
+        redirect(
+            StatusCode::FOUND,
+            &format!("https://{}/proxy-login/{}", gate.hostname, state),
+        )
+    }
+
+    /// Exchanges a code from the StartOS UI for a proxy session cookie
+    fn finish_login(&self, req: &Request<Body>, host: &str) -> Response<Body> {
+        let code = req
+            .uri()
+            .query()
+            .and_then(|q| q.split('&').find_map(|p| p.strip_prefix("code=")));
+        let Some((token, path)) =
+            code.and_then(|code| self.proxy.gate.logins.lock().unwrap().finish(code, host))
+        else {
+            return response(StatusCode::UNAUTHORIZED, "Login expired, try again");
+        };
+        let mut res = redirect(StatusCode::SEE_OTHER, &path);
+        res.headers_mut().insert(
+            SET_COOKIE,
+            HeaderValue::from_str(&format!(
+                "{PROXY_SESSION_COOKIE}={token}; Path=/; Secure; HttpOnly; SameSite=Lax"
+            ))
+            .unwrap(),
+        );
+        res
+    }
+}
+
+/// Listens for Tor to connect to in place of the target of an interface
+struct TorListener {
+    addr: SocketAddr,
+    rc: Weak<()>,
+    _thread: NonDetachingJoinHandle<()>,
+}
+
+/// The proxy settings of every package interface, looked up by the vhost proxy for each connection
+pub struct ProxyController {
+    gate: Arc<SessionGate>,
+    proxies: RwLock<BTreeMap<(PackageId, InterfaceId), Arc<HttpProxy>>>,
+    /// Container addresses of each interface, which tunnel peers could otherwise reach around the proxy
+    targets: Mutex<BTreeMap<(PackageId, InterfaceId), BTreeMap<SocketAddr, Weak<()>>>>,
+    tor: Mutex<BTreeMap<(PackageId, InterfaceId, u16), TorListener>>,
+}
+impl ProxyController {
+    #[instrument(skip_all)]
+    pub async fn load(secrets: &PgPool, hostname: &Hostname) -> Result<Self, Error> {
+        let gate = Arc::new(SessionGate {
+            hostname: hostname.local_domain_name(),
+            secrets: secrets.clone(),
+            logins: std::sync::Mutex::new(Logins::default()),
+        });
+        let mut proxies = BTreeMap::new();
+        for (package, interface, settings) in list_settings(secrets).await? {
+            proxies.insert(
+                (package, interface),
+                Arc::new(HttpProxy::new(settings, gate.clone())),
+            );
+        }
+        let res = Self {
+            gate,
+            proxies: RwLock::new(proxies),
+            targets: Mutex::new(BTreeMap::new()),
+            tor: Mutex::new(BTreeMap::new()),
+        };
+        // clears the rules left over by a previous run
+        if let Err(e) = res.sync_tunnel().await {
+            tracing::error!("Error clearing tunnel rules: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        Ok(res)
+    }
+
+    /// Asks the user to confirm a login started on a session gated host, for a request to the StartOS UI carrying
+    /// the session cookie
+    pub async fn login_page(
+        &self,
+        state: &str,
+        req: &RequestParts,
+    ) -> Result<Response<Body>, Error> {
+        let session = HashSessionToken::from_request_parts(req)?;
+        HasValidSession::from_secret_store(&session, &self.gate.secrets).await?;
+        let Some(host) = self
+            .gate
+            .logins
+            .lock()
+            .unwrap()
+            .pending(state)
+            .map(|h| h.to_owned())
+        else {
+            return Ok(response(StatusCode::NOT_FOUND, "Login expired, try again"));
+        };
+        // the host was checked against the server name of the connection, so it needs no escaping
+        let mut res = response(
+            StatusCode::OK,
+            &format!(
+                "<!DOCTYPE html>\n\
+                 <html><head><meta charset=\"utf-8\"><title>StartOS</title></head><body>\n\
+                 <form method=\"post\"><p>Log in to {host} with your StartOS session?</p>\n\
+                 <button type=\"submit\">Log In</button></form>\n\
+                 </body></html>\n"
+            ),
+        );
+        let headers = res.headers_mut();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        // the confirmation must not be clickjacked
+        headers.insert(X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+        headers.insert(
+            CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static(
+                "default-src 'none'; frame-ancestors 'none'; form-action 'self'",
+            ),
+        );
+        Ok(res)
+    }
+
+    /// Confirms a login started on a session gated host, for a form submitted from [`Self::login_page`]
+    pub async fn login(&self, state: &str, req: &RequestParts) -> Result<Response<Body>, Error> {
+        let origin = format!("https://{}", self.gate.hostname);
+        if req.headers.get(ORIGIN).and_then(|o| o.to_str().ok()) != Some(origin.as_str()) {
+            return Err(Error::new(
+                eyre!("Login confirmations must come from {}", origin),
+                ErrorKind::Authorization,
+            ));
+        }
+        let session = HashSessionToken::from_request_parts(req)?;
+        HasValidSession::from_secret_store(&session, &self.gate.secrets).await?;
+        let confirmed = self
+            .gate
+            .logins
+            .lock()
+            .unwrap()
+            .confirm(state, session.hashed());
+        Ok(match confirmed {
+            Some((host, code)) => redirect(
+                StatusCode::SEE_OTHER,
+                &format!("https://{host}{HANDOFF_PATH}?code={code}"),
+            ),
+            None => response(StatusCode::NOT_FOUND, "Login expired, try again"),
+        })
+    }
+
+    pub async fn get(
+        &self,
+        package: &PackageId,
+        interface: &InterfaceId,
+    ) -> Option<Arc<HttpProxy>> {
+        self.proxies
+            .read()
+            .await
+            .get(&(package.clone(), interface.clone()))
+            .cloned()
+    }
+
+    /// Applies to new connections; open ones keep the settings they were accepted with
+    pub async fn set(
+        &self,
+        package: PackageId,
+        interface: InterfaceId,
+        settings: Option<ProxySettings>,
+    ) -> Result<(), Error> {
+        {
+            let mut proxies = self.proxies.write().await;
+            if let Some(settings) = settings {
+                proxies.insert(
+                    (package, interface),
+                    Arc::new(HttpProxy::new(settings, self.gate.clone())),
+                );
+            } else {
+                proxies.remove(&(package, interface));
+            }
+        }
+        self.sync_tunnel().await
+    }
+
+    async fn is_gated(&self, package: &PackageId, interface: &InterfaceId) -> bool {
+        self.get(package, interface)
+            .await
+            .map_or(false, |proxy| proxy.settings.gates())
+    }
+
+    /// Tracks a container address of an interface, so it is closed to tunnel peers while the interface is gated
+    pub async fn add_target(
+        &self,
+        package: PackageId,
+        interface: InterfaceId,
+        target: SocketAddr,
+    ) -> Result<Arc<()>, Error> {
+        let gated = self.is_gated(&package, &interface).await;
+        let (rc, new) = {
+            let mut targets = self.targets.lock().await;
+            let addrs = targets.entry((package, interface)).or_default();
+            match addrs.get(&target).and_then(Weak::upgrade) {
+                Some(rc) => (rc, false),
+                None => {
+                    let rc = Arc::new(());
+                    addrs.insert(target, Arc::downgrade(&rc));
+                    (rc, true)
+                }
+            }
+        };
+        if gated && new {
+            self.sync_tunnel().await?;
+        }
+        Ok(rc)
+    }
+
+    pub async fn gc_targets(
+        &self,
+        package: &PackageId,
+        interface: &InterfaceId,
+    ) -> Result<(), Error> {
+        let key = (package.clone(), interface.clone());
+        let removed = {
+            let mut targets = self.targets.lock().await;
+            let Some(addrs) = targets.get_mut(&key) else {
+                return Ok(());
+            };
+            let count = addrs.len();
+            addrs.retain(|_, rc| rc.strong_count() > 0);
+            let removed = addrs.len() != count;
+            if addrs.is_empty() {
+                targets.remove(&key);
+            }
+            removed
+        };
+        if removed && self.is_gated(package, interface).await {
+            self.sync_tunnel().await?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the nftables table that rejects tunnel peers connecting straight to gated containers, atomically.
+    /// They still reach gated interfaces through the proxy, at their `.local` names.
+    async fn sync_tunnel(&self) -> Result<(), Error> {
+        let gated: BTreeSet<_> = self
+            .proxies
+            .read()
+            .await
+            .iter()
+            .filter(|(_, proxy)| proxy.settings.gates())
+            .map(|(key, _)| key.clone())
+            .collect();
+        // held while nft runs, so concurrent syncs apply in order
+        let targets = self.targets.lock().await;
+        let mut rules = String::new();
+        for (_, addrs) in targets.iter().filter(|(key, _)| gated.contains(*key)) {
+            for (addr, _) in addrs.iter().filter(|(_, rc)| rc.strong_count() > 0) {
+                // peers are only routed IPv4
+                let SocketAddr::V4(addr) = addr else {
+                    continue;
+                };
+                writeln!(
+                    rules,
+                    "        iifname \"{}\" ip daddr {} tcp dport {} reject with tcp reset",
+                    WIREGUARD_IFACE,
+                    addr.ip(),
+                    addr.port()
+                )
+                .unwrap();
+            }
+        }
+        let script = format!(
+            "table ip {NFT_TABLE}\n\
+             delete table ip {NFT_TABLE}\n\
+             table ip {NFT_TABLE} {{\n    \
+             chain forward {{\n        \
+             type filter hook forward priority filter; policy accept;\n\
+             {rules}    }}\n\
+             }}\n"
+        );
+        Command::new("nft")
+            .arg("-f")
+            .arg("-")
+            .input(Some(&mut std::io::Cursor::new(script.into_bytes())))
+            .invoke(ErrorKind::Network)
+            .await?;
+        Ok(())
+    }
+
+    /// Listens on loopback for Tor to connect to in place of `target`, so the settings of the interface apply over
+    /// Tor too. Tor ports of a gated interface must serve plain HTTP.
+    pub async fn add_tor(
+        self: &Arc<Self>,
+        package: PackageId,
+        interface: InterfaceId,
+        external: u16,
+        target: SocketAddr,
+    ) -> Result<(SocketAddr, Arc<()>), Error> {
+        let mut listeners = self.tor.lock().await;
+        listeners.retain(|_, l| l.rc.strong_count() > 0);
+        let key = (package.clone(), interface.clone(), external);
+        if let Some(listener) = listeners.get(&key) {
+            if let Some(rc) = listener.rc.upgrade() {
+                return Ok((listener.addr, rc));
+            }
+        }
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .with_kind(ErrorKind::Network)?;
+        let addr = listener.local_addr().with_kind(ErrorKind::Network)?;
+        let controller = Arc::downgrade(self);
+        let thread = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((mut stream, _)) => {
+                        let Some(controller) = controller.upgrade() else {
+                            break;
+                        };
+                        // looked up per connection, like the vhost does
+                        let http_proxy = controller.get(&package, &interface).await;
+                        tokio::spawn(async move {
+                            if let Err(e) = async {
+                                let mut target_stream = TcpStream::connect(target).await?;
+                                if let Some(http_proxy) = http_proxy {
+                                    http_proxy.serve(None, None, stream, target_stream).await
+                                } else {
+                                    tokio::io::copy_bidirectional(&mut stream, &mut target_stream)
+                                        .await
+                                        .map(|_| ())
+                                }
+                            }
+                            .await
+                            {
+                                tracing::trace!(
+                                    "ProxyController: Tor connection to {target} failed: {e}"
+                                );
+                                tracing::trace!("{e:?}");
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        tracing::trace!(
+                            "ProxyController: failed to accept Tor connection for {target}: {e}"
+                        );
+                        tracing::trace!("{e:?}");
+                    }
+                }
+            }
+        })
+        .into();
+        let rc = Arc::new(());
+        listeners.insert(
+            key,
+            TorListener {
+                addr,
+                rc: Arc::downgrade(&rc),
+                _thread: thread,
+            },
+        );
+        Ok((addr, rc))
+    }
+
+    pub async fn gc_tor(&self) {
+        self.tor
+            .lock()
+            .await
+            .retain(|_, listener| listener.rc.strong_count() > 0);
+    }
+
+    pub async fn remove_package(&self, package: &PackageId) {
+        self.proxies
+            .write()
+            .await
+            .retain(|(pkg, _), _| pkg != package);
+    }
+}
+
+async fn list_settings(
+    secrets: &PgPool,
+) -> Result<Vec<(PackageId, InterfaceId, ProxySettings)>, Error> {
+    sqlx::query!("SELECT package, interface, forwarded_headers, auth_username, auth_password_hash, require_session, rate_limit, rate_limit_period FROM proxy_settings")
+        .fetch_all(secrets)
+        .await?
+        .into_iter()
+        .map(|r| {
+            Ok((
+                r.package.parse()?,
+                r.interface.parse()?,
+                ProxySettings {
+                    forwarded_headers: r.forwarded_headers,
+                    auth: match (r.auth_username, r.auth_password_hash) {
+                        (Some(username), Some(password_hash)) => ProxyAuth::Basic {
+                            username,
+                            password_hash,
+                        },
+                        _ if r.require_session => ProxyAuth::Session,
+                        _ => ProxyAuth::None,
+                    },
+                    rate_limit: r.rate_limit.map(|requests| RateLimit {
+                        requests: requests as u32,
+                        period: r.rate_limit_period.unwrap_or(60) as u32,
+                    }),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// HTTP proxying for the LAN, custom domain and Tor bindings of package interfaces
+#[command(subcommands(set, clear, list))]
+pub fn proxy() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn set(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg(rename = "forwarded-headers", long = "forwarded-headers")] forwarded_headers: bool,
+    #[arg(long = "username")] username: Option<String>,
+    #[arg(long = "password")] password: Option<String>,
+    #[arg(rename = "require-session", long = "require-session")] require_session: bool,
+    #[arg(rename = "rate-limit", long = "rate-limit")] rate_limit: Option<u32>,
+    #[arg(rename = "rate-limit-period", long = "rate-limit-period")] rate_limit_period: Option<u32>,
+) -> Result<(), Error> {
+    let manifest = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(&package)
+        .and_then(|pde| pde.as_installed())
+        .map(|i| i.as_manifest().de())
+        .transpose()?
+        .or_not_found(&package)?;
+    if manifest
+        .interfaces
+        .0
+        .get(&interface)
+        .or_not_found(&interface)?
+        .lan_config
+        .is_none()
+    {
+        return Err(Error::new(
+            eyre!("{}/{} is not served on the LAN", package, interface),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+
+    let auth = match (username, password) {
+        (Some(_), _) if require_session => {
+            return Err(Error::new(
+                eyre!("Basic auth and session gating are mutually exclusive"),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        (Some(username), Some(password)) => {
+            if username.contains(':') {
+                return Err(Error::new(
+                    eyre!("Username may not contain ':'"),
+                    ErrorKind::InvalidRequest,
+                ));
+            }
+            ProxyAuth::Basic {
+                username,
+                password_hash: argon2::hash_encoded(
+                    password.as_bytes(),
+                    &rand::random::<[u8; 16]>()[..],
+                    &argon2::Config::rfc9106_low_mem(),
+                )
+                .with_kind(ErrorKind::PasswordHashGeneration)?,
+            }
+        }
+        (None, None) if require_session => ProxyAuth::Session,
+        (None, None) => ProxyAuth::None,
+        _ => {
+            return Err(Error::new(
+                eyre!("Basic auth requires both a username and a password"),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+    };
+    let rate_limit = match (rate_limit, rate_limit_period) {
+        (Some(0), _) | (_, Some(0)) => {
+            return Err(Error::new(
+                eyre!("Rate limits must be positive"),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        (Some(requests), period) => Some(RateLimit {
+            requests,
+            period: period.unwrap_or(60),
+        }),
+        (None, Some(_)) => {
+            return Err(Error::new(
+                eyre!("--rate-limit-period requires --rate-limit"),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        (None, None) => None,
+    };
+    let settings = ProxySettings {
+        forwarded_headers,
+        auth,
+        rate_limit,
+    };
+    let column = |n: u32| {
+        i32::try_from(n).map_err(|_| {
+            Error::new(
+                eyre!("Rate limits must be at most {}", i32::MAX),
+                ErrorKind::InvalidRequest,
+            )
+        })
+    };
+    let (limit_requests, limit_period) = match &settings.rate_limit {
+        Some(r) => (Some(column(r.requests)?), Some(column(r.period)?)),
+        None => (None, None),
+    };
+
+    let (auth_username, auth_password_hash) = match &settings.auth {
+        ProxyAuth::Basic {
+            username,
+            password_hash,
+        } => (Some(username.as_str()), Some(password_hash.as_str())),
+        _ => (None, None),
+    };
+    sqlx::query!(
+        "INSERT INTO proxy_settings (package, interface, forwarded_headers, auth_username, auth_password_hash, require_session, rate_limit, rate_limit_period) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (package, interface) DO UPDATE SET forwarded_headers = $3, auth_username = $4, auth_password_hash = $5, require_session = $6, rate_limit = $7, rate_limit_period = $8",
+        &*package,
+        &*interface,
+        settings.forwarded_headers,
+        auth_username,
+        auth_password_hash,
+        matches!(settings.auth, ProxyAuth::Session),
+        limit_requests,
+        limit_period,
+    )
+    .execute(&ctx.secret_store)
+    .await?;
+    ctx.net_controller
+        .proxy
+        .set(package, interface, Some(settings))
+        .await
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn clear(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "DELETE FROM proxy_settings WHERE package = $1 AND interface = $2",
+        &*package,
+        &*interface
+    )
+    .execute(&ctx.secret_store)
+    .await?;
+    ctx.net_controller.proxy.set(package, interface, None).await
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProxyInfo {
+    pub package: PackageId,
+    pub interface: InterfaceId,
+    #[serde(flatten)]
+    pub settings: ProxySettings,
+}
+
+fn display_proxies(all: Vec<ProxyInfo>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(all, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "PACKAGE", "INTERFACE", "FORWARDED HEADERS", "AUTH", "RATE LIMIT"]);
+    for info in all {
+        table.add_row(row![
+            &*info.package,
+            &*info.interface,
+            info.settings.forwarded_headers,
+            &match info.settings.auth {
+                ProxyAuth::None => "none".to_owned(),
+                ProxyAuth::Basic { username, .. } => format!("basic ({})", username),
+                ProxyAuth::Session => "session".to_owned(),
+            },
+            &info
+                .settings
+                .rate_limit
+                .map_or("none".to_owned(), |r| format!(
+                    "{} / {}s",
+                    r.requests, r.period
+                ))
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_proxies))]
+#[instrument(skip_all)]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<ProxyInfo>, Error> {
+    Ok(list_settings(&ctx.secret_store)
+        .await?
+        .into_iter()
+        .map(|(package, interface, settings)| ProxyInfo {
+            package,
+            interface,
+            settings,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_limit_per_client() {
+        let limiter = RateLimiter::new(RateLimit {
+            requests: 2,
+            period: 60,
+        });
+        let a = IpAddr::from([192, 168, 1, 2]);
+        let b = IpAddr::from([192, 168, 1, 3]);
+        assert_eq!(limiter.throttle(a), None);
+        assert_eq!(limiter.throttle(a), None);
+        let retry = limiter.throttle(a).unwrap();
+        assert!(retry > Duration::from_secs(29) && retry <= Duration::from_secs(30));
+        assert_eq!(limiter.throttle(b), None);
+    }
+
+    #[test]
+    fn take_proxy_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            COOKIE,
+            HeaderValue::from_static("theme=dark; startos-proxy-session=abc"),
+        );
+        headers.append(COOKIE, HeaderValue::from_static("lang=en"));
+        assert_eq!(
+            take_cookie(&mut headers, PROXY_SESSION_COOKIE).as_deref(),
+            Some("abc")
+        );
+        let rest: Vec<_> = headers.get_all(COOKIE).iter().collect();
+        assert_eq!(rest, ["theme=dark", "lang=en"]);
+        assert_eq!(take_cookie(&mut headers, PROXY_SESSION_COOKIE), None);
+    }
+
+    #[test]
+    fn proxy_sessions_expire() {
+        let mut logins = Logins::default();
+        let state = logins.start("test-package.local".into(), "/".into());
+        let (_, code) = logins.confirm(&state, "hash").unwrap();
+        let (token, _) = logins.finish(&code, "test-package.local").unwrap();
+        assert_eq!(
+            logins.session(&token, "test-package.local").as_deref(),
+            Some("hash")
+        );
+        assert_eq!(logins.session(&token, "other.local"), None);
+        // the monotonic clock starts at boot
+        let Some(issued) = Instant::now().checked_sub(SESSION_TIMEOUT) else {
+            return;
+        };
+        logins.sessions.get_mut(&token).unwrap().issued = issued;
+        assert_eq!(logins.session(&token, "test-package.local"), None);
+        assert!(logins.sessions.is_empty());
+    }
+
+    /// Requires the StartOS secrets database:
+    /// `cargo test -- --ignored session_login_through_proxy`
+    #[tokio::test]
+    #[ignore]
+    async fn session_login_through_proxy() {
+        let secrets = PgPool::connect_with(
+            sqlx::postgres::PgConnectOptions::new()
+                .database("secrets")
+                .username("root"),
+        )
+        .await
+        .unwrap();
+        sqlx::migrate!().run(&secrets).await.unwrap();
+        let session = HashSessionToken::new();
+        sqlx::query!(
+            "INSERT INTO session (id, user_agent, metadata) VALUES ($1, $2, $3)",
+            session.hashed(),
+            None::<&str>,
+            "{}",
+        )
+        .execute(&secrets)
+        .await
+        .unwrap();
+
+        // echoes the cookies the package receives
+        let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let _target: NonDetachingJoinHandle<()> = tokio::spawn(async move {
+            while let Ok((stream, _)) = target.accept().await {
+                tokio::spawn(Http::new().serve_connection(
+                    stream,
+                    service_fn(|req: Request<Body>| async move {
+                        let cookies = req
+                            .headers()
+                            .get(COOKIE)
+                            .map_or(String::new(), |c| c.to_str().unwrap().to_owned());
+                        Ok::<_, Infallible>(Response::new(Body::from(cookies)))
+                    }),
+                ));
+            }
+        })
+        .into();
+
+        let controller = ProxyController::load(&secrets, &Hostname("test-host".into()))
+            .await
+            .unwrap();
+        let package: PackageId = "test-package".parse().unwrap();
+        let interface: InterfaceId = "main".parse().unwrap();
+        controller
+            .set(
+                package.clone(),
+                interface.clone(),
+                Some(ProxySettings {
+                    forwarded_headers: false,
+                    auth: ProxyAuth::Session,
+                    rate_limit: None,
+                }),
+            )
+            .await
+            .unwrap();
+        let proxy = controller.get(&package, &interface).await.unwrap();
+        let send_to = |host: &str, path: &str, cookie: Option<&str>| {
+            let mut req = Request::get(path).header(HOST, host);
+            if let Some(cookie) = cookie {
+                req = req.header(COOKIE, cookie);
+            }
+            let req = req.body(Body::empty()).unwrap();
+            let proxy = proxy.clone();
+            async move {
+                let (client, server) = tokio::io::duplex(4096);
+                let target = tokio::net::TcpStream::connect(target_addr).await.unwrap();
+                let _serve: NonDetachingJoinHandle<_> = tokio::spawn(proxy.serve(
+                    Some(IpAddr::from([127, 0, 0, 1])),
+                    Some("test-package.local".to_owned()),
+                    server,
+                    target,
+                ))
+                .into();
+                let (mut sender, conn) = hyper::client::conn::handshake(client).await.unwrap();
+                let _conn: NonDetachingJoinHandle<_> = tokio::spawn(conn).into();
+                let res = sender.send_request(req).await.unwrap();
+                let (parts, body) = res.into_parts();
+                let body = hyper::body::to_bytes(body).await.unwrap();
+                (parts, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+        let send = |path: &str, cookie: Option<&str>| send_to("test-package.local", path, cookie);
+
+        // logins are only handed back to the name the connection was made to
+        for host in ["evil.example", "test-package.local:@evil.example"] {
+            assert_eq!(
+                send_to(host, "/page", None).await.0.status,
+                StatusCode::MISDIRECTED_REQUEST
+            );
+        }
+
+        // the StartOS session cookie never reaches a gated host, so it starts a login
+        let (res, _) = send("/page?a=b", None).await;
+        assert_eq!(res.status, StatusCode::FOUND);
+        let location = res.headers[LOCATION].to_str().unwrap().to_owned();
+        let state = location
+            .strip_prefix("https://test-host.local/proxy-login/")
+            .unwrap();
+
+        // the StartOS UI asks the user to confirm it, with the cookie set by `auth.login`
+        let (anonymous, _) = Request::get(&location).body(()).unwrap().into_parts();
+        assert!(controller.login_page(state, &anonymous).await.is_err());
+        let login_cookie = session.header_value().unwrap();
+        let login_cookie = login_cookie.to_str().unwrap().split(';').next().unwrap();
+        let (ui, _) = Request::get(&location)
+            .header(COOKIE, login_cookie)
+            .body(())
+            .unwrap()
+            .into_parts();
+        let page = controller.login_page(state, &ui).await.unwrap();
+        assert_eq!(page.status(), StatusCode::OK);
+        // opening the page is not enough, and neither is a form submitted from another site
+        assert!(controller.login(state, &ui).await.is_err());
+        let (cross_site, _) = Request::post(&location)
+            .header(COOKIE, login_cookie)
+            .header(ORIGIN, "https://evil.example")
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert!(controller.login(state, &cross_site).await.is_err());
+        let (ui, _) = Request::post(&location)
+            .header(COOKIE, login_cookie)
+            .header(ORIGIN, "https://test-host.local")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let confirmed = controller.login(state, &ui).await.unwrap();
+        assert_eq!(confirmed.status(), StatusCode::SEE_OTHER);
+        let handoff = confirmed.headers()[LOCATION]
+            .to_str()
+            .unwrap()
+            .strip_prefix("https://test-package.local")
+            .unwrap()
+            .to_owned();
+
+        let (res, _) = send(&handoff, None).await;
+        assert_eq!(res.status, StatusCode::SEE_OTHER);
+        assert_eq!(res.headers[LOCATION], "/page?a=b");
+        let proxy_cookie = res.headers[SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+        // codes are single use
+        assert_eq!(
+            send(&handoff, None).await.0.status,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let (res, body) = send("/page?a=b", Some(&format!("{proxy_cookie}; theme=dark"))).await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(body, "theme=dark");
+
+        // logging out of StartOS ends access through the proxy
+        sqlx::query!(
+            "UPDATE session SET logged_out = CURRENT_TIMESTAMP WHERE id = $1",
+            session.hashed()
+        )
+        .execute(&secrets)
+        .await
+        .unwrap();
+        assert_eq!(
+            send("/page", Some(&proxy_cookie)).await.0.status,
+            StatusCode::FOUND
+        );
+    }
+}
@@ -47,11 +47,20 @@ impl CertPair {
         signer: (&PKey<Private>, &X509),
         applicant: &Key,
         ip: BTreeSet<IpAddr>,
+        domains: &BTreeSet<String>,
     ) -> Result<(Self, bool), Error> {
         let mut updated = false;
         let mut updated_cert = |cert: Option<&X509>, osk: PKey<Private>| -> Result<X509, Error> {
             let mut ips = BTreeSet::new();
+            let mut dns = BTreeSet::new();
             if let Some(cert) = cert {
+                dns.extend(
+                    cert.subject_alt_names()
+                        .iter()
+                        .flatten()
+                        .filter_map(|a| a.dnsname())
+                        .map(|a| a.to_owned()),
+                );
                 ips.extend(
                     cert.subject_alt_names()
                         .iter()
@@ -72,13 +81,29 @@ impl CertPair {
                         .compare(Asn1Time::days_from_now(30)?.as_ref())?
                         == Ordering::Greater
                     && ips.is_superset(&ip)
+                    && domains.iter().all(|domain| {
+                        dns.iter().any(|name| match name.strip_prefix("*.") {
+                            Some(parent) => domain.split_once('.').map(|(_, p)| p) == Some(parent),
+                            None => name == domain,
+                        })
+                    })
                 {
                     return Ok(cert.clone());
                 }
             }
             ips.extend(ip.iter().copied());
             updated = true;
-            make_leaf_cert(signer, (&osk, &SANInfo::new(&applicant, hostname, ips)))
+            let mut san = SANInfo::new(&applicant, hostname, ips);
+            // custom domains served by an earlier certificate are kept, like its IPs
+            let extra = dns
+                .iter()
+                .chain(domains)
+                .filter(|domain| !domain.starts_with("*.") && !san.covers(domain))
+                .cloned()
+                .collect::<Vec<_>>();
+            san.dns
+                .extend(extra.into_iter().map(MaybeWildcard::WithoutWildcard));
+            make_leaf_cert(signer, (&osk, &san))
         };
         Ok((
             Self {
@@ -125,7 +150,13 @@ impl SslManager {
             cert_cache: RwLock::new(BTreeMap::new()),
         })
     }
-    pub async fn with_certs(&self, key: Key, ip: IpAddr) -> Result<KeyInfo, Error> {
+    /// Certificates for `key`, which also cover `domains`: the custom domains it is served on
+    pub async fn with_certs(
+        &self,
+        key: Key,
+        ip: IpAddr,
+        domains: &BTreeSet<String>,
+    ) -> Result<KeyInfo, Error> {
         let mut ips = ips().await?;
         ips.insert(ip);
         let (pair, updated) = CertPair::updated(
@@ -134,6 +165,7 @@ impl SslManager {
             (&self.int_key, &self.int_cert),
             &key,
             ips,
+            domains,
         )?;
         if updated {
             self.cert_cache
@@ -348,6 +380,17 @@ impl SANInfo {
         dns.insert(MaybeWildcard::WithWildcard(key.tor_address().to_string()));
         Self { dns, ips }
     }
+
+    /// Whether a certificate with these names is valid for `domain`
+    pub fn covers(&self, domain: &str) -> bool {
+        self.dns.iter().any(|dns| match dns {
+            MaybeWildcard::WithWildcard(name) => {
+                domain == name
+                    || domain.split_once('.').map(|(_, parent)| parent) == Some(name.as_str())
+            }
+            MaybeWildcard::WithoutWildcard(name) => domain == name,
+        })
+    }
 }
 impl std::fmt::Display for SANInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -29,6 +29,7 @@ use crate::middleware::auth::{auth as auth_middleware, HasValidSession};
 use crate::middleware::cors::cors;
 use crate::middleware::db::db as db_middleware;
 use crate::middleware::diagnostic::diagnostic as diagnostic_middleware;
+use crate::net::acme::HTTP_CHALLENGE_PATH;
 use crate::net::HttpHandler;
 use crate::{diagnostic_api, install_api, main_api, setup_api, Error, ErrorKind, ResultExt};
 
@@ -222,6 +223,22 @@ pub async fn main_ui_server_router(ctx: RpcContext) -> Result<HttpHandler, Error
                         },
                     }
                 }
+                path if path.starts_with(HTTP_CHALLENGE_PATH) => {
+                    match ctx
+                        .net_controller
+                        .acme
+                        .challenges()
+                        .http_response(path.strip_prefix(HTTP_CHALLENGE_PATH).unwrap())
+                        .await
+                    {
+                        Some(key_auth) => Ok(Response::builder()
+                            .status(StatusCode::OK)
+                            .header(http::header::CONTENT_TYPE, "application/octet-stream")
+                            .body(key_auth.into())
+                            .with_kind(ErrorKind::Network)?),
+                        None => Ok(not_found()),
+                    }
+                }
                 _ => main_embassy_ui(req, ctx).await,
             };
 
@@ -339,6 +356,23 @@ async fn main_embassy_ui(req: Request<Body>, ctx: RpcContext) -> Result<Response
             })
             .await
         }
+        (&Method::GET, Some(("proxy-login", state))) => {
+            match ctx
+                .net_controller
+                .proxy
+                .login_page(state, &request_parts)
+                .await
+            {
+                Ok(res) => Ok(res),
+                Err(e) => un_authorized(e, request_parts.uri.path()),
+            }
+        }
+        (&Method::POST, Some(("proxy-login", state))) => {
+            match ctx.net_controller.proxy.login(state, &request_parts).await {
+                Ok(res) => Ok(res),
+                Err(e) => un_authorized(e, request_parts.uri.path()),
+            }
+        }
         (&Method::GET, Some(("eos", "local.crt"))) => {
             let account = ctx.account.read().await;
             cert_send(&account.root_ca_cert, &account.hostname)
@@ -1,9 +1,11 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::net::SocketAddr;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Weak};
 use std::time::Duration;
 
+use base64::Engine;
+use chrono::{DateTime, Utc};
 use clap::ArgMatches;
 use color_eyre::eyre::eyre;
 use futures::future::BoxFuture;
@@ -11,28 +13,56 @@ use futures::{FutureExt, TryStreamExt};
 use helpers::NonDetachingJoinHandle;
 use itertools::Itertools;
 use lazy_static::lazy_static;
+use models::{InterfaceId, OptionExt, PackageId};
+use openssl::pkey::PKey;
 use regex::Regex;
 use rpc_toolkit::command;
 use rpc_toolkit::yajrc::RpcError;
+use serde::{Deserialize, Serialize};
+use sqlx::PgExecutor;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 use tokio::process::Command;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, RwLock};
 use tokio::time::Instant;
 use torut::control::{AsyncEvent, AuthenticatedConn, ConnError};
 use torut::onion::{OnionAddressV3, TorSecretKeyV3};
 use tracing::instrument;
 
 use crate::context::{CliContext, RpcContext};
+use crate::db::prelude::PatchDbExt;
 use crate::logs::{
     cli_logs_generic_follow, cli_logs_generic_nofollow, fetch_logs, follow_logs, journalctl,
     LogFollowResponse, LogResponse, LogSource,
 };
+use crate::net::keys::Key;
+use crate::notifications::NotificationLevel;
 use crate::util::serde::{display_serializable, IoFormat};
 use crate::util::{display_none, Invoke};
 use crate::{Error, ErrorKind, ResultExt as _};
 
 pub const SYSTEMD_UNIT: &str = "tor@default";
 const STARTING_HEALTH_TIMEOUT: u64 = 120; // 2min
+const SUPERVISOR_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+/// 24h of checks
+const HEALTH_HISTORY_LEN: usize = 288;
+const RESET_HISTORY_LEN: usize = 32;
+/// Consecutive failed checks before tor is restarted
+const RESTART_AFTER: usize = 2;
+/// Consecutive failed checks of a bootstrapped tor before its state is wiped
+const WIPE_STATE_AFTER: usize = 4;
+const CONNECTIVITY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Some of the Tor directory authorities, which tor must reach to bootstrap
+const DIRECTORY_AUTHORITIES: &[([u8; 4], u16)] = &[
+    ([128, 31, 0, 39], 9131),  // moria1
+    ([45, 66, 35, 11], 80),    // dizum
+    ([131, 188, 40, 189], 80), // gabelmoo
+    ([193, 23, 244, 244], 80), // dannenberg
+    ([171, 25, 193, 9], 443),  // maatuska
+    ([199, 58, 81, 140], 80),  // longclaw
+];
 
 enum ErrorLogSeverity {
     Fatal { wipe_state: bool },
@@ -53,7 +83,7 @@ lazy_static! {
     static ref PROGRESS_REGEX: Regex = Regex::new("PROGRESS=([0-9]+)").unwrap();
 }
 
-#[command(subcommands(list_services, logs, reset))]
+#[command(subcommands(list_services, logs, reset, status, client_auth))]
 pub fn tor() -> Result<(), Error> {
     Ok(())
 }
@@ -95,6 +125,263 @@ pub async fn list_services(
     ctx.net_controller.tor.list_services().await
 }
 
+fn display_status(status: TorStatus, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(status, matches);
+    }
+
+    if let Some(uptime) = status.uptime {
+        println!(
+            "Uptime: {:.1}% of {} checks",
+            uptime * 100.0,
+            status.history.len()
+        );
+    }
+    if let Some(reset) = status.resets.last() {
+        println!("Last Reset: {} ({})", reset.at, reset.reason);
+    }
+    let Some(latest) = status.latest else {
+        println!("Not checked yet");
+        return;
+    };
+    println!(
+        "Checked At: {}\nBootstrapped: {}",
+        latest.checked_at, latest.bootstrapped
+    );
+    let mut table = Table::new();
+    table.add_row(row![bc => "ADDRESS", "PORT", "DESCRIPTOR", "REACHABLE"]);
+    for service in latest.services {
+        table.add_row(row![
+            &service.address.to_string(),
+            service.port,
+            service.descriptor,
+            service
+                .reachable
+                .map_or("unknown".to_owned(), |r| r.to_string())
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+/// The results of the Tor supervisor, which checks the published onion services every 5 minutes
+#[command(display(display_status))]
+pub async fn status(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<TorStatus, Error> {
+    Ok(ctx.net_controller.tor.status().await)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClientAuth {
+    pub name: String,
+    /// base32 x25519 public key, as passed to `ClientAuthV3`
+    pub public_key: String,
+    pub created_at: String,
+}
+
+/// A newly authorized client. The private key is not stored, so this is the only time it is available
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClientAuthKey {
+    pub name: String,
+    pub onion_address: String,
+    /// base32 x25519 private key, as entered in Tor Browser
+    pub private_key: String,
+    /// The line for a `.auth_private` file in the `ClientOnionAuthDir` of tor
+    pub auth_private: String,
+}
+
+/// Onion client authorization for package interfaces: once a client is authorized, only authorized
+/// clients can find the onion service of the interface
+#[command(
+    rename = "client-auth",
+    subcommands(add_client, remove_client, list_clients)
+)]
+pub fn client_auth() -> Result<(), Error> {
+    Ok(())
+}
+
+pub(super) async fn authorized_clients(
+    secrets: impl PgExecutor<'_>,
+    package: &PackageId,
+    interface: &InterfaceId,
+) -> Result<BTreeSet<String>, Error> {
+    Ok(sqlx::query!(
+        "SELECT public_key FROM tor_client_auth WHERE package = $1 AND interface = $2",
+        &**package,
+        &**interface
+    )
+    .fetch_all(secrets)
+    .await?
+    .into_iter()
+    .map(|row| row.public_key)
+    .collect())
+}
+
+/// Republishes the onion service of an interface with its current clients
+async fn update_client_auth(
+    ctx: &RpcContext,
+    package: &PackageId,
+    interface: &InterfaceId,
+) -> Result<(), Error> {
+    let mut secrets = ctx.secret_store.acquire().await?;
+    let key = Key::for_interface(&mut *secrets, Some((package.clone(), interface.clone()))).await?;
+    let clients = authorized_clients(&mut *secrets, package, interface).await?;
+    ctx.net_controller
+        .tor
+        .set_client_auth(key.tor_key(), clients)
+        .await
+}
+
+fn display_client_auth_key(key: ClientAuthKey, matches: &ArgMatches) {
+    if matches.is_present("format") {
+        return display_serializable(key, matches);
+    }
+    println!("{}", key.auth_private);
+}
+
+#[command(rename = "add", display(display_client_auth_key))]
+#[instrument(skip_all)]
+pub async fn add_client(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] name: String,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<ClientAuthKey, Error> {
+    let manifest = ctx
+        .db
+        .peek()
+        .await
+        .as_package_data()
+        .as_idx(&package)
+        .and_then(|pde| pde.as_installed())
+        .map(|i| i.as_manifest().de())
+        .transpose()?
+        .or_not_found(&package)?;
+    if manifest
+        .interfaces
+        .0
+        .get(&interface)
+        .or_not_found(&interface)?
+        .tor_config
+        .is_none()
+    {
+        return Err(Error::new(
+            eyre!("{}/{} is not served over Tor", package, interface),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+
+    let client_key = PKey::generate_x25519()?;
+    let alphabet = base32::Alphabet::RFC4648 { padding: false };
+    let public_key = base32::encode(alphabet, &client_key.raw_public_key()?);
+    let private_key = base32::encode(alphabet, &client_key.raw_private_key()?);
+    let n = sqlx::query!(
+        "INSERT INTO tor_client_auth (package, interface, name, public_key, created_at) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (package, interface, name) DO NOTHING",
+        &*package,
+        &*interface,
+        name,
+        public_key,
+        Utc::now().to_rfc3339(),
+    )
+    .execute(&ctx.secret_store)
+    .await?
+    .rows_affected();
+    if n == 0 {
+        return Err(Error::new(
+            eyre!("Client {} is already authorized", name),
+            ErrorKind::Duplicate,
+        ));
+    }
+    update_client_auth(&ctx, &package, &interface).await?;
+
+    let onion_address = Key::for_interface(
+        &mut *ctx.secret_store.acquire().await?,
+        Some((package, interface)),
+    )
+    .await?
+    .base_address();
+    Ok(ClientAuthKey {
+        auth_private: format!("{}:descriptor:x25519:{}", onion_address, private_key),
+        name,
+        onion_address: format!("{}.onion", onion_address),
+        private_key,
+    })
+}
+
+#[command(rename = "remove", display(display_none))]
+#[instrument(skip_all)]
+pub async fn remove_client(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] name: String,
+) -> Result<(), Error> {
+    let n = sqlx::query!(
+        "DELETE FROM tor_client_auth WHERE package = $1 AND interface = $2 AND name = $3",
+        &*package,
+        &*interface,
+        name,
+    )
+    .execute(&ctx.secret_store)
+    .await?
+    .rows_affected();
+    if n == 0 {
+        return Err(Error::new(eyre!("Client Not Found"), ErrorKind::NotFound));
+    }
+    update_client_auth(&ctx, &package, &interface).await
+}
+
+fn display_clients(clients: Vec<ClientAuth>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(clients, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "NAME", "PUBLIC KEY", "CREATED AT"]);
+    for client in clients {
+        table.add_row(row![&client.name, &client.public_key, &client.created_at]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(rename = "list", display(display_clients))]
+#[instrument(skip_all)]
+pub async fn list_clients(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<ClientAuth>, Error> {
+    Ok(sqlx::query!(
+        "SELECT name, public_key, created_at FROM tor_client_auth WHERE package = $1 AND interface = $2",
+        &*package,
+        &*interface,
+    )
+    .fetch_all(&ctx.secret_store)
+    .await?
+    .into_iter()
+    .map(|row| ClientAuth {
+        name: row.name,
+        public_key: row.public_key,
+        created_at: row.created_at,
+    })
+    .collect())
+}
+
 #[command(
     custom_cli(cli_logs(async, context(CliContext))),
     subcommands(self(logs_nofollow(async)), logs_follow),
@@ -149,10 +436,16 @@ fn event_handler(_event: AsyncEvent<'static>) -> BoxFuture<'static, Result<(), C
     async move { Ok(()) }.boxed()
 }
 
-pub struct TorController(TorControl);
+pub struct TorController {
+    control: TorControl,
+    health: RwLock<HealthHistory>,
+}
 impl TorController {
     pub fn new(tor_control: SocketAddr, tor_socks: SocketAddr) -> Self {
-        TorController(TorControl::new(tor_control, tor_socks))
+        TorController {
+            control: TorControl::new(tor_control, tor_socks),
+            health: RwLock::new(HealthHistory::default()),
+        }
     }
 
     pub async fn add(
@@ -162,7 +455,7 @@ impl TorController {
         target: SocketAddr,
     ) -> Result<Arc<()>, Error> {
         let (reply, res) = oneshot::channel();
-        self.0
+        self.control
             .send
             .send(TorCommand::AddOnion {
                 key,
@@ -182,15 +475,28 @@ impl TorController {
         key: Option<TorSecretKeyV3>,
         external: Option<u16>,
     ) -> Result<(), Error> {
-        self.0
+        self.control
             .send
             .send(TorCommand::GC { key, external })
             .ok()
             .ok_or_else(|| Error::new(eyre!("TorControl died"), ErrorKind::Tor))
     }
 
+    /// Restricts the onion service of `key` to `clients` (base32 x25519 public keys), or makes it public if empty
+    pub async fn set_client_auth(
+        &self,
+        key: TorSecretKeyV3,
+        clients: BTreeSet<String>,
+    ) -> Result<(), Error> {
+        self.control
+            .send
+            .send(TorCommand::SetClientAuth { key, clients })
+            .ok()
+            .ok_or_else(|| Error::new(eyre!("TorControl died"), ErrorKind::Tor))
+    }
+
     pub async fn reset(&self, wipe_state: bool, context: Error) -> Result<(), Error> {
-        self.0
+        self.control
             .send
             .send(TorCommand::Reset {
                 wipe_state,
@@ -200,19 +506,36 @@ impl TorController {
             .ok_or_else(|| Error::new(eyre!("TorControl died"), ErrorKind::Tor))
     }
 
-    pub async fn list_services(&self) -> Result<Vec<OnionAddressV3>, Error> {
+    async fn get_info(&self, query: &str) -> Result<String, Error> {
         let (reply, res) = oneshot::channel();
-        self.0
+        self.control
             .send
             .send(TorCommand::GetInfo {
-                query: "onions/current".into(),
+                query: query.into(),
                 reply,
             })
             .ok()
             .ok_or_else(|| Error::new(eyre!("TorControl died"), ErrorKind::Tor))?;
         res.await
             .ok()
-            .ok_or_else(|| Error::new(eyre!("TorControl died"), ErrorKind::Tor))??
+            .ok_or_else(|| Error::new(eyre!("TorControl died"), ErrorKind::Tor))?
+    }
+
+    async fn list_bindings(&self) -> Result<Vec<OnionBinding>, Error> {
+        let (reply, res) = oneshot::channel();
+        self.control
+            .send
+            .send(TorCommand::ListBindings { reply })
+            .ok()
+            .ok_or_else(|| Error::new(eyre!("TorControl died"), ErrorKind::Tor))?;
+        res.await
+            .ok()
+            .ok_or_else(|| Error::new(eyre!("TorControl died"), ErrorKind::Tor))
+    }
+
+    pub async fn list_services(&self) -> Result<Vec<OnionAddressV3>, Error> {
+        self.get_info("onions/current")
+            .await?
             .lines()
             .map(|l| l.trim())
             .filter(|l| !l.is_empty())
@@ -221,6 +544,261 @@ impl TorController {
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OnionHealth {
+    pub address: OnionAddressV3,
+    pub port: u16,
+    /// Whether tor has built a descriptor for the service
+    pub descriptor: bool,
+    /// Whether the service could be reached through the SOCKS port. Unknown if it requires client authorization
+    pub reachable: Option<bool>,
+}
+impl OnionHealth {
+    fn healthy(&self) -> bool {
+        self.descriptor && self.reachable != Some(false)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TorHealth {
+    pub checked_at: DateTime<Utc>,
+    pub bootstrapped: bool,
+    pub services: Vec<OnionHealth>,
+}
+impl TorHealth {
+    pub fn healthy(&self) -> bool {
+        self.bootstrapped && self.services.iter().all(|s| s.healthy())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthSample {
+    pub checked_at: DateTime<Utc>,
+    pub healthy: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TorReset {
+    pub at: DateTime<Utc>,
+    pub wipe_state: bool,
+    pub reason: String,
+}
+
+#[derive(Default)]
+struct HealthHistory {
+    latest: Option<TorHealth>,
+    samples: VecDeque<HealthSample>,
+    resets: VecDeque<TorReset>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TorStatus {
+    pub latest: Option<TorHealth>,
+    /// Fraction of the checks in `history` that were healthy
+    pub uptime: Option<f64>,
+    pub history: Vec<HealthSample>,
+    pub resets: Vec<TorReset>,
+}
+
+impl TorController {
+    async fn probe(&self, tor_socks: SocketAddr, binding: OnionBinding) -> OnionHealth {
+        let port = binding.ports[0];
+        let descriptor = self
+            .get_info(&format!(
+                "hs/service/desc/id/{}",
+                binding.address.get_address_without_dot_onion()
+            ))
+            .await
+            .map_or(false, |desc| !desc.is_empty());
+        let reachable = if binding.client_auth {
+            None
+        } else {
+            // a refused connection still went through the onion service to its target
+            Some(matches!(
+                tokio::time::timeout(
+                    PROBE_TIMEOUT,
+                    tokio_socks::tcp::Socks5Stream::connect(
+                        tor_socks,
+                        (binding.address.to_string(), port),
+                    ),
+                )
+                .await,
+                Ok(Ok(_)) | Ok(Err(tokio_socks::Error::ConnectionRefused))
+            ))
+        };
+        OnionHealth {
+            address: binding.address,
+            port,
+            descriptor,
+            reachable,
+        }
+    }
+
+    pub async fn check_health(&self, tor_socks: SocketAddr) -> TorHealth {
+        let checked_at = Utc::now();
+        let bootstrapped = self
+            .get_info("status/bootstrap-phase")
+            .await
+            .map_or(false, |phase| phase.contains("TAG=done"));
+        let services = if bootstrapped {
+            futures::future::join_all(
+                self.list_bindings()
+                    .await
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|binding| self.probe(tor_socks, binding)),
+            )
+            .await
+        } else {
+            Vec::new()
+        };
+        TorHealth {
+            checked_at,
+            bootstrapped,
+            services,
+        }
+    }
+
+    async fn record_health(&self, health: TorHealth) {
+        let mut history = self.health.write().await;
+        history.samples.push_back(HealthSample {
+            checked_at: health.checked_at,
+            healthy: health.healthy(),
+        });
+        while history.samples.len() > HEALTH_HISTORY_LEN {
+            history.samples.pop_front();
+        }
+        history.latest = Some(health);
+    }
+
+    async fn record_reset(&self, wipe_state: bool, reason: String) {
+        let mut history = self.health.write().await;
+        history.resets.push_back(TorReset {
+            at: Utc::now(),
+            wipe_state,
+            reason,
+        });
+        while history.resets.len() > RESET_HISTORY_LEN {
+            history.resets.pop_front();
+        }
+    }
+
+    pub async fn status(&self) -> TorStatus {
+        let history = self.health.read().await;
+        let uptime = if history.samples.is_empty() {
+            None
+        } else {
+            Some(
+                history.samples.iter().filter(|s| s.healthy).count() as f64
+                    / history.samples.len() as f64,
+            )
+        };
+        TorStatus {
+            latest: history.latest.clone(),
+            uptime,
+            history: history.samples.iter().cloned().collect(),
+            resets: history.resets.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Whether the internet can be reached around tor, so that its failures are not blamed on it during an outage
+async fn is_online() -> bool {
+    futures::future::select_ok(DIRECTORY_AUTHORITIES.iter().map(|(ip, port)| {
+        tokio::time::timeout(
+            CONNECTIVITY_TIMEOUT,
+            TcpStream::connect(SocketAddr::from((*ip, *port))),
+        )
+        .map(|res| res.ok().and_then(|res| res.ok()).ok_or(()))
+        .boxed()
+    }))
+    .await
+    .is_ok()
+}
+
+/// Periodically checks that tor is bootstrapped and that every published onion service can be reached,
+/// restarting tor when they can not, and wiping its state if onion services stay unreachable once bootstrapped.
+/// Checks that fail while the internet is unreachable are not counted.
+pub async fn supervisor(ctx: RpcContext) {
+    let mut failures = 0;
+    loop {
+        tokio::time::sleep(SUPERVISOR_INTERVAL).await;
+        let tor = &ctx.net_controller.tor;
+        let health = tor.check_health(ctx.tor_socks).await;
+        let healthy = health.healthy();
+        let bootstrapped = health.bootstrapped;
+        let reason = if !bootstrapped {
+            "Tor is not bootstrapped".to_owned()
+        } else {
+            format!(
+                "Onion services unreachable: {}",
+                health
+                    .services
+                    .iter()
+                    .filter(|s| !s.healthy())
+                    .map(|s| s.address.to_string())
+                    .join(", ")
+            )
+        };
+        tor.record_health(health).await;
+        if healthy {
+            failures = 0;
+            continue;
+        }
+        if !is_online().await {
+            tracing::warn!("Tor health check failed while offline: {}", reason);
+            continue;
+        }
+        failures += 1;
+        tracing::warn!("Tor health check failed: {}", reason);
+        // a tor that can not bootstrap is only restarted, since its state is not what keeps it from the network
+        let wipe_state = bootstrapped && failures >= WIPE_STATE_AFTER;
+        if failures % RESTART_AFTER != 0 && !wipe_state {
+            continue;
+        }
+        if wipe_state {
+            failures = 0;
+        }
+        if let Err(e) = ctx
+            .notification_manager
+            .notify(
+                ctx.db.clone(),
+                None,
+                NotificationLevel::Warning,
+                "Tor Restarted".to_owned(),
+                format!(
+                    "{}. Tor was restarted{}.",
+                    reason,
+                    if wipe_state {
+                        " and its state was cleared"
+                    } else {
+                        ""
+                    }
+                ),
+                (),
+                Some(3600),
+            )
+            .await
+        {
+            tracing::error!("Error sending Tor notification: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        tor.record_reset(wipe_state, reason.clone()).await;
+        if let Err(e) = tor
+            .reset(wipe_state, Error::new(eyre!("{}", reason), ErrorKind::Tor))
+            .await
+        {
+            tracing::error!("Error resetting Tor: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+    }
+}
+
 type AuthenticatedConnection = AuthenticatedConn<
     TcpStream,
     Box<dyn Fn(AsyncEvent<'static>) -> BoxFuture<'static, Result<(), ConnError>> + Send + Sync>,
@@ -237,22 +815,177 @@ enum TorCommand {
         key: Option<TorSecretKeyV3>,
         external: Option<u16>,
     },
+    SetClientAuth {
+        key: TorSecretKeyV3,
+        clients: BTreeSet<String>,
+    },
     GetInfo {
         query: String,
         reply: oneshot::Sender<Result<String, Error>>,
     },
+    ListBindings {
+        reply: oneshot::Sender<Vec<OnionBinding>>,
+    },
     Reset {
         wipe_state: bool,
         context: Error,
     },
 }
 
+/// A published onion service, and the external ports it is currently serving
+struct OnionBinding {
+    address: OnionAddressV3,
+    ports: Vec<u16>,
+    client_auth: bool,
+}
+
+fn active_bindings(
+    services: &BTreeMap<[u8; 64], BTreeMap<u16, BTreeMap<SocketAddr, Weak<()>>>>,
+    client_auth: &BTreeMap<[u8; 64], BTreeSet<String>>,
+) -> Vec<OnionBinding> {
+    services
+        .iter()
+        .filter_map(|(key, service)| {
+            let ports = service
+                .iter()
+                .filter(|(_, int)| int.values().any(|rc| rc.strong_count() > 0))
+                .map(|(ext, _)| *ext)
+                .collect::<Vec<_>>();
+            if ports.is_empty() {
+                return None;
+            }
+            Some(OnionBinding {
+                address: TorSecretKeyV3::from(*key).public().get_onion_address(),
+                ports,
+                client_auth: client_auth.contains_key(key),
+            })
+        })
+        .collect()
+}
+
+/// A raw control connection for onion services, since torut can not pass `ClientAuthV3` to `ADD_ONION`
+struct OnionControl {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+impl OnionControl {
+    async fn connect(tor_control: SocketAddr) -> Result<Self, Error> {
+        let (reader, writer) = TcpStream::connect(tor_control).await?.into_split();
+        let mut res = Self {
+            reader: BufReader::new(reader),
+            writer,
+        };
+        let cookie_file = res
+            .command("PROTOCOLINFO 1")
+            .await?
+            .iter()
+            .find_map(|line| line.split_once("COOKIEFILE=\""))
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(path, _)| path.replace("\\\\", "\\"))
+            .ok_or_else(|| Error::new(eyre!("Cookie Auth Not Available"), ErrorKind::Tor))?;
+        let cookie = tokio::fs::read(&cookie_file).await?;
+        res.command(&format!("AUTHENTICATE {}", hex::encode(cookie)))
+            .await?;
+        Ok(res)
+    }
+
+    /// Returns the lines of the reply, or an error if it is not `250 OK`
+    async fn command(&mut self, command: &str) -> Result<Vec<String>, Error> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).await? == 0 {
+                return Err(Error::new(
+                    eyre!("Tor control connection closed"),
+                    ErrorKind::Tor,
+                ));
+            }
+            let line = line.trim_end();
+            if line.len() < 4 || !line.is_char_boundary(3) {
+                return Err(Error::new(
+                    eyre!("Invalid reply from tor: {}", line),
+                    ErrorKind::Tor,
+                ));
+            }
+            let (code, rest) = line.split_at(3);
+            lines.push(rest[1..].to_owned());
+            if rest.starts_with('+') {
+                // data reply, terminated by a line containing only "."
+                loop {
+                    let mut data = String::new();
+                    if self.reader.read_line(&mut data).await? == 0 {
+                        break;
+                    }
+                    let data = data.trim_end();
+                    if data == "." {
+                        break;
+                    }
+                    lines.push(data.strip_prefix('.').unwrap_or(data).to_owned());
+                }
+            } else if rest.starts_with(' ') {
+                if code != "250" {
+                    return Err(Error::new(
+                        eyre!("{} {}", code, lines.join("; ")),
+                        ErrorKind::Tor,
+                    ));
+                }
+                return Ok(lines);
+            }
+        }
+    }
+
+    /// `GETINFO` through this connection, which is the one that owns the onion services
+    async fn get_info(&mut self, query: &str) -> Result<String, Error> {
+        let mut lines = self.command(&format!("GETINFO {}", query)).await?;
+        lines.pop(); // OK
+        let prefix = format!("{}=", query);
+        Ok(lines
+            .iter()
+            .map(|l| l.strip_prefix(&prefix).unwrap_or(l))
+            .filter(|l| !l.is_empty())
+            .join("\n"))
+    }
+
+    async fn add_onion(
+        &mut self,
+        key: &TorSecretKeyV3,
+        bindings: &[(u16, SocketAddr)],
+        clients: Option<&BTreeSet<String>>,
+    ) -> Result<(), Error> {
+        let mut command = format!(
+            "ADD_ONION ED25519-V3:{}",
+            base64::engine::general_purpose::STANDARD.encode(key.as_bytes())
+        );
+        let clients = clients.into_iter().flatten().collect::<Vec<_>>();
+        if !clients.is_empty() {
+            command += " Flags=V3Auth";
+        }
+        for (external, target) in bindings {
+            command += &format!(" Port={},{}", external, target);
+        }
+        for client in clients {
+            command += &format!(" ClientAuthV3={}", client);
+        }
+        self.command(&command).await?;
+        Ok(())
+    }
+
+    async fn del_onion(&mut self, onion_base: &str) -> Result<(), Error> {
+        self.command(&format!("DEL_ONION {}", onion_base)).await?;
+        Ok(())
+    }
+}
+
 #[instrument(skip_all)]
 async fn torctl(
     tor_control: SocketAddr,
     tor_socks: SocketAddr,
     recv: &mut mpsc::UnboundedReceiver<TorCommand>,
     services: &mut BTreeMap<[u8; 64], BTreeMap<u16, BTreeMap<SocketAddr, Weak<()>>>>,
+    client_auth: &mut BTreeMap<[u8; 64], BTreeSet<String>>,
     wipe_state: &AtomicBool,
     health_timeout: &mut Duration,
 ) -> Result<(), Error> {
@@ -401,6 +1134,18 @@ async fn torctl(
                         .unwrap_or_default();
                 }
                 TorCommand::GC { .. } => (),
+                TorCommand::ListBindings { reply } => {
+                    reply
+                        .send(active_bindings(services, client_auth))
+                        .unwrap_or_default();
+                }
+                TorCommand::SetClientAuth { key, clients } => {
+                    if clients.is_empty() {
+                        client_auth.remove(&key.as_bytes());
+                    } else {
+                        client_auth.insert(key.as_bytes(), clients);
+                    }
+                }
                 TorCommand::Reset {
                     wipe_state: new_wipe_state,
                     context,
@@ -417,6 +1162,7 @@ async fn torctl(
         res = bootstrap => res?,
         res = pre_handler => return res,
     };
+    let mut onions = OnionControl::connect(tor_control).await?;
 
     let hck_key = TorSecretKeyV3::generate();
     connection
@@ -442,8 +1188,8 @@ async fn torctl(
             .collect::<Vec<_>>();
         if !bindings.is_empty() {
             services.insert(key.as_bytes(), service);
-            connection
-                .add_onion_v3(&key, false, false, false, None, &mut bindings.iter())
+            onions
+                .add_onion(&key, &bindings, client_auth.get(&key.as_bytes()))
                 .await?;
         }
     }
@@ -463,7 +1209,7 @@ async fn torctl(
                         .get_onion_address()
                         .get_address_without_dot_onion();
                     let mut service = if let Some(service) = services.remove(&key.as_bytes()) {
-                        rm_res = connection.del_onion(&onion_base).await;
+                        rm_res = onions.del_onion(&onion_base).await;
                         service
                     } else {
                         BTreeMap::new()
@@ -489,8 +1235,8 @@ async fn torctl(
                     services.insert(key.as_bytes(), service);
                     reply.send(rc).unwrap_or_default();
                     rm_res?;
-                    connection
-                        .add_onion_v3(&key, false, false, false, None, &mut bindings.iter())
+                    onions
+                        .add_onion(&key, &bindings, client_auth.get(&key.as_bytes()))
                         .await?;
                 }
                 TorCommand::GC { key, external } => {
@@ -522,7 +1268,7 @@ async fn torctl(
                                     }
                                 }
                             }
-                            let rm_res = connection.del_onion(&onion_base).await;
+                            let rm_res = onions.del_onion(&onion_base).await;
                             if !service.is_empty() {
                                 let bindings = service
                                     .iter()
@@ -537,14 +1283,11 @@ async fn torctl(
                                 }
                                 rm_res?;
                                 if !bindings.is_empty() {
-                                    connection
-                                        .add_onion_v3(
+                                    onions
+                                        .add_onion(
                                             &key,
-                                            false,
-                                            false,
-                                            false,
-                                            None,
-                                            &mut bindings.iter(),
+                                            &bindings,
+                                            client_auth.get(&key.as_bytes()),
                                         )
                                         .await?;
                                 }
@@ -554,9 +1297,43 @@ async fn torctl(
                         }
                     }
                 }
+                TorCommand::SetClientAuth { key, clients } => {
+                    if clients.is_empty() {
+                        client_auth.remove(&key.as_bytes());
+                    } else {
+                        client_auth.insert(key.as_bytes(), clients);
+                    }
+                    if let Some(service) = services.get(&key.as_bytes()) {
+                        let bindings = service
+                            .iter()
+                            .flat_map(|(ext, int)| {
+                                int.iter()
+                                    .find(|(_, rc)| rc.strong_count() > 0)
+                                    .map(|(addr, _)| (*ext, SocketAddr::from(*addr)))
+                            })
+                            .collect::<Vec<_>>();
+                        if !bindings.is_empty() {
+                            onions
+                                .del_onion(
+                                    &key.public()
+                                        .get_onion_address()
+                                        .get_address_without_dot_onion(),
+                                )
+                                .await?;
+                            onions
+                                .add_onion(&key, &bindings, client_auth.get(&key.as_bytes()))
+                                .await?;
+                        }
+                    }
+                }
                 TorCommand::GetInfo { query, reply } => {
                     reply
-                        .send(connection.get_info(&query).await.with_kind(ErrorKind::Tor))
+                        .send(onions.get_info(&query).await)
+                        .unwrap_or_default();
+                }
+                TorCommand::ListBindings { reply } => {
+                    reply
+                        .send(active_bindings(services, client_auth))
                         .unwrap_or_default();
                 }
                 TorCommand::Reset {
@@ -593,7 +1370,7 @@ async fn torctl(
                         })
                         .is_err()
                     {
-                        if wipe_state {
+                        if wipe_state && is_online().await {
                             Command::new("systemctl")
                                 .arg("stop")
                                 .arg("tor")
@@ -624,7 +1401,10 @@ async fn torctl(
             .and_then(|e| e.map_err(|e| e.to_string()))
             .is_err()
             {
-                if last_success.elapsed() > *health_timeout {
+                if !is_online().await {
+                    // tor is not at fault while the internet is unreachable
+                    last_success = Instant::now();
+                } else if last_success.elapsed() > *health_timeout {
                     let err = Error::new(eyre!("Tor health check failed for longer than current timeout ({health_timeout:?})"), crate::ErrorKind::Tor);
                     *health_timeout *= 2;
                     wipe_state.store(true, std::sync::atomic::Ordering::SeqCst);
@@ -655,6 +1435,7 @@ impl TorControl {
         Self {
             _thread: tokio::spawn(async move {
                 let mut services = BTreeMap::new();
+                let mut client_auth = BTreeMap::new();
                 let wipe_state = AtomicBool::new(false);
                 let mut health_timeout = Duration::from_secs(STARTING_HEALTH_TIMEOUT);
                 while let Err(e) = torctl(
@@ -662,6 +1443,7 @@ impl TorControl {
                     tor_socks,
                     &mut recv,
                     &mut services,
+                    &mut client_auth,
                     &wipe_state,
                     &mut health_timeout,
                 )
@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::Infallible;
 use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
@@ -18,22 +18,34 @@ use tokio_rustls::rustls::{RootCertStore, ServerConfig};
 use tokio_rustls::{LazyConfigAcceptor, TlsConnector};
 use tracing::instrument;
 
+use crate::net::acme::AcmeManager;
 use crate::net::keys::Key;
+use crate::net::proxy::ProxyController;
 use crate::net::ssl::SslManager;
 use crate::net::utils::SingleAccept;
 use crate::prelude::*;
 use crate::util::io::{BackTrackingReader, TimeoutStream};
 
-// not allowed: <=1024, >=32768, 5355, 5432, 9050, 6010, 9051, 5353
+/// Used by the OS, in addition to <=1024 and >=32768
+const RESERVED_PORTS: &[u16] = &[5355, 5432, 9050, 6010, 9051, 5353];
+
+/// Whether a package may claim `port` on the LAN for itself
+pub fn port_allowed(port: u16) -> bool {
+    port > 1024 && port < 32768 && !RESERVED_PORTS.contains(&port)
+}
 
 pub struct VHostController {
     ssl: Arc<SslManager>,
+    acme: Arc<AcmeManager>,
+    proxy: Arc<ProxyController>,
     servers: Mutex<BTreeMap<u16, VHostServer>>,
 }
 impl VHostController {
-    pub fn new(ssl: Arc<SslManager>) -> Self {
+    pub fn new(ssl: Arc<SslManager>, acme: Arc<AcmeManager>, proxy: Arc<ProxyController>) -> Self {
         Self {
             ssl,
+            acme,
+            proxy,
             servers: Mutex::new(BTreeMap::new()),
         }
     }
@@ -50,7 +62,13 @@ impl VHostController {
         let server = if let Some(server) = writable.remove(&external) {
             server
         } else {
-            VHostServer::new(external, self.ssl.clone()).await?
+            VHostServer::new(
+                external,
+                self.ssl.clone(),
+                self.acme.clone(),
+                self.proxy.clone(),
+            )
+            .await?
         };
         let rc = server
             .add(
@@ -97,7 +115,12 @@ struct VHostServer {
 }
 impl VHostServer {
     #[instrument(skip_all)]
-    async fn new(port: u16, ssl: Arc<SslManager>) -> Result<Self, Error> {
+    async fn new(
+        port: u16,
+        ssl: Arc<SslManager>,
+        acme: Arc<AcmeManager>,
+        proxy: Arc<ProxyController>,
+    ) -> Result<Self, Error> {
         // check if port allowed
         let listener = TcpListener::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port))
             .await
@@ -108,13 +131,21 @@ impl VHostServer {
             _thread: tokio::spawn(async move {
                 loop {
                     match listener.accept().await {
-                        Ok((stream, _)) => {
+                        Ok((stream, client_addr)) => {
+                            let client_ip = match client_addr.ip() {
+                                IpAddr::V6(ip) => ip
+                                    .to_ipv4_mapped()
+                                    .map_or(IpAddr::V6(ip), IpAddr::V4),
+                                ip => ip,
+                            };
                             let stream =
                                 Box::pin(TimeoutStream::new(stream, Duration::from_secs(300)));
                             let mut stream = BackTrackingReader::new(stream);
                             stream.start_buffering();
                             let mapping = mapping.clone();
                             let ssl = ssl.clone();
+                            let acme = acme.clone();
+                            let proxy = proxy.clone();
                             tokio::spawn(async move {
                                 if let Err(e) = async {
                                     let mid = match LazyConfigAcceptor::new(
@@ -160,6 +191,12 @@ impl VHostServer {
                                     };
                                     let target_name =
                                         mid.client_hello().server_name().map(|s| s.to_owned());
+                                    let acme_cert = match &target_name {
+                                        Some(name) => acme.cert(name).await,
+                                        None => None,
+                                    };
+                                    // a name the target is bound to, which its certificate must cover
+                                    let mut domains = BTreeSet::new();
                                     let target = {
                                         let mapping = mapping.read().await;
                                         mapping
@@ -167,6 +204,10 @@ impl VHostServer {
                                             .into_iter()
                                             .flatten()
                                             .find(|(_, rc)| rc.strong_count() > 0)
+                                            .map(|target| {
+                                                domains.extend(target_name.clone());
+                                                target
+                                            })
                                             .or_else(|| {
                                                 if target_name
                                                     .map(|s| s.parse::<IpAddr>().is_ok())
@@ -184,15 +225,38 @@ impl VHostServer {
                                             .map(|(target, _)| target.clone())
                                     };
                                     if let Some(target) = target {
+                                        let http_proxy = match target.key.interface() {
+                                            Some((package, interface)) => {
+                                                proxy.get(&package, &interface).await
+                                            }
+                                            None => None,
+                                        };
+                                        let bound_name = domains.iter().next().cloned();
                                         let mut tcp_stream =
                                             TcpStream::connect(target.addr).await?;
-                                        let key =
-                                            ssl.with_certs(target.key, target.addr.ip()).await?;
+                                        let key = ssl
+                                            .with_certs(target.key, target.addr.ip(), &domains)
+                                            .await?;
                                         let cfg = ServerConfig::builder()
                                             .with_safe_defaults()
                                             .with_no_client_auth();
-                                        let mut cfg =
-                                            if mid.client_hello().signature_schemes().contains(
+                                        let mut cfg = if let Some(acme_cert) = acme_cert {
+                                            // publicly trusted, so clients need not trust the root CA
+                                            cfg.with_single_cert(
+                                                acme_cert
+                                                    .fullchain
+                                                    .iter()
+                                                    .map(|c| {
+                                                        Ok(tokio_rustls::rustls::Certificate(
+                                                            c.to_der()?,
+                                                        ))
+                                                    })
+                                                    .collect::<Result<_, Error>>()?,
+                                                tokio_rustls::rustls::PrivateKey(
+                                                    acme_cert.key.private_key_to_pkcs8()?,
+                                                ),
+                                            )
+                                        } else if mid.client_hello().signature_schemes().contains(
                                                 &tokio_rustls::rustls::SignatureScheme::ED25519,
                                             ) {
                                                 cfg.with_single_cert(
@@ -243,13 +307,16 @@ impl VHostServer {
                                                             store
                                                         })
                                                         .with_no_client_auth();
-                                                client_cfg.alpn_protocols = mid
-                                                    .client_hello()
-                                                    .alpn()
-                                                    .into_iter()
-                                                    .flatten()
-                                                    .map(|x| x.to_vec())
-                                                    .collect();
+                                                client_cfg.alpn_protocols = if http_proxy.is_some() {
+                                                    vec![b"http/1.1".to_vec()]
+                                                } else {
+                                                    mid.client_hello()
+                                                        .alpn()
+                                                        .into_iter()
+                                                        .flatten()
+                                                        .map(|x| x.to_vec())
+                                                        .collect()
+                                                };
                                                 let mut target_stream =
                                                     TlsConnector::from(Arc::new(client_cfg))
                                                         .connect_with(
@@ -281,17 +348,31 @@ impl VHostServer {
                                                         }
                                                     };
                                                 tls_stream.get_mut().0.stop_buffering();
-                                                tokio::io::copy_bidirectional(
-                                                    &mut tls_stream,
-                                                    &mut target_stream,
-                                                )
-                                                .await
+                                                if let Some(http_proxy) = http_proxy {
+                                                    http_proxy
+                                                        .serve(Some(client_ip), bound_name, tls_stream, target_stream)
+                                                        .await
+                                                } else {
+                                                    tokio::io::copy_bidirectional(
+                                                        &mut tls_stream,
+                                                        &mut target_stream,
+                                                    )
+                                                    .await
+                                                    .map(|_| ())
+                                                }
                                             }
                                             Err(AlpnInfo::Reflect) => {
-                                                for proto in
-                                                    mid.client_hello().alpn().into_iter().flatten()
-                                                {
-                                                    cfg.alpn_protocols.push(proto.into());
+                                                if http_proxy.is_some() {
+                                                    cfg.alpn_protocols.push(b"http/1.1".to_vec());
+                                                } else {
+                                                    for proto in mid
+                                                        .client_hello()
+                                                        .alpn()
+                                                        .into_iter()
+                                                        .flatten()
+                                                    {
+                                                        cfg.alpn_protocols.push(proto.into());
+                                                    }
                                                 }
                                                 let mut tls_stream =
                                                     match mid.into_stream(Arc::new(cfg)).await {
@@ -303,14 +384,25 @@ impl VHostServer {
                                                         }
                                                     };
                                                 tls_stream.get_mut().0.stop_buffering();
-                                                tokio::io::copy_bidirectional(
-                                                    &mut tls_stream,
-                                                    &mut tcp_stream,
-                                                )
-                                                .await
+                                                if let Some(http_proxy) = http_proxy {
+                                                    http_proxy
+                                                        .serve(Some(client_ip), bound_name, tls_stream, tcp_stream)
+                                                        .await
+                                                } else {
+                                                    tokio::io::copy_bidirectional(
+                                                        &mut tls_stream,
+                                                        &mut tcp_stream,
+                                                    )
+                                                    .await
+                                                    .map(|_| ())
+                                                }
                                             }
                                             Err(AlpnInfo::Specified(alpn)) => {
-                                                cfg.alpn_protocols = alpn;
+                                                cfg.alpn_protocols = if http_proxy.is_some() {
+                                                    vec![b"http/1.1".to_vec()]
+                                                } else {
+                                                    alpn
+                                                };
                                                 let mut tls_stream =
                                                     match mid.into_stream(Arc::new(cfg)).await {
                                                         Ok(a) => a,
@@ -321,11 +413,18 @@ impl VHostServer {
                                                         }
                                                     };
                                                 tls_stream.get_mut().0.stop_buffering();
-                                                tokio::io::copy_bidirectional(
-                                                    &mut tls_stream,
-                                                    &mut tcp_stream,
-                                                )
-                                                .await
+                                                if let Some(http_proxy) = http_proxy {
+                                                    http_proxy
+                                                        .serve(Some(client_ip), bound_name, tls_stream, tcp_stream)
+                                                        .await
+                                                } else {
+                                                    tokio::io::copy_bidirectional(
+                                                        &mut tls_stream,
+                                                        &mut tcp_stream,
+                                                    )
+                                                    .await
+                                                    .map(|_| ())
+                                                }
                                             }
                                         }
                                         .map_or_else(
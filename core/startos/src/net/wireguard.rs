@@ -0,0 +1,624 @@
+use std::collections::BTreeSet;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use base64::Engine;
+use chrono::Utc;
+use clap::ArgMatches;
+use ipnet::Ipv4Net;
+use openssl::pkey::{Id, PKey};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::process::Command;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::prelude::*;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Invoke};
+use crate::BRIDGE_SUBNET;
+
+pub const WIREGUARD_IFACE: &str = "wg-start9";
+const DEFAULT_PORT: u16 = 51820;
+/// The address of the server, and the subnet peers are assigned addresses from
+const DEFAULT_SUBNET: &str = "10.59.0.1/24";
+/// The docker bridge, where `.embassy` names resolve to
+pub(super) const BRIDGE_IFACE: &str = "br-start9";
+/// Keeps the NAT mappings of peers alive, so that the server can reach them
+const PERSISTENT_KEEPALIVE: u64 = 25;
+
+/// A Curve25519 private key, as used by WireGuard
+#[derive(Clone)]
+pub struct WgKey([u8; 32]);
+impl WgKey {
+    pub fn generate() -> Result<Self, Error> {
+        Self::from_bytes(&PKey::generate_x25519()?.raw_private_key()?)
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(Self(bytes.try_into().map_err(|_| {
+            Error::new(
+                eyre!("Invalid WireGuard Key Length: {}", bytes.len()),
+                ErrorKind::WireGuard,
+            )
+        })?))
+    }
+    pub fn public_key(&self) -> Result<String, Error> {
+        Ok(encode(
+            &PKey::private_key_from_raw_bytes(&self.0, Id::X25519)?.raw_public_key()?,
+        ))
+    }
+    pub fn to_base64(&self) -> String {
+        encode(&self.0)
+    }
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+struct Server {
+    key: WgKey,
+    port: u16,
+    subnet: Ipv4Net,
+    /// The public address of the server, if it is not the LAN address
+    endpoint: Option<String>,
+    enabled: bool,
+}
+impl Server {
+    /// Generates the server key the first time
+    async fn load(secrets: &PgPool) -> Result<Self, Error> {
+        if let Some(row) = sqlx::query!(
+            "SELECT private_key, listen_port, subnet, endpoint, enabled FROM wireguard WHERE id = 0"
+        )
+        .fetch_optional(secrets)
+        .await?
+        {
+            return Ok(Self {
+                key: WgKey::from_bytes(&row.private_key)?,
+                port: row.listen_port as u16,
+                subnet: row.subnet.parse()?,
+                endpoint: row.endpoint,
+                enabled: row.enabled,
+            });
+        }
+        let server = Self {
+            key: WgKey::generate()?,
+            port: DEFAULT_PORT,
+            subnet: DEFAULT_SUBNET.parse()?,
+            endpoint: None,
+            enabled: false,
+        };
+        sqlx::query!(
+            "INSERT INTO wireguard (id, private_key, listen_port, subnet, enabled) VALUES (0, $1, $2, $3, false)",
+            &server.key.0[..],
+            server.port as i32,
+            server.subnet.to_string(),
+        )
+        .execute(secrets)
+        .await?;
+        Ok(server)
+    }
+
+    async fn save(&self, secrets: &PgPool) -> Result<(), Error> {
+        sqlx::query!(
+            "UPDATE wireguard SET listen_port = $1, subnet = $2, endpoint = $3, enabled = $4 WHERE id = 0",
+            self.port as i32,
+            self.subnet.to_string(),
+            self.endpoint,
+            self.enabled,
+        )
+        .execute(secrets)
+        .await?;
+        Ok(())
+    }
+
+    /// The configuration of the interface, in the format of `wg setconf`
+    fn conf(&self, peers: &[Peer]) -> Result<String, Error> {
+        let mut conf = format!(
+            "[Interface]\nPrivateKey = {}\nListenPort = {}\n",
+            self.key.to_base64(),
+            self.port
+        );
+        for peer in peers {
+            conf += &format!(
+                "\n[Peer]\nPublicKey = {}\nPresharedKey = {}\nAllowedIPs = {}/32\n",
+                peer.public_key,
+                peer.preshared_key.to_base64(),
+                peer.address
+            );
+        }
+        Ok(conf)
+    }
+
+    /// The configuration of a peer, in the format of `wg-quick`, which clients also accept as a QR code
+    fn client_conf(&self, endpoint: &str, peer: &Peer, key: &WgKey) -> Result<String, Error> {
+        let endpoint = if endpoint.parse::<Ipv6Addr>().is_ok() {
+            format!("[{}]", endpoint)
+        } else {
+            endpoint.to_owned()
+        };
+        Ok(format!(
+            concat!(
+                "[Interface]\n",
+                "PrivateKey = {}\n",
+                "Address = {}/32\n",
+                "DNS = {}\n",
+                "\n",
+                "[Peer]\n",
+                "PublicKey = {}\n",
+                "PresharedKey = {}\n",
+                "Endpoint = {}:{}\n",
+                "AllowedIPs = {}, {}\n",
+                "PersistentKeepalive = {}\n",
+            ),
+            key.to_base64(),
+            peer.address,
+            self.subnet.addr(),
+            self.key.public_key()?,
+            peer.preshared_key.to_base64(),
+            endpoint,
+            self.port,
+            self.subnet.trunc(),
+            BRIDGE_SUBNET.parse::<Ipv4Net>()?.trunc(),
+            PERSISTENT_KEEPALIVE,
+        ))
+    }
+}
+
+/// Only the public key of a peer is kept: its private key is handed out once, when it is added
+struct Peer {
+    name: String,
+    public_key: String,
+    preshared_key: WgKey,
+    address: Ipv4Addr,
+    created_at: String,
+}
+impl Peer {
+    async fn load_all(secrets: &PgPool) -> Result<Vec<Self>, Error> {
+        sqlx::query!(
+            "SELECT name, public_key, preshared_key, address, created_at FROM wireguard_peers"
+        )
+        .fetch_all(secrets)
+        .await?
+        .into_iter()
+        .map(|row| {
+            Ok(Self {
+                name: row.name,
+                public_key: row.public_key,
+                preshared_key: WgKey::from_bytes(&row.preshared_key)?,
+                address: row.address.parse()?,
+                created_at: row.created_at,
+            })
+        })
+        .collect()
+    }
+}
+
+async fn iface_exists() -> bool {
+    tokio::fs::metadata(Path::new("/sys/class/net").join(WIREGUARD_IFACE))
+        .await
+        .is_ok()
+}
+
+/// Docker drops forwarded traffic that is not its own, so peers must be let through to the containers
+/// while the tunnel is up
+async fn allow_forwarding(allow: bool) -> Result<(), Error> {
+    let rule = [
+        "FORWARD",
+        "-i",
+        WIREGUARD_IFACE,
+        "-o",
+        BRIDGE_IFACE,
+        "-j",
+        "ACCEPT",
+    ];
+    let exists = Command::new("iptables")
+        .arg("-C")
+        .args(rule)
+        .invoke(ErrorKind::WireGuard)
+        .await
+        .is_ok();
+    if allow != exists {
+        Command::new("iptables")
+            .arg(if allow { "-I" } else { "-D" })
+            .args(rule)
+            .invoke(ErrorKind::WireGuard)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Brings the interface in line with the secret store
+#[instrument(skip_all)]
+async fn sync(ctx: &RpcContext) -> Result<(), Error> {
+    let server = Server::load(&ctx.secret_store).await?;
+    if !server.enabled {
+        ctx.net_controller.dns.set_tunnel(None).await?;
+        allow_forwarding(false).await?;
+        if iface_exists().await {
+            Command::new("ip")
+                .arg("link")
+                .arg("del")
+                .arg("dev")
+                .arg(WIREGUARD_IFACE)
+                .invoke(ErrorKind::WireGuard)
+                .await?;
+        }
+        return Ok(());
+    }
+    let peers = Peer::load_all(&ctx.secret_store).await?;
+    if !iface_exists().await {
+        Command::new("ip")
+            .arg("link")
+            .arg("add")
+            .arg("dev")
+            .arg(WIREGUARD_IFACE)
+            .arg("type")
+            .arg("wireguard")
+            .invoke(ErrorKind::WireGuard)
+            .await?;
+    }
+    Command::new("wg")
+        .arg("setconf")
+        .arg(WIREGUARD_IFACE)
+        .arg("/dev/stdin")
+        .input(Some(&mut std::io::Cursor::new(
+            server.conf(&peers)?.into_bytes(),
+        )))
+        .invoke(ErrorKind::WireGuard)
+        .await?;
+    Command::new("ip")
+        .arg("address")
+        .arg("flush")
+        .arg("dev")
+        .arg(WIREGUARD_IFACE)
+        .invoke(ErrorKind::WireGuard)
+        .await?;
+    Command::new("ip")
+        .arg("address")
+        .arg("add")
+        .arg(server.subnet.to_string())
+        .arg("dev")
+        .arg(WIREGUARD_IFACE)
+        .invoke(ErrorKind::WireGuard)
+        .await?;
+    Command::new("ip")
+        .arg("link")
+        .arg("set")
+        .arg("up")
+        .arg("dev")
+        .arg(WIREGUARD_IFACE)
+        .invoke(ErrorKind::WireGuard)
+        .await?;
+    allow_forwarding(true).await?;
+    ctx.net_controller.dns.set_tunnel(Some(server.subnet)).await
+}
+
+/// Restores the interface at startup
+pub async fn init(ctx: RpcContext) {
+    if let Err(e) = sync(&ctx).await {
+        tracing::error!("Error initializing WireGuard: {}", e);
+        tracing::debug!("{:?}", e);
+    }
+}
+
+/// The stored endpoint, or else the first LAN address of the server
+async fn endpoint(ctx: &RpcContext, server: &Server) -> Result<String, Error> {
+    if let Some(endpoint) = &server.endpoint {
+        return Ok(endpoint.clone());
+    }
+    ctx.db
+        .peek()
+        .await
+        .as_server_info()
+        .as_ip_info()
+        .de()?
+        .into_values()
+        .find_map(|info| info.ipv4)
+        .map(|ip| ip.to_string())
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("No WireGuard endpoint: pass `--endpoint` to `net.wireguard.enable`"),
+                ErrorKind::WireGuard,
+            )
+        })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WireGuardInfo {
+    pub enabled: bool,
+    pub public_key: String,
+    pub listen_port: u16,
+    pub subnet: Ipv4Net,
+    pub endpoint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PeerInfo {
+    pub name: String,
+    pub public_key: String,
+    pub address: Ipv4Addr,
+    pub created_at: String,
+}
+
+/// A WireGuard server giving peers access to the LAN interfaces of the server and its packages,
+/// as well as to the `.embassy` names of the packages
+#[command(subcommands(get_wireguard, enable, disable, peer))]
+pub fn wireguard() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(rename = "get", display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn get_wireguard(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<WireGuardInfo, Error> {
+    let server = Server::load(&ctx.secret_store).await?;
+    Ok(WireGuardInfo {
+        enabled: server.enabled,
+        public_key: server.key.public_key()?,
+        listen_port: server.port,
+        subnet: server.subnet,
+        endpoint: server.endpoint,
+    })
+}
+
+/// Options that are not passed keep their previous values
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn enable(
+    #[context] ctx: RpcContext,
+    #[arg(long = "port")] port: Option<u16>,
+    #[arg(long = "subnet")] subnet: Option<Ipv4Net>,
+    #[arg(long = "endpoint")] endpoint: Option<String>,
+) -> Result<(), Error> {
+    let mut server = Server::load(&ctx.secret_store).await?;
+    if let Some(subnet) = subnet {
+        if subnet.prefix_len() > 30 || subnet.addr() == subnet.network() {
+            return Err(Error::new(
+                eyre!(
+                    "{} is not an address in a subnet with room for peers",
+                    subnet
+                ),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        if subnet != server.subnet && !Peer::load_all(&ctx.secret_store).await?.is_empty() {
+            return Err(Error::new(
+                eyre!("Remove all peers before changing the WireGuard subnet"),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+        server.subnet = subnet;
+    }
+    if let Some(port) = port {
+        server.port = port;
+    }
+    if let Some(endpoint) = endpoint {
+        let endpoint = endpoint.trim_start_matches('[').trim_end_matches(']');
+        server.endpoint = if endpoint.is_empty() {
+            None
+        } else {
+            Some(endpoint.to_owned())
+        };
+    }
+    server.enabled = true;
+    server.save(&ctx.secret_store).await?;
+    sync(&ctx).await
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn disable(#[context] ctx: RpcContext) -> Result<(), Error> {
+    let mut server = Server::load(&ctx.secret_store).await?;
+    server.enabled = false;
+    server.save(&ctx.secret_store).await?;
+    sync(&ctx).await
+}
+
+#[command(subcommands(add, remove, list))]
+pub fn peer() -> Result<(), Error> {
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PeerConfig {
+    /// In the format of `wg-quick`
+    pub config: String,
+    /// The config as a QR code, drawn with unicode blocks, for WireGuard apps to scan
+    pub qr: String,
+}
+
+fn display_peer_config(conf: PeerConfig, matches: &ArgMatches) {
+    if matches.is_present("format") {
+        return display_serializable(conf, matches);
+    }
+    print!("{}\n{}", conf.qr, conf.config);
+}
+
+async fn qr_code(data: &str) -> Result<String, Error> {
+    Ok(String::from_utf8(
+        Command::new("qrencode")
+            .arg("-t")
+            .arg("UTF8")
+            .arg("-m")
+            .arg("2")
+            .input(Some(&mut std::io::Cursor::new(data.as_bytes())))
+            .invoke(ErrorKind::WireGuard)
+            .await?,
+    )?)
+}
+
+/// Returns the configuration for the new peer.
+/// Its private key is not kept, so this is the only time the configuration can be shown.
+#[command(display(display_peer_config))]
+#[instrument(skip_all)]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] name: String,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<PeerConfig, Error> {
+    if name.is_empty() || name.len() > 64 || name.chars().any(|c| c.is_control()) {
+        return Err(Error::new(
+            eyre!("Invalid Peer Name"),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let server = Server::load(&ctx.secret_store).await?;
+    let used = Peer::load_all(&ctx.secret_store)
+        .await?
+        .into_iter()
+        .map(|p| p.address)
+        .collect::<BTreeSet<_>>();
+    let address = server
+        .subnet
+        .hosts()
+        .find(|ip| *ip != server.subnet.addr() && !used.contains(ip))
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("WireGuard subnet {} is full", server.subnet.trunc()),
+                ErrorKind::WireGuard,
+            )
+        })?;
+    let key = WgKey::generate()?;
+    let peer = Peer {
+        name,
+        public_key: key.public_key()?,
+        preshared_key: WgKey::from_bytes(&rand::random::<[u8; 32]>())?,
+        address,
+        created_at: Utc::now().to_rfc3339(),
+    };
+    let n = sqlx::query!(
+        "INSERT INTO wireguard_peers (name, public_key, preshared_key, address, created_at) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (name) DO NOTHING",
+        peer.name,
+        peer.public_key,
+        &peer.preshared_key.0[..],
+        peer.address.to_string(),
+        peer.created_at,
+    )
+    .execute(&ctx.secret_store)
+    .await?
+    .rows_affected();
+    if n == 0 {
+        return Err(Error::new(
+            eyre!("Peer {} already exists", peer.name),
+            ErrorKind::Duplicate,
+        ));
+    }
+    sync(&ctx).await?;
+    let config = server.client_conf(&endpoint(&ctx, &server).await?, &peer, &key)?;
+    Ok(PeerConfig {
+        qr: qr_code(&config).await?,
+        config,
+    })
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn remove(#[context] ctx: RpcContext, #[arg] name: String) -> Result<(), Error> {
+    let n = sqlx::query!("DELETE FROM wireguard_peers WHERE name = $1", name)
+        .execute(&ctx.secret_store)
+        .await?
+        .rows_affected();
+    if n == 0 {
+        return Err(Error::new(eyre!("Peer Not Found"), ErrorKind::NotFound));
+    }
+    sync(&ctx).await
+}
+
+fn display_peers(peers: Vec<PeerInfo>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(peers, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "NAME", "ADDRESS", "PUBLIC KEY", "CREATED AT"]);
+    for peer in peers {
+        table.add_row(row![
+            &peer.name,
+            peer.address,
+            &peer.public_key,
+            &peer.created_at
+        ]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_peers))]
+#[instrument(skip_all)]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<PeerInfo>, Error> {
+    Peer::load_all(&ctx.secret_store)
+        .await?
+        .into_iter()
+        .map(|peer| {
+            Ok(PeerInfo {
+                public_key: peer.public_key,
+                name: peer.name,
+                address: peer.address,
+                created_at: peer.created_at,
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn peer_configs() {
+    // RFC 7748 §6.1
+    let key = WgKey::from_bytes(
+        &hex::decode("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a").unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        key.public_key().unwrap(),
+        encode(
+            &hex::decode("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+                .unwrap()
+        )
+    );
+    let server = Server {
+        key: WgKey::from_bytes(&[1; 32]).unwrap(),
+        port: DEFAULT_PORT,
+        subnet: DEFAULT_SUBNET.parse().unwrap(),
+        endpoint: None,
+        enabled: true,
+    };
+    let peer = Peer {
+        name: "phone".into(),
+        public_key: key.public_key().unwrap(),
+        preshared_key: WgKey::from_bytes(&[2; 32]).unwrap(),
+        address: Ipv4Addr::new(10, 59, 0, 2),
+        created_at: Utc::now().to_rfc3339(),
+    };
+
+    let conf = server.conf(std::slice::from_ref(&peer)).unwrap();
+    assert!(conf.contains(&format!("PublicKey = {}\n", peer.public_key)));
+    assert!(conf.contains("AllowedIPs = 10.59.0.2/32\n"));
+    assert!(!conf.contains(&key.to_base64()));
+
+    let client = server.client_conf("fe80::1", &peer, &key).unwrap();
+    assert!(client.contains(&format!("PrivateKey = {}\n", key.to_base64())));
+    assert!(client.contains("Address = 10.59.0.2/32\n"));
+    assert!(client.contains("DNS = 10.59.0.1\n"));
+    assert!(client.contains(&format!(
+        "PublicKey = {}\n",
+        server.key.public_key().unwrap()
+    )));
+    assert!(client.contains("Endpoint = [fe80::1]:51820\n"));
+    assert!(client.contains("AllowedIPs = 10.59.0.0/24, 172.18.0.0/24\n"));
+}
@@ -22,6 +22,7 @@ use tracing::instrument;
 
 use super::ProcedureName;
 use crate::context::RpcContext;
+use crate::manager::resources::slice_name;
 use crate::prelude::*;
 use crate::s9pk::manifest::PackageId;
 use crate::util::docker::{remove_container, CONTAINER_TOOL};
@@ -236,6 +237,7 @@ impl DockerProcedure {
             .arg("--rm")
             .arg("--network=start9")
             .arg(format!("--add-host=embassy:{}", Ipv4Addr::from(HOST_IP)))
+            .arg(format!("--dns={}", Ipv4Addr::from(HOST_IP)))
             .arg("--name")
             .arg(&container_name)
             .arg(format!("--hostname={}", &container_name))
@@ -733,6 +735,7 @@ impl DockerProcedure {
         }
         res.push(OsStr::new("--interactive").into());
         res.push(OsStr::new("--log-driver=journald").into());
+        res.push(OsString::from(format!("--cgroup-parent={}", slice_name(pkg_id))).into());
         res.push(OsStr::new("--entrypoint").into());
         res.push(OsStr::new(&self.entrypoint).into());
         if self.system {
@@ -750,7 +753,7 @@ impl DockerProcedure {
         Vec::with_capacity(
             (2 * self.mounts.len()) // --mount <MOUNT_ARG>
                 + (2 * self.shm_size_mb.is_some() as usize) // --shm-size <SHM_SIZE>
-                + 5 // --interactive --log-driver=journald --entrypoint <ENTRYPOINT> <IMAGE>
+                + 6 // --interactive --log-driver=journald --cgroup-parent=<SLICE> --entrypoint <ENTRYPOINT> <IMAGE>
                 + self.args.len(), // [ARG...]
         )
     }
@@ -836,6 +839,7 @@ impl LongRunning {
         cmd.arg("run")
             .arg("--network=start9")
             .arg(format!("--add-host=embassy:{}", Ipv4Addr::from(HOST_IP)))
+            .arg(format!("--dns={}", Ipv4Addr::from(HOST_IP)))
             .arg("--mount")
             .arg(format!(
                 "type=bind,src={BIND_LOCATION},dst=/start9/bin/,readonly"
@@ -848,6 +852,7 @@ impl LongRunning {
             .arg("--name")
             .arg(container_name)
             .arg(format!("--hostname={}", &container_name))
+            .arg(format!("--cgroup-parent={}", slice_name(pkg_id)))
             .arg("--entrypoint")
             .arg(format!("{INIT_EXEC}.{image_architecture}"))
             .arg("-i")
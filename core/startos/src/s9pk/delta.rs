@@ -0,0 +1,425 @@
+//! Binary diffs between two versions of an s9pk.
+//!
+//! A delta is a header followed by a zstd compressed stream of ops, each of which either copies a range of
+//! the source s9pk or inserts new bytes. Applying the ops in order produces the target s9pk.
+//! See `delta.md`.
+
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use async_compression::tokio::bufread::ZstdDecoder;
+use async_compression::tokio::write::ZstdEncoder;
+use async_stream::try_stream;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::instrument;
+
+use super::reader::S9pkReader;
+use super::v2::varint::{deserialize_varint, serialize_varint};
+use crate::prelude::*;
+
+pub const MAGIC: [u8; 4] = *b"s9dl";
+pub const VERSION: u8 = 1;
+
+const OP_END: u64 = 0x00;
+const OP_COPY: u64 = 0x01;
+const OP_INSERT: u64 = 0x02;
+
+/// Granularity at which ranges of the source are matched
+const BLOCK_SIZE: usize = 8 * 1024;
+/// Inserts are split so that a diff never buffers more than this much of the target
+const MAX_INSERT: usize = 1024 * 1024;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The hashes are those given by [`S9pkReader::hash`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeltaHeader {
+    pub source_hash: [u8; 64],
+    pub target_hash: [u8; 64],
+    pub target_len: u64,
+}
+impl DeltaHeader {
+    pub fn serialize(&self, w: &mut Vec<u8>) {
+        w.extend_from_slice(&MAGIC);
+        serialize_varint(VERSION as u64, w);
+        w.extend_from_slice(&self.source_hash);
+        w.extend_from_slice(&self.target_hash);
+        w.extend_from_slice(&self.target_len.to_be_bytes());
+    }
+
+    pub async fn deserialize<R: AsyncRead + Unpin>(mut reader: R) -> Result<Self, Error> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic).await?;
+        if magic != MAGIC {
+            return Err(Error::new(
+                eyre!("Incorrect Magic: {:?}", magic),
+                ErrorKind::ParseS9pk,
+            ));
+        }
+        let version = deserialize_varint(&mut reader).await?;
+        if version != VERSION as u64 {
+            return Err(Error::new(
+                eyre!("Unknown Delta Version: {}", version),
+                ErrorKind::ParseS9pk,
+            ));
+        }
+        let mut source_hash = [0; 64];
+        reader.read_exact(&mut source_hash).await?;
+        let mut target_hash = [0; 64];
+        reader.read_exact(&mut target_hash).await?;
+        let target_len = reader.read_u64().await?;
+        Ok(DeltaHeader {
+            source_hash,
+            target_hash,
+            target_len,
+        })
+    }
+}
+
+/// The hash an s9pk is signed over, after checking the signature
+pub async fn signed_hash(path: &Path) -> Result<[u8; 64], Error> {
+    let rdr = S9pkReader::open(path, true).await?;
+    let hash = rdr
+        .hash()
+        .ok_or_else(|| Error::new(eyre!("s9pk hash missing"), ErrorKind::ParseS9pk))?;
+    let mut res = [0; 64];
+    res.copy_from_slice(hash.as_slice());
+    Ok(res)
+}
+
+/// The weak checksum from rsync: cheap to roll forward one byte at a time
+#[derive(Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+}
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, byte) in block.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((block.len() - i) as u32 * *byte as u32);
+        }
+        Rolling { a, b }
+    }
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self
+            .b
+            .wrapping_sub(BLOCK_SIZE as u32 * out as u32)
+            .wrapping_add(self.a);
+    }
+    fn digest(&self) -> u32 {
+        (self.b << 16) | (self.a & 0xffff)
+    }
+}
+
+struct OpWriter<W: AsyncWrite + Unpin> {
+    writer: ZstdEncoder<W>,
+    copy: Option<(u64, u64)>,
+}
+impl<W: AsyncWrite + Unpin> OpWriter<W> {
+    async fn copy(&mut self, position: u64, length: u64) -> Result<(), Error> {
+        match &mut self.copy {
+            Some((pos, len)) if *pos + *len == position => *len += length,
+            _ => {
+                self.flush_copy().await?;
+                self.copy = Some((position, length));
+            }
+        }
+        Ok(())
+    }
+    async fn flush_copy(&mut self) -> Result<(), Error> {
+        if let Some((position, length)) = self.copy.take() {
+            let mut op = Vec::new();
+            serialize_varint(OP_COPY, &mut op);
+            serialize_varint(position, &mut op);
+            serialize_varint(length, &mut op);
+            self.writer.write_all(&op).await?;
+        }
+        Ok(())
+    }
+    async fn insert(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.flush_copy().await?;
+        let mut op = Vec::new();
+        serialize_varint(OP_INSERT, &mut op);
+        serialize_varint(data.len() as u64, &mut op);
+        self.writer.write_all(&op).await?;
+        self.writer.write_all(data).await?;
+        Ok(())
+    }
+    async fn finish(mut self) -> Result<W, Error> {
+        self.flush_copy().await?;
+        let mut op = Vec::new();
+        serialize_varint(OP_END, &mut op);
+        self.writer.write_all(&op).await?;
+        self.writer.shutdown().await?;
+        Ok(self.writer.into_inner())
+    }
+}
+
+/// Writes a delta that turns `source` into `target`. Both must be validly signed.
+#[instrument(skip_all)]
+pub async fn diff<W: AsyncWrite + Unpin>(
+    source: &Path,
+    target: &Path,
+    mut writer: W,
+) -> Result<(), Error> {
+    let target_len = tokio::fs::metadata(target).await?.len();
+    let header = DeltaHeader {
+        source_hash: signed_hash(source).await?,
+        target_hash: signed_hash(target).await?,
+        target_len,
+    };
+
+    // index every aligned block of the source by its weak checksum
+    let mut index: HashMap<u32, Vec<(u64, blake3::Hash)>> = HashMap::new();
+    let mut src = BufReader::new(File::open(source).await?);
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut position = 0;
+    loop {
+        let n = read_full(&mut src, &mut block).await?;
+        if n < BLOCK_SIZE {
+            break;
+        }
+        index
+            .entry(Rolling::new(&block).digest())
+            .or_default()
+            .push((position, blake3::hash(&block)));
+        position += BLOCK_SIZE as u64;
+    }
+
+    let mut header_bytes = Vec::new();
+    header.serialize(&mut header_bytes);
+    writer.write_all(&header_bytes).await?;
+    let mut ops = OpWriter {
+        writer: ZstdEncoder::new(writer),
+        copy: None,
+    };
+
+    let mut tgt = File::open(target).await?;
+    let mut buf: Vec<u8> = Vec::new();
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut eof = false;
+    // buf[start..pos] is pending insertion, buf[pos..pos + BLOCK_SIZE] is the window being matched
+    let mut start = 0;
+    let mut pos = 0;
+    let mut rolling: Option<Rolling> = None;
+    loop {
+        while !eof && buf.len() - pos <= BLOCK_SIZE {
+            if start > 0 {
+                buf.drain(..start);
+                pos -= start;
+                start = 0;
+            }
+            let n = tgt.read(&mut chunk).await?;
+            eof = n == 0;
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        if buf.len() - pos < BLOCK_SIZE {
+            break;
+        }
+        let window = &buf[pos..pos + BLOCK_SIZE];
+        let weak = *rolling.get_or_insert_with(|| Rolling::new(window));
+        let found = match index.get(&weak.digest()) {
+            Some(candidates) => {
+                let strong = blake3::hash(window);
+                candidates
+                    .iter()
+                    .find(|(_, hash)| *hash == strong)
+                    .map(|(position, _)| *position)
+            }
+            None => None,
+        };
+        if let Some(position) = found {
+            ops.insert(&buf[start..pos]).await?;
+            ops.copy(position, BLOCK_SIZE as u64).await?;
+            pos += BLOCK_SIZE;
+            start = pos;
+            rolling = None;
+            continue;
+        }
+        if pos + BLOCK_SIZE == buf.len() {
+            // only possible at eof
+            break;
+        }
+        if let Some(rolling) = &mut rolling {
+            rolling.roll(buf[pos], buf[pos + BLOCK_SIZE]);
+        }
+        pos += 1;
+        if pos - start >= MAX_INSERT {
+            ops.insert(&buf[start..pos]).await?;
+            start = pos;
+        }
+    }
+    ops.insert(&buf[start..]).await?;
+    ops.finish().await?.flush().await?;
+
+    Ok(())
+}
+
+async fn read_full<R: AsyncRead + Unpin>(rdr: &mut R, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut n = 0;
+    while n < buf.len() {
+        let read = rdr.read(&mut buf[n..]).await?;
+        if read == 0 {
+            break;
+        }
+        n += read;
+    }
+    Ok(n)
+}
+
+/// Reconstructs the target of a delta from its source, after checking that the source is the one the delta expects.
+/// The hash of the result must still be checked against the `target_hash` of the header.
+pub fn apply<R: AsyncRead + Unpin + Send + 'static>(
+    source: PathBuf,
+    header: DeltaHeader,
+    delta: R,
+) -> BoxStream<'static, Result<Bytes, Error>> {
+    try_stream! {
+        if signed_hash(&source).await? != header.source_hash {
+            Err(Error::new(
+                eyre!("Installed s9pk does not match the source of the delta"),
+                ErrorKind::ValidateS9pk,
+            ))?;
+        }
+        let mut src = File::open(&source)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, source.display().to_string()))?;
+        let mut ops = ZstdDecoder::new(BufReader::new(delta));
+        let mut written = 0;
+        loop {
+            let (rdr, length) =
+                match deserialize_varint(&mut ops).await? {
+                    OP_END => break,
+                    OP_COPY => {
+                        let position = deserialize_varint(&mut ops).await?;
+                        let length = deserialize_varint(&mut ops).await?;
+                        src.seek(SeekFrom::Start(position)).await?;
+                        (&mut src as &mut (dyn AsyncRead + Unpin + Send), length)
+                    }
+                    OP_INSERT => {
+                        let length = deserialize_varint(&mut ops).await?;
+                        (&mut ops as &mut (dyn AsyncRead + Unpin + Send), length)
+                    }
+                    op => Err(Error::new(
+                        eyre!("Unknown Delta Op: {}", op),
+                        ErrorKind::ParseS9pk,
+                    ))?,
+                };
+            let mut rdr = rdr.take(length);
+            let mut copied = 0;
+            loop {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let n = rdr.read(&mut chunk).await?;
+                if n == 0 {
+                    break;
+                }
+                chunk.truncate(n);
+                copied += n as u64;
+                yield Bytes::from(chunk);
+            }
+            if copied != length {
+                Err(Error::new(
+                    eyre!("Delta Op Exceeds End of Input"),
+                    ErrorKind::ParseS9pk,
+                ))?;
+            }
+            written += length;
+        }
+        if written != header.target_len {
+            Err(Error::new(
+                eyre!(
+                    "Reconstructed {} bytes, expected {}",
+                    written,
+                    header.target_len
+                ),
+                ErrorKind::ParseS9pk,
+            ))?;
+        }
+    }
+    .boxed()
+}
+
+#[test]
+fn rolling_matches_fresh() {
+    let data = (0..BLOCK_SIZE * 2)
+        .map(|i| (i * 31 % 251) as u8)
+        .collect::<Vec<_>>();
+    let mut rolling = Rolling::new(&data[..BLOCK_SIZE]);
+    for i in 0..BLOCK_SIZE {
+        rolling.roll(data[i], data[i + BLOCK_SIZE]);
+        assert_eq!(
+            rolling.digest(),
+            Rolling::new(&data[i + 1..i + 1 + BLOCK_SIZE]).digest()
+        );
+    }
+}
+
+#[cfg(test)]
+async fn test_s9pk(path: &Path, body: &[u8]) {
+    use ed25519_dalek::SigningKey;
+    use sha2::{Digest, Sha512};
+
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let mut header = super::header::Header::placeholder();
+    header.pubkey = key.verifying_key();
+    header.signature = key
+        .sign_prehashed(Sha512::new_with_prefix(body), Some(super::SIG_CONTEXT))
+        .unwrap();
+    let mut file = Vec::new();
+    header.serialize(&mut file).await.unwrap();
+    file.extend_from_slice(body);
+    tokio::fs::write(path, file).await.unwrap();
+}
+
+#[tokio::test]
+async fn diff_apply_roundtrip() {
+    let dir = std::env::temp_dir().join(format!("s9pk-delta-{:016x}", rand::random::<u64>()));
+    tokio::fs::create_dir_all(&dir).await.unwrap();
+    let source_body = (0..BLOCK_SIZE * 40)
+        .map(|i| (i * 31 % 251) as u8)
+        .collect::<Vec<_>>();
+    // an insertion that shifts everything after it, a changed block, and new bytes at the end
+    let mut target_body = source_body[..BLOCK_SIZE * 10 + 5].to_vec();
+    target_body.extend_from_slice(b"inserted");
+    target_body.extend_from_slice(&source_body[BLOCK_SIZE * 10 + 5..BLOCK_SIZE * 20]);
+    target_body.extend(std::iter::repeat(0xff).take(BLOCK_SIZE));
+    target_body.extend_from_slice(&source_body[BLOCK_SIZE * 21..]);
+    target_body.extend_from_slice(b"appended");
+    let source = dir.join("source.s9pk");
+    let target = dir.join("target.s9pk");
+    test_s9pk(&source, &source_body).await;
+    test_s9pk(&target, &target_body).await;
+
+    let mut delta = Vec::new();
+    diff(&source, &target, &mut delta).await.unwrap();
+    let target_bytes = tokio::fs::read(&target).await.unwrap();
+    assert!(delta.len() < target_bytes.len() / 4);
+    let mut rdr = std::io::Cursor::new(delta);
+    let header = DeltaHeader::deserialize(&mut rdr).await.unwrap();
+    assert_eq!(header.target_hash, signed_hash(&target).await.unwrap());
+    assert_eq!(header.target_len, target_bytes.len() as u64);
+
+    let applied = apply(source.clone(), header.clone(), rdr.clone())
+        .map(|chunk| chunk.unwrap())
+        .collect::<Vec<_>>()
+        .await
+        .concat();
+    assert_eq!(applied, target_bytes);
+
+    // the installed s9pk is not the source of the delta, so installing falls back to the full download
+    let res = apply(target.clone(), header, rdr).collect::<Vec<_>>().await;
+    assert!(matches!(res.first(), Some(Err(_))));
+
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
@@ -104,6 +104,19 @@ impl TableOfContents {
             .await?;
         Ok(())
     }
+    /// Looks up a section by its label
+    pub fn section(&self, label: &str) -> Option<FileSection> {
+        match label {
+            "manifest" => Some(self.manifest),
+            "license" => Some(self.license),
+            "instructions" => Some(self.instructions),
+            "icon" => Some(self.icon),
+            "docker_images" => Some(self.docker_images),
+            "assets" => Some(self.assets),
+            "scripts" => self.scripts,
+            _ => None,
+        }
+    }
     pub async fn deserialize<R: AsyncRead + Unpin>(mut reader: R) -> std::io::Result<Self> {
         let mut toc_len = [0; 4];
         reader.read_exact(&mut toc_len).await?;
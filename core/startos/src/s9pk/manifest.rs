@@ -11,12 +11,14 @@ use crate::action::Actions;
 use crate::backup::BackupActions;
 use crate::config::action::ConfigActions;
 use crate::dependencies::Dependencies;
+use crate::manager::resources::ResourceLimits;
 use crate::migration::Migrations;
 use crate::net::interface::Interfaces;
 use crate::prelude::*;
 use crate::procedure::docker::DockerContainers;
 use crate::procedure::PackageProcedure;
 use crate::status::health_check::HealthChecks;
+use crate::status::restart::RestartPolicy;
 use crate::util::serde::Regex;
 use crate::util::Version;
 use crate::version::{Current, VersionT};
@@ -76,6 +78,12 @@ pub struct Manifest {
 
     #[serde(default)]
     pub hardware_requirements: HardwareRequirements,
+
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+
+    #[serde(default)]
+    pub resource_limits: ResourceLimits,
 }
 
 impl Manifest {
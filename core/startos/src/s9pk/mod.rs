@@ -6,7 +6,7 @@ use futures::TryStreamExt;
 use imbl::OrdMap;
 use rpc_toolkit::command;
 use serde_json::Value;
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncWriteExt};
 use tracing::instrument;
 
 use crate::context::SdkContext;
@@ -15,6 +15,7 @@ use crate::s9pk::docker::DockerMultiArch;
 use crate::s9pk::git_hash::GitHash;
 use crate::s9pk::manifest::Manifest;
 use crate::s9pk::reader::S9pkReader;
+use crate::s9pk::v2::writer::S9pkV2Writer;
 use crate::util::display_none;
 use crate::util::io::BufferedWriteReader;
 use crate::util::serde::IoFormat;
@@ -22,11 +23,13 @@ use crate::volume::Volume;
 use crate::{Error, ErrorKind, ResultExt};
 
 pub mod builder;
+pub mod delta;
 pub mod docker;
 pub mod git_hash;
 pub mod header;
 pub mod manifest;
 pub mod reader;
+pub mod v2;
 
 pub const SIG_CONTEXT: &[u8] = b"s9pk";
 
@@ -203,6 +206,93 @@ pub async fn verify(#[arg] path: PathBuf) -> Result<(), Error> {
     Ok(())
 }
 
+/// Repacks a v1 s9pk in the v2 format, signed with the developer key
+#[command(cli_only, display(display_none))]
+#[instrument(skip_all)]
+pub async fn convert(
+    #[context] ctx: SdkContext,
+    #[arg] path: PathBuf,
+    #[arg] output: Option<PathBuf>,
+) -> Result<(), Error> {
+    let mut s9pk = S9pkReader::open(&path, true).await?;
+    if s9pk.version() != header::VERSION {
+        return Err(Error::new(
+            eyre!("{} is not a v1 s9pk", path.display()),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let output = output.unwrap_or_else(|| path.with_extension("v2.s9pk"));
+    let body_path = output.with_extension("body");
+    let res = async {
+        let mut outfile = tokio::fs::File::create(&output).await?;
+        let mut writer = S9pkV2Writer::new(&mut outfile, &body_path).await?;
+        v2::convert::convert(&mut s9pk, &mut writer).await?;
+        writer.finish(&ctx.developer_key()?).await?;
+        outfile.sync_all().await?;
+        Ok::<_, Error>(())
+    }
+    .await;
+    if tokio::fs::metadata(&body_path).await.is_ok() {
+        tokio::fs::remove_file(&body_path).await?;
+    }
+    res
+}
+
+/// Adds a countersignature by the developer key to a v2 s9pk, e.g. when publishing it to a registry
+#[command(cli_only, display(display_none))]
+#[instrument(skip_all)]
+pub async fn countersign(#[context] ctx: SdkContext, #[arg] path: PathBuf) -> Result<(), Error> {
+    let mut file = tokio::fs::File::open(&path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+    let mut header = v2::Header::deserialize(&mut file).await.map_err(|e| {
+        Error::new(
+            eyre!("{} is not a v2 s9pk: {}", path.display(), e.source),
+            ErrorKind::InvalidRequest,
+        )
+    })?;
+    header.verify()?;
+    header.countersign(&ctx.developer_key()?);
+
+    let tmp_path = path.with_extension("s9pk.tmp");
+    let res = async {
+        let mut outfile = tokio::fs::File::create(&tmp_path).await?;
+        outfile.write_all(&header.to_vec()).await?;
+        tokio::io::copy(&mut file, &mut outfile).await?;
+        outfile.sync_all().await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok::<_, Error>(())
+    }
+    .await;
+    if res.is_err() && tokio::fs::metadata(&tmp_path).await.is_ok() {
+        tokio::fs::remove_file(&tmp_path).await?;
+    }
+    res
+}
+
+/// Writes a delta from one version of a package to another, for a registry to serve alongside the s9pk.
+/// See `delta.md`.
+#[command(rename = "delta", cli_only, display(display_none))]
+#[instrument(skip_all)]
+pub async fn make_delta(
+    #[arg] source: PathBuf,
+    #[arg] target: PathBuf,
+    #[arg] output: Option<PathBuf>,
+) -> Result<(), Error> {
+    let output = output.unwrap_or_else(|| target.with_extension("s9pk.delta"));
+    let res = async {
+        let mut outfile = tokio::fs::File::create(&output).await?;
+        delta::diff(&source, &target, &mut outfile).await?;
+        outfile.sync_all().await?;
+        Ok::<_, Error>(())
+    }
+    .await;
+    if res.is_err() && tokio::fs::metadata(&output).await.is_ok() {
+        tokio::fs::remove_file(&output).await?;
+    }
+    res
+}
+
 fn enumerate_extra_keys(reference: &Value, candidate: &Value) -> Vec<String> {
     match (reference, candidate) {
         (Value::Object(m_r), Value::Object(m_c)) => {
@@ -18,11 +18,13 @@ use tracing::instrument;
 
 use super::header::{FileSection, Header, TableOfContents};
 use super::manifest::{Manifest, PackageId};
+use super::v2::file::FileReader;
+use super::v2::toc::DirectoryContents;
 use super::SIG_CONTEXT;
 use crate::install::progress::InstallProgressTracker;
 use crate::s9pk::docker::DockerReader;
 use crate::util::Version;
-use crate::{Error, ResultExt};
+use crate::{Error, ErrorKind, ResultExt};
 
 const MAX_REPLACES: usize = 10;
 const MAX_TITLE_LEN: usize = 30;
@@ -84,6 +86,65 @@ impl<'a, R: AsyncSeek + Unpin> AsyncSeek for ReadHandle<'a, R> {
     }
 }
 
+/// A file of the package: a raw section of a v1 s9pk, or a (possibly compressed) file of a v2 s9pk
+#[pin_project::pin_project(project = SectionReaderProject)]
+#[derive(Debug)]
+pub enum SectionReader<'a, R: AsyncRead + Unpin = File> {
+    V1(#[pin] ReadHandle<'a, R>),
+    V2(#[pin] FileReader<ReadHandle<'a, R>>),
+}
+impl<'a, R: AsyncRead + Unpin> SectionReader<'a, R> {
+    pub async fn to_vec(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            SectionReader::V1(rdr) => rdr.to_vec().await,
+            SectionReader::V2(mut rdr) => {
+                let mut buf = Vec::new();
+                rdr.read_to_end(&mut buf).await?;
+                Ok(buf)
+            }
+        }
+    }
+}
+impl<'a, R: AsyncRead + Unpin> AsyncRead for SectionReader<'a, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.project() {
+            SectionReaderProject::V1(r) => r.poll_read(cx, buf),
+            SectionReaderProject::V2(r) => r.poll_read(cx, buf),
+        }
+    }
+}
+/// Compressed files cannot be seeked
+impl<'a, R: AsyncRead + AsyncSeek + Unpin> AsyncSeek for SectionReader<'a, R> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        match self.project() {
+            SectionReaderProject::V1(r) => r.start_seek(position),
+            SectionReaderProject::V2(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "cannot seek within a v2 s9pk file",
+            )),
+        }
+    }
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        match self.project() {
+            SectionReaderProject::V1(r) => r.poll_complete(cx),
+            SectionReaderProject::V2(_) => Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "cannot seek within a v2 s9pk file",
+            ))),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Toc {
+    V1(TableOfContents),
+    V2(DirectoryContents),
+}
+
 #[derive(Debug)]
 pub struct ImageTag {
     pub package_id: PackageId,
@@ -148,7 +209,9 @@ pub struct S9pkReader<R: AsyncRead + AsyncSeek + Unpin + Send + Sync = File> {
     hash: Option<Output<Sha512>>,
     hash_string: Option<String>,
     developer_key: VerifyingKey,
-    toc: TableOfContents,
+    /// Keys whose signatures have been verified: empty unless opened with `check_sig`
+    signers: Vec<VerifyingKey>,
+    toc: Toc,
     pos: u64,
     rdr: R,
 }
@@ -170,7 +233,8 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<InstallProgressT
 impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
     #[instrument(skip_all)]
     pub async fn validate(&mut self) -> Result<(), Error> {
-        if self.toc.icon.length > 102_400 {
+        self.verify_contents().await?;
+        if self.file_size("icon")? > 102_400 {
             // 100 KiB
             return Err(Error::new(
                 eyre!("icon must be less than 100KiB"),
@@ -207,9 +271,16 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
                 &validated_image_ids,
             )?;
         }
-        man.health_checks
-            .validate(&man.eos_version, &man.volumes, &validated_image_ids)?;
+        man.health_checks.validate(
+            &man.eos_version,
+            &man.volumes,
+            &validated_image_ids,
+            &man.actions,
+        )?;
         man.interfaces.validate()?;
+        man.resource_limits
+            .validate()
+            .with_ctx(|_| (crate::ErrorKind::ValidateS9pk, "Resource Limits"))?;
         man.main
             .validate(&man.eos_version, &man.volumes, &validated_image_ids, false)
             .with_ctx(|_| (crate::ErrorKind::ValidateS9pk, "Main"))?;
@@ -296,9 +367,16 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
     }
     #[instrument(skip_all)]
     pub async fn from_reader(mut rdr: R, check_sig: bool) -> Result<Self, Error> {
+        let mut prefix = [0; 3];
+        rdr.read_exact(&mut prefix).await?;
+        rdr.seek(SeekFrom::Start(0)).await?;
+        if prefix[2] == super::v2::VERSION {
+            return Self::from_reader_v2(rdr, check_sig).await;
+        }
+
         let header = Header::deserialize(&mut rdr).await?;
 
-        let (hash, hash_string) = if check_sig {
+        let (hash, hash_string, signers) = if check_sig {
             let mut hasher = Sha512::new();
             let mut buf = [0; 1024];
             let mut read;
@@ -318,9 +396,10 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
                     base32::Alphabet::RFC4648 { padding: false },
                     hash.as_slice(),
                 )),
+                vec![header.pubkey],
             )
         } else {
-            (None, None)
+            (None, None, Vec::new())
         };
 
         let pos = rdr.stream_position().await?;
@@ -329,12 +408,57 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
             hash_string,
             hash,
             developer_key: header.pubkey,
-            toc: header.table_of_contents,
+            signers,
+            toc: Toc::V1(header.table_of_contents),
             pos,
             rdr,
         })
     }
 
+    /// Only reads the header: the TOC commits to the hash of every file, which is checked as it is read
+    #[instrument(skip_all)]
+    async fn from_reader_v2(mut rdr: R, check_sig: bool) -> Result<Self, Error> {
+        let mut header = super::v2::Header::deserialize(&mut rdr).await?;
+
+        // countersignatures are left out of the hash, so countersigning does not change it
+        let (hash, hash_string, signers) = if check_sig {
+            header.verify()?;
+            let hash = Sha512::digest(&header.signed_bytes());
+            (
+                Some(hash),
+                Some(base32::encode(
+                    base32::Alphabet::RFC4648 { padding: false },
+                    hash.as_slice(),
+                )),
+                header.signers(),
+            )
+        } else {
+            (None, None, Vec::new())
+        };
+
+        let pos = rdr.stream_position().await?;
+        // positions in the TOC are relative to the end of the header
+        header.toc.offset_positions(pos);
+
+        Ok(S9pkReader {
+            hash_string,
+            hash,
+            developer_key: header.pubkey,
+            signers,
+            toc: Toc::V2(header.toc),
+            pos,
+            rdr,
+        })
+    }
+
+    /// The s9pk format version
+    pub fn version(&self) -> u8 {
+        match self.toc {
+            Toc::V1(_) => super::header::VERSION,
+            Toc::V2(_) => super::v2::VERSION,
+        }
+    }
+
     pub fn hash(&self) -> Option<&Output<Sha512>> {
         self.hash.as_ref()
     }
@@ -347,6 +471,11 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
         &self.developer_key
     }
 
+    /// The developer key, followed by any countersigners
+    pub fn signers(&self) -> &[VerifyingKey] {
+        &self.signers
+    }
+
     pub async fn reset(&mut self) -> Result<(), Error> {
         self.rdr.seek(SeekFrom::Start(0)).await?;
         Ok(())
@@ -367,8 +496,80 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
         })
     }
 
-    pub async fn manifest_raw(&mut self) -> Result<ReadHandle<'_, R>, Error> {
-        self.read_handle(self.toc.manifest).await
+    /// Opens a file by its path in the TOC.
+    /// The sections of a v1 s9pk are available as top level files under their v1 labels.
+    pub async fn file(&mut self, path: &str) -> Result<SectionReader<'_, R>, Error> {
+        match &self.toc {
+            Toc::V1(toc) => {
+                let section = toc.section(path).ok_or_else(|| {
+                    Error::new(
+                        eyre!("Missing Required Label: {}", path),
+                        ErrorKind::ParseS9pk,
+                    )
+                })?;
+                Ok(SectionReader::V1(self.read_handle(section).await?))
+            }
+            Toc::V2(toc) => {
+                let file = toc.get_file(path)?.clone();
+                let handle = self
+                    .read_handle(FileSection {
+                        position: file.position,
+                        length: file.length,
+                    })
+                    .await?;
+                Ok(SectionReader::V2(FileReader::new(handle, &file)))
+            }
+        }
+    }
+
+    pub fn has_file(&self, path: &str) -> bool {
+        match &self.toc {
+            Toc::V1(toc) => toc.section(path).is_some(),
+            Toc::V2(toc) => toc.get_file(path).is_ok(),
+        }
+    }
+
+    /// Size of a file once decompressed
+    pub fn file_size(&self, path: &str) -> Result<u64, Error> {
+        match &self.toc {
+            Toc::V1(toc) => toc.section(path).map(|s| s.length).ok_or_else(|| {
+                Error::new(
+                    eyre!("Missing Required Label: {}", path),
+                    ErrorKind::ParseS9pk,
+                )
+            }),
+            Toc::V2(toc) => Ok(toc.get_file(path)?.size),
+        }
+    }
+
+    /// Reads a single file to check it against its hash, without reading the rest of the archive.
+    pub async fn verify_file(&mut self, path: &str) -> Result<(), Error> {
+        tokio::io::copy(&mut self.file(path).await?, &mut tokio::io::sink())
+            .await
+            .with_ctx(|_| (ErrorKind::ValidateS9pk, path.to_owned()))?;
+        Ok(())
+    }
+
+    /// Checks every file of a v2 s9pk against its hash.
+    /// A v1 s9pk can only be checked as a whole, which happens when it is opened.
+    #[instrument(skip_all)]
+    pub async fn verify_contents(&mut self) -> Result<(), Error> {
+        let paths = match &self.toc {
+            Toc::V1(_) => return Ok(()),
+            Toc::V2(toc) => toc
+                .files()
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect::<Vec<_>>(),
+        };
+        for path in paths {
+            self.verify_file(&path).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn manifest_raw(&mut self) -> Result<SectionReader<'_, R>, Error> {
+        self.file("manifest").await
     }
 
     pub async fn manifest(&mut self) -> Result<Manifest, Error> {
@@ -377,30 +578,38 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
             .with_ctx(|_| (crate::ErrorKind::ParseS9pk, "Deserializing Manifest (CBOR)"))
     }
 
-    pub async fn license(&mut self) -> Result<ReadHandle<'_, R>, Error> {
-        self.read_handle(self.toc.license).await
+    pub async fn license(&mut self) -> Result<SectionReader<'_, R>, Error> {
+        self.file("license").await
     }
 
-    pub async fn instructions(&mut self) -> Result<ReadHandle<'_, R>, Error> {
-        self.read_handle(self.toc.instructions).await
+    pub async fn instructions(&mut self) -> Result<SectionReader<'_, R>, Error> {
+        self.file("instructions").await
     }
 
-    pub async fn icon(&mut self) -> Result<ReadHandle<'_, R>, Error> {
-        self.read_handle(self.toc.icon).await
+    pub async fn icon(&mut self) -> Result<SectionReader<'_, R>, Error> {
+        self.file("icon").await
     }
 
-    pub async fn docker_images(&mut self) -> Result<DockerReader<ReadHandle<'_, R>>, Error> {
-        DockerReader::new(self.read_handle(self.toc.docker_images).await?).await
+    pub async fn docker_images(&mut self) -> Result<DockerReader<SectionReader<'_, R>>, Error> {
+        let v2_path = match &self.toc {
+            Toc::V1(_) => None,
+            Toc::V2(toc) => Some(super::v2::docker_images_path(toc)?),
+        };
+        match v2_path {
+            None => DockerReader::new(self.file("docker_images").await?).await,
+            Some(path) => Ok(DockerReader::SingleArch(self.file(&path).await?)),
+        }
     }
 
-    pub async fn assets(&mut self) -> Result<ReadHandle<'_, R>, Error> {
-        self.read_handle(self.toc.assets).await
+    pub async fn assets(&mut self) -> Result<SectionReader<'_, R>, Error> {
+        self.file("assets").await
     }
 
-    pub async fn scripts(&mut self) -> Result<Option<ReadHandle<'_, R>>, Error> {
-        Ok(match self.toc.scripts {
-            None => None,
-            Some(a) => Some(self.read_handle(a).await?),
+    pub async fn scripts(&mut self) -> Result<Option<SectionReader<'_, R>>, Error> {
+        Ok(if self.has_file("scripts") {
+            Some(self.file("scripts").await?)
+        } else {
+            None
         })
     }
 }
@@ -0,0 +1,122 @@
+use std::ffi::OsStr;
+use std::path::Path;
+
+use futures::TryStreamExt;
+use models::mime;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
+use tracing::instrument;
+
+use super::toc::Compression;
+use super::writer::S9pkV2Writer;
+use super::DOCKER_IMAGES_DIR;
+use crate::prelude::*;
+use crate::s9pk::reader::S9pkReader;
+
+/// Repacks the sections of a v1 s9pk as files of a v2 s9pk.
+/// Everything but the icon, which is already compressed, is compressed with zstd.
+#[instrument(skip_all)]
+pub async fn convert<R, W>(
+    rdr: &mut S9pkReader<R>,
+    writer: &mut S9pkV2Writer<W>,
+) -> Result<(), Error>
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + Sync,
+    W: AsyncWrite + Unpin,
+{
+    let manifest = rdr.manifest().await?;
+    writer
+        .add_file(
+            "manifest",
+            "application/cbor",
+            Compression::Zstd,
+            rdr.manifest_raw().await?,
+        )
+        .await?;
+    writer
+        .add_file(
+            "license",
+            "text/markdown",
+            Compression::Zstd,
+            rdr.license().await?,
+        )
+        .await?;
+    writer
+        .add_file(
+            "instructions",
+            "text/markdown",
+            Compression::Zstd,
+            rdr.instructions().await?,
+        )
+        .await?;
+    writer
+        .add_file(
+            "icon",
+            mime(manifest.assets.icon_type()).unwrap_or("image/png"),
+            Compression::None,
+            rdr.icon().await?,
+        )
+        .await?;
+
+    // multi-arch sections are a tarball of per-arch tarballs, led by multiarch.cbor
+    let multiarch = {
+        let mut tar = tokio_tar::Archive::new(rdr.file(DOCKER_IMAGES_DIR).await?);
+        let mut entries = tar.entries()?;
+        match entries.try_next().await? {
+            Some(entry) => &*entry.path()? == Path::new("multiarch.cbor"),
+            None => false,
+        }
+    };
+    if multiarch {
+        let mut tar = tokio_tar::Archive::new(rdr.file(DOCKER_IMAGES_DIR).await?);
+        let mut entries = tar.entries()?;
+        while let Some(entry) = entries.try_next().await? {
+            let path = entry.path()?.into_owned();
+            if path.extension() != Some(OsStr::new("tar")) {
+                continue;
+            }
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| {
+                    Error::new(
+                        eyre!("Invalid Docker Image Tarball: {}", path.display()),
+                        ErrorKind::ParseS9pk,
+                    )
+                })?
+                .to_owned();
+            writer
+                .add_file(
+                    &format!("{}/{}", DOCKER_IMAGES_DIR, name),
+                    "application/x-tar",
+                    Compression::Zstd,
+                    entry,
+                )
+                .await?;
+        }
+    } else {
+        writer
+            .add_file(
+                &format!("{}/image.tar", DOCKER_IMAGES_DIR),
+                "application/x-tar",
+                Compression::Zstd,
+                rdr.file(DOCKER_IMAGES_DIR).await?,
+            )
+            .await?;
+    }
+
+    writer
+        .add_file(
+            "assets",
+            "application/x-tar",
+            Compression::Zstd,
+            rdr.assets().await?,
+        )
+        .await?;
+    if let Some(scripts) = rdr.scripts().await? {
+        writer
+            .add_file("scripts", "text/javascript", Compression::Zstd, scripts)
+            .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_compression::tokio::bufread::ZstdDecoder;
+use tokio::io::{AsyncRead, BufReader, ReadBuf};
+
+use super::toc::{Compression, FileContents};
+
+#[pin_project::pin_project(project = DecodedProject)]
+#[derive(Debug)]
+enum Decoded<R: AsyncRead> {
+    Raw(#[pin] R),
+    Zstd(#[pin] ZstdDecoder<BufReader<R>>),
+}
+
+/// Decompresses a file of the archive as it is read.
+/// Reaching the end of a file that does not match the hash in its TOC entry is an error.
+#[pin_project::pin_project]
+#[derive(Debug)]
+pub struct FileReader<R: AsyncRead> {
+    #[pin]
+    inner: Decoded<R>,
+    hasher: blake3::Hasher,
+    hash: [u8; 32],
+    size: u64,
+    read: u64,
+}
+impl<R: AsyncRead> FileReader<R> {
+    pub fn new(rdr: R, file: &FileContents) -> Self {
+        FileReader {
+            inner: match file.compression {
+                Compression::None => Decoded::Raw(rdr),
+                Compression::Zstd => Decoded::Zstd(ZstdDecoder::new(BufReader::new(rdr))),
+            },
+            hasher: blake3::Hasher::new(),
+            hash: file.hash,
+            size: file.size,
+            read: 0,
+        }
+    }
+}
+impl<R: AsyncRead> AsyncRead for FileReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        let start = buf.filled().len();
+        let had_capacity = buf.remaining() > 0;
+        let res = match this.inner.project() {
+            DecodedProject::Raw(r) => r.poll_read(cx, buf),
+            DecodedProject::Zstd(r) => r.poll_read(cx, buf),
+        };
+        if let Poll::Ready(Ok(())) = res {
+            let new = &buf.filled()[start..];
+            *this.read += new.len() as u64;
+            this.hasher.update(new);
+            let eof = new.is_empty() && had_capacity;
+            if *this.read > *this.size
+                || (eof
+                    && (*this.read != *this.size || this.hasher.finalize().as_bytes() != this.hash))
+            {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "file contents do not match the hash in the s9pk TOC",
+                )));
+            }
+        }
+        res
+    }
+}
@@ -0,0 +1,234 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use self::toc::{DirectoryContents, EntryContents};
+use self::varint::{deserialize_varint, serialize_varint};
+use super::header::MAGIC;
+use crate::prelude::*;
+use crate::ARCH;
+
+pub mod convert;
+pub mod file;
+pub mod toc;
+pub mod varint;
+pub mod writer;
+
+pub const VERSION: u8 = 2;
+
+/// Directory holding one docker image tarball per architecture, named `<arch>.tar`
+pub const DOCKER_IMAGES_DIR: &str = "docker_images";
+
+/// Bounds the allocation for countersignatures while parsing an untrusted header
+const MAX_COUNTERSIGNATURES: u64 = 64;
+
+/// A signature by someone other than the developer (e.g. a registry) vouching for the package
+#[derive(Clone, Debug)]
+pub struct Countersignature {
+    pub pubkey: VerifyingKey,
+    /// Signs the BLAKE-3 of the signed portion of the header
+    pub signature: Signature,
+}
+
+/// See `specv2.md`
+#[derive(Debug)]
+pub struct Header {
+    pub pubkey: VerifyingKey,
+    pub toc: DirectoryContents,
+    /// Developer signature of the BLAKE-3 of the signed portion of the header, which covers the whole TOC
+    pub signature: Signature,
+    pub countersignatures: Vec<Countersignature>,
+}
+impl Header {
+    pub fn new(key: &SigningKey, toc: DirectoryContents) -> Self {
+        let mut header = Header {
+            pubkey: key.verifying_key(),
+            toc,
+            signature: Signature::from_bytes(&[0; 64]),
+            countersignatures: Vec::new(),
+        };
+        header.signature = key.sign(blake3::hash(&header.signed_bytes()).as_bytes());
+        header
+    }
+    /// Everything but the countersignatures, which may be added without invalidating the rest
+    fn serialize_signed(&self, w: &mut Vec<u8>) {
+        w.extend_from_slice(&MAGIC);
+        serialize_varint(VERSION as u64, w);
+        w.extend_from_slice(self.pubkey.as_bytes());
+        self.toc.serialize(w);
+    }
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        self.serialize_signed(&mut res);
+        res
+    }
+    pub fn serialize(&self, w: &mut Vec<u8>) {
+        self.serialize_signed(w);
+        w.extend_from_slice(&self.signature.to_bytes());
+        serialize_varint(self.countersignatures.len() as u64, w);
+        for countersig in &self.countersignatures {
+            w.extend_from_slice(countersig.pubkey.as_bytes());
+            w.extend_from_slice(&countersig.signature.to_bytes());
+        }
+    }
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut res = Vec::new();
+        self.serialize(&mut res);
+        res
+    }
+    pub async fn deserialize<R: AsyncRead + Unpin + Send>(mut reader: R) -> Result<Self, Error> {
+        let mut magic = [0; 2];
+        reader.read_exact(&mut magic).await?;
+        if magic != MAGIC {
+            return Err(Error::new(
+                eyre!("Incorrect Magic: {:?}", magic),
+                ErrorKind::ParseS9pk,
+            ));
+        }
+        let version = deserialize_varint(&mut reader).await?;
+        if version != VERSION as u64 {
+            return Err(Error::new(
+                eyre!("Unknown Version: {}", version),
+                ErrorKind::ParseS9pk,
+            ));
+        }
+        let mut pubkey_bytes = [0; 32];
+        reader.read_exact(&mut pubkey_bytes).await?;
+        let pubkey = VerifyingKey::from_bytes(&pubkey_bytes)
+            .map_err(|e| Error::new(e, ErrorKind::ParseS9pk))?;
+        let toc = DirectoryContents::deserialize(&mut reader).await?;
+        let mut signature = [0; 64];
+        reader.read_exact(&mut signature).await?;
+        let signature = Signature::from_bytes(&signature);
+        let count = deserialize_varint(&mut reader).await?;
+        if count > MAX_COUNTERSIGNATURES {
+            return Err(Error::new(
+                eyre!(
+                    "{} countersignatures exceeds maximum of {}",
+                    count,
+                    MAX_COUNTERSIGNATURES
+                ),
+                ErrorKind::ParseS9pk,
+            ));
+        }
+        let mut countersignatures = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let mut pubkey_bytes = [0; 32];
+            reader.read_exact(&mut pubkey_bytes).await?;
+            let mut signature = [0; 64];
+            reader.read_exact(&mut signature).await?;
+            countersignatures.push(Countersignature {
+                pubkey: VerifyingKey::from_bytes(&pubkey_bytes)
+                    .map_err(|e| Error::new(e, ErrorKind::ParseS9pk))?,
+                signature: Signature::from_bytes(&signature),
+            });
+        }
+
+        Ok(Header {
+            pubkey,
+            toc,
+            signature,
+            countersignatures,
+        })
+    }
+
+    /// Checks the developer signature and every countersignature
+    pub fn verify(&self) -> Result<(), Error> {
+        let hash = blake3::hash(&self.signed_bytes());
+        self.pubkey
+            .verify_strict(hash.as_bytes(), &self.signature)
+            .map_err(|e| {
+                Error::new(
+                    eyre!("Invalid Signature: {}", e),
+                    ErrorKind::InvalidSignature,
+                )
+            })?;
+        for countersig in &self.countersignatures {
+            countersig
+                .pubkey
+                .verify_strict(hash.as_bytes(), &countersig.signature)
+                .map_err(|e| {
+                    Error::new(
+                        eyre!("Invalid Countersignature: {}", e),
+                        ErrorKind::InvalidSignature,
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Every key that has signed this header, starting with the developer
+    pub fn signers(&self) -> Vec<VerifyingKey> {
+        std::iter::once(self.pubkey)
+            .chain(self.countersignatures.iter().map(|c| c.pubkey))
+            .collect()
+    }
+
+    /// Adds a countersignature, replacing any previous one by the same key
+    pub fn countersign(&mut self, key: &SigningKey) {
+        let pubkey = key.verifying_key();
+        let signature = key.sign(blake3::hash(&self.signed_bytes()).as_bytes());
+        self.countersignatures.retain(|c| c.pubkey != pubkey);
+        self.countersignatures
+            .push(Countersignature { pubkey, signature });
+    }
+}
+
+/// The image tarball for this architecture.
+/// A package built for a single architecture may name its only tarball anything.
+pub fn docker_images_path(toc: &DirectoryContents) -> Result<String, Error> {
+    let images = match toc.get(DOCKER_IMAGES_DIR) {
+        Some(EntryContents::Directory(images)) => images,
+        _ => {
+            return Err(Error::new(
+                eyre!("Missing Required Entry: {}", DOCKER_IMAGES_DIR),
+                ErrorKind::ParseS9pk,
+            ))
+        }
+    };
+    let native = format!("{}.tar", &**ARCH);
+    if images.0.contains_key(&native) {
+        return Ok(format!("{}/{}", DOCKER_IMAGES_DIR, native));
+    }
+    let mut tars = images.0.keys().filter(|name| name.ends_with(".tar"));
+    match (tars.next(), tars.next()) {
+        (Some(only), None) => Ok(format!("{}/{}", DOCKER_IMAGES_DIR, only)),
+        _ => Err(Error::new(
+            eyre!("Docker images do not contain a tarball for {}", &**ARCH),
+            ErrorKind::ParseS9pk,
+        )),
+    }
+}
+
+#[tokio::test]
+async fn header_signature() {
+    use self::toc::{Compression, FileContents};
+
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let file = |position| FileContents {
+        mime: "application/octet-stream".to_owned(),
+        compression: Compression::Zstd,
+        position,
+        length: 10,
+        size: 20,
+        hash: *blake3::hash(&[0; 20]).as_bytes(),
+    };
+    let mut toc = DirectoryContents::default();
+    toc.insert_file("manifest", file(0)).unwrap();
+    toc.insert_file("docker_images/x86_64.tar", file(10))
+        .unwrap();
+    let mut header = Header::new(&key, toc);
+    header.countersign(&SigningKey::from_bytes(&[8; 32]));
+
+    let parsed = Header::deserialize(header.to_vec().as_slice())
+        .await
+        .unwrap();
+    parsed.verify().unwrap();
+    assert_eq!(parsed.signers().len(), 2);
+
+    // dropping an entry invalidates the developer signature, even though every remaining entry is unchanged
+    header.toc.0.remove("manifest");
+    let parsed = Header::deserialize(header.to_vec().as_slice())
+        .await
+        .unwrap();
+    assert!(parsed.verify().is_err());
+}
@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::varint::{
+    deserialize_varint, deserialize_varstring, serialize_varint, serialize_varstring,
+};
+use crate::prelude::*;
+
+const TYPE_FILE: u64 = 0x01;
+const TYPE_TOC: u64 = 0x02;
+/// Bounds the recursion while parsing an untrusted TOC
+const MAX_DEPTH: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+impl Compression {
+    fn as_u64(self) -> u64 {
+        match self {
+            Compression::None => 0x00,
+            Compression::Zstd => 0x01,
+        }
+    }
+    fn from_u64(n: u64) -> Result<Self, Error> {
+        match n {
+            0x00 => Ok(Compression::None),
+            0x01 => Ok(Compression::Zstd),
+            n => Err(Error::new(
+                eyre!("Unknown Compression: {}", n),
+                ErrorKind::ParseS9pk,
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FileContents {
+    pub mime: String,
+    pub compression: Compression,
+    /// Offset of the stored contents from the end of the header
+    pub position: u64,
+    /// Length of the contents as stored
+    pub length: u64,
+    /// Length of the contents once decompressed
+    pub size: u64,
+    /// BLAKE-3 of the decompressed contents
+    pub hash: [u8; 32],
+}
+
+#[derive(Clone, Debug)]
+pub enum EntryContents {
+    File(FileContents),
+    Directory(DirectoryContents),
+}
+
+impl EntryContents {
+    fn serialize(&self, name: &str, w: &mut Vec<u8>) {
+        serialize_varstring(name, w);
+        match self {
+            EntryContents::File(file) => {
+                serialize_varint(TYPE_FILE, w);
+                serialize_varstring(&file.mime, w);
+                serialize_varint(file.compression.as_u64(), w);
+                w.extend_from_slice(&file.position.to_be_bytes());
+                w.extend_from_slice(&file.length.to_be_bytes());
+                w.extend_from_slice(&file.size.to_be_bytes());
+                w.extend_from_slice(&file.hash);
+            }
+            EntryContents::Directory(dir) => {
+                serialize_varint(TYPE_TOC, w);
+                dir.serialize(w);
+            }
+        }
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(Error::new(
+            eyre!("Invalid Entry Name: {:?}", name),
+            ErrorKind::ParseS9pk,
+        ));
+    }
+    Ok(())
+}
+
+/// A TOC: the root of the archive, or a directory within it.
+/// Paths into it are `/` separated. The whole TOC is signed at once, as part of the header.
+#[derive(Clone, Debug, Default)]
+pub struct DirectoryContents(pub BTreeMap<String, EntryContents>);
+impl DirectoryContents {
+    pub fn serialize(&self, w: &mut Vec<u8>) {
+        serialize_varint(self.0.len() as u64, w);
+        for (name, entry) in &self.0 {
+            entry.serialize(name, w);
+        }
+    }
+
+    pub async fn deserialize<R: AsyncRead + Unpin + Send>(r: &mut R) -> Result<Self, Error> {
+        Self::deserialize_nested(r, 0).await
+    }
+
+    fn deserialize_nested<'a, R: AsyncRead + Unpin + Send>(
+        r: &'a mut R,
+        depth: usize,
+    ) -> BoxFuture<'a, Result<Self, Error>> {
+        async move {
+            if depth > MAX_DEPTH {
+                return Err(Error::new(
+                    eyre!("TOC is nested deeper than {} levels", MAX_DEPTH),
+                    ErrorKind::ParseS9pk,
+                ));
+            }
+            let count = deserialize_varint(r).await?;
+            let mut entries = BTreeMap::new();
+            for _ in 0..count {
+                let name = deserialize_varstring(r).await?;
+                check_name(&name)?;
+                let contents = match deserialize_varint(r).await? {
+                    TYPE_FILE => {
+                        let mime = deserialize_varstring(r).await?;
+                        let compression = Compression::from_u64(deserialize_varint(r).await?)?;
+                        let position = r.read_u64().await?;
+                        let length = r.read_u64().await?;
+                        let size = r.read_u64().await?;
+                        let mut hash = [0; 32];
+                        r.read_exact(&mut hash).await?;
+                        EntryContents::File(FileContents {
+                            mime,
+                            compression,
+                            position,
+                            length,
+                            size,
+                            hash,
+                        })
+                    }
+                    TYPE_TOC => {
+                        EntryContents::Directory(Self::deserialize_nested(r, depth + 1).await?)
+                    }
+                    ty => {
+                        return Err(Error::new(
+                            eyre!("Unknown Entry Type: {}", ty),
+                            ErrorKind::ParseS9pk,
+                        ))
+                    }
+                };
+                if entries.insert(name.clone(), contents).is_some() {
+                    return Err(Error::new(
+                        eyre!("Duplicate Entry: {}", name),
+                        ErrorKind::ParseS9pk,
+                    ));
+                }
+            }
+            Ok(Self(entries))
+        }
+        .boxed()
+    }
+
+    pub fn get(&self, path: &str) -> Option<&EntryContents> {
+        let (name, rest) = match path.split_once('/') {
+            Some((name, rest)) => (name, Some(rest)),
+            None => (path, None),
+        };
+        let entry = self.0.get(name)?;
+        match (rest, entry) {
+            (None, _) => Some(entry),
+            (Some(rest), EntryContents::Directory(dir)) => dir.get(rest),
+            (Some(_), EntryContents::File(_)) => None,
+        }
+    }
+
+    pub fn get_file(&self, path: &str) -> Result<&FileContents, Error> {
+        match self.get(path) {
+            Some(EntryContents::File(file)) => Ok(file),
+            Some(EntryContents::Directory(_)) => Err(Error::new(
+                eyre!("{} is a directory", path),
+                ErrorKind::ParseS9pk,
+            )),
+            None => Err(Error::new(
+                eyre!("Missing Required Entry: {}", path),
+                ErrorKind::ParseS9pk,
+            )),
+        }
+    }
+
+    /// Adds a file, creating its parent directories
+    pub fn insert_file(&mut self, path: &str, file: FileContents) -> Result<(), Error> {
+        match path.split_once('/') {
+            Some((name, rest)) => {
+                check_name(name)?;
+                match self
+                    .0
+                    .entry(name.to_owned())
+                    .or_insert_with(|| EntryContents::Directory(DirectoryContents::default()))
+                {
+                    EntryContents::Directory(dir) => dir.insert_file(rest, file),
+                    EntryContents::File(_) => Err(Error::new(
+                        eyre!("{} is a file", name),
+                        ErrorKind::ParseS9pk,
+                    )),
+                }
+            }
+            None => {
+                check_name(path)?;
+                if self.0.contains_key(path) {
+                    return Err(Error::new(
+                        eyre!("Duplicate Entry: {}", path),
+                        ErrorKind::ParseS9pk,
+                    ));
+                }
+                self.0.insert(path.to_owned(), EntryContents::File(file));
+                Ok(())
+            }
+        }
+    }
+
+    /// Every file below this directory, with its path
+    pub fn files(&self) -> Vec<(String, &FileContents)> {
+        let mut res = Vec::new();
+        for (name, entry) in &self.0 {
+            match entry {
+                EntryContents::File(file) => res.push((name.clone(), file)),
+                EntryContents::Directory(dir) => res.extend(
+                    dir.files()
+                        .into_iter()
+                        .map(|(path, file)| (format!("{}/{}", name, path), file)),
+                ),
+            }
+        }
+        res
+    }
+
+    pub fn offset_positions(&mut self, offset: u64) {
+        for entry in self.0.values_mut() {
+            match entry {
+                EntryContents::File(file) => file.position += offset,
+                EntryContents::Directory(dir) => dir.offset_positions(offset),
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn toc_roundtrip() {
+    let file = |position| FileContents {
+        mime: "application/octet-stream".to_owned(),
+        compression: Compression::Zstd,
+        position,
+        length: 10,
+        size: 20,
+        hash: *blake3::hash(&[0; 20]).as_bytes(),
+    };
+    let mut toc = DirectoryContents::default();
+    toc.insert_file("manifest", file(0)).unwrap();
+    toc.insert_file("docker_images/x86_64.tar", file(10))
+        .unwrap();
+    assert!(toc.insert_file("manifest/nested", file(20)).is_err());
+
+    let mut buf = Vec::new();
+    toc.serialize(&mut buf);
+    let parsed = DirectoryContents::deserialize(&mut buf.as_slice())
+        .await
+        .unwrap();
+    assert_eq!(
+        parsed
+            .get_file("docker_images/x86_64.tar")
+            .unwrap()
+            .position,
+        10
+    );
+    assert_eq!(parsed.files().len(), 2);
+}
+
+#[tokio::test]
+async fn toc_depth_limit() {
+    let nested = |depth| {
+        let mut toc = DirectoryContents::default();
+        let path = vec!["d"; depth]
+            .into_iter()
+            .chain(["file"])
+            .collect::<Vec<_>>()
+            .join("/");
+        toc.insert_file(
+            &path,
+            FileContents {
+                mime: "application/octet-stream".to_owned(),
+                compression: Compression::None,
+                position: 0,
+                length: 0,
+                size: 0,
+                hash: *blake3::hash(&[]).as_bytes(),
+            },
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        toc.serialize(&mut buf);
+        buf
+    };
+    assert!(
+        DirectoryContents::deserialize(&mut nested(MAX_DEPTH).as_slice())
+            .await
+            .is_ok()
+    );
+    assert!(
+        DirectoryContents::deserialize(&mut nested(MAX_DEPTH + 1).as_slice())
+            .await
+            .is_err()
+    );
+}
@@ -0,0 +1,68 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::prelude::*;
+
+/// Bounds the allocation for a string while parsing an untrusted TOC
+const MAX_STRING_LEN: u64 = 4096;
+
+/// LEB128
+pub fn serialize_varint(mut n: u64, w: &mut Vec<u8>) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            w.push(byte);
+            return;
+        }
+        w.push(byte | 0x80);
+    }
+}
+
+pub fn serialize_varstring(s: &str, w: &mut Vec<u8>) {
+    serialize_varint(s.len() as u64, w);
+    w.extend_from_slice(s.as_bytes());
+}
+
+pub async fn deserialize_varint<R: AsyncRead + Unpin>(r: &mut R) -> Result<u64, Error> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = r.read_u8().await?;
+        if shift == 63 && byte > 1 {
+            break;
+        }
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(Error::new(eyre!("Varint Overflow"), ErrorKind::ParseS9pk))
+}
+
+pub async fn deserialize_varstring<R: AsyncRead + Unpin>(r: &mut R) -> Result<String, Error> {
+    let len = deserialize_varint(r).await?;
+    if len > MAX_STRING_LEN {
+        return Err(Error::new(
+            eyre!(
+                "String of length {} exceeds maximum of {}",
+                len,
+                MAX_STRING_LEN
+            ),
+            ErrorKind::ParseS9pk,
+        ));
+    }
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf).await?;
+    String::from_utf8(buf).with_kind(ErrorKind::ParseS9pk)
+}
+
+#[tokio::test]
+async fn varint_roundtrip() {
+    for n in [0, 1, 0x7f, 0x80, 300, u32::MAX as u64, u64::MAX] {
+        let mut buf = Vec::new();
+        serialize_varint(n, &mut buf);
+        assert_eq!(deserialize_varint(&mut buf.as_slice()).await.unwrap(), n);
+    }
+    assert!(deserialize_varint(&mut [0xff; 10].as_slice())
+        .await
+        .is_err());
+}
@@ -0,0 +1,111 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use async_compression::tokio::write::ZstdEncoder;
+use ed25519_dalek::SigningKey;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tracing::instrument;
+
+use super::toc::{Compression, DirectoryContents, FileContents};
+use super::Header;
+use crate::prelude::*;
+
+/// Builds a v2 s9pk. File contents are staged in a separate file,
+/// since the header (which precedes them) cannot be written until every file is known.
+pub struct S9pkV2Writer<W> {
+    writer: W,
+    body_path: PathBuf,
+    body: File,
+    toc: DirectoryContents,
+}
+impl<W: AsyncWrite + Unpin> S9pkV2Writer<W> {
+    pub async fn new(writer: W, body_path: impl AsRef<Path>) -> Result<Self, Error> {
+        let body_path = body_path.as_ref().to_owned();
+        let body = File::create(&body_path)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, body_path.display().to_string()))?;
+        Ok(S9pkV2Writer {
+            writer,
+            body_path,
+            body,
+            toc: DirectoryContents::default(),
+        })
+    }
+
+    #[instrument(skip(self, rdr))]
+    pub async fn add_file(
+        &mut self,
+        path: &str,
+        mime: &str,
+        compression: Compression,
+        mut rdr: impl AsyncRead + Unpin,
+    ) -> Result<(), Error> {
+        let position = self.body.stream_position().await?;
+        let mut hasher = blake3::Hasher::new();
+        let size = async {
+            match compression {
+                Compression::None => copy_hashed(&mut rdr, &mut self.body, &mut hasher).await,
+                Compression::Zstd => {
+                    let mut encoder = ZstdEncoder::new(&mut self.body);
+                    let size = copy_hashed(&mut rdr, &mut encoder, &mut hasher).await?;
+                    encoder.shutdown().await?;
+                    Ok(size)
+                }
+            }
+        }
+        .await
+        .with_ctx(|_| (ErrorKind::Pack, format!("Copying {}", path)))?;
+        let length = self.body.stream_position().await? - position;
+        self.toc.insert_file(
+            path,
+            FileContents {
+                mime: mime.to_owned(),
+                compression,
+                position,
+                length,
+                size,
+                hash: *hasher.finalize().as_bytes(),
+            },
+        )
+    }
+
+    /// Signs the TOC and writes out the archive
+    #[instrument(skip_all)]
+    pub async fn finish(mut self, key: &SigningKey) -> Result<(), Error> {
+        let header = Header::new(key, self.toc);
+        self.writer
+            .write_all(&header.to_vec())
+            .await
+            .with_ctx(|_| (ErrorKind::Serialization, "Writing Header"))?;
+
+        self.body.flush().await?;
+        self.body.seek(SeekFrom::Start(0)).await?;
+        tokio::io::copy(&mut self.body, &mut self.writer)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, "Copying Contents"))?;
+        self.writer.flush().await?;
+        drop(self.body);
+        tokio::fs::remove_file(&self.body_path).await?;
+
+        Ok(())
+    }
+}
+
+async fn copy_hashed(
+    rdr: &mut (impl AsyncRead + Unpin),
+    dst: &mut (impl AsyncWrite + Unpin),
+    hasher: &mut blake3::Hasher,
+) -> std::io::Result<u64> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = rdr.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(size);
+        }
+        hasher.update(&buf[..n]);
+        dst.write_all(&buf[..n]).await?;
+        size += n as u64;
+    }
+}
@@ -5,10 +5,12 @@ use std::time::Duration;
 use color_eyre::eyre::eyre;
 use josekit::jwk::Jwk;
 use openssl::x509::X509;
+use reqwest::Url;
 use rpc_toolkit::command;
 use rpc_toolkit::yajrc::RpcError;
 use serde::{Deserialize, Serialize};
 use sqlx::Connection;
+use ssh_key::public::Ed25519PublicKey;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 use tokio::try_join;
@@ -24,6 +26,8 @@ use crate::context::SetupContext;
 use crate::disk::fsck::RepairStrategy;
 use crate::disk::main::DEFAULT_PASSWORD;
 use crate::disk::mount::filesystem::cifs::Cifs;
+use crate::disk::mount::filesystem::s3fs::S3;
+use crate::disk::mount::filesystem::sshfs::Sftp;
 use crate::disk::mount::filesystem::ReadWrite;
 use crate::disk::mount::guard::TmpMountGuard;
 use crate::disk::util::{pvscan, recovery_info, DiskInfo, EmbassyOsRecoveryInfo};
@@ -36,7 +40,9 @@ use crate::prelude::*;
 use crate::util::io::{dir_copy, dir_size, Counter};
 use crate::{Error, ErrorKind, ResultExt};
 
-#[command(subcommands(status, disk, attach, execute, cifs, complete, get_pubkey, exit))]
+#[command(subcommands(
+    status, disk, attach, execute, cifs, s3, sftp, complete, get_pubkey, exit
+))]
 pub fn setup() -> Result<(), Error> {
     Ok(())
 }
@@ -214,6 +220,77 @@ pub async fn verify_cifs(
     embassy_os.ok_or_else(|| Error::new(eyre!("No Backup Found"), crate::ErrorKind::NotFound))
 }
 
+#[command(subcommands(verify_s3))]
+pub fn s3() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(rename = "verify", rpc_only)]
+pub async fn verify_s3(
+    #[context] ctx: SetupContext,
+    #[arg] endpoint: Url,
+    #[arg] bucket: String,
+    #[arg] prefix: Option<String>,
+    #[arg(rename = "access-key-id")] access_key_id: String,
+    #[arg(rename = "secret-access-key")] secret_access_key: EncryptedWire,
+) -> Result<EmbassyOsRecoveryInfo, Error> {
+    let secret_access_key = secret_access_key.decrypt(&*ctx).ok_or_else(|| {
+        Error::new(
+            eyre!("Couldn't decode secret-access-key"),
+            crate::ErrorKind::InvalidRequest,
+        )
+    })?;
+    let guard = TmpMountGuard::mount(
+        &S3 {
+            endpoint,
+            bucket,
+            prefix: prefix.unwrap_or_default(),
+            access_key_id,
+            secret_access_key,
+        },
+        ReadWrite,
+    )
+    .await?;
+    let embassy_os = recovery_info(&guard).await?;
+    guard.unmount().await?;
+    embassy_os.ok_or_else(|| Error::new(eyre!("No Backup Found"), crate::ErrorKind::NotFound))
+}
+
+#[command(subcommands(get_sftp_pubkey, verify_sftp))]
+pub fn sftp() -> Result<(), Error> {
+    Ok(())
+}
+
+/// The public key a remote host must authorize for the server to recover from it over SFTP
+#[command(rename = "get-pubkey", rpc_only)]
+pub async fn get_sftp_pubkey(#[context] ctx: SetupContext) -> Result<String, Error> {
+    Ok(ssh_key::PublicKey::from(Ed25519PublicKey::from(&ctx.sftp_key.ssh_key())).to_openssh()?)
+}
+
+#[command(rename = "verify", rpc_only)]
+pub async fn verify_sftp(
+    #[context] ctx: SetupContext,
+    #[arg] hostname: String,
+    #[arg] port: Option<u16>,
+    #[arg] path: PathBuf,
+    #[arg] username: String,
+) -> Result<EmbassyOsRecoveryInfo, Error> {
+    let guard = TmpMountGuard::mount(
+        &Sftp {
+            hostname,
+            port: port.unwrap_or(22),
+            path,
+            username,
+            key: Some(ctx.sftp_key.clone()),
+        },
+        ReadWrite,
+    )
+    .await?;
+    let embassy_os = recovery_info(&guard).await?;
+    guard.unmount().await?;
+    embassy_os.ok_or_else(|| Error::new(eyre!("No Backup Found"), crate::ErrorKind::NotFound))
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "kebab-case")]
@@ -398,9 +475,12 @@ async fn recover(
     ctx: SetupContext,
     guid: Arc<String>,
     embassy_password: String,
-    recovery_source: BackupTargetFS,
+    mut recovery_source: BackupTargetFS,
     recovery_password: Option<String>,
 ) -> Result<(Arc<String>, Hostname, OnionAddressV3, X509), Error> {
+    if let BackupTargetFS::Sftp(sftp) = &mut recovery_source {
+        sftp.key.get_or_insert_with(|| ctx.sftp_key.clone());
+    }
     let recovery_source = TmpMountGuard::mount(&recovery_source, ReadWrite).await?;
     recover_full_embassy(
         ctx,
@@ -1,14 +1,19 @@
 use std::collections::{BTreeMap, BTreeSet};
+use std::net::Ipv4Addr;
 
 use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
 pub use models::HealthCheckId;
-use models::ImageId;
+use models::{ActionId, ImageId};
+use patch_db::Value;
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
+use crate::action::{Actions, DockerStatus};
 use crate::context::RpcContext;
 use crate::procedure::{NoOutput, PackageProcedure, ProcedureName};
 use crate::s9pk::manifest::PackageId;
+use crate::status::probe::Probe;
 use crate::util::serde::Duration;
 use crate::util::Version;
 use crate::volume::Volumes;
@@ -23,17 +28,29 @@ impl HealthChecks {
         eos_version: &Version,
         volumes: &Volumes,
         image_ids: &BTreeSet<ImageId>,
+        actions: &Actions,
     ) -> Result<(), Error> {
         for check in self.0.values() {
-            check
-                .implementation
-                .validate(eos_version, volumes, image_ids, false)
-                .with_ctx(|_| {
+            match &check.implementation {
+                HealthCheckImplementation::Probe(probe) => probe.validate(),
+                HealthCheckImplementation::Procedure(procedure) => {
+                    procedure.validate(eos_version, volumes, image_ids, false)
+                }
+            }
+            .with_ctx(|_| {
+                (
+                    crate::ErrorKind::ValidateS9pk,
+                    format!("Health Check {}", check.name),
+                )
+            })?;
+            if let Some(remediation) = &check.remediation {
+                remediation.validate(actions).with_ctx(|_| {
                     (
                         crate::ErrorKind::ValidateS9pk,
-                        format!("Health Check {}", check.name),
+                        format!("Health Check {} Remediation", check.name),
                     )
                 })?;
+            }
         }
         Ok(())
     }
@@ -44,12 +61,13 @@ impl HealthChecks {
         pkg_id: &PackageId,
         pkg_version: &Version,
         volumes: &Volumes,
+        ip: Option<Ipv4Addr>,
     ) -> Result<BTreeMap<HealthCheckId, HealthCheckResult>, Error> {
         let res = futures::future::try_join_all(self.0.iter().map(|(id, check)| async move {
             Ok::<_, Error>((
                 id.clone(),
                 check
-                    .check(ctx, id, started, pkg_id, pkg_version, volumes)
+                    .check(ctx, id, started, pkg_id, pkg_version, volumes, ip)
                     .await?,
             ))
         }))
@@ -58,14 +76,54 @@ impl HealthChecks {
     }
 }
 
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum HealthCheckImplementation {
+    Probe(Probe),
+    Procedure(PackageProcedure),
+}
+impl<'de> Deserialize<'de> for HealthCheckImplementation {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Tag {
+            #[serde(rename = "type")]
+            ty: String,
+        }
+
+        let value = Value::deserialize(deserializer)?;
+        let tag: Tag =
+            patch_db::value::from_value(value.clone()).map_err(serde::de::Error::custom)?;
+        match tag.ty.as_str() {
+            "http" | "tcp" | "tls" => patch_db::value::from_value(value).map(Self::Probe),
+            "docker" | "script" => patch_db::value::from_value(value).map(Self::Procedure),
+            ty => {
+                return Err(serde::de::Error::unknown_variant(
+                    ty,
+                    &["http", "tcp", "tls", "docker", "script"],
+                ))
+            }
+        }
+        .map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct HealthCheck {
     pub name: String,
     pub success_message: Option<String>,
     #[serde(flatten)]
-    implementation: PackageProcedure,
+    implementation: HealthCheckImplementation,
     pub timeout: Option<Duration>,
+    /// Probe failures within this long after the service started are reported as starting.
+    /// Procedures are given the time since the service started to decide on their own
+    #[serde(default)]
+    pub start_period: Option<Duration>,
+    #[serde(default)]
+    pub remediation: Option<Remediation>,
 }
 impl HealthCheck {
     #[instrument(skip_all)]
@@ -77,9 +135,33 @@ impl HealthCheck {
         pkg_id: &PackageId,
         pkg_version: &Version,
         volumes: &Volumes,
+        ip: Option<Ipv4Addr>,
     ) -> Result<HealthCheckResult, Error> {
-        let res = self
-            .implementation
+        let timeout = self
+            .timeout
+            .map_or(std::time::Duration::from_secs(30), |d| *d);
+        let procedure = match &self.implementation {
+            HealthCheckImplementation::Procedure(procedure) => procedure,
+            HealthCheckImplementation::Probe(probe) => {
+                let Some(ip) = ip else {
+                    return Ok(HealthCheckResult::Failure {
+                        error: "The service has no LAN address to probe".to_owned(),
+                    });
+                };
+                let res = probe.check(ip, timeout).await;
+                let starting = self.start_period.map_or(false, |period| {
+                    Utc::now()
+                        .signed_duration_since(started)
+                        .to_std()
+                        .map_or(true, |elapsed| elapsed < *period)
+                });
+                return Ok(match res {
+                    HealthCheckResult::Failure { .. } if starting => HealthCheckResult::Starting,
+                    res => res,
+                });
+            }
+        };
+        let res = procedure
             .execute(
                 ctx,
                 pkg_id,
@@ -87,10 +169,7 @@ impl HealthCheck {
                 ProcedureName::Health(id.clone()),
                 volumes,
                 Some(Utc::now().signed_duration_since(started).num_milliseconds()),
-                Some(
-                    self.timeout
-                        .map_or(std::time::Duration::from_secs(30), |d| *d),
-                ),
+                Some(timeout),
             )
             .await?;
         Ok(match res {
@@ -103,6 +182,90 @@ impl HealthCheck {
     }
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(tag = "type")]
+pub enum RemediationAction {
+    /// Restart the service
+    Restart,
+    /// Run an action of the package, which must be allowed while the service is running and take no input
+    Action { action_id: ActionId },
+}
+impl std::fmt::Display for RemediationAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemediationAction::Restart => write!(f, "restart"),
+            RemediationAction::Action { action_id } => write!(f, "action {}", action_id),
+        }
+    }
+}
+
+fn default_remediate_after() -> u32 {
+    3
+}
+
+fn default_remediation_cooldown() -> Duration {
+    std::time::Duration::from_secs(10 * 60).into()
+}
+
+fn default_remediations_per_day() -> u32 {
+    6
+}
+
+/// What the manager does on its own when a health check keeps failing
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Remediation {
+    #[serde(flatten)]
+    pub action: RemediationAction,
+    /// Consecutive failures of the health check before remediating
+    #[serde(default = "default_remediate_after")]
+    pub after: u32,
+    /// Minimum time between two remediations
+    #[serde(default = "default_remediation_cooldown")]
+    pub cooldown: Duration,
+    #[serde(default = "default_remediations_per_day")]
+    pub max_per_day: u32,
+}
+impl Remediation {
+    /// With the defaults of a manifest that only names the action
+    pub fn new(action: RemediationAction) -> Self {
+        Self {
+            action,
+            after: default_remediate_after(),
+            cooldown: default_remediation_cooldown(),
+            max_per_day: default_remediations_per_day(),
+        }
+    }
+
+    pub fn validate(&self, actions: &Actions) -> Result<(), Error> {
+        if self.after == 0 {
+            return Err(Error::new(
+                eyre!("after must be at least 1"),
+                crate::ErrorKind::ValidateS9pk,
+            ));
+        }
+        if let RemediationAction::Action { action_id } = &self.action {
+            let action = actions.0.get(action_id).ok_or_else(|| {
+                Error::new(
+                    eyre!("unknown action {}", action_id),
+                    crate::ErrorKind::ValidateS9pk,
+                )
+            })?;
+            if !action.allowed_statuses.contains(&DockerStatus::Running) {
+                return Err(Error::new(
+                    eyre!(
+                        "action {} cannot run while the service is running",
+                        action_id
+                    ),
+                    crate::ErrorKind::ValidateS9pk,
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 #[serde(tag = "result")]
@@ -124,3 +287,95 @@ impl std::fmt::Display for HealthCheckResult {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn health_checks() -> serde_json::Value {
+        json!({
+            "web": {
+                "name": "Web Interface",
+                "success-message": "The web interface is ready",
+                "type": "http",
+                "port": 8080,
+                "path": "/health",
+                "body": "^ok",
+                "timeout": "5s",
+                "start-period": "1m",
+            },
+            "sync": {
+                "name": "Synced",
+                "type": "docker",
+                "image": "main",
+                "entrypoint": "check-synced.sh",
+                "args": ["--quiet"],
+                "io-format": "yaml",
+                "inject": true,
+                "remediation": {
+                    "type": "restart",
+                    "after": 5,
+                },
+            },
+        })
+    }
+
+    fn assert_checks(checks: &HealthChecks) {
+        let web = &checks.0[&"web".parse::<HealthCheckId>().unwrap()];
+        match &web.implementation {
+            HealthCheckImplementation::Probe(Probe::Http {
+                port, path, body, ..
+            }) => {
+                assert_eq!(*port, 8080);
+                assert_eq!(path, "/health");
+                assert!(body.as_ref().unwrap().as_ref().is_match("ok"));
+            }
+            a => panic!("expected http probe, got {:?}", a),
+        }
+        assert_eq!(
+            web.timeout.map(|t| *t),
+            Some(std::time::Duration::from_secs(5))
+        );
+        assert_eq!(
+            web.start_period.map(|t| *t),
+            Some(std::time::Duration::from_secs(60))
+        );
+        let sync = &checks.0[&"sync".parse::<HealthCheckId>().unwrap()];
+        match &sync.implementation {
+            HealthCheckImplementation::Procedure(PackageProcedure::Docker(docker)) => {
+                assert_eq!(docker.entrypoint, "check-synced.sh");
+                assert_eq!(docker.args, vec!["--quiet".to_owned()]);
+                assert!(docker.inject);
+            }
+            a => panic!("expected docker procedure, got {:?}", a),
+        }
+        assert_eq!(sync.name, "Synced");
+        assert_eq!(sync.remediation.as_ref().map(|r| r.after), Some(5));
+    }
+
+    #[test]
+    fn deserialize_probe_and_procedure() {
+        let checks: HealthChecks = serde_json::from_value(health_checks()).unwrap();
+        assert_checks(&checks);
+        let checks: HealthChecks =
+            serde_json::from_value(serde_json::to_value(&checks).unwrap()).unwrap();
+        assert_checks(&checks);
+    }
+
+    #[test]
+    fn reject_invalid_body_regex() {
+        let mut checks = health_checks();
+        checks["web"]["body"] = json!("(unclosed");
+        assert!(serde_json::from_value::<HealthChecks>(checks).is_err());
+    }
+
+    #[test]
+    fn reject_probe_without_port() {
+        let mut checks = health_checks();
+        checks["web"].as_object_mut().unwrap().remove("port");
+        let err = serde_json::from_value::<HealthChecks>(checks).unwrap_err();
+        assert!(err.to_string().contains("port"), "{}", err);
+    }
+}
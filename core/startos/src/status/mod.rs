@@ -4,11 +4,16 @@ use chrono::{DateTime, Utc};
 use models::PackageId;
 use serde::{Deserialize, Serialize};
 
-use self::health_check::HealthCheckId;
+use self::health_check::{HealthCheckId, Remediation};
+use self::restart::{Crashes, RestartPolicy};
+use crate::manager::resources::ResourceLimits;
 use crate::prelude::*;
 use crate::status::health_check::HealthCheckResult;
 
 pub mod health_check;
+pub mod probe;
+pub mod restart;
+
 #[derive(Clone, Debug, Deserialize, Serialize, HasModel)]
 #[serde(rename_all = "kebab-case")]
 #[model = "Model<Self>"]
@@ -17,6 +22,17 @@ pub struct Status {
     pub main: MainStatus,
     #[serde(default)]
     pub dependency_config_errors: DependencyConfigErrors,
+    #[serde(default)]
+    pub crashes: Crashes,
+    /// Set by the user, in place of the restart policy of the manifest
+    #[serde(default)]
+    pub restart_policy: Option<RestartPolicy>,
+    /// Set by the user, in place of the resource limits of the manifest
+    #[serde(default)]
+    pub resource_limits: Option<ResourceLimits>,
+    /// Set by the user, in place of the remediations of the manifest. `None` turns off remediating a health check
+    #[serde(default)]
+    pub remediations: BTreeMap<HealthCheckId, Option<Remediation>>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, HasModel, Default)]
@@ -44,6 +60,11 @@ pub enum MainStatus {
         started: Option<DateTime<Utc>>,
         health: BTreeMap<HealthCheckId, HealthCheckResult>,
     },
+    /// Waiting to restart after the main process crashed
+    CrashLooping {
+        crashes: u32,
+        restart_at: DateTime<Utc>,
+    },
 }
 impl MainStatus {
     pub fn running(&self) -> bool {
@@ -56,6 +77,7 @@ impl MainStatus {
             MainStatus::Stopped
             | MainStatus::Stopping
             | MainStatus::Restarting
+            | MainStatus::CrashLooping { .. }
             | MainStatus::BackingUp { started: None, .. } => false,
         }
     }
@@ -67,6 +89,9 @@ impl MainStatus {
             MainStatus::BackingUp { started, .. } => {
                 *started = None;
             }
+            MainStatus::CrashLooping { .. } => {
+                *self = MainStatus::Stopped;
+            }
             MainStatus::Stopped | MainStatus::Stopping | MainStatus::Restarting => (),
         }
     }
@@ -78,15 +103,17 @@ impl MainStatus {
             MainStatus::Restarting => None,
             MainStatus::Stopping => None,
             MainStatus::Starting { .. } => None,
+            MainStatus::CrashLooping { .. } => None,
         }
     }
     pub fn backing_up(&self) -> Self {
         let (started, health) = match self {
             MainStatus::Starting { .. } => (Some(Utc::now()), Default::default()),
             MainStatus::Running { started, health } => (Some(started.clone()), health.clone()),
-            MainStatus::Stopped | MainStatus::Stopping | MainStatus::Restarting => {
-                (None, Default::default())
-            }
+            MainStatus::Stopped
+            | MainStatus::Stopping
+            | MainStatus::Restarting
+            | MainStatus::CrashLooping { .. } => (None, Default::default()),
             MainStatus::BackingUp { .. } => return self.clone(),
         };
         MainStatus::BackingUp { started, health }
@@ -0,0 +1,302 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+
+use crate::prelude::*;
+use crate::status::health_check::HealthCheckResult;
+use crate::util::serde::Regex;
+
+lazy_static! {
+    static ref HTTP_PROBE_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+}
+
+/// Only this much of a response body is read and matched against `body`
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+fn default_path() -> String {
+    "/".to_owned()
+}
+
+/// A health check evaluated by the server itself against the LAN address of the service,
+/// instead of running a procedure in the container
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(tag = "type")]
+pub enum Probe {
+    #[serde(rename_all = "kebab-case")]
+    Http {
+        port: u16,
+        #[serde(default = "default_path")]
+        path: String,
+        /// Any 2xx or 3xx status if not set
+        #[serde(default)]
+        expected_status: Option<u16>,
+        /// Regular expression the response body has to match. Only the first 64 KiB are matched
+        #[serde(default)]
+        body: Option<Regex>,
+    },
+    Tcp {
+        port: u16,
+    },
+    /// Succeeds once a TLS handshake completes. The certificate is not verified
+    Tls {
+        port: u16,
+    },
+}
+impl Probe {
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            Probe::Http { path, .. } => {
+                if !path.starts_with('/') {
+                    return Err(Error::new(
+                        eyre!("path must start with /"),
+                        ErrorKind::ValidateS9pk,
+                    ));
+                }
+                Ok(())
+            }
+            Probe::Tcp { .. } | Probe::Tls { .. } => Ok(()),
+        }
+    }
+
+    pub async fn check(&self, ip: Ipv4Addr, timeout: Duration) -> HealthCheckResult {
+        match tokio::time::timeout(timeout, self.probe(ip, timeout)).await {
+            Ok(Ok(())) => HealthCheckResult::Success,
+            Ok(Err(e)) => HealthCheckResult::Failure {
+                error: e.source.to_string(),
+            },
+            Err(_) => HealthCheckResult::Failure {
+                error: format!(
+                    "timed out after {}",
+                    crate::util::serde::Duration::from(timeout)
+                ),
+            },
+        }
+    }
+
+    async fn probe(&self, ip: Ipv4Addr, timeout: Duration) -> Result<(), Error> {
+        match self {
+            Probe::Http {
+                port,
+                path,
+                expected_status,
+                body,
+            } => {
+                let mut res = HTTP_PROBE_CLIENT
+                    .get(format!("http://{}:{}{}", ip, port, path))
+                    .send()
+                    .await?;
+                let status = res.status();
+                let expected = expected_status.map_or_else(
+                    || status.is_success() || status.is_redirection(),
+                    |s| status.as_u16() == s,
+                );
+                if !expected {
+                    return Err(Error::new(
+                        eyre!("unexpected status {}", status),
+                        ErrorKind::Network,
+                    ));
+                }
+                if let Some(body) = body {
+                    let mut buf = Vec::new();
+                    while let Some(chunk) = res.chunk().await? {
+                        let len = chunk.len().min(MAX_BODY_LEN - buf.len());
+                        buf.extend_from_slice(&chunk[..len]);
+                        if buf.len() >= MAX_BODY_LEN {
+                            break;
+                        }
+                    }
+                    if !body.as_ref().is_match(&String::from_utf8_lossy(&buf)) {
+                        return Err(Error::new(
+                            eyre!("response body does not match {}", body.as_ref()),
+                            ErrorKind::Network,
+                        ));
+                    }
+                }
+                Ok(())
+            }
+            Probe::Tcp { port } => {
+                TcpStream::connect(SocketAddr::from((ip, *port))).await?;
+                Ok(())
+            }
+            Probe::Tls { port } => {
+                let stream = TcpStream::connect(SocketAddr::from((ip, *port)))
+                    .await?
+                    .into_std()?;
+                tokio::task::spawn_blocking(move || {
+                    stream.set_nonblocking(false)?;
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    let mut connector = SslConnector::builder(SslMethod::tls_client())?;
+                    connector.set_verify(SslVerifyMode::NONE);
+                    connector
+                        .build()
+                        .configure()?
+                        .use_server_name_indication(false)
+                        .verify_hostname(false)
+                        .connect(&ip.to_string(), stream)
+                        .map_err(|e| Error::new(eyre!("{}", e), ErrorKind::OpenSsl))?;
+                    Ok::<_, Error>(())
+                })
+                .await
+                .with_kind(ErrorKind::Unknown)?
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::util::NonDetachingJoinHandle;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Answers every request with `status` and `body` until the returned handle is dropped
+    async fn serve(status: &'static str, body: &'static str) -> (u16, NonDetachingJoinHandle<()>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut req = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !req.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => req.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let _ = stream
+                        .write_all(
+                            format!(
+                                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                status,
+                                body.len(),
+                                body
+                            )
+                            .as_bytes(),
+                        )
+                        .await;
+                });
+            }
+        });
+        (port, server.into())
+    }
+
+    fn http(port: u16, expected_status: Option<u16>, body: Option<&str>) -> Probe {
+        Probe::Http {
+            port,
+            path: "/health".to_owned(),
+            expected_status,
+            body: body.map(|b| regex::Regex::new(b).unwrap().into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_probe() {
+        let (port, _server) = serve("200 OK", "").await;
+        assert_eq!(
+            Probe::Tcp { port }
+                .check(Ipv4Addr::LOCALHOST, TIMEOUT)
+                .await,
+            HealthCheckResult::Success
+        );
+        let closed = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        assert!(matches!(
+            Probe::Tcp { port: closed }
+                .check(Ipv4Addr::LOCALHOST, TIMEOUT)
+                .await,
+            HealthCheckResult::Failure { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn http_probe_status() {
+        let (port, _server) = serve("200 OK", "ready").await;
+        assert_eq!(
+            http(port, None, None)
+                .check(Ipv4Addr::LOCALHOST, TIMEOUT)
+                .await,
+            HealthCheckResult::Success
+        );
+        assert_eq!(
+            http(port, Some(200), None)
+                .check(Ipv4Addr::LOCALHOST, TIMEOUT)
+                .await,
+            HealthCheckResult::Success
+        );
+        assert!(matches!(
+            http(port, Some(204), None)
+                .check(Ipv4Addr::LOCALHOST, TIMEOUT)
+                .await,
+            HealthCheckResult::Failure { .. }
+        ));
+
+        let (port, _server) = serve("503 Service Unavailable", "").await;
+        assert!(matches!(
+            http(port, None, None)
+                .check(Ipv4Addr::LOCALHOST, TIMEOUT)
+                .await,
+            HealthCheckResult::Failure { .. }
+        ));
+        assert_eq!(
+            http(port, Some(503), None)
+                .check(Ipv4Addr::LOCALHOST, TIMEOUT)
+                .await,
+            HealthCheckResult::Success
+        );
+    }
+
+    #[tokio::test]
+    async fn http_probe_body() {
+        let (port, _server) = serve("200 OK", "status: ready").await;
+        assert_eq!(
+            http(port, None, Some("ready$"))
+                .check(Ipv4Addr::LOCALHOST, TIMEOUT)
+                .await,
+            HealthCheckResult::Success
+        );
+        assert!(matches!(
+            http(port, None, Some("^ready"))
+                .check(Ipv4Addr::LOCALHOST, TIMEOUT)
+                .await,
+            HealthCheckResult::Failure { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn http_probe_body_is_capped() {
+        let body: &'static str =
+            Box::leak(format!("{}ready", " ".repeat(MAX_BODY_LEN)).into_boxed_str());
+        let (port, _server) = serve("200 OK", body).await;
+        assert!(matches!(
+            http(port, None, Some("ready"))
+                .check(Ipv4Addr::LOCALHOST, TIMEOUT)
+                .await,
+            HealthCheckResult::Failure { .. }
+        ));
+        assert_eq!(
+            http(port, None, Some("^ +$"))
+                .check(Ipv4Addr::LOCALHOST, TIMEOUT)
+                .await,
+            HealthCheckResult::Success
+        );
+    }
+}
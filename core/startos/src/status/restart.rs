@@ -0,0 +1,112 @@
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+use crate::util::serde::Duration;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartMode {
+    /// Whenever the main process exits
+    #[default]
+    Always,
+    /// When the main process exits with an error
+    OnFailure,
+    Never,
+}
+impl std::str::FromStr for RestartMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "on-failure" => Ok(Self::OnFailure),
+            "never" => Ok(Self::Never),
+            _ => Err(Error::new(
+                eyre!("unknown restart mode {}", s),
+                ErrorKind::Deserialization,
+            )),
+        }
+    }
+}
+
+fn default_backoff() -> Duration {
+    StdDuration::from_secs(15).into()
+}
+
+fn default_max_backoff() -> Duration {
+    StdDuration::from_secs(10 * 60).into()
+}
+
+/// What to do when the main process of a package exits
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RestartPolicy {
+    #[serde(default)]
+    pub mode: RestartMode,
+    /// Consecutive crashes after which the package is stopped. Unlimited if not set
+    #[serde(default)]
+    pub max_retries: Option<u32>,
+    /// Delay before restarting after the first crash, doubled for every consecutive crash
+    #[serde(default = "default_backoff")]
+    pub backoff: Duration,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: Duration,
+}
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            mode: RestartMode::default(),
+            max_retries: None,
+            backoff: default_backoff(),
+            max_backoff: default_max_backoff(),
+        }
+    }
+}
+impl RestartPolicy {
+    /// How long to wait before restarting after `crashes` consecutive crashes
+    pub fn delay(&self, crashes: u32) -> StdDuration {
+        self.backoff
+            .checked_mul(1u32 << crashes.saturating_sub(1).min(16))
+            .map_or(*self.max_backoff, |d| d.min(*self.max_backoff))
+    }
+
+    /// Whether to stop restarting after `crashes` consecutive crashes
+    pub fn gives_up(&self, crashes: u32) -> bool {
+        self.mode == RestartMode::Never || self.max_retries.map_or(false, |max| crashes > max)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, HasModel)]
+#[serde(rename_all = "kebab-case")]
+#[model = "Model<Self>"]
+pub struct Crashes {
+    /// Since the last time the package ran long enough to be considered stable
+    pub consecutive: u32,
+    pub total: u64,
+    pub last: Option<DateTime<Utc>>,
+    pub last_exit_code: Option<i32>,
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = RestartPolicy {
+            max_retries: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1), Duration::from_secs(15));
+        assert_eq!(policy.delay(2), Duration::from_secs(30));
+        assert_eq!(policy.delay(4), Duration::from_secs(120));
+        assert_eq!(policy.delay(8), Duration::from_secs(600));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(600));
+        assert!(!policy.gives_up(3));
+        assert!(policy.gives_up(4));
+    }
+}
@@ -1,5 +1,6 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
+use std::time::Instant;
 
 use chrono::Utc;
 use clap::ArgMatches;
@@ -19,7 +20,9 @@ use crate::logs::{
     cli_logs_generic_follow, cli_logs_generic_nofollow, fetch_logs, follow_logs, LogFollowResponse,
     LogResponse, LogSource,
 };
+use crate::manager::resources::{usage_all, CgroupUsage};
 use crate::prelude::*;
+use crate::s9pk::manifest::PackageId;
 use crate::shutdown::Shutdown;
 use crate::util::cpupower::{get_available_governors, set_governor, Governor};
 use crate::util::serde::{display_serializable, IoFormat};
@@ -444,6 +447,18 @@ pub struct MetricsDisk {
     available: GigaBytes,
     capacity: GigaBytes,
 }
+/// Usage of the containers of a package
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct MetricsPackage {
+    /// Of all CPU cores
+    cpu: Percentage,
+    memory: MebiBytes,
+    memory_limit: Option<MebiBytes>,
+    /// Since the package first started after boot
+    io_read: MebiBytes,
+    io_written: MebiBytes,
+}
 #[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct Metrics {
@@ -451,6 +466,8 @@ pub struct Metrics {
     memory: MetricsMemory,
     cpu: MetricsCpu,
     disk: MetricsDisk,
+    #[serde(default)]
+    packages: BTreeMap<PackageId, MetricsPackage>,
 }
 
 #[command(display(display_serializable))]
@@ -547,6 +564,7 @@ pub async fn launch_metrics_task<F: FnMut() -> Receiver<Option<Shutdown>>>(
             memory: init_mem,
             cpu: init_cpu,
             disk: init_disk,
+            packages: BTreeMap::new(),
         })
     }
 
@@ -569,6 +587,8 @@ pub async fn launch_metrics_task<F: FnMut() -> Receiver<Option<Shutdown>>>(
     task_vec.push(launch_mem_task(cache, mk_shutdown()).boxed());
     // launch persistent disk task
     task_vec.push(launch_disk_task(cache, mk_shutdown()).boxed());
+    // launch persistent package task
+    task_vec.push(launch_package_task(cache, mk_shutdown()).boxed());
 
     futures::future::join_all(task_vec).await;
 }
@@ -664,6 +684,59 @@ async fn launch_disk_task(
     }
 }
 
+async fn launch_package_task(
+    cache: &RwLock<Option<Metrics>>,
+    mut shutdown: Receiver<Option<Shutdown>>,
+) {
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get()) as f64;
+    let mut prev: Option<(Instant, BTreeMap<PackageId, CgroupUsage>)> = None;
+    loop {
+        // read the cgroups of the package slices, diff cpu time against the previous reading
+        match usage_all().await {
+            Ok(usage) => {
+                let now = Instant::now();
+                let packages = usage
+                    .iter()
+                    .map(|(id, u)| {
+                        let cpu = prev
+                            .as_ref()
+                            .and_then(|(at, prev)| Some((at, prev.get(id)?)))
+                            .map_or(0.0, |(at, p)| {
+                                u.cpu_usec.saturating_sub(p.cpu_usec) as f64
+                                    / (now - *at).as_micros().max(1) as f64
+                                    / cpus
+                                    * 100.0
+                            });
+                        (
+                            id.clone(),
+                            MetricsPackage {
+                                cpu: Percentage(cpu),
+                                memory: MebiBytes(u.memory as f64 / 1024.0 / 1024.0),
+                                memory_limit: u
+                                    .memory_max
+                                    .map(|m| MebiBytes(m as f64 / 1024.0 / 1024.0)),
+                                io_read: MebiBytes(u.io_read as f64 / 1024.0 / 1024.0),
+                                io_written: MebiBytes(u.io_written as f64 / 1024.0 / 1024.0),
+                            },
+                        )
+                    })
+                    .collect();
+                prev = Some((now, usage));
+                let mut lock = cache.write().await;
+                (*lock).as_mut().unwrap().packages = packages;
+            }
+            Err(e) => {
+                tracing::error!("Could not get new Package Metrics: {}", e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(4)) => (),
+        }
+    }
+}
+
 #[instrument(skip_all)]
 async fn get_temp() -> Result<Celsius, Error> {
     let temp = serde_json::from_slice::<serde_json::Value>(
@@ -0,0 +1,176 @@
+use chrono::Utc;
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use ed25519_dalek::VerifyingKey;
+use rpc_toolkit::command;
+use sqlx::{Executor, Postgres};
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::{Error, ErrorKind};
+
+/// An ed25519 package signing key, encoded as padded RFC4648 base32
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct DeveloperKey(#[serde(with = "crate::util::serde::ed25519_pubkey")] pub VerifyingKey);
+impl std::fmt::Display for DeveloperKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            base32::encode(
+                base32::Alphabet::RFC4648 { padding: true },
+                self.0.as_bytes()
+            )
+        )
+    }
+}
+impl std::str::FromStr for DeveloperKey {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = base32::decode(base32::Alphabet::RFC4648 { padding: true }, s)
+            .and_then(|b| <[u8; 32]>::try_from(b).ok())
+            .ok_or_else(|| {
+                Error::new(
+                    eyre!("Expected an RFC4648 encoded ed25519 key"),
+                    ErrorKind::Deserialization,
+                )
+            })?;
+        VerifyingKey::from_bytes(&bytes)
+            .map(DeveloperKey)
+            .map_err(|e| Error::new(e, ErrorKind::Deserialization))
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TrustedKeyResponse {
+    pub key: DeveloperKey,
+    pub name: String,
+    pub created_at: String,
+}
+
+/// Keys trusted to sign packages. While none are trusted, packages signed by any key may be installed.
+#[command(subcommands(add, remove, list))]
+pub fn trust() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] key: DeveloperKey,
+    #[arg] name: String,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<TrustedKeyResponse, Error> {
+    let pubkey = key.to_string();
+    let created_at = Utc::now().to_rfc3339();
+    let n = sqlx::query!(
+        "INSERT INTO trusted_keys (pubkey, name, created_at) VALUES ($1, $2, $3) ON CONFLICT (pubkey) DO NOTHING",
+        pubkey,
+        name,
+        created_at
+    )
+    .execute(&ctx.secret_store)
+    .await?
+    .rows_affected();
+    if n == 0 {
+        return Err(Error::new(
+            eyre!("Duplicate trusted key"),
+            ErrorKind::Duplicate,
+        ));
+    }
+    Ok(TrustedKeyResponse {
+        key,
+        name,
+        created_at,
+    })
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn remove(#[context] ctx: RpcContext, #[arg] key: DeveloperKey) -> Result<(), Error> {
+    let pubkey = key.to_string();
+    let n = sqlx::query!("DELETE FROM trusted_keys WHERE pubkey = $1", pubkey)
+        .execute(&ctx.secret_store)
+        .await?
+        .rows_affected();
+    if n == 0 {
+        return Err(Error::new(
+            eyre!("Trusted Key Not Found"),
+            ErrorKind::NotFound,
+        ));
+    }
+    Ok(())
+}
+
+fn display_trusted_keys(all: Vec<TrustedKeyResponse>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(all, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "CREATED AT", "NAME", "KEY"]);
+    for key in all {
+        table.add_row(row![&key.created_at, &key.name, &key.key.to_string()]);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_trusted_keys))]
+#[instrument(skip_all)]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<TrustedKeyResponse>, Error> {
+    sqlx::query!("SELECT pubkey, name, created_at FROM trusted_keys")
+        .fetch_all(&ctx.secret_store)
+        .await?
+        .into_iter()
+        .map(|r| {
+            Ok(TrustedKeyResponse {
+                key: r.pubkey.parse()?,
+                name: r.name,
+                created_at: r.created_at,
+            })
+        })
+        .collect()
+}
+
+/// Fails unless one of the signers of a package is trusted, or no keys are trusted at all.
+/// `signers` must only contain keys whose signatures have been verified.
+#[instrument(skip_all)]
+pub async fn check_signers<Ex>(secrets: &mut Ex, signers: &[VerifyingKey]) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    let trusted = sqlx::query!("SELECT pubkey FROM trusted_keys")
+        .fetch_all(&mut *secrets)
+        .await?;
+    if trusted.is_empty() {
+        return Ok(());
+    }
+    let signers = signers
+        .iter()
+        .map(|k| DeveloperKey(*k).to_string())
+        .collect::<Vec<_>>();
+    if trusted.iter().any(|r| signers.contains(&r.pubkey)) {
+        Ok(())
+    } else {
+        Err(Error::new(
+            eyre!(
+                "Package is signed by [{}], none of which are trusted",
+                signers.join(", ")
+            ),
+            ErrorKind::UntrustedSignature,
+        ))
+    }
+}
@@ -1,4 +1,5 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 use std::time::Duration;
 
 use models::{Error, ErrorKind, PackageId, ResultExt, Version};
@@ -74,12 +75,29 @@ pub async fn prune_images() -> Result<(), Error> {
 
 // docker container inspect ${name} --format '{{.NetworkSettings.Networks.start9.IPAddress}}'
 pub async fn get_container_ip(name: &str) -> Result<Option<Ipv4Addr>, Error> {
+    inspect_container_ip(name, "{{.NetworkSettings.Networks.start9.IPAddress}}").await
+}
+
+/// Only set if IPv6 is enabled for the `start9` network
+pub async fn get_container_ipv6(name: &str) -> Result<Option<Ipv6Addr>, Error> {
+    inspect_container_ip(
+        name,
+        "{{.NetworkSettings.Networks.start9.GlobalIPv6Address}}",
+    )
+    .await
+}
+
+async fn inspect_container_ip<T>(name: &str, format: &str) -> Result<Option<T>, Error>
+where
+    T: FromStr,
+    color_eyre::eyre::Error: From<T::Err>,
+{
     match Command::new(CONTAINER_TOOL)
         .arg("container")
         .arg("inspect")
         .arg(name)
         .arg("--format")
-        .arg("{{.NetworkSettings.Networks.start9.IPAddress}}")
+        .arg(format)
         .invoke(ErrorKind::Docker)
         .await
     {
@@ -112,6 +112,24 @@ pub fn backup_dir(pkg_id: &PackageId) -> PathBuf {
     Path::new(BACKUP_DIR).join(pkg_id).join("data")
 }
 
+/// Local copy of the backup of a package while it is chunked into a snapshot, or restored from one
+pub fn backup_staging_dir<P: AsRef<Path>>(datadir: P, pkg_id: &PackageId) -> PathBuf {
+    datadir
+        .as_ref()
+        .join(PKG_VOLUME_DIR)
+        .join(pkg_id)
+        .join("backup")
+}
+
+/// Copy of the data volumes, s9pk and db entry of a package from before its last update
+pub fn rollback_dir<P: AsRef<Path>>(datadir: P, pkg_id: &PackageId) -> PathBuf {
+    datadir
+        .as_ref()
+        .join(PKG_VOLUME_DIR)
+        .join(pkg_id)
+        .join("rollback")
+}
+
 pub fn cert_dir(pkg_id: &PackageId, interface_id: &InterfaceId) -> PathBuf {
     Path::new(PACKAGE_CERT_PATH).join(pkg_id).join(interface_id)
 }
@@ -261,6 +261,7 @@ export type LanConfig = {
 export interface BackupActions {
   create: ActionImpl
   restore: ActionImpl
+  volumes?: { [path: string]: string } // path in the backup volume -> volume id
 }
 
 export interface Migrations {
@@ -290,6 +291,7 @@ export type MainStatus =
   | MainStatusRunning
   | MainStatusBackingUp
   | MainStatusRestarting
+  | MainStatusCrashLooping
 
 export interface MainStatusStopped {
   status: PackageMainStatus.Stopped
@@ -319,6 +321,12 @@ export interface MainStatusRestarting {
   status: PackageMainStatus.Restarting
 }
 
+export interface MainStatusCrashLooping {
+  status: PackageMainStatus.CrashLooping
+  crashes: number
+  'restart-at': string // UTC date string
+}
+
 export enum PackageMainStatus {
   Starting = 'starting',
   Running = 'running',
@@ -326,6 +334,7 @@ export enum PackageMainStatus {
   Stopped = 'stopped',
   BackingUp = 'backing-up',
   Restarting = 'restarting',
+  CrashLooping = 'crash-looping',
 }
 
 export type HealthCheckResult =
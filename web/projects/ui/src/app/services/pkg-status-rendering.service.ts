@@ -100,6 +100,7 @@ export enum PrimaryStatus {
   Restarting = 'restarting',
   Stopped = 'stopped',
   BackingUp = 'backing-up',
+  CrashLooping = 'crash-looping',
   // config
   NeedsConfig = 'needs-config',
 }
@@ -158,6 +159,11 @@ export const PrimaryRendering: Record<string, StatusRendering> = {
     color: 'primary',
     showDots: true,
   },
+  [PrimaryStatus.CrashLooping]: {
+    display: 'Crashed, Restarting',
+    color: 'danger',
+    showDots: true,
+  },
   [PrimaryStatus.Starting]: {
     display: 'Starting',
     color: 'primary',